@@ -0,0 +1,55 @@
+//! Compares the tree-walking evaluator against the bytecode VM on a recursive fibonacci.
+//!
+//! Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+use monkey_interpreter::compiler::Compiler;
+use monkey_interpreter::evaluator;
+use monkey_interpreter::lexer::{Lexer, RawMonkeyProgram};
+use monkey_interpreter::object::{Environment, Object};
+use monkey_interpreter::parser::{Parser, Program};
+use monkey_interpreter::vm::Vm;
+
+const INPUT: &str = "
+let fibonacci = fn(x) {
+    if (x == 0) {
+        0
+    } else {
+        if (x == 1) {
+            return 1;
+        } else {
+            fibonacci(x - 1) + fibonacci(x - 2);
+        }
+    }
+};
+fibonacci(27);
+";
+
+fn evaluate(program: &Program) -> Object {
+    evaluator::eval_program(program, &Environment::new())
+}
+
+fn compile_and_run(program: &Program) -> Object {
+    let mut compiler = Compiler::new();
+    compiler.compile(program).expect("benchmark compiles");
+    let mut vm = Vm::new(compiler.bytecode());
+    vm.run().expect("benchmark runs");
+    vm.last_popped_stack_elem().clone()
+}
+
+fn time(name: &str, program: &Program, backend: fn(&Program) -> Object) -> Duration {
+    let start = Instant::now();
+    let result = backend(program);
+    let elapsed = start.elapsed();
+    println!("  {:<10} {:>10.2?}  result={}", name, elapsed, result);
+    elapsed
+}
+
+fn main() {
+    let p = RawMonkeyProgram::new(INPUT);
+    let program = Parser::new(Lexer::new(&p)).parse().expect("benchmark parses");
+    println!("fibonacci(27)");
+    let evaluator = time("evaluator", &program, evaluate);
+    let vm = time("vm", &program, compile_and_run);
+    println!("  speedup    {:>10.2}x", evaluator.as_secs_f64() / vm.as_secs_f64());
+}
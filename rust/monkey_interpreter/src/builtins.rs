@@ -0,0 +1,93 @@
+use std::rc::Rc;
+use crate::object::{BuiltinFunction, Object};
+
+/// Functions available everywhere without being bound, unless a binding shadows them.
+pub const BUILTINS: &[(&str, BuiltinFunction)] = &[
+    ("len", len),
+    ("puts", puts),
+    ("first", first),
+    ("last", last),
+    ("rest", rest),
+    ("push", push),
+];
+
+pub fn lookup(name: &str) -> Option<Object> {
+    BUILTINS.iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(name, function)| Object::Builtin(name, *function))
+}
+
+fn wrong_number_of_arguments(args: &[Object], want: usize) -> Option<Object> {
+    (args.len() != want).then(|| Object::Error(format!("wrong number of arguments. got={}, want={}", args.len(), want)))
+}
+
+fn len(args: &[Object]) -> Object {
+    if let Some(error) = wrong_number_of_arguments(args, 1) {
+        return error;
+    }
+    match &args[0] {
+        Object::String(value) => Object::Integer(value.chars().count() as i64),
+        Object::Array(elements) => Object::Integer(elements.len() as i64),
+        other => Object::Error(format!("argument to `len` not supported, got {}", other.type_name())),
+    }
+}
+
+fn puts(args: &[Object]) -> Object {
+    for arg in args {
+        println!("{}", arg);
+    }
+    Object::Null
+}
+
+/// The array a builtin called `name` works on, or the error to return instead.
+fn array_argument<'a>(name: &str, args: &'a [Object]) -> Result<&'a Rc<Vec<Object>>, Object> {
+    match &args[0] {
+        Object::Array(elements) => Ok(elements),
+        other => Err(Object::Error(format!("argument to `{}` must be ARRAY, got {}", name, other.type_name()))),
+    }
+}
+
+fn first(args: &[Object]) -> Object {
+    if let Some(error) = wrong_number_of_arguments(args, 1) {
+        return error;
+    }
+    match array_argument("first", args) {
+        Ok(elements) => elements.first().cloned().unwrap_or(Object::Null),
+        Err(error) => error,
+    }
+}
+
+fn last(args: &[Object]) -> Object {
+    if let Some(error) = wrong_number_of_arguments(args, 1) {
+        return error;
+    }
+    match array_argument("last", args) {
+        Ok(elements) => elements.last().cloned().unwrap_or(Object::Null),
+        Err(error) => error,
+    }
+}
+
+fn rest(args: &[Object]) -> Object {
+    if let Some(error) = wrong_number_of_arguments(args, 1) {
+        return error;
+    }
+    match array_argument("rest", args) {
+        Ok(elements) if elements.is_empty() => Object::Null,
+        Ok(elements) => Object::Array(Rc::new(elements[1..].to_vec())),
+        Err(error) => error,
+    }
+}
+
+fn push(args: &[Object]) -> Object {
+    if let Some(error) = wrong_number_of_arguments(args, 2) {
+        return error;
+    }
+    match array_argument("push", args) {
+        Ok(elements) => {
+            let mut elements = elements.to_vec();
+            elements.push(args[1].clone());
+            Object::Array(Rc::new(elements))
+        }
+        Err(error) => error,
+    }
+}
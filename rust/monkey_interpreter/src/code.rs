@@ -0,0 +1,213 @@
+use std::fmt::{self, Write};
+
+/// Bytecode, a flat sequence of opcodes each directly followed by their big-endian operands.
+pub type Instructions = Vec<u8>;
+
+#[repr(u8)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Opcode {
+    Constant,
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    True,
+    False,
+    Null,
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    Minus,
+    Bang,
+    JumpNotTruthy,
+    Jump,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    GetBuiltin,
+    GetFree,
+    Array,
+    Hash,
+    Index,
+    Call,
+    ReturnValue,
+    Return,
+    Closure,
+    CurrentClosure,
+    /// Fails with the name in the constant operand, which nothing was bound to at compile time
+    IdentifierNotFound,
+}
+
+const OPCODES: [Opcode; 34] = {
+    use Opcode as O;
+    [
+        O::Constant, O::Pop, O::Add, O::Sub, O::Mul, O::Div, O::True, O::False, O::Null, O::Equal,
+        O::NotEqual, O::GreaterThan, O::GreaterThanOrEqual, O::LessThan, O::LessThanOrEqual, O::Minus, O::Bang, O::JumpNotTruthy, O::Jump, O::GetGlobal,
+        O::SetGlobal, O::GetLocal, O::SetLocal, O::GetBuiltin, O::GetFree, O::Array, O::Hash,
+        O::Index, O::Call, O::ReturnValue, O::Return, O::Closure, O::CurrentClosure,
+        O::IdentifierNotFound,
+    ]
+};
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    /// Size in bytes of each operand following the opcode.
+    pub fn operand_widths(self) -> &'static [usize] {
+        use Opcode as O;
+        match self {
+            O::Constant | O::IdentifierNotFound | O::JumpNotTruthy | O::Jump | O::GetGlobal | O::SetGlobal | O::Array | O::Hash => &[2],
+            O::GetLocal | O::SetLocal | O::GetBuiltin | O::GetFree | O::Call => &[1],
+            O::Closure => &[2, 1],
+            _ => &[],
+        }
+    }
+}
+
+/// An operand too large for the bytes its opcode has for it.
+#[derive(PartialEq, Debug)]
+pub struct OperandTooLarge {
+    pub op: Opcode,
+    /// Which of the opcode's operands
+    pub index: usize,
+    pub operand: usize,
+}
+
+impl fmt::Display for OperandTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Opcode as O;
+        let what = match (self.op, self.index) {
+            (O::Constant | O::IdentifierNotFound, _) | (O::Closure, 0) => "too many constants",
+            (O::Jump | O::JumpNotTruthy, _) => "jump target too far",
+            (O::GetGlobal | O::SetGlobal, _) => "too many global bindings",
+            (O::GetLocal | O::SetLocal, _) => "too many local bindings",
+            (O::GetFree, _) | (O::Closure, _) => "too many free variables",
+            (O::Call, _) => "too many arguments",
+            (O::Array | O::Hash, _) => "too many elements",
+            _ => "operand too large",
+        };
+        let max = (1usize << (8 * self.op.operand_widths()[self.index])) - 1;
+        write!(f, "{}: {} does not fit in bytecode, the limit is {}", what, self.operand, max)
+    }
+}
+
+/// Encode `op` with its `operands`, or the first operand that does not fit.
+pub fn try_make(op: Opcode, operands: &[usize]) -> Result<Instructions, OperandTooLarge> {
+    let widths = op.operand_widths();
+    debug_assert_eq!(widths.len(), operands.len(), "{:?} takes {} operands", op, widths.len());
+    let mut instruction = vec![op as u8];
+    for (index, (&operand, width)) in operands.iter().zip(widths).enumerate() {
+        let too_large = || OperandTooLarge { op, index, operand };
+        match width {
+            2 => instruction.extend_from_slice(&u16::try_from(operand).map_err(|_| too_large())?.to_be_bytes()),
+            1 => instruction.push(u8::try_from(operand).map_err(|_| too_large())?),
+            _ => unreachable!("no operand is {} bytes wide", width),
+        }
+    }
+    Ok(instruction)
+}
+
+/// Encode `op` with operands known to fit.
+pub fn make(op: Opcode, operands: &[usize]) -> Instructions {
+    try_make(op, operands).unwrap_or_else(|e| panic!("{:?}: {}", op, e))
+}
+
+/// Decode the operands of `op` at the start of `ins`, returning them and how many bytes they took.
+pub fn read_operands(op: Opcode, ins: &[u8]) -> (Vec<usize>, usize) {
+    let mut offset = 0;
+    let mut operands = Vec::new();
+    for width in op.operand_widths() {
+        operands.push(match width {
+            2 => read_u16(&ins[offset..]),
+            _ => ins[offset] as usize,
+        });
+        offset += width;
+    }
+    (operands, offset)
+}
+
+pub fn read_u16(ins: &[u8]) -> usize {
+    u16::from_be_bytes([ins[0], ins[1]]) as usize
+}
+
+/// One line per instruction, prefixed with its offset: `0003 Constant 1`.
+pub fn disassemble(ins: &[u8]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < ins.len() {
+        let Some(op) = Opcode::from_byte(ins[i]) else {
+            writeln!(out, "{:04} ERROR: unknown opcode {}", i, ins[i]).unwrap();
+            i += 1;
+            continue;
+        };
+        let (operands, read) = read_operands(op, &ins[i + 1..]);
+        write!(out, "{:04} {:?}", i, op).unwrap();
+        operands.iter().for_each(|operand| write!(out, " {}", operand).unwrap());
+        out.push('\n');
+        i += 1 + read;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_make() {
+        assert_eq!(make(Opcode::Constant, &[65534]), [Opcode::Constant as u8, 255, 254]);
+        assert_eq!(make(Opcode::Add, &[]), [Opcode::Add as u8]);
+        assert_eq!(make(Opcode::GetLocal, &[255]), [Opcode::GetLocal as u8, 255]);
+        assert_eq!(make(Opcode::Closure, &[65534, 255]), [Opcode::Closure as u8, 255, 254, 255]);
+    }
+
+    #[test]
+    fn test_operands_too_large() {
+        assert_eq!(try_make(Opcode::Constant, &[65536]), Err(OperandTooLarge { op: Opcode::Constant, index: 0, operand: 65536 }));
+        assert_eq!(try_make(Opcode::Call, &[256]), Err(OperandTooLarge { op: Opcode::Call, index: 0, operand: 256 }));
+        let error = try_make(Opcode::Closure, &[1, 300]).unwrap_err();
+        assert_eq!(error.to_string(), "too many free variables: 300 does not fit in bytecode, the limit is 255");
+    }
+
+    #[test]
+    fn test_read_operands() {
+        let tests: [(Opcode, &[usize], usize); 3] = [
+            (Opcode::Constant, &[65535], 2),
+            (Opcode::GetLocal, &[255], 1),
+            (Opcode::Closure, &[65535, 255], 3),
+        ];
+        for (op, operands, bytes) in tests {
+            let instruction = make(op, operands);
+            assert_eq!(read_operands(op, &instruction[1..]), (operands.to_vec(), bytes));
+        }
+    }
+
+    #[test]
+    fn test_opcodes_round_trip_through_bytes() {
+        for (i, op) in OPCODES.iter().enumerate() {
+            assert_eq!(*op as usize, i);
+            assert_eq!(Opcode::from_byte(i as u8), Some(*op));
+        }
+        assert_eq!(Opcode::from_byte(OPCODES.len() as u8), None);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let ins = [
+            make(Opcode::Add, &[]),
+            make(Opcode::GetLocal, &[1]),
+            make(Opcode::Constant, &[2]),
+            make(Opcode::Constant, &[65535]),
+            make(Opcode::Closure, &[65535, 255]),
+        ].concat();
+        let expected = "0000 Add\n0001 GetLocal 1\n0003 Constant 2\n0006 Constant 65535\n0009 Closure 65535 255\n";
+        assert_eq!(disassemble(&ins), expected);
+    }
+}
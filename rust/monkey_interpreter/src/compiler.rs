@@ -0,0 +1,580 @@
+use std::{fmt, mem};
+use std::rc::Rc;
+use crate::builtins::BUILTINS;
+use crate::code::{self, Instructions, Opcode, OperandTooLarge};
+use crate::diagnostic::Diagnostic;
+use crate::object::{CompiledFunction, Object};
+use crate::parser::{BlockStatement, Expression, Identifier, Program, Statement};
+use crate::symbol_table::{Symbol, SymbolScope, SymbolTable};
+use crate::token::{Slice, Token};
+
+/// Compiled program: the instructions of the top level and the constants they refer to.
+pub struct Bytecode {
+    pub instructions: Instructions,
+    pub constants: Vec<Object>,
+}
+
+/// Uses the evaluator's messages, `span` is the token the error is about.
+#[derive(PartialEq, Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub span: Slice,
+}
+
+impl CompileError {
+    fn new(message: String, token: &Token) -> Self {
+        Self { message, span: token.literal }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.message.clone(), self.span)
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct EmittedInstruction {
+    opcode: Opcode,
+    position: usize,
+}
+
+/// Instructions of the function being compiled, the top level being the outermost one.
+#[derive(Default)]
+struct CompilationScope {
+    instructions: Instructions,
+    last_instruction: Option<EmittedInstruction>,
+    previous_instruction: Option<EmittedInstruction>,
+}
+
+pub struct Compiler {
+    constants: Vec<Object>,
+    symbol_table: SymbolTable,
+    scopes: Vec<CompilationScope>,
+    /// The innermost node being compiled, what an operand that does not fit is blamed on
+    node: Slice,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        let mut symbol_table = SymbolTable::new();
+        for (i, (name, _)) in BUILTINS.iter().enumerate() {
+            symbol_table.define_builtin(i, name);
+        }
+        Self::with_state(symbol_table, Vec::new())
+    }
+
+    /// Continue compiling with the globals and constants of earlier programs, as a REPL does.
+    pub fn with_state(symbol_table: SymbolTable, constants: Vec<Object>) -> Self {
+        Self {
+            constants,
+            symbol_table,
+            scopes: vec![CompilationScope::default()],
+            node: Slice::new(0, 0),
+        }
+    }
+
+    /// Give back the globals and constants, to be passed to [`Compiler::with_state`].
+    pub fn into_state(self) -> (SymbolTable, Vec<Object>) {
+        (self.symbol_table, self.constants)
+    }
+
+    pub fn bytecode(&self) -> Bytecode {
+        Bytecode {
+            instructions: self.scope().instructions.clone(),
+            constants: self.constants.clone(),
+        }
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<(), CompileError> {
+        program.statements.iter().try_for_each(|statement| self.compile_statement(statement))
+    }
+
+    fn compile_statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        let token = match statement {
+            Statement::Let { name, .. } => &name.token,
+            Statement::Return { token, .. } | Statement::Expression { token, .. } => token,
+        };
+        let outer = mem::replace(&mut self.node, token.literal);
+        let result = self.compile_statement_node(statement);
+        self.node = outer;
+        result
+    }
+
+    fn compile_statement_node(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match statement {
+            Statement::Expression { expression, .. } => {
+                self.compile_expression(expression)?;
+                self.emit(Opcode::Pop, &[])?;
+            }
+            Statement::Let { name, value, .. } => {
+                match value {
+                    Expression::Function { parameters, body, .. } => {
+                        self.compile_function(parameters, body, Some(&name.value))?
+                    }
+                    value => self.compile_expression(value)?,
+                }
+                // Defined only now so the value still sees what the name was bound to before
+                let symbol = self.symbol_table.define(&name.value);
+                match symbol.scope {
+                    SymbolScope::Global => self.emit(Opcode::SetGlobal, &[symbol.index])?,
+                    _ => self.emit(Opcode::SetLocal, &[symbol.index])?,
+                };
+            }
+            Statement::Return { value, .. } => {
+                self.compile_expression(value)?;
+                self.emit(Opcode::ReturnValue, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile `block` so that it leaves the value of its last expression on the stack, or null
+    /// when it does not end in one.
+    fn compile_block(&mut self, block: &BlockStatement) -> Result<(), CompileError> {
+        block.statements.iter().try_for_each(|statement| self.compile_statement(statement))?;
+        match self.scope().last_instruction.map(|i| i.opcode) {
+            Some(Opcode::Pop) => self.remove_last_pop(),
+            Some(Opcode::ReturnValue) => {}
+            _ => {
+                self.emit(Opcode::Null, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        let outer = mem::replace(&mut self.node, expression.token().literal);
+        let result = self.compile_expression_node(expression);
+        self.node = outer;
+        result
+    }
+
+    fn compile_expression_node(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        match expression {
+            Expression::Integer { value, .. } => {
+                let constant = self.add_constant(Object::Integer(*value));
+                self.emit(Opcode::Constant, &[constant])?;
+            }
+            Expression::Boolean { value: true, .. } => {
+                self.emit(Opcode::True, &[])?;
+            }
+            Expression::Boolean { value: false, .. } => {
+                self.emit(Opcode::False, &[])?;
+            }
+            Expression::String { value, .. } => {
+                let constant = self.add_constant(Object::String(value.as_str().into()));
+                self.emit(Opcode::Constant, &[constant])?;
+            }
+            Expression::Identifier(identifier) => match self.symbol_table.resolve(&identifier.value) {
+                Some(symbol) => self.load_symbol(&symbol)?,
+                // Only an error once it runs, as in the evaluator, so `false && nope` is false
+                None => {
+                    let name = self.add_constant(Object::String(identifier.value.as_str().into()));
+                    self.emit(Opcode::IdentifierNotFound, &[name])?;
+                }
+            },
+            Expression::Prefix { token, operator, right } => {
+                self.compile_expression(right)?;
+                match operator.as_str() {
+                    "!" => self.emit(Opcode::Bang, &[])?,
+                    "-" => self.emit(Opcode::Minus, &[])?,
+                    _ => return Err(CompileError::new(format!("unknown operator: {}", operator), token)),
+                };
+            }
+            Expression::Infix { left, operator, right, .. } if operator == "&&" || operator == "||" => {
+                self.compile_logical(left, operator, right)?;
+            }
+            Expression::Infix { token, left, operator, right } => {
+                self.compile_expression(left)?;
+                self.compile_expression(right)?;
+                let op = match operator.as_str() {
+                    "+" => Opcode::Add,
+                    "-" => Opcode::Sub,
+                    "*" => Opcode::Mul,
+                    "/" => Opcode::Div,
+                    ">" => Opcode::GreaterThan,
+                    ">=" => Opcode::GreaterThanOrEqual,
+                    "<" => Opcode::LessThan,
+                    "<=" => Opcode::LessThanOrEqual,
+                    "==" => Opcode::Equal,
+                    "!=" => Opcode::NotEqual,
+                    _ => return Err(CompileError::new(format!("unknown operator: {}", operator), token)),
+                };
+                self.emit(op, &[])?;
+            }
+            Expression::Float { value, .. } => {
+                let constant = self.add_constant(Object::Float(*value));
+                self.emit(Opcode::Constant, &[constant])?;
+            }
+            Expression::If { condition, consequence, alternative, .. } => {
+                self.compile_expression(condition)?;
+                // Jump targets are patched in once they are known
+                let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999])?;
+                self.compile_block(consequence)?;
+                let jump = self.emit(Opcode::Jump, &[9999])?;
+
+                let after_consequence = self.scope().instructions.len();
+                self.change_operand(jump_not_truthy, after_consequence)?;
+                match alternative {
+                    Some(alternative) => self.compile_block(alternative)?,
+                    None => {
+                        self.emit(Opcode::Null, &[])?;
+                    }
+                }
+                let after_alternative = self.scope().instructions.len();
+                self.change_operand(jump, after_alternative)?;
+            }
+            Expression::Array { elements, .. } => {
+                elements.iter().try_for_each(|element| self.compile_expression(element))?;
+                self.emit(Opcode::Array, &[elements.len()])?;
+            }
+            Expression::Hash { pairs, .. } => {
+                for (key, value) in pairs {
+                    self.compile_expression(key)?;
+                    self.compile_expression(value)?;
+                }
+                self.emit(Opcode::Hash, &[pairs.len() * 2])?;
+            }
+            Expression::Index { left, index, .. } => {
+                self.compile_expression(left)?;
+                self.compile_expression(index)?;
+                self.emit(Opcode::Index, &[])?;
+            }
+            Expression::Function { parameters, body, .. } => self.compile_function(parameters, body, None)?,
+            Expression::Macro { token, .. } => {
+                return Err(CompileError::new("macros can only be defined by a top-level let".to_string(), token));
+            }
+            Expression::Call { function, arguments, .. } => {
+                self.compile_expression(function)?;
+                arguments.iter().try_for_each(|argument| self.compile_expression(argument))?;
+                self.emit(Opcode::Call, &[arguments.len()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `&&` and `||` evaluate to a boolean, the right operand only when the left one does not
+    /// decide it.
+    fn compile_logical(&mut self, left: &Expression, operator: &str, right: &Expression) -> Result<(), CompileError> {
+        let compile_truthiness = |compiler: &mut Self, expression| -> Result<(), CompileError> {
+            compiler.compile_expression(expression)?;
+            compiler.emit(Opcode::Bang, &[])?;
+            compiler.emit(Opcode::Bang, &[])?;
+            Ok(())
+        };
+        self.compile_expression(left)?;
+        let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999])?;
+        match operator {
+            "&&" => compile_truthiness(self, right)?,
+            _ => {
+                self.emit(Opcode::True, &[])?;
+            }
+        }
+        let jump = self.emit(Opcode::Jump, &[9999])?;
+
+        let after_truthy = self.scope().instructions.len();
+        self.change_operand(jump_not_truthy, after_truthy)?;
+        match operator {
+            "&&" => {
+                self.emit(Opcode::False, &[])?;
+            }
+            _ => compile_truthiness(self, right)?,
+        }
+        let after_falsy = self.scope().instructions.len();
+        self.change_operand(jump, after_falsy)?;
+        Ok(())
+    }
+
+    /// Compile a function literal into a constant and emit the closure creating it. `name` is
+    /// what the function is bound to, so it can call itself without capturing itself.
+    fn compile_function(
+        &mut self,
+        parameters: &[Identifier],
+        body: &BlockStatement,
+        name: Option<&str>,
+    ) -> Result<(), CompileError> {
+        self.enter_scope();
+        if let Some(name) = name {
+            self.symbol_table.define_function_name(name);
+        }
+        for parameter in parameters {
+            self.symbol_table.define(&parameter.value);
+        }
+        self.compile_block(body)?;
+        if self.scope().last_instruction.map(|i| i.opcode) != Some(Opcode::ReturnValue) {
+            self.emit(Opcode::ReturnValue, &[])?;
+        }
+
+        let free_symbols = self.symbol_table.free_symbols.clone();
+        let num_locals = self.symbol_table.num_definitions();
+        let instructions = self.leave_scope();
+        for symbol in &free_symbols {
+            self.load_symbol(symbol)?;
+        }
+        let function = Object::CompiledFunction(Rc::new(CompiledFunction {
+            instructions,
+            num_locals,
+            num_parameters: parameters.len(),
+        }));
+        let constant = self.add_constant(function);
+        self.emit(Opcode::Closure, &[constant, free_symbols.len()])?;
+        Ok(())
+    }
+
+    fn load_symbol(&mut self, symbol: &Symbol) -> Result<(), CompileError> {
+        match symbol.scope {
+            SymbolScope::Global => self.emit(Opcode::GetGlobal, &[symbol.index])?,
+            SymbolScope::Local => self.emit(Opcode::GetLocal, &[symbol.index])?,
+            SymbolScope::Builtin => self.emit(Opcode::GetBuiltin, &[symbol.index])?,
+            SymbolScope::Free => self.emit(Opcode::GetFree, &[symbol.index])?,
+            SymbolScope::Function => self.emit(Opcode::CurrentClosure, &[])?,
+        };
+        Ok(())
+    }
+
+    fn add_constant(&mut self, object: Object) -> usize {
+        self.constants.push(object);
+        self.constants.len() - 1
+    }
+
+    /// Append an instruction to the current scope, returning where it starts.
+    fn emit(&mut self, op: Opcode, operands: &[usize]) -> Result<usize, CompileError> {
+        let instruction = code::try_make(op, operands).map_err(|e| self.operand_error(e))?;
+        let scope = self.scope_mut();
+        let position = scope.instructions.len();
+        scope.instructions.extend(instruction);
+        scope.previous_instruction = scope.last_instruction;
+        scope.last_instruction = Some(EmittedInstruction { opcode: op, position });
+        Ok(position)
+    }
+
+    fn remove_last_pop(&mut self) {
+        let scope = self.scope_mut();
+        if let Some(last) = scope.last_instruction {
+            scope.instructions.truncate(last.position);
+            scope.last_instruction = scope.previous_instruction;
+        }
+    }
+
+    fn change_operand(&mut self, position: usize, operand: usize) -> Result<(), CompileError> {
+        let op = Opcode::from_byte(self.scope().instructions[position]).expect("patching a valid instruction");
+        let patched = code::try_make(op, &[operand]).map_err(|e| self.operand_error(e))?;
+        self.scope_mut().instructions[position..position + patched.len()].copy_from_slice(&patched);
+        Ok(())
+    }
+
+    fn operand_error(&self, error: OperandTooLarge) -> CompileError {
+        CompileError { message: error.to_string(), span: self.node }
+    }
+
+    fn enter_scope(&mut self) {
+        self.scopes.push(CompilationScope::default());
+        let outer = mem::take(&mut self.symbol_table);
+        self.symbol_table = SymbolTable::enclosed(outer);
+    }
+
+    fn leave_scope(&mut self) -> Instructions {
+        let scope = self.scopes.pop().expect("leaving a scope that was entered");
+        let table = mem::take(&mut self.symbol_table);
+        self.symbol_table = table.into_outer().expect("leaving a scope that was entered");
+        scope.instructions
+    }
+
+    fn scope(&self) -> &CompilationScope {
+        self.scopes.last().expect("the top level scope is never left")
+    }
+
+    fn scope_mut(&mut self) -> &mut CompilationScope {
+        self.scopes.last_mut().expect("the top level scope is never left")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lexer::{Lexer, RawMonkeyProgram};
+    use crate::parser::Parser;
+    use super::*;
+
+    fn compile(input: &str) -> Bytecode {
+        let p = RawMonkeyProgram::new(input);
+        let program = Parser::new(Lexer::new(&p)).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&program).unwrap_or_else(|e| panic!("compiler error for {:?}: {}", input, e));
+        compiler.bytecode()
+    }
+
+    fn assert_instructions(input: &str, expected: &[Instructions]) {
+        let bytecode = compile(input);
+        assert_eq!(code::disassemble(&bytecode.instructions), code::disassemble(&expected.concat()), "{}", input);
+    }
+
+    fn function_instructions(bytecode: &Bytecode, constant: usize) -> String {
+        match &bytecode.constants[constant] {
+            Object::CompiledFunction(function) => code::disassemble(&function.instructions),
+            other => panic!("constant {} is not a function: {:?}", constant, other),
+        }
+    }
+
+    use code::make;
+    use Opcode as O;
+
+    #[test]
+    fn test_integer_arithmetic() {
+        assert_instructions("1 + 2", &[make(O::Constant, &[0]), make(O::Constant, &[1]), make(O::Add, &[]), make(O::Pop, &[])]);
+        assert_instructions("1; 2", &[make(O::Constant, &[0]), make(O::Pop, &[]), make(O::Constant, &[1]), make(O::Pop, &[])]);
+        assert_instructions("-1", &[make(O::Constant, &[0]), make(O::Minus, &[]), make(O::Pop, &[])]);
+        assert_eq!(compile("2 * 3").constants, [Object::Integer(2), Object::Integer(3)]);
+    }
+
+    #[test]
+    fn test_less_than_swaps_operands() {
+        assert_instructions("1 < 2", &[make(O::Constant, &[0]), make(O::Constant, &[1]), make(O::LessThan, &[]), make(O::Pop, &[])]);
+        assert_eq!(compile("1 < 2").constants, [Object::Integer(1), Object::Integer(2)]);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        assert_instructions("if (true) { 10 }; 3333;", &[
+            make(O::True, &[]),
+            make(O::JumpNotTruthy, &[10]),
+            make(O::Constant, &[0]),
+            make(O::Jump, &[11]),
+            make(O::Null, &[]),
+            make(O::Pop, &[]),
+            make(O::Constant, &[1]),
+            make(O::Pop, &[]),
+        ]);
+        assert_instructions("if (true) { } else { 20 }", &[
+            make(O::True, &[]),
+            make(O::JumpNotTruthy, &[8]),
+            make(O::Null, &[]),
+            make(O::Jump, &[11]),
+            make(O::Constant, &[0]),
+            make(O::Pop, &[]),
+        ]);
+    }
+
+    #[test]
+    fn test_global_let_statements() {
+        assert_instructions("let one = 1; let two = one; two;", &[
+            make(O::Constant, &[0]),
+            make(O::SetGlobal, &[0]),
+            make(O::GetGlobal, &[0]),
+            make(O::SetGlobal, &[1]),
+            make(O::GetGlobal, &[1]),
+            make(O::Pop, &[]),
+        ]);
+    }
+
+    #[test]
+    fn test_collections() {
+        assert_instructions("[1, 2][0]", &[
+            make(O::Constant, &[0]),
+            make(O::Constant, &[1]),
+            make(O::Array, &[2]),
+            make(O::Constant, &[2]),
+            make(O::Index, &[]),
+            make(O::Pop, &[]),
+        ]);
+        assert_instructions("{1: 2}", &[make(O::Constant, &[0]), make(O::Constant, &[1]), make(O::Hash, &[2]), make(O::Pop, &[])]);
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        let bytecode = compile("fn(a) { let b = a; fn(c) { a + b + c } }");
+        let inner = [
+            make(O::GetFree, &[0]),
+            make(O::GetFree, &[1]),
+            make(O::Add, &[]),
+            make(O::GetLocal, &[0]),
+            make(O::Add, &[]),
+            make(O::ReturnValue, &[]),
+        ].concat();
+        assert_eq!(function_instructions(&bytecode, 0), code::disassemble(&inner));
+        let outer = [
+            make(O::GetLocal, &[0]),
+            make(O::SetLocal, &[1]),
+            make(O::GetLocal, &[0]),
+            make(O::GetLocal, &[1]),
+            make(O::Closure, &[0, 2]),
+            make(O::ReturnValue, &[]),
+        ].concat();
+        assert_eq!(function_instructions(&bytecode, 1), code::disassemble(&outer));
+
+        let bytecode = compile("fn() { }");
+        let empty = [make(O::Null, &[]), make(O::ReturnValue, &[])].concat();
+        assert_eq!(function_instructions(&bytecode, 0), code::disassemble(&empty));
+    }
+
+    #[test]
+    fn test_recursive_functions_use_current_closure() {
+        let bytecode = compile("let countDown = fn(x) { countDown(x - 1) }; countDown(1);");
+        let body = [
+            make(O::CurrentClosure, &[]),
+            make(O::GetLocal, &[0]),
+            make(O::Constant, &[0]),
+            make(O::Sub, &[]),
+            make(O::Call, &[1]),
+            make(O::ReturnValue, &[]),
+        ].concat();
+        assert_eq!(function_instructions(&bytecode, 1), code::disassemble(&body));
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_instructions("len([])", &[make(O::GetBuiltin, &[0]), make(O::Array, &[0]), make(O::Call, &[1]), make(O::Pop, &[])]);
+    }
+
+    fn compile_error(input: &str) -> CompileError {
+        let p = RawMonkeyProgram::new(input);
+        let program = Parser::new(Lexer::new(&p)).parse().unwrap();
+        Compiler::new().compile(&program).unwrap_err()
+    }
+
+    #[test]
+    fn test_operands_past_their_limits() {
+        // Constant indexes are two bytes, so the 65537th constant is one too many
+        let constants = "1;".repeat(65536);
+        assert_eq!(compile(&constants).constants.len(), 65536);
+        assert_eq!(compile_error(&format!("{}2", constants)), CompileError {
+            message: "too many constants: 65536 does not fit in bytecode, the limit is 65535".to_string(),
+            span: Slice::new(2 * 65536, 1),
+        });
+
+        let arguments = format!("len({})", vec!["1"; 256].join(", "));
+        assert_eq!(compile_error(&arguments), CompileError {
+            message: "too many arguments: 256 does not fit in bytecode, the limit is 255".to_string(),
+            span: Slice::new(3, 1),
+        });
+
+        let locals = (0..257).map(|i| format!("let a{} = 0;", i)).collect::<String>();
+        let error = compile_error(&format!("fn() {{ {} }}", locals));
+        assert!(error.message.starts_with("too many local bindings: 256"), "{}", error);
+
+        let error = compile_error(&format!("if (true) {{ {} }}", "1;".repeat(20000)));
+        assert!(error.message.starts_with("jump target too far"), "{}", error);
+        assert_eq!(error.span, Slice::new(0, 2));
+    }
+
+    #[test]
+    fn test_undefined_identifier() {
+        assert_instructions("x + 1", &[
+            make(O::IdentifierNotFound, &[0]),
+            make(O::Constant, &[1]),
+            make(O::Add, &[]),
+            make(O::Pop, &[]),
+        ]);
+        assert_eq!(compile("x + 1").constants, [Object::String("x".into()), Object::Integer(1)]);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt::Write;
+use crate::lexer::RawMonkeyProgram;
+use crate::token::Slice;
+
+/// An error pointing at the part of the program it is about.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Slice,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Slice) -> Self {
+        Self { message: message.into(), span }
+    }
+
+    /// The message followed by the offending line of `program`, underlined with carets:
+    ///
+    /// ```text
+    /// error: expected `)`, found `;`
+    ///  --> script.monkey:1:15
+    ///   |
+    /// 1 | let x = (1 + 2;
+    ///   |               ^
+    /// ```
+    ///
+    /// `name` is what the program is called in the location line, e.g. its file name.
+    pub fn render(&self, program: &RawMonkeyProgram, name: &str) -> String {
+        let (line, column) = program.line_col(self.span.start);
+        let text = program.line(line);
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // Keep tabs in the indentation so the carets line up with what they point at
+        let indent = text.chars()
+            .take(column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let available = text.chars().count().saturating_sub(column - 1).max(1);
+        let carets = "^".repeat(self.span.len.clamp(1, available));
+
+        let mut out = String::new();
+        writeln!(out, "error: {}", self.message).unwrap();
+        writeln!(out, "{}--> {}:{}:{}", gutter, name, line, column).unwrap();
+        writeln!(out, "{} |", gutter).unwrap();
+        writeln!(out, "{} | {}", number, text).unwrap();
+        write!(out, "{} | {}{}", gutter, indent, carets).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_underlines_span() {
+        let p = RawMonkeyProgram::new("let a = 1;\nlet b = a + foo;\n");
+        let diagnostic = Diagnostic::new("identifier not found: foo", Slice::new(23, 3));
+        let expected = "\
+error: identifier not found: foo
+ --> test.monkey:2:13
+  |
+2 | let b = a + foo;
+  |             ^^^";
+        assert_eq!(diagnostic.render(&p, "test.monkey"), expected);
+    }
+
+    #[test]
+    fn test_render_at_end_of_line_and_with_tabs() {
+        let p = RawMonkeyProgram::new("if (x) {\n\tx +");
+        let diagnostic = Diagnostic::new("expected an expression, found end of input", Slice::new(p.end_of_input(), 0));
+        assert_eq!(diagnostic.render(&p, "<repl>").lines().last(), Some("  | \t   ^"));
+    }
+}
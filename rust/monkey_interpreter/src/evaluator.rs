@@ -0,0 +1,600 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use crate::{builtins, macro_expansion};
+use crate::object::{Env, Environment, Function, HashPair, Object};
+use crate::parser::{BlockStatement, Expression, Identifier, Program, Statement};
+
+/// Calls that can be in progress at once, as many as the VM allows besides its top level frame.
+/// Every call takes a few frames of the Rust stack, so deeper recursion would overflow it.
+const MAX_CALL_DEPTH: usize = 1023;
+
+thread_local! {
+    /// Calls in progress on this thread
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn eval_program(program: &Program, env: &Env) -> Object {
+    let mut result = Object::Null;
+    for statement in &program.statements {
+        result = eval_statement(statement, env);
+        match result {
+            Object::ReturnValue(value) => return *value,
+            Object::Error(_) => return result,
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Unlike a program, a block hands `return` values up unwrapped so they stop every enclosing
+/// block until they reach the function call.
+pub(crate) fn eval_block_statement(block: &BlockStatement, env: &Env) -> Object {
+    let mut result = Object::Null;
+    for statement in &block.statements {
+        result = eval_statement(statement, env);
+        if let Object::ReturnValue(_) | Object::Error(_) = result {
+            return result;
+        }
+    }
+    result
+}
+
+fn eval_statement(statement: &Statement, env: &Env) -> Object {
+    match statement {
+        Statement::Expression { expression, .. } => eval_expression(expression, env),
+        Statement::Return { value, .. } => {
+            let value = eval_expression(value, env);
+            if value.is_error() {
+                return value;
+            }
+            Object::ReturnValue(Box::new(value))
+        }
+        Statement::Let { name, value, .. } => {
+            let value = eval_expression(value, env);
+            if value.is_error() {
+                return value;
+            }
+            env.borrow_mut().set(&name.value, value);
+            Object::Null
+        }
+    }
+}
+
+pub(crate) fn eval_expression(expression: &Expression, env: &Env) -> Object {
+    match expression {
+        Expression::Integer { value, .. } => Object::Integer(*value),
+        Expression::Float { value, .. } => Object::Float(*value),
+        Expression::Boolean { value, .. } => Object::Boolean(*value),
+        Expression::String { value, .. } => Object::String(value.as_str().into()),
+        Expression::Array { elements, .. } => match eval_expressions(elements, env) {
+            Ok(elements) => Object::Array(Rc::new(elements)),
+            Err(error) => error,
+        },
+        Expression::Hash { pairs, .. } => eval_hash_literal(pairs, env),
+        Expression::Index { left, index, .. } => {
+            let left = eval_expression(left, env);
+            if left.is_error() {
+                return left;
+            }
+            let index = eval_expression(index, env);
+            if index.is_error() {
+                return index;
+            }
+            eval_index_expression(left, index)
+        }
+        Expression::Identifier(identifier) => eval_identifier(identifier, env),
+        Expression::Prefix { operator, right, .. } => {
+            let right = eval_expression(right, env);
+            if right.is_error() {
+                return right;
+            }
+            eval_prefix_expression(operator, right)
+        }
+        Expression::Infix { left, operator, right, .. } => {
+            let left = eval_expression(left, env);
+            if left.is_error() {
+                return left;
+            }
+            // The right operand of `&&` and `||` only counts if the left one does not decide
+            match (operator.as_str(), left.is_truthy()) {
+                ("&&", false) => return Object::Boolean(false),
+                ("||", true) => return Object::Boolean(true),
+                ("&&" | "||", _) => {
+                    let right = eval_expression(right, env);
+                    if right.is_error() {
+                        return right;
+                    }
+                    return Object::Boolean(right.is_truthy());
+                }
+                _ => {}
+            }
+            let right = eval_expression(right, env);
+            if right.is_error() {
+                return right;
+            }
+            eval_infix_expression(operator, left, right)
+        }
+        Expression::If { condition, consequence, alternative, .. } => {
+            let condition = eval_expression(condition, env);
+            if condition.is_error() {
+                return condition;
+            }
+            if condition.is_truthy() {
+                eval_block_statement(consequence, env)
+            } else if let Some(alternative) = alternative {
+                eval_block_statement(alternative, env)
+            } else {
+                Object::Null
+            }
+        }
+        Expression::Function { parameters, body, .. } => Object::Function(Rc::new(Function {
+            parameters: parameters.clone(),
+            body: body.clone(),
+            env: env.clone(),
+        })),
+        Expression::Call { function, arguments, .. } => {
+            // `quote` hands back its argument unevaluated, so it cannot be an ordinary function
+            if matches!(function.as_ref(), Expression::Identifier(f) if f.value == "quote") {
+                return macro_expansion::quote(arguments, env);
+            }
+            let function = eval_expression(function, env);
+            if function.is_error() {
+                return function;
+            }
+            match eval_expressions(arguments, env) {
+                Ok(args) => apply_function(function, args),
+                Err(error) => error,
+            }
+        }
+        Expression::Macro { .. } => Object::Error("macros can only be defined by a top-level let".to_string()),
+    }
+}
+
+/// Evaluate `expressions` left to right, stopping at the first error.
+fn eval_expressions(expressions: &[Expression], env: &Env) -> Result<Vec<Object>, Object> {
+    let mut values = Vec::with_capacity(expressions.len());
+    for expression in expressions {
+        let value = eval_expression(expression, env);
+        if value.is_error() {
+            return Err(value);
+        }
+        values.push(value);
+    }
+    Ok(values)
+}
+
+fn eval_hash_literal(pairs: &[(Expression, Expression)], env: &Env) -> Object {
+    let mut hash = BTreeMap::new();
+    for (key, value) in pairs {
+        let key = eval_expression(key, env);
+        if key.is_error() {
+            return key;
+        }
+        let Some(hash_key) = key.hash_key() else {
+            return Object::Error(format!("unusable as hash key: {}", key.type_name()));
+        };
+        let value = eval_expression(value, env);
+        if value.is_error() {
+            return value;
+        }
+        hash.insert(hash_key, HashPair { key, value });
+    }
+    Object::Hash(Rc::new(hash))
+}
+
+pub(crate) fn eval_index_expression(left: Object, index: Object) -> Object {
+    match (&left, &index) {
+        (Object::Array(elements), Object::Integer(i)) => usize::try_from(*i)
+            .ok()
+            .and_then(|i| elements.get(i))
+            .cloned()
+            .unwrap_or(Object::Null),
+        (Object::Hash(pairs), index) => match index.hash_key() {
+            Some(key) => pairs.get(&key).map_or(Object::Null, |pair| pair.value.clone()),
+            None => Object::Error(format!("unusable as hash key: {}", index.type_name())),
+        },
+        _ => Object::Error(format!("index operator not supported: {}", left.type_name())),
+    }
+}
+
+fn eval_identifier(identifier: &Identifier, env: &Env) -> Object {
+    env.borrow()
+        .get(&identifier.value)
+        .or_else(|| builtins::lookup(&identifier.value))
+        .unwrap_or_else(|| Object::Error(format!("identifier not found: {}", identifier.value)))
+}
+
+pub(crate) fn eval_prefix_expression(operator: &str, right: Object) -> Object {
+    match (operator, right) {
+        ("!", right) => Object::Boolean(!right.is_truthy()),
+        ("-", Object::Integer(value)) => Object::Integer(value.wrapping_neg()),
+        ("-", Object::Float(value)) => Object::Float(-value),
+        (operator, right) => Object::Error(format!("unknown operator: {}{}", operator, right.type_name())),
+    }
+}
+
+pub(crate) fn eval_infix_expression(operator: &str, left: Object, right: Object) -> Object {
+    match (left, right) {
+        (Object::Integer(left), Object::Integer(right)) => eval_integer_infix_expression(operator, left, right),
+        // Integers mixed with floats count as floats
+        (Object::Float(left), Object::Float(right)) => eval_float_infix_expression(operator, left, right),
+        (Object::Integer(left), Object::Float(right)) => eval_float_infix_expression(operator, left as f64, right),
+        (Object::Float(left), Object::Integer(right)) => eval_float_infix_expression(operator, left, right as f64),
+        (Object::String(left), Object::String(right)) => match operator {
+            "+" => Object::String(format!("{}{}", left, right).into()),
+            "==" => Object::Boolean(left == right),
+            "!=" => Object::Boolean(left != right),
+            _ => Object::Error(format!("unknown operator: STRING {} STRING", operator)),
+        },
+        (Object::Boolean(left), Object::Boolean(right)) if operator == "==" => Object::Boolean(left == right),
+        (Object::Boolean(left), Object::Boolean(right)) if operator == "!=" => Object::Boolean(left != right),
+        (left, right) if left.type_name() != right.type_name() => Object::Error(format!(
+            "type mismatch: {} {} {}", left.type_name(), operator, right.type_name()
+        )),
+        (left, right) => Object::Error(format!(
+            "unknown operator: {} {} {}", left.type_name(), operator, right.type_name()
+        )),
+    }
+}
+
+fn eval_integer_infix_expression(operator: &str, left: i64, right: i64) -> Object {
+    match operator {
+        "+" => Object::Integer(left.wrapping_add(right)),
+        "-" => Object::Integer(left.wrapping_sub(right)),
+        "*" => Object::Integer(left.wrapping_mul(right)),
+        "/" if right == 0 => Object::Error("division by zero".to_string()),
+        "/" => Object::Integer(left.wrapping_div(right)),
+        "<" => Object::Boolean(left < right),
+        ">" => Object::Boolean(left > right),
+        "<=" => Object::Boolean(left <= right),
+        ">=" => Object::Boolean(left >= right),
+        "==" => Object::Boolean(left == right),
+        "!=" => Object::Boolean(left != right),
+        _ => Object::Error(format!("unknown operator: INTEGER {} INTEGER", operator)),
+    }
+}
+
+fn eval_float_infix_expression(operator: &str, left: f64, right: f64) -> Object {
+    match operator {
+        "+" => Object::Float(left + right),
+        "-" => Object::Float(left - right),
+        "*" => Object::Float(left * right),
+        "/" => Object::Float(left / right),
+        "<" => Object::Boolean(left < right),
+        ">" => Object::Boolean(left > right),
+        "<=" => Object::Boolean(left <= right),
+        ">=" => Object::Boolean(left >= right),
+        "==" => Object::Boolean(left == right),
+        "!=" => Object::Boolean(left != right),
+        _ => Object::Error(format!("unknown operator: FLOAT {} FLOAT", operator)),
+    }
+}
+
+fn apply_function(function: Object, args: Vec<Object>) -> Object {
+    let function = match function {
+        Object::Function(function) => function,
+        Object::Builtin(_, builtin) => return builtin(&args),
+        other => return Object::Error(format!("not a function: {}", other.type_name())),
+    };
+    if function.parameters.len() != args.len() {
+        return Object::Error(format!(
+            "wrong number of arguments: want={}, got={}", function.parameters.len(), args.len()
+        ));
+    }
+    let depth = CALL_DEPTH.get();
+    if depth >= MAX_CALL_DEPTH {
+        return Object::Error("stack overflow".to_string());
+    }
+    let env = Environment::enclosed(function.env.clone());
+    for (parameter, arg) in function.parameters.iter().zip(args) {
+        env.borrow_mut().set(&parameter.value, arg);
+    }
+    CALL_DEPTH.set(depth + 1);
+    let result = eval_block_statement(&function.body, &env);
+    CALL_DEPTH.set(depth);
+    match result {
+        Object::ReturnValue(value) => *value,
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lexer::{Lexer, RawMonkeyProgram};
+    use crate::parser::Parser;
+    use super::*;
+
+    fn eval(input: &str) -> Object {
+        let p = RawMonkeyProgram::new(input);
+        let mut parser = Parser::new(Lexer::new(&p));
+        let program = parser.parse().unwrap_or_else(|e| panic!("parser had errors for {:?}:\n{:?}", input, e));
+        eval_program(&program, &Environment::new())
+    }
+
+    fn assert_evals(tests: &[(&str, Object)]) {
+        for (input, expected) in tests {
+            assert_eq!(&eval(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_integer_expressions() {
+        use Object::Integer as I;
+        assert_evals(&[
+            ("5", I(5)),
+            ("-10", I(-10)),
+            ("--10", I(10)),
+            ("5 + 5 + 5 + 5 - 10", I(10)),
+            ("2 * 2 * 2 * 2 * 2", I(32)),
+            ("-50 + 100 + -50", I(0)),
+            ("20 + 2 * -10", I(0)),
+            ("50 / 2 * 2 + 10", I(60)),
+            ("2 * (5 + 10)", I(30)),
+            ("(5 + 10 * 2 + 15 / 3) * 2 + -10", I(50)),
+        ]);
+    }
+
+    #[test]
+    fn test_float_expressions() {
+        use Object::Float as F;
+        assert_evals(&[
+            ("1.5", F(1.5)),
+            ("-0.5 * 4.0", F(-2.0)),
+            ("1 / 4.0", F(0.25)),
+            ("2.5 + 1", F(3.5)),
+            ("1.0 / 0.0", F(f64::INFINITY)),
+            ("0.1 + 0.2 > 0.3", Object::Boolean(true)),
+            ("1 <= 1.0", Object::Boolean(true)),
+            ("2.0 == 2", Object::Boolean(true)),
+            ("1.5 + true", Object::Error("type mismatch: FLOAT + BOOLEAN".to_string())),
+            ("{1.5: 1}", Object::Error("unusable as hash key: FLOAT".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        use Object::Boolean as B;
+        assert_evals(&[
+            ("true && false", B(false)),
+            ("true && 1", B(true)),
+            ("false || 0", B(true)),
+            ("1 < 2 && 2 <= 2 && 3 >= 4 || !false", B(true)),
+            // The right operand is not evaluated when the left one decides
+            ("false && undefined", B(false)),
+            ("true || undefined", B(true)),
+            ("true && undefined", Object::Error("identifier not found: undefined".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn test_boolean_expressions() {
+        use Object::Boolean as B;
+        assert_evals(&[
+            ("true", B(true)),
+            ("1 < 2", B(true)),
+            ("1 > 2", B(false)),
+            ("1 == 1", B(true)),
+            ("1 != 2", B(true)),
+            ("true == true", B(true)),
+            ("true != false", B(true)),
+            ("(1 < 2) == true", B(true)),
+            ("(1 > 2) == true", B(false)),
+            ("!true", B(false)),
+            ("!5", B(false)),
+            ("!!5", B(true)),
+        ]);
+    }
+
+    #[test]
+    fn test_if_else_expressions() {
+        use Object::Integer as I;
+        assert_evals(&[
+            ("if (true) { 10 }", I(10)),
+            ("if (false) { 10 }", Object::Null),
+            ("if (1) { 10 }", I(10)),
+            ("if (1 > 2) { 10 }", Object::Null),
+            ("if (1 > 2) { 10 } else { 20 }", I(20)),
+            ("if (1 < 2) { 10 } else { 20 }", I(10)),
+        ]);
+    }
+
+    #[test]
+    fn test_return_statements() {
+        use Object::Integer as I;
+        assert_evals(&[
+            ("return 10;", I(10)),
+            ("return 10; 9;", I(10)),
+            ("9; return 2 * 5; 9;", I(10)),
+            ("if (10 > 1) { if (10 > 1) { return 10; } return 1; }", I(10)),
+            ("let f = fn(x) { return x; x + 10; }; f(10);", I(10)),
+        ]);
+    }
+
+    #[test]
+    fn test_error_handling() {
+        let error = |message: &str| Object::Error(message.to_string());
+        assert_evals(&[
+            ("5 + true;", error("type mismatch: INTEGER + BOOLEAN")),
+            ("5 + true; 5;", error("type mismatch: INTEGER + BOOLEAN")),
+            ("-true", error("unknown operator: -BOOLEAN")),
+            ("true + false;", error("unknown operator: BOOLEAN + BOOLEAN")),
+            ("if (10 > 1) { if (10 > 1) { return true + false; } return 1; }", error("unknown operator: BOOLEAN + BOOLEAN")),
+            ("foobar", error("identifier not found: foobar")),
+            ("1 / 0", error("division by zero")),
+            ("5(1)", error("not a function: INTEGER")),
+            ("fn(x) { x }(1, 2)", error("wrong number of arguments: want=1, got=2")),
+            (r#""Hello" - "World""#, error("unknown operator: STRING - STRING")),
+            (r#"{"name": "Monkey"}[fn(x) { x }];"#, error("unusable as hash key: FUNCTION")),
+            ("{[1]: 2}", error("unusable as hash key: ARRAY")),
+            ("1[0]", error("index operator not supported: INTEGER")),
+        ]);
+    }
+
+    #[test]
+    fn test_call_depth_is_limited() {
+        // The test threads have less stack than the calls allowed need
+        let deep = std::thread::Builder::new().stack_size(256 << 20).spawn(|| {
+            let env = Environment::new();
+            let run = |input: &str| {
+                let p = RawMonkeyProgram::new(input);
+                eval_program(&Parser::new(Lexer::new(&p)).parse().unwrap(), &env)
+            };
+            run("let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } };");
+            assert_eq!(run("f(100000)"), Object::Error("stack overflow".to_string()));
+            // Calls that failed do not count against the next ones
+            assert_eq!(run("f(1000)"), Object::Integer(0));
+        });
+        deep.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_let_statements() {
+        use Object::Integer as I;
+        assert_evals(&[
+            ("let a = 5; a;", I(5)),
+            ("let a = 5 * 5; a;", I(25)),
+            ("let a = 5; let b = a; b;", I(5)),
+            ("let a = 5; let b = a; let c = a + b + 5; c;", I(15)),
+        ]);
+    }
+
+    #[test]
+    fn test_functions() {
+        match eval("fn(x) { x + 2; };") {
+            Object::Function(function) => {
+                assert_eq!(function.parameters.len(), 1);
+                assert_eq!(function.body.to_string(), "(x + 2)");
+            }
+            other => panic!("not a function: {:?}", other),
+        }
+        use Object::Integer as I;
+        assert_evals(&[
+            ("let add = fn(a, b) { a + b }; add(1, 2)", I(3)),
+            ("let identity = fn(x) { x; }; identity(5);", I(5)),
+            ("let double = fn(x) { x * 2; }; double(5);", I(10)),
+            ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", I(20)),
+            ("fn(x) { x; }(5)", I(5)),
+        ]);
+    }
+
+    #[test]
+    fn test_closures_and_higher_order_functions() {
+        use Object::Integer as I;
+        assert_evals(&[
+            ("let newAdder = fn(x) { fn(y) { x + y } }; let addTwo = newAdder(2); addTwo(2);", I(4)),
+            ("let add = fn(a, b) { a + b }; let applyFunc = fn(a, b, func) { func(a, b) }; applyFunc(2, 2, add);", I(4)),
+            ("let x = 1; let f = fn() { x }; let g = fn(x) { f() }; g(5)", I(1)),
+            ("let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)", I(610)),
+        ]);
+    }
+
+    fn string(value: &str) -> Object {
+        Object::String(value.into())
+    }
+
+    fn array(elements: &[i64]) -> Object {
+        Object::Array(Rc::new(elements.iter().map(|e| Object::Integer(*e)).collect()))
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_evals(&[
+            (r#""Hello World!""#, string("Hello World!")),
+            (r#""Hello" + " " + "World!""#, string("Hello World!")),
+            (r#""a" == "a""#, Object::Boolean(true)),
+            (r#""a" != "a""#, Object::Boolean(false)),
+        ]);
+    }
+
+    #[test]
+    fn test_arrays() {
+        use Object::Integer as I;
+        assert_evals(&[
+            ("[1, 2 * 2, 3 + 3]", array(&[1, 4, 6])),
+            ("[1, 2, 3][0]", I(1)),
+            ("[1, 2, 3][1 + 1];", I(3)),
+            ("let i = 0; [1][i];", I(1)),
+            ("let myArray = [1, 2, 3]; myArray[0] + myArray[1] + myArray[2];", I(6)),
+            ("[1, 2, 3][3]", Object::Null),
+            ("[1, 2, 3][-1]", Object::Null),
+        ]);
+    }
+
+    #[test]
+    fn test_hashes() {
+        use Object::Integer as I;
+        let input = r#"let two = "two";
+            {"one": 10 - 9, two: 1 + 1, "thr" + "ee": 6 / 2, 4: 4, true: 5, false: 6}"#;
+        match eval(input) {
+            Object::Hash(pairs) => {
+                let expected = [
+                    (string("one"), 1), (string("two"), 2), (string("three"), 3),
+                    (I(4), 4), (Object::Boolean(true), 5), (Object::Boolean(false), 6),
+                ];
+                assert_eq!(pairs.len(), expected.len());
+                for (key, value) in expected {
+                    assert_eq!(pairs[&key.hash_key().unwrap()].value, I(value));
+                }
+            }
+            other => panic!("not a hash: {:?}", other),
+        }
+        assert_evals(&[
+            (r#"{"foo": 5}["foo"]"#, I(5)),
+            (r#"{"foo": 5}["bar"]"#, Object::Null),
+            (r#"let key = "foo"; {"foo": 5}[key]"#, I(5)),
+            (r#"{}["foo"]"#, Object::Null),
+            ("{5: 5}[5]", I(5)),
+            ("{true: 5}[true]", I(5)),
+        ]);
+    }
+
+    #[test]
+    fn test_builtin_functions() {
+        use Object::Integer as I;
+        let error = |message: &str| Object::Error(message.to_string());
+        assert_evals(&[
+            (r#"len("")"#, I(0)),
+            (r#"len("four")"#, I(4)),
+            (r#"len("hello world")"#, I(11)),
+            ("len(1)", error("argument to `len` not supported, got INTEGER")),
+            (r#"len("one", "two")"#, error("wrong number of arguments. got=2, want=1")),
+            ("len([1, 2, 3])", I(3)),
+            ("len([])", I(0)),
+            ("first([1, 2, 3])", I(1)),
+            ("first([])", Object::Null),
+            ("first(1)", error("argument to `first` must be ARRAY, got INTEGER")),
+            ("last([1, 2, 3])", I(3)),
+            ("last([])", Object::Null),
+            ("rest([1, 2, 3])", array(&[2, 3])),
+            ("rest([])", Object::Null),
+            ("push([], 1)", array(&[1])),
+            ("let a = [1]; push(a, 2); a", array(&[1])),
+            ("push(1, 1)", error("argument to `push` must be ARRAY, got INTEGER")),
+            ("puts()", Object::Null),
+            ("let len = fn(x) { 42 }; len([])", I(42)),
+        ]);
+    }
+
+    #[test]
+    fn test_map_and_reduce() {
+        let input = "
+            let map = fn(arr, f) {
+                let iter = fn(arr, accumulated) {
+                    if (len(arr) == 0) { accumulated } else { iter(rest(arr), push(accumulated, f(first(arr)))) }
+                };
+                iter(arr, []);
+            };
+            let reduce = fn(arr, initial, f) {
+                let iter = fn(arr, result) {
+                    if (len(arr) == 0) { result } else { iter(rest(arr), f(result, first(arr))) }
+                };
+                iter(arr, initial);
+            };
+            let doubled = map([1, 2, 3, 4], fn(x) { x * 2 });
+            [doubled, reduce(doubled, 0, fn(acc, x) { acc + x })]
+        ";
+        let expected = Object::Array(Rc::new(vec![array(&[2, 4, 6, 8]), Object::Integer(20)]));
+        assert_eq!(eval(input), expected);
+    }
+}
@@ -0,0 +1,350 @@
+//! Canonical source text for a program, as `monkey fmt` writes it.
+//!
+//! Unlike the `Display` of the syntax tree, which puts every operation in parentheses, this only
+//! puts them where precedence requires and lays blocks out over several indented lines.
+//! Comments are kept, each on its own line before the statement following it.
+
+use crate::lexer::RawMonkeyProgram;
+use crate::parser::{BlockStatement, Expression, Identifier, Precedence, Program, Statement};
+use crate::token::Slice;
+
+const INDENT: &str = "    ";
+
+/// Format `program`, parsed from `p`, with the `comments` the parser skipped in it.
+pub fn format_program(program: &Program, p: &RawMonkeyProgram, comments: &[Slice]) -> String {
+    let mut formatter = Formatter {
+        out: String::new(),
+        depth: 0,
+        comments: comments.iter().map(|c| (c.start, p.substring(*c))).collect(),
+        next_comment: 0,
+    };
+    formatter.statements(&program.statements, false);
+    formatter.comments_before(usize::MAX);
+    formatter.out
+}
+
+struct Formatter {
+    out: String,
+    /// How many blocks deep the current line is
+    depth: usize,
+    /// Where each comment starts and its text
+    comments: Vec<(usize, String)>,
+    next_comment: usize,
+}
+
+impl Formatter {
+    /// One statement per line. The last one of a block is its value and goes without `;`, as
+    /// does an `if` unless the next statement would otherwise continue it.
+    fn statements(&mut self, statements: &[Statement], in_block: bool) {
+        let mut open_if = None;
+        for (i, statement) in statements.iter().enumerate() {
+            self.comments_before(statement_start(statement));
+            self.out.push_str(&INDENT.repeat(self.depth));
+            let start = self.out.len();
+            self.statement(statement, in_block && i == statements.len() - 1);
+            if let Some(end) = open_if.take() {
+                if self.out[start..].starts_with(['(', '[', '-']) {
+                    self.out.insert(end, ';');
+                }
+            }
+            if let Statement::Expression { expression: Expression::If { .. }, .. } = statement {
+                open_if = Some(self.out.len());
+            }
+            self.out.push('\n');
+        }
+    }
+
+    /// Put the comments up to `position` that are not out yet on lines of their own.
+    fn comments_before(&mut self, position: usize) {
+        while let Some((start, text)) = self.comments.get(self.next_comment) {
+            if *start >= position {
+                break;
+            }
+            self.out.push_str(&INDENT.repeat(self.depth));
+            self.out.push_str(text);
+            self.out.push('\n');
+            self.next_comment += 1;
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement, is_value: bool) {
+        match statement {
+            Statement::Let { name, value, .. } => {
+                self.out.push_str("let ");
+                self.out.push_str(&name.value);
+                self.out.push_str(" = ");
+                self.expression(value);
+                self.out.push(';');
+            }
+            Statement::Return { value, .. } => {
+                self.out.push_str("return ");
+                self.expression(value);
+                self.out.push(';');
+            }
+            Statement::Expression { expression, .. } => {
+                self.expression(expression);
+                if !is_value && !matches!(expression, Expression::If { .. }) {
+                    self.out.push(';');
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, block: &BlockStatement) {
+        if block.statements.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push_str("{\n");
+        self.depth += 1;
+        self.statements(&block.statements, true);
+        self.depth -= 1;
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.out.push('}');
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Identifier(identifier) => self.out.push_str(&identifier.value),
+            Expression::Integer { value, .. } => self.out.push_str(&value.to_string()),
+            Expression::Float { value, .. } => {
+                // Never in exponent notation, but without a fraction it would read as an integer
+                let literal = value.to_string();
+                self.out.push_str(&literal);
+                if !literal.contains('.') {
+                    self.out.push_str(".0");
+                }
+            }
+            Expression::Boolean { value, .. } => self.out.push_str(&value.to_string()),
+            Expression::String { value, .. } => {
+                self.out.push('"');
+                self.out.push_str(value);
+                self.out.push('"');
+            }
+            Expression::Array { elements, .. } => {
+                self.out.push('[');
+                self.list(elements);
+                self.out.push(']');
+            }
+            Expression::Hash { pairs, .. } => {
+                self.out.push('{');
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(key);
+                    self.out.push_str(": ");
+                    self.expression(value);
+                }
+                self.out.push('}');
+            }
+            Expression::Index { left, index, .. } => {
+                self.operand(left, precedence(left) < Precedence::Call);
+                self.out.push('[');
+                self.expression(index);
+                self.out.push(']');
+            }
+            Expression::Prefix { operator, right, .. } => {
+                self.out.push_str(operator);
+                self.operand(right, precedence(right) < Precedence::Prefix);
+            }
+            Expression::Infix { token, left, operator, right } => {
+                // Operators associate to the left, so only the right operand needs parentheses
+                // at the same precedence
+                let own = Precedence::of(token.tok_type);
+                self.operand(left, precedence(left) < own);
+                self.out.push(' ');
+                self.out.push_str(operator);
+                self.out.push(' ');
+                self.operand(right, precedence(right) <= own);
+            }
+            Expression::If { condition, consequence, alternative, .. } => {
+                self.out.push_str("if (");
+                self.expression(condition);
+                self.out.push_str(") ");
+                self.block(consequence);
+                if let Some(alternative) = alternative {
+                    self.out.push_str(" else ");
+                    self.block(alternative);
+                }
+            }
+            Expression::Function { parameters, body, .. } => self.function("fn", parameters, body),
+            Expression::Macro { parameters, body, .. } => self.function("macro", parameters, body),
+            Expression::Call { function, arguments, .. } => {
+                self.operand(function, precedence(function) < Precedence::Call);
+                self.out.push('(');
+                self.list(arguments);
+                self.out.push(')');
+            }
+        }
+    }
+
+    fn operand(&mut self, expression: &Expression, parenthesize: bool) {
+        if parenthesize {
+            self.out.push('(');
+        }
+        self.expression(expression);
+        if parenthesize {
+            self.out.push(')');
+        }
+    }
+
+    fn list(&mut self, expressions: &[Expression]) {
+        for (i, expression) in expressions.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expression(expression);
+        }
+    }
+
+    fn function(&mut self, keyword: &str, parameters: &[Identifier], body: &BlockStatement) {
+        self.out.push_str(keyword);
+        self.out.push('(');
+        let parameters = parameters.iter().map(|p| p.value.as_str()).collect::<Vec<_>>();
+        self.out.push_str(&parameters.join(", "));
+        self.out.push_str(") ");
+        self.block(body);
+    }
+}
+
+fn statement_start(statement: &Statement) -> usize {
+    match statement {
+        Statement::Let { token, .. } | Statement::Return { token, .. } | Statement::Expression { token, .. } => {
+            token.literal.start
+        }
+    }
+}
+
+/// How tightly `expression` holds together, everything but operators is as tight as it gets.
+fn precedence(expression: &Expression) -> Precedence {
+    match expression {
+        Expression::Infix { token, .. } => Precedence::of(token.tok_type),
+        Expression::Prefix { .. } => Precedence::Prefix,
+        Expression::Call { .. } => Precedence::Call,
+        _ => Precedence::Index,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lexer::{Lexer, RawMonkeyProgram};
+    use crate::parser::Parser;
+    use super::*;
+
+    fn parse(input: &str) -> Program {
+        let p = RawMonkeyProgram::new(input);
+        Parser::new(Lexer::new(&p)).parse().unwrap_or_else(|e| panic!("parser had errors for {:?}:\n{:?}", input, e))
+    }
+
+    fn format(input: &str) -> String {
+        let p = RawMonkeyProgram::new(input);
+        let mut parser = Parser::new(Lexer::new(&p));
+        let program = parser.parse().unwrap_or_else(|e| panic!("parser had errors for {:?}:\n{:?}", input, e));
+        format_program(&program, &p, parser.comments())
+    }
+
+    #[test]
+    fn test_canonical_source_round_trips() {
+        let input = r#"let fibonacci = fn(x) {
+    if (x < 2) {
+        return x;
+    }
+    fibonacci(x - 1) + fibonacci(x - 2)
+};
+let people = [{"name": "Anna", "age": 24}, {}];
+let unless = macro(condition, consequence) {
+    quote(if (!unquote(condition)) {
+        unquote(consequence)
+    })
+};
+puts(fibonacci(10), people[0]["name"], fn() {}());
+if (true) {
+    1
+} else {
+    let x = 2;
+    x
+}
+"#;
+        assert_eq!(format(input), input);
+    }
+
+    #[test]
+    fn test_normalizes_layout() {
+        let input = "let   add=fn(a,b){a+b;} ;  if(add(1,2)>2){puts( \"big\" );}else{ 3 }; add ( 1 , 2 )";
+        let expected = r#"let add = fn(a, b) {
+    a + b
+};
+if (add(1, 2) > 2) {
+    puts("big")
+} else {
+    3
+}
+add(1, 2);
+"#;
+        assert_eq!(format(input), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn test_parentheses_only_where_needed() {
+        let tests = [
+            ("((a + b)) * c", "(a + b) * c;\n"),
+            ("a + (b * c)", "a + b * c;\n"),
+            ("(a - b) - c", "a - b - c;\n"),
+            ("a - (b - c)", "a - (b - c);\n"),
+            ("-(a + b)", "-(a + b);\n"),
+            ("-(a[0])", "-a[0];\n"),
+            ("(-a)[0]", "(-a)[0];\n"),
+            ("(a + b)(c)", "(a + b)(c);\n"),
+            ("(f(1))[0]", "f(1)[0];\n"),
+            ("!(a == b) != (c < d)", "!(a == b) != c < d;\n"),
+            ("a == (b == c)", "a == (b == c);\n"),
+            ("(a || b) && c || d", "(a || b) && c || d;\n"),
+            ("a <= (b >= c)", "a <= (b >= c);\n"),
+            ("if (a) { b }; -c; if (a) { b } c", "if (a) {\n    b\n};\n-c;\nif (a) {\n    b\n}\nc;\n"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(format(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_keeps_comments() {
+        let input = "// adds\nlet add = fn(a, b) {\n/* the sum */ a + b // of both\n};\nadd(1.5, 2) /* done */";
+        let expected = "\
+// adds
+let add = fn(a, b) {
+    /* the sum */
+    a + b
+};
+// of both
+add(1.5, 2);
+/* done */
+";
+        assert_eq!(format(input), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn test_floats_stay_floats() {
+        assert_eq!(format("1.0 + 0.25 * 100000000000000000000.0"), "1.0 + 0.25 * 100000000000000000000.0;\n");
+    }
+
+    #[test]
+    fn test_formatting_keeps_meaning() {
+        let inputs = [
+            "a + b * c + d / e - f",
+            "3 + 4 * 5 == 3 * 1 + 4 * 5",
+            "-(5 + 5) * -a[1 + 2]",
+            "a * [1, 2, 3, 4][b * c] * d",
+            "add(a, b, 1, 2 * 3, 4 + 5, add(6, 7 * 8))(9)",
+            "!-a; !(true == !false)",
+            "let f = fn(x, y) { x - (y - (x - y)) }; f(1, 2)",
+        ];
+        for input in inputs {
+            let formatted = format(input);
+            assert_eq!(parse(&formatted).to_string(), parse(input).to_string(), "{} became {}", input, formatted);
+        }
+    }
+}
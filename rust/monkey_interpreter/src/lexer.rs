@@ -1,5 +1,4 @@
-use std::marker::PhantomData;
-use crate::token::{TokenType, Token, Slice};
+use crate::token::{IllegalReason, TokenType, Token, Slice};
 
 pub struct RawMonkeyProgram {
     input: Vec<char>,
@@ -23,6 +22,25 @@ impl RawMonkeyProgram {
     pub fn token_substring(&self, token: Token) -> String {
         String::from_iter(self.slice(token.literal).iter())
     }
+
+    /// 1-based line and column of the character at `offset`.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let before = &self.input[..offset.min(self.input.len())];
+        let line = before.iter().filter(|ch| **ch == '\n').count() + 1;
+        let column = before.iter().rev().take_while(|ch| **ch != '\n').count() + 1;
+        (line, column)
+    }
+
+    /// Text of the 1-based `line`, without its line break.
+    pub fn line(&self, line: usize) -> String {
+        let text = String::from_iter(self.input.iter());
+        text.lines().nth(line - 1).unwrap_or("").to_string()
+    }
+
+    /// Just past the last character that is not whitespace, where a missing token is reported.
+    pub fn end_of_input(&self) -> usize {
+        self.input.len() - self.input.iter().rev().take_while(|ch| ch.is_whitespace()).count()
+    }
 }
 
 
@@ -33,13 +51,11 @@ impl From<&str> for RawMonkeyProgram {
 }
 
 pub struct Lexer<'a> {
-     program: &'a RawMonkeyProgram,
+    program: &'a RawMonkeyProgram,
     /// current position in input (points to current char)
     position: usize,
-    /// current reading position in input (after current char)
-    read_position: usize,
-    /// current char under examination
-    current_ch: char,
+    /// Every comment skipped so far
+    comments: Vec<Slice>,
 }
 
 impl<'a> Lexer<'a> {
@@ -47,8 +63,7 @@ impl<'a> Lexer<'a> {
         Self {
             program,
             position: 0,
-            read_position: 0,
-            current_ch: '\0',
+            comments: Vec::new(),
         }
     }
 
@@ -56,90 +71,147 @@ impl<'a> Lexer<'a> {
         self.program
     }
 
+    /// The comments skipped so far, whole, `//` or `/*` included.
+    pub fn comments(&self) -> &[Slice] {
+        &self.comments
+    }
+
+    /// Never fails, whatever cannot be made sense of becomes an `Illegal` token saying why.
     pub fn next_token(&mut self) -> Option<Token> {
-        self.eat_whitespace();
-        if self.position >= self.program.input.len() {
-            return None;
+        if let Some(illegal) = self.skip_whitespace_and_comments() {
+            return Some(illegal);
         }
+        let ch = self.peek(0)?;
         use TokenType as T;
-        let (t, len) = match self.program.input[self.position] {
-            '=' => {
-                if self.program.input[self.position + 1] == '=' {
-                    (T::Eq, 2)
-                } else {
-                    (T::Assign, 1)
-                }
-            }
-            '+' => (T::Plus, 1),
-            '-' => (T::Minus, 1),
-            '!' => {
-                if self.program.input[self.position + 1] == '=' {
-                    (T::NotEq, 2)
-                } else {
-                    (T::Bang, 1)
-                }
-            }
-            '/' => (T::Slash, 1),
-            '*' => (T::Asterisk, 1),
-            '<' => (T::Lt, 1),
-            '>' => (T::Gt, 1),
-            ';' => (T::Semicolon, 1),
-            ',' => (T::Comma, 1),
-            '(' => (T::LParen, 1),
-            ')' => (T::RParen, 1),
-            '{' => (T::LBrace, 1),
-            '}' => (T::RBrace, 1),
-            ch => {
-                if is_identifier_char(ch) {
-                    let len = find_identifier_end(&self.program.input[self.position..]);
-                    let tok_type = TokenType::from_identifier(
-                        &self.program.input[self.position..self.position + len]);
-                    (tok_type, len)
-                } else if ch.is_ascii_digit() {
-                    let len = self.program.input[self.position..].iter()
-                        .take_while(|ch| ch.is_ascii_digit())
-                        .count();
-                    (T::Int, len)
-                } else {
-                    (T::Illegal, 1)
-                }
+        let (t, len) = match (ch, self.peek(1)) {
+            ('=', Some('=')) => (T::Eq, 2),
+            ('=', _) => (T::Assign, 1),
+            ('!', Some('=')) => (T::NotEq, 2),
+            ('!', _) => (T::Bang, 1),
+            ('<', Some('=')) => (T::LtEq, 2),
+            ('<', _) => (T::Lt, 1),
+            ('>', Some('=')) => (T::GtEq, 2),
+            ('>', _) => (T::Gt, 1),
+            ('&', Some('&')) => (T::And, 2),
+            ('|', Some('|')) => (T::Or, 2),
+            ('+', _) => (T::Plus, 1),
+            ('-', _) => (T::Minus, 1),
+            ('/', _) => (T::Slash, 1),
+            ('*', _) => (T::Asterisk, 1),
+            (';', _) => (T::Semicolon, 1),
+            (':', _) => (T::Colon, 1),
+            (',', _) => (T::Comma, 1),
+            ('(', _) => (T::LParen, 1),
+            (')', _) => (T::RParen, 1),
+            ('{', _) => (T::LBrace, 1),
+            ('}', _) => (T::RBrace, 1),
+            ('[', _) => (T::LBracket, 1),
+            (']', _) => (T::RBracket, 1),
+            ('"', _) => return Some(self.read_string()),
+            (ch, _) if is_identifier_start(ch) => {
+                let len = find_identifier_end(&self.program.input[self.position..]);
+                let tok_type = TokenType::from_identifier(
+                    &self.program.input[self.position..self.position + len]);
+                (tok_type, len)
             }
+            (ch, _) if ch.is_ascii_digit() => self.number(),
+            (ch, _) => (T::Illegal(IllegalReason::UnexpectedCharacter(ch)), 1),
         };
         let p = self.position;
         self.position += len;
         Some(Token::new(t, Slice::new(p, len)))
     }
 
-    fn eat_whitespace(&mut self) {
-        self.position +=
-            self.program.input[self.position..].iter()
-                .take_while(|ch| ch.is_whitespace())
-                .count()
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.program.input.get(self.position + offset).copied()
+    }
+
+    /// The literal is only what is between the quotes.
+    fn read_string(&mut self) -> Token {
+        let start = self.position + 1;
+        let len = self.program.input[start..].iter()
+            .take_while(|ch| **ch != '"')
+            .count();
+        if start + len >= self.program.input.len() {
+            let token = Token::new(
+                TokenType::Illegal(IllegalReason::UnterminatedString), Slice::new(self.position, len + 1));
+            self.position = self.program.input.len();
+            return token;
+        }
+        self.position = start + len + 1;
+        Token::new(TokenType::String, Slice::new(start, len))
+    }
+
+    /// Digits, followed by a fraction for a float.
+    fn number(&self) -> (TokenType, usize) {
+        let digits = |from: usize| self.program.input[from.min(self.program.input.len())..].iter()
+            .take_while(|ch| ch.is_ascii_digit())
+            .count();
+        let len = digits(self.position);
+        if self.peek(len) != Some('.') {
+            return (TokenType::Int, len);
+        }
+        match digits(self.position + len + 1) {
+            0 => (TokenType::Illegal(IllegalReason::MissingFraction), len + 1),
+            fraction => (TokenType::Float, len + 1 + fraction),
+        }
     }
-}
 
-//
-// impl<'a> Iterator for Lexer<'a> {
-//     type Item = Token<'a>;
-//
-//     fn next(&mut self) -> Option<Self::Item> {
-//     }
-// }
+    /// Move past whitespace and comments, or return an unterminated block comment.
+    fn skip_whitespace_and_comments(&mut self) -> Option<Token> {
+        loop {
+            self.position +=
+                self.program.input[self.position..].iter()
+                    .take_while(|ch| ch.is_whitespace())
+                    .count();
+            let start = self.position;
+            match (self.peek(0), self.peek(1)) {
+                (Some('/'), Some('/')) => {
+                    self.position += self.program.input[start..].iter()
+                        .take_while(|ch| **ch != '\n')
+                        .count();
+                }
+                (Some('/'), Some('*')) => {
+                    let close = self.program.input[start + 2..].windows(2).position(|w| w == ['*', '/']);
+                    let Some(close) = close else {
+                        self.position = self.program.input.len();
+                        let span = Slice::new(start, self.position - start);
+                        return Some(Token::new(TokenType::Illegal(IllegalReason::UnterminatedComment), span));
+                    };
+                    self.position = start + 2 + close + 2;
+                }
+                _ => return None,
+            }
+            self.comments.push(Slice::new(start, self.position - start));
+        }
+    }
+}
 
-fn is_identifier_char(ch: char) -> bool {
-    ch.is_alphabetic()
+fn is_identifier_start(ch: char) -> bool {
+    ch.is_alphabetic() || ch == '_'
 }
 
+/// Identifiers start with a letter or `_`, in any script, and go on with letters, digits and `_`.
 fn find_identifier_end(chs: &[char]) -> usize {
     chs.iter()
-        .take_while(|ch| is_identifier_char(**ch))
+        .take_while(|ch| ch.is_alphanumeric() || **ch == '_')
         .count()
 }
 
 #[cfg(test)]
 mod test {
     use crate::lexer::{Lexer, RawMonkeyProgram};
-    use crate::token::TokenType;
+    use crate::parser::Parser;
+    use crate::token::{IllegalReason, Slice, TokenType};
+
+    /// Every token of `input` with its text.
+    fn tokens(input: &str) -> Vec<(TokenType, String)> {
+        let p = RawMonkeyProgram::new(input);
+        let mut l = Lexer::new(&p);
+        std::iter::from_fn(|| l.next_token())
+            .map(|t| (t.tok_type, p.token_substring(t)))
+            .collect()
+    }
 
     #[test]
     fn test_next_token_basics() {
@@ -175,14 +247,14 @@ mod test {
 
     #[test]
     fn test_next_token() {
-        let input = r"let five = 5;
+        let input = r#"let five = 5;
 let ten = 10;
 let add = fn(x, y) {
   x + y;
 };
 
 let result = add(five, ten);
-!-/*5;
+!-/ *5;
 5 < 10 > 5;
 
 if (5 < 10) {
@@ -192,7 +264,11 @@ if (5 < 10) {
 }
 10 == 10;
 10 != 9;
-";
+"foobar"
+"foo bar"
+[1, 2];
+{"foo": "bar"}
+"#;
 
         use TokenType as T;
         let tests: Vec<(TokenType, &str)> = vec![
@@ -269,6 +345,19 @@ if (5 < 10) {
             (T::NotEq, "!="),
             (T::Int, "9"),
             (T::Semicolon, ";"),
+            (T::String, "foobar"),
+            (T::String, "foo bar"),
+            (T::LBracket, "["),
+            (T::Int, "1"),
+            (T::Comma, ","),
+            (T::Int, "2"),
+            (T::RBracket, "]"),
+            (T::Semicolon, ";"),
+            (T::LBrace, "{"),
+            (T::String, "foo"),
+            (T::Colon, ":"),
+            (T::String, "bar"),
+            (T::RBrace, "}"),
             (T::Eof, ""),
         ];
 
@@ -286,4 +375,89 @@ if (5 < 10) {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_comments_floats_and_operators() {
+        use TokenType as T;
+        let input = "a <= b >= c && d || e // to the end\n/* over\n lines */ 1.5 x_1 _y ünïcödé 変数";
+        let expected = [
+            (T::Identifier, "a"), (T::LtEq, "<="), (T::Identifier, "b"), (T::GtEq, ">="), (T::Identifier, "c"),
+            (T::And, "&&"), (T::Identifier, "d"), (T::Or, "||"), (T::Identifier, "e"), (T::Float, "1.5"),
+            (T::Identifier, "x_1"), (T::Identifier, "_y"), (T::Identifier, "ünïcödé"), (T::Identifier, "変数"),
+        ];
+        let expected = expected.iter().map(|(t, s)| (*t, s.to_string())).collect::<Vec<_>>();
+        assert_eq!(tokens(input), expected);
+
+        let p = RawMonkeyProgram::new(input);
+        let mut l = Lexer::new(&p);
+        while l.next_token().is_some() {}
+        let comments = l.comments().iter().map(|c| p.substring(*c)).collect::<Vec<_>>();
+        assert_eq!(comments, ["// to the end", "/* over\n lines */"]);
+    }
+
+    #[test]
+    fn test_illegal_tokens() {
+        use TokenType as T;
+        use IllegalReason as R;
+        let illegal = |reason, s: &str| (T::Illegal(reason), s.to_string());
+        assert_eq!(tokens("a @ b")[1], illegal(R::UnexpectedCharacter('@'), "@"));
+        assert_eq!(tokens("a & b")[1], illegal(R::UnexpectedCharacter('&'), "&"));
+        assert_eq!(tokens("x + \"abc"), [(T::Identifier, "x".to_string()), (T::Plus, "+".to_string()), illegal(R::UnterminatedString, "\"abc")]);
+        assert_eq!(tokens("1 /* never closed"), [(T::Int, "1".to_string()), illegal(R::UnterminatedComment, "/* never closed")]);
+        assert_eq!(tokens("1.;"), [illegal(R::MissingFraction, "1."), (T::Semicolon, ";".to_string())]);
+        // Operators that might have had a second character at the very end
+        for (input, t) in [("=", T::Assign), ("!", T::Bang), ("<", T::Lt), (">", T::Gt), ("/", T::Slash)] {
+            assert_eq!(tokens(input), [(t, input.to_string())]);
+        }
+        assert_eq!(tokens("|"), [illegal(R::UnexpectedCharacter('|'), "|")]);
+    }
+
+    /// Lex and parse `input`, checking every token lies within it after the previous one.
+    fn check_lexes(input: &str) {
+        let p = RawMonkeyProgram::new(input);
+        let len = input.chars().count();
+        let mut l = Lexer::new(&p);
+        let mut end = 0;
+        let mut count = 0;
+        while let Some(t) = l.next_token() {
+            let Slice { start, len: token_len } = t.literal;
+            assert!(start >= end && start + token_len <= len, "{:?} in {:?}", t, input);
+            end = start + token_len;
+            count += 1;
+            assert!(count <= len, "more tokens than characters in {:?}", input);
+        }
+        let _ = Parser::new(Lexer::new(&p)).parse();
+    }
+
+    #[test]
+    fn test_never_panics() {
+        // Every short combination of the characters the lexer looks ahead on
+        let alphabet = ['=', '!', '<', '>', '&', '|', '/', '*', '"', '.', '1', 'a', ' ', '\n', '{', '('];
+        let mut inputs = vec![String::new()];
+        for _ in 0..4 {
+            inputs = inputs.iter()
+                .flat_map(|s| alphabet.iter().map(move |ch| format!("{}{}", s, ch)))
+                .collect();
+            inputs.iter().for_each(|input| check_lexes(input));
+        }
+
+        // And longer random ones, anywhere in Unicode
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let len = next() % 40;
+            let input = (0..len)
+                .map(|_| match next() % 4 {
+                    0 => char::from_u32((next() % 0x11_0000) as u32).unwrap_or('\u{fffd}'),
+                    _ => alphabet[(next() % alphabet.len() as u64) as usize],
+                })
+                .collect::<String>();
+            check_lexes(&input);
+        }
+    }
+}
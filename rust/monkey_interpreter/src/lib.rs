@@ -0,0 +1,15 @@
+pub mod lexer;
+pub mod token;
+pub mod repl;
+pub mod parser;
+pub mod modify;
+pub mod format;
+pub mod diagnostic;
+pub mod object;
+pub mod evaluator;
+pub mod macro_expansion;
+pub mod builtins;
+pub mod code;
+pub mod symbol_table;
+pub mod compiler;
+pub mod vm;
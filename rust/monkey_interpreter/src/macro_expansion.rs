@@ -0,0 +1,390 @@
+//! Macros, expanded before a program is evaluated.
+//!
+//! `let name = macro(a, b) { ... };` at the top level defines a macro, [`define_macros`] takes
+//! those out of the program. [`expand_macros`] then replaces every call to one by what its body
+//! returns when called with the arguments quoted instead of evaluated. That has to be quoted
+//! code, usually built with `quote(...)` and `unquote(...)`:
+//!
+//! ```text
+//! let unless = macro(condition, consequence, alternative) {
+//!     quote(if (!(unquote(condition))) { unquote(consequence) } else { unquote(alternative) })
+//! };
+//! ```
+//!
+//! Expansion is hygienic: names bound by `let` or as parameters in the quoted code of a macro are
+//! renamed for every expansion, so they can neither capture nor shadow the caller's names.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use crate::evaluator;
+use crate::modify::{self, is_call_to, Modifier};
+use crate::object::{Env, Environment, Macro, Object};
+use crate::parser::{Expression, Program, Statement};
+use crate::token::{Token, TokenType};
+
+/// `quote(expression)` is `expression` itself rather than its value, with every `unquote(...)`
+/// in it replaced by the syntax of what its argument evaluates to.
+pub(crate) fn quote(arguments: &[Expression], env: &Env) -> Object {
+    let [expression] = arguments else {
+        return Object::Error(format!("wrong number of arguments: want=1, got={}", arguments.len()));
+    };
+    let mut error = None;
+    let expression = modify::modify_expression(expression.clone(), &mut |expression: Expression| {
+        if error.is_some() || !is_call_to(&expression, "unquote") {
+            return expression;
+        }
+        match unquote(&expression, env) {
+            Ok(unquoted) => unquoted,
+            Err(e) => {
+                error = Some(e);
+                expression
+            }
+        }
+    });
+    error.unwrap_or_else(|| Object::Quote(Rc::new(expression)))
+}
+
+fn unquote(call: &Expression, env: &Env) -> Result<Expression, Object> {
+    let Expression::Call { token, arguments, .. } = call else {
+        unreachable!("only called on calls to unquote");
+    };
+    let [argument] = arguments.as_slice() else {
+        return Err(Object::Error(format!("wrong number of arguments: want=1, got={}", arguments.len())));
+    };
+    let value = evaluator::eval_expression(argument, env);
+    if value.is_error() {
+        return Err(value);
+    }
+    to_syntax(value, *token)
+}
+
+/// The literal `value` would be written as, located where the `unquote` call was.
+fn to_syntax(value: Object, at: Token) -> Result<Expression, Object> {
+    let token = |tok_type| Token::new(tok_type, at.literal);
+    Ok(match value {
+        Object::Integer(value) => Expression::Integer { token: token(TokenType::Int), value },
+        Object::Float(value) => Expression::Float { token: token(TokenType::Float), value },
+        Object::Boolean(value) => {
+            let tok_type = if value { TokenType::True } else { TokenType::False };
+            Expression::Boolean { token: token(tok_type), value }
+        }
+        Object::String(value) => Expression::String { token: token(TokenType::String), value: value.to_string() },
+        Object::Array(elements) => Expression::Array {
+            token: token(TokenType::LBracket),
+            elements: elements.iter().map(|e| to_syntax(e.clone(), at)).collect::<Result<_, _>>()?,
+        },
+        Object::Quote(expression) => Rc::unwrap_or_clone(expression),
+        other => return Err(Object::Error(format!("cannot unquote {}", other.type_name()))),
+    })
+}
+
+/// Take the macro definitions out of `program` and bind them in `env`.
+pub fn define_macros(program: &mut Program, env: &Env) {
+    program.statements.retain(|statement| match statement {
+        Statement::Let { name, value: Expression::Macro { parameters, body, .. }, .. } => {
+            let definition = Macro { parameters: parameters.clone(), body: body.clone(), env: env.clone() };
+            env.borrow_mut().set(&name.value, Object::Macro(Rc::new(definition)));
+            false
+        }
+        _ => true,
+    });
+}
+
+/// Replace the calls to macros defined in `env` by their expansion, innermost first.
+pub fn expand_macros(program: Program, env: &Env) -> Result<Program, String> {
+    let mut expansions = 0;
+    let mut error = None;
+    let program = modify::modify_program(program, &mut |expression: Expression| {
+        if error.is_some() {
+            return expression;
+        }
+        let Some((definition, arguments)) = macro_call(&expression, env) else {
+            return expression;
+        };
+        expansions += 1;
+        match expand(&definition, arguments, expansions) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                error = Some(e);
+                expression
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(program),
+    }
+}
+
+fn macro_call(expression: &Expression, env: &Env) -> Option<(Rc<Macro>, Vec<Expression>)> {
+    let Expression::Call { function, arguments, .. } = expression else {
+        return None;
+    };
+    let Expression::Identifier(identifier) = function.as_ref() else {
+        return None;
+    };
+    match env.borrow().get(&identifier.value)? {
+        Object::Macro(definition) => Some((definition, arguments.clone())),
+        _ => None,
+    }
+}
+
+fn expand(definition: &Macro, arguments: Vec<Expression>, expansion: usize) -> Result<Expression, String> {
+    if definition.parameters.len() != arguments.len() {
+        return Err(format!(
+            "wrong number of arguments: want={}, got={}", definition.parameters.len(), arguments.len()
+        ));
+    }
+    let env = Environment::enclosed(definition.env.clone());
+    for (parameter, argument) in definition.parameters.iter().zip(arguments) {
+        env.borrow_mut().set(&parameter.value, Object::Quote(Rc::new(argument)));
+    }
+    let body = modify::modify_block(definition.body.clone(), &mut |expression: Expression| {
+        match is_call_to(&expression, "quote") {
+            true => hygienic(expression, expansion),
+            false => expression,
+        }
+    });
+    let value = match evaluator::eval_block_statement(&body, &env) {
+        Object::ReturnValue(value) => *value,
+        value => value,
+    };
+    match value {
+        Object::Quote(expression) => Ok(Rc::unwrap_or_clone(expression)),
+        Object::Error(message) => Err(message),
+        other => Err(format!("macros must return quoted code, got {}", other.type_name())),
+    }
+}
+
+/// Give the names bound in `quote`d code, other than in what is unquoted, a suffix no
+/// identifier in a program can have.
+fn hygienic(quote: Expression, expansion: usize) -> Expression {
+    let mut binders = Binders(HashSet::new());
+    let quote = modify::modify_expression(quote, &mut binders);
+    let renames = binders.0.into_iter()
+        .map(|name| {
+            let fresh = format!("{}#{}", name, expansion);
+            (name, fresh)
+        })
+        .collect();
+    modify::modify_expression(quote, &mut Rename(renames))
+}
+
+/// Collects the names bound by `let` and function parameters.
+struct Binders(HashSet<String>);
+
+impl Modifier for Binders {
+    fn enter(&mut self, expression: &Expression) -> bool {
+        !is_call_to(expression, "unquote")
+    }
+
+    fn expression(&mut self, expression: Expression) -> Expression {
+        if let Expression::Function { parameters, .. } = &expression {
+            self.0.extend(parameters.iter().map(|p| p.value.clone()));
+        }
+        expression
+    }
+
+    fn statement(&mut self, statement: Statement) -> Statement {
+        if let Statement::Let { name, .. } = &statement {
+            self.0.insert(name.value.clone());
+        }
+        statement
+    }
+}
+
+/// Renames identifiers wherever they are bound or used.
+struct Rename(HashMap<String, String>);
+
+impl Rename {
+    fn rename(&self, name: &mut String) {
+        if let Some(fresh) = self.0.get(name) {
+            name.clone_from(fresh);
+        }
+    }
+}
+
+impl Modifier for Rename {
+    fn enter(&mut self, expression: &Expression) -> bool {
+        !is_call_to(expression, "unquote")
+    }
+
+    fn expression(&mut self, mut expression: Expression) -> Expression {
+        match &mut expression {
+            Expression::Identifier(identifier) => self.rename(&mut identifier.value),
+            Expression::Function { parameters, .. } => {
+                parameters.iter_mut().for_each(|p| self.rename(&mut p.value));
+            }
+            _ => {}
+        }
+        expression
+    }
+
+    fn statement(&mut self, mut statement: Statement) -> Statement {
+        if let Statement::Let { name, .. } = &mut statement {
+            self.rename(&mut name.value);
+        }
+        statement
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lexer::{Lexer, RawMonkeyProgram};
+    use crate::parser::Parser;
+    use super::*;
+
+    fn parse(input: &str) -> Program {
+        let p = RawMonkeyProgram::new(input);
+        Parser::new(Lexer::new(&p)).parse().unwrap_or_else(|e| panic!("parser had errors for {:?}:\n{:?}", input, e))
+    }
+
+    fn eval(input: &str) -> Object {
+        evaluator::eval_program(&parse(input), &Environment::new())
+    }
+
+    /// Define the macros of `input`, expand them and evaluate what is left.
+    fn expand_and_eval(input: &str) -> Object {
+        let env = Environment::new();
+        let mut program = parse(input);
+        define_macros(&mut program, &env);
+        let program = expand_macros(program, &env).unwrap_or_else(|e| panic!("{}", e));
+        evaluator::eval_program(&program, &Environment::new())
+    }
+
+    fn quoted(input: &str) -> String {
+        match eval(input) {
+            Object::Quote(expression) => expression.to_string(),
+            other => panic!("not a quote for {:?}: {:?}", input, other),
+        }
+    }
+
+    #[test]
+    fn test_quote() {
+        let tests = [
+            ("quote(5)", "5"),
+            ("quote(5 + 8)", "(5 + 8)"),
+            ("quote(foobar)", "foobar"),
+            ("quote(foobar + barfoo)", "(foobar + barfoo)"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(quoted(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_quote_unquote() {
+        let tests = [
+            ("quote(unquote(4))", "4"),
+            ("quote(unquote(4 + 4))", "8"),
+            ("quote(8 + unquote(4 + 4))", "(8 + 8)"),
+            ("quote(unquote(4 + 4) + 8)", "(8 + 8)"),
+            ("let foobar = 8; quote(foobar)", "foobar"),
+            ("let foobar = 8; quote(unquote(foobar))", "8"),
+            ("quote(unquote(true))", "true"),
+            ("quote(unquote(true == false))", "false"),
+            ("quote(unquote(quote(4 + 4)))", "(4 + 4)"),
+            (
+                "let quotedInfixExpression = quote(4 + 4);
+                quote(unquote(4 + 4) + unquote(quotedInfixExpression))",
+                "(8 + (4 + 4))",
+            ),
+            (r#"quote(unquote([1, "two"]))"#, "[1, two]"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(quoted(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_quote_errors() {
+        assert_eq!(eval("quote(1, 2)"), Object::Error("wrong number of arguments: want=1, got=2".to_string()));
+        assert_eq!(eval("quote(unquote(fn(x) { x }))"), Object::Error("cannot unquote FUNCTION".to_string()));
+        assert_eq!(eval("quote(unquote(x))"), Object::Error("identifier not found: x".to_string()));
+    }
+
+    #[test]
+    fn test_define_macros() {
+        let input = "
+            let number = 1;
+            let function = fn(x, y) { x + y };
+            let mymacro = macro(x, y) { x + y; };
+        ";
+        let env = Environment::new();
+        let mut program = parse(input);
+        define_macros(&mut program, &env);
+        assert_eq!(program.statements.len(), 2);
+        assert_eq!(env.borrow().get("number"), None);
+        assert_eq!(env.borrow().get("function"), None);
+        let definition = env.borrow().get("mymacro");
+        match definition {
+            Some(Object::Macro(definition)) => {
+                let names = definition.parameters.iter().map(|p| p.value.as_str()).collect::<Vec<_>>();
+                assert_eq!(names, ["x", "y"]);
+                assert_eq!(definition.body.to_string(), "(x + y)");
+            }
+            other => panic!("not a macro: {:?}", other),
+        }
+    }
+
+    fn expand(input: &str) -> Result<String, String> {
+        let env = Environment::new();
+        let mut program = parse(input);
+        define_macros(&mut program, &env);
+        expand_macros(program, &env).map(|program| program.to_string())
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let tests = [
+            ("let infixExpression = macro() { quote(1 + 2); }; infixExpression();", "(1 + 2)"),
+            ("let reverse = macro(a, b) { quote(unquote(b) - unquote(a)); }; reverse(2 + 2, 10 - 5);", "(10 - 5) - (2 + 2)"),
+            (
+                r#"
+                let unless = macro(condition, consequence, alternative) {
+                    quote(if (!(unquote(condition))) {
+                        unquote(consequence);
+                    } else {
+                        unquote(alternative);
+                    });
+                };
+                unless(10 > 5, puts("not greater"), puts("greater"));
+                "#,
+                r#"if (!(10 > 5)) { puts("not greater") } else { puts("greater") }"#,
+            ),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(expand(input), Ok(parse(expected).to_string()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_expand_macros_errors() {
+        assert_eq!(
+            expand("let m = macro(a) { quote(a) }; m(1, 2)"),
+            Err("wrong number of arguments: want=1, got=2".to_string())
+        );
+        assert_eq!(expand("let m = macro() { 1 }; m()"), Err("macros must return quoted code, got INTEGER".to_string()));
+        assert_eq!(expand("let m = macro() { x }; m()"), Err("identifier not found: x".to_string()));
+    }
+
+    #[test]
+    fn test_expansion_is_hygienic() {
+        // Without renaming, the parameter `x` of the quoted function would capture the caller's `x`
+        let input = "
+            let addOne = macro(e) { quote(fn(x) { unquote(e) + x }(1)) };
+            let x = 10;
+            addOne(x)
+        ";
+        assert_eq!(expand_and_eval(input), Object::Integer(11));
+
+        // Nor can a `let` in the quoted code
+        let input = "
+            let plusOne = macro(e) { quote(fn() { let tmp = 1; unquote(e) + tmp }()) };
+            let tmp = 10;
+            plusOne(plusOne(tmp))
+        ";
+        assert_eq!(expand_and_eval(input), Object::Integer(12));
+        assert_eq!(expand_and_eval("let m = macro() { return quote(1) }; m()"), Object::Integer(1));
+    }
+}
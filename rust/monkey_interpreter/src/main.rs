@@ -1,12 +1,92 @@
-use std::io::{BufReader, stdin, stdout};
+use std::io::{stderr, stdin, stdout};
+use std::{env, fs, process, thread};
+use monkey_interpreter::format;
+use monkey_interpreter::lexer::{Lexer, RawMonkeyProgram};
+use monkey_interpreter::parser::Parser;
+use monkey_interpreter::repl;
 
-mod lexer;
-mod token;
-mod repl;
-mod parser;
+const USAGE: &str = "usage: monkey [run <file.monkey> | fmt [--check] <file.monkey>...]";
+/// The parser and evaluator recurse on the Rust stack, this leaves room for every call the
+/// evaluator allows before it reports a stack overflow itself.
+const STACK_SIZE: usize = 256 << 20;
 
 fn main() -> std::io::Result<()> {
-    println!("Hello! This is the Monkey programming language!");
-    println!("Feel free to type in commands\n");
-    repl::start(stdin().lock(), stdout())
+    let monkey = thread::Builder::new().stack_size(STACK_SIZE).spawn(monkey)?;
+    monkey.join().unwrap()
+}
+
+fn monkey() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("run") => {
+            let Some(path) = args.get(2) else {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            };
+            if !repl::run(&read(path), path, stderr())? {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Some("fmt") => {
+            // `--check` only reports the files that are not formatted yet
+            let check = args[2..].iter().any(|a| a == "--check");
+            let paths = args[2..].iter().filter(|a| *a != "--check").collect::<Vec<_>>();
+            if paths.is_empty() {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+            let mut ok = true;
+            for path in paths {
+                ok &= fmt(path, check)?;
+            }
+            if !ok {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Some(command) => {
+            eprintln!("unknown command {}, {}", command, USAGE);
+            process::exit(2);
+        }
+        None => {
+            println!("Hello! This is the Monkey programming language!");
+            println!("Feel free to type in commands, :tokens and :ast show what the lexer and parser make of them\n");
+            repl::start(stdin().lock(), stdout())
+        }
+    }
+}
+
+fn read(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+/// Rewrite the file at `path` in canonical form, returning whether it parsed and, when only
+/// checking, whether it already was.
+fn fmt(path: &str, check: bool) -> std::io::Result<bool> {
+    let source = read(path);
+    let p = RawMonkeyProgram::new(&source);
+    let mut parser = Parser::new(Lexer::new(&p));
+    let program = match parser.parse() {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error.diagnostic().render(&p, path));
+            }
+            return Ok(false);
+        }
+    };
+    let formatted = format::format_program(&program, &p, parser.comments());
+    if formatted == source {
+        return Ok(true);
+    }
+    if check {
+        println!("{} is not formatted", path);
+        return Ok(false);
+    }
+    fs::write(path, formatted)?;
+    Ok(true)
 }
@@ -0,0 +1,167 @@
+use crate::parser::{BlockStatement, Expression, Identifier, Program, Statement};
+
+/// Rewrites a syntax tree bottom up, the children of a node are rewritten before the node itself.
+///
+/// Any `FnMut(Expression) -> Expression` is a modifier that only rewrites expressions.
+pub trait Modifier {
+    /// Whether to go into `expression` at all, `false` leaves it and everything inside it as is.
+    fn enter(&mut self, _expression: &Expression) -> bool {
+        true
+    }
+
+    fn expression(&mut self, expression: Expression) -> Expression {
+        expression
+    }
+
+    fn statement(&mut self, statement: Statement) -> Statement {
+        statement
+    }
+}
+
+impl<F: FnMut(Expression) -> Expression> Modifier for F {
+    fn expression(&mut self, expression: Expression) -> Expression {
+        self(expression)
+    }
+}
+
+pub fn modify_program<M: Modifier + ?Sized>(program: Program, modifier: &mut M) -> Program {
+    let statements = program.statements.into_iter()
+        .map(|statement| modify_statement(statement, modifier))
+        .collect();
+    Program { statements }
+}
+
+pub fn modify_block<M: Modifier + ?Sized>(block: BlockStatement, modifier: &mut M) -> BlockStatement {
+    let statements = block.statements.into_iter()
+        .map(|statement| modify_statement(statement, modifier))
+        .collect();
+    BlockStatement { token: block.token, statements }
+}
+
+pub fn modify_statement<M: Modifier + ?Sized>(statement: Statement, modifier: &mut M) -> Statement {
+    let statement = match statement {
+        Statement::Let { token, name, value } => {
+            Statement::Let { token, name, value: modify_expression(value, modifier) }
+        }
+        Statement::Return { token, value } => Statement::Return { token, value: modify_expression(value, modifier) },
+        Statement::Expression { token, expression } => {
+            Statement::Expression { token, expression: modify_expression(expression, modifier) }
+        }
+    };
+    modifier.statement(statement)
+}
+
+pub fn modify_expression<M: Modifier + ?Sized>(expression: Expression, modifier: &mut M) -> Expression {
+    if !modifier.enter(&expression) {
+        return expression;
+    }
+    let mut modify = |e: Expression| modify_expression(e, modifier);
+    let expression = match expression {
+        Expression::Array { token, elements } => {
+            Expression::Array { token, elements: elements.into_iter().map(&mut modify).collect() }
+        }
+        Expression::Hash { token, pairs } => {
+            let pairs = pairs.into_iter().map(|(key, value)| (modify(key), modify(value))).collect();
+            Expression::Hash { token, pairs }
+        }
+        Expression::Index { token, left, index } => {
+            Expression::Index { token, left: Box::new(modify(*left)), index: Box::new(modify(*index)) }
+        }
+        Expression::Prefix { token, operator, right } => {
+            Expression::Prefix { token, operator, right: Box::new(modify(*right)) }
+        }
+        Expression::Infix { token, left, operator, right } => {
+            Expression::Infix { token, left: Box::new(modify(*left)), operator, right: Box::new(modify(*right)) }
+        }
+        Expression::If { token, condition, consequence, alternative } => Expression::If {
+            token,
+            condition: Box::new(modify(*condition)),
+            consequence: modify_block(consequence, modifier),
+            alternative: alternative.map(|alternative| modify_block(alternative, modifier)),
+        },
+        Expression::Function { token, parameters, body } => {
+            Expression::Function { token, parameters, body: modify_block(body, modifier) }
+        }
+        Expression::Macro { token, parameters, body } => {
+            Expression::Macro { token, parameters, body: modify_block(body, modifier) }
+        }
+        Expression::Call { token, function, arguments } => Expression::Call {
+            token,
+            function: Box::new(modify(*function)),
+            arguments: arguments.into_iter().map(&mut modify).collect(),
+        },
+        leaf @ (Expression::Identifier(_) | Expression::Integer { .. } | Expression::Float { .. }
+            | Expression::Boolean { .. } | Expression::String { .. }) => leaf,
+    };
+    modifier.expression(expression)
+}
+
+/// Whether `expression` calls the function named `name`, as `quote(...)` does.
+pub fn is_call_to(expression: &Expression, name: &str) -> bool {
+    matches!(expression, Expression::Call { function, .. }
+        if matches!(function.as_ref(), Expression::Identifier(Identifier { value, .. }) if value == name))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lexer::{Lexer, RawMonkeyProgram};
+    use crate::parser::Parser;
+    use super::*;
+
+    fn parse(input: &str) -> Program {
+        let p = RawMonkeyProgram::new(input);
+        Parser::new(Lexer::new(&p)).parse().unwrap_or_else(|e| panic!("parser had errors for {:?}:\n{:?}", input, e))
+    }
+
+    fn one_to_two(expression: Expression) -> Expression {
+        match expression {
+            Expression::Integer { token, value: 1 } => Expression::Integer { token, value: 2 },
+            e => e,
+        }
+    }
+
+    #[test]
+    fn test_modify() {
+        let tests = [
+            ("1", "2"),
+            ("1 + 2", "(2 + 2)"),
+            ("-1", "(-2)"),
+            ("[1, 1][1]", "([2, 2][2])"),
+            ("{1: 1}", "{2: 2}"),
+            ("if (1) { 1 } else { 1 }", "if2 2else 2"),
+            ("return 1;", "return 2;"),
+            ("let x = 1;", "let x = 2;"),
+            ("fn(x) { 1 }", "fn(x) 2"),
+            ("macro(x) { 1 }", "macro(x) 2"),
+            ("f(1)(1)", "f(2)(2)"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(modify_program(parse(input), &mut one_to_two).to_string(), expected, "{}", input);
+        }
+    }
+
+    /// Renames `let` bindings, but not inside calls to `keep`.
+    struct Rename;
+
+    impl Modifier for Rename {
+        fn enter(&mut self, expression: &Expression) -> bool {
+            !is_call_to(expression, "keep")
+        }
+
+        fn statement(&mut self, statement: Statement) -> Statement {
+            match statement {
+                Statement::Let { token, mut name, value } => {
+                    name.value.push('_');
+                    Statement::Let { token, name, value }
+                }
+                s => s,
+            }
+        }
+    }
+
+    #[test]
+    fn test_modifier_statements_and_enter() {
+        let program = parse("let a = fn() { let b = 1; keep(fn() { let c = 1; }) };");
+        assert_eq!(modify_program(program, &mut Rename).to_string(), "let a_ = fn() let b_ = 1;keep(fn() let c = 1;);");
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use crate::code::{self, Instructions};
+use crate::parser::{BlockStatement, Expression, Identifier};
+
+/// Environments are shared between the scope that created them and every closure capturing them.
+pub type Env = Rc<RefCell<Environment>>;
+
+pub type BuiltinFunction = fn(&[Object]) -> Object;
+
+#[derive(Clone, Debug)]
+pub enum Object {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(Rc<str>),
+    Array(Rc<Vec<Object>>),
+    Hash(Rc<BTreeMap<HashKey, HashPair>>),
+    Null,
+    /// Value of a `return` on its way out of the enclosing function
+    ReturnValue(Box<Object>),
+    Error(String),
+    Function(Rc<Function>),
+    Builtin(&'static str, BuiltinFunction),
+    CompiledFunction(Rc<CompiledFunction>),
+    Closure(Rc<Closure>),
+    /// Unevaluated syntax, as returned by `quote`
+    Quote(Rc<Expression>),
+    Macro(Rc<Macro>),
+}
+
+/// What hash keys are compared by, only integers, booleans and strings can be keys.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub enum HashKey {
+    Integer(i64),
+    Boolean(bool),
+    String(Rc<str>),
+}
+
+/// A hash entry keeps the original key around so it can be shown again.
+#[derive(PartialEq, Clone, Debug)]
+pub struct HashPair {
+    pub key: Object,
+    pub value: Object,
+}
+
+pub struct Function {
+    pub parameters: Vec<Identifier>,
+    pub body: BlockStatement,
+    pub env: Env,
+}
+
+/// Like a function, but called with its arguments quoted while macros are expanded.
+pub struct Macro {
+    pub parameters: Vec<Identifier>,
+    pub body: BlockStatement,
+    pub env: Env,
+}
+
+/// A function body compiled to bytecode, see [`crate::compiler`].
+#[derive(PartialEq, Debug)]
+pub struct CompiledFunction {
+    pub instructions: Instructions,
+    /// Parameters and `let` bindings, the parameters first
+    pub num_locals: usize,
+    pub num_parameters: usize,
+}
+
+/// A compiled function together with the values of the free variables it closes over.
+#[derive(PartialEq, Debug)]
+pub struct Closure {
+    pub function: Rc<CompiledFunction>,
+    pub free: Vec<Object>,
+}
+
+impl Object {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Integer(_) => "INTEGER",
+            Object::Float(_) => "FLOAT",
+            Object::Boolean(_) => "BOOLEAN",
+            Object::String(_) => "STRING",
+            Object::Array(_) => "ARRAY",
+            Object::Hash(_) => "HASH",
+            Object::Null => "NULL",
+            Object::ReturnValue(_) => "RETURN_VALUE",
+            Object::Error(_) => "ERROR",
+            Object::Function(_) => "FUNCTION",
+            Object::Builtin(..) => "BUILTIN",
+            Object::CompiledFunction(_) => "COMPILED_FUNCTION",
+            // Closures are what functions are at runtime in the VM
+            Object::Closure(_) => "FUNCTION",
+            Object::Quote(_) => "QUOTE",
+            Object::Macro(_) => "MACRO",
+        }
+    }
+
+    pub fn hash_key(&self) -> Option<HashKey> {
+        match self {
+            Object::Integer(value) => Some(HashKey::Integer(*value)),
+            Object::Boolean(value) => Some(HashKey::Boolean(*value)),
+            Object::String(value) => Some(HashKey::String(value.clone())),
+            _ => None,
+        }
+    }
+
+    /// Everything but `false` and `null` counts as true in conditions.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Object::Boolean(false) | Object::Null)
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Object::Error(_))
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Object::Integer(a), Object::Integer(b)) => a == b,
+            (Object::Float(a), Object::Float(b)) => a == b,
+            (Object::Boolean(a), Object::Boolean(b)) => a == b,
+            (Object::String(a), Object::String(b)) => a == b,
+            (Object::Array(a), Object::Array(b)) => a == b,
+            (Object::Hash(a), Object::Hash(b)) => a == b,
+            (Object::Null, Object::Null) => true,
+            (Object::ReturnValue(a), Object::ReturnValue(b)) => a == b,
+            (Object::Error(a), Object::Error(b)) => a == b,
+            (Object::Function(a), Object::Function(b)) => Rc::ptr_eq(a, b),
+            (Object::Builtin(a, _), Object::Builtin(b, _)) => a == b,
+            (Object::CompiledFunction(a), Object::CompiledFunction(b)) => a == b,
+            (Object::Closure(a), Object::Closure(b)) => Rc::ptr_eq(a, b),
+            (Object::Quote(a), Object::Quote(b)) => a == b,
+            (Object::Macro(a), Object::Macro(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Object::Integer(value) => write!(f, "{}", value),
+            Object::Float(value) => write!(f, "{:?}", value),
+            Object::Boolean(value) => write!(f, "{}", value),
+            Object::String(value) => write!(f, "{}", value),
+            Object::Array(elements) => {
+                let elements = elements.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Object::Hash(pairs) => {
+                let pairs = pairs.values().map(|p| format!("{}: {}", p.key, p.value)).collect::<Vec<_>>();
+                write!(f, "{{{}}}", pairs.join(", "))
+            }
+            Object::Null => write!(f, "null"),
+            Object::ReturnValue(value) => write!(f, "{}", value),
+            Object::Error(message) => write!(f, "ERROR: {}", message),
+            Object::Function(function) => write!(f, "{}", function),
+            Object::Builtin(name, _) => write!(f, "builtin function {}", name),
+            Object::CompiledFunction(function) => {
+                write!(f, "CompiledFunction[\n{}]", code::disassemble(&function.instructions))
+            }
+            Object::Closure(closure) => {
+                write!(f, "Closure[\n{}]", code::disassemble(&closure.function.instructions))
+            }
+            Object::Quote(expression) => write!(f, "QUOTE({})", expression),
+            Object::Macro(m) => {
+                let parameters = m.parameters.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "macro({}) {{\n{}\n}}", parameters.join(", "), m.body)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parameters = self.parameters.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        write!(f, "fn({}) {{\n{}\n}}", parameters.join(", "), self.body)
+    }
+}
+
+// The environment usually contains the function itself, so it is left out
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("parameters", &self.parameters)
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Macro")
+            .field("parameters", &self.parameters)
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Bindings of one scope, falling back to the scope it was created in.
+#[derive(Default)]
+pub struct Environment {
+    store: HashMap<String, Object>,
+    outer: Option<Env>,
+}
+
+impl Environment {
+    pub fn new() -> Env {
+        Rc::new(RefCell::new(Self::default()))
+    }
+
+    /// A new scope inside `outer`, as entered when calling a function.
+    pub fn enclosed(outer: Env) -> Env {
+        Rc::new(RefCell::new(Self {
+            store: HashMap::new(),
+            outer: Some(outer),
+        }))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Object> {
+        match self.store.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.outer.as_ref()?.borrow().get(name),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: Object) {
+        self.store.insert(name.to_string(), value);
+    }
+}
@@ -1,20 +1,105 @@
-use crate::lexer::{Lexer, RawMonkeyProgram};
+use std::fmt;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
 use crate::token;
-use crate::token::{Token, TokenType};
+use crate::token::{IllegalReason, Slice, Token, TokenType};
 
-#[derive(PartialEq)]
-struct Identifier {
-    token: Token,
-    value: String,
+#[derive(PartialEq, Clone, Debug)]
+pub struct Identifier {
+    pub token: Token,
+    pub value: String,
 }
 
-#[derive(PartialEq)]
-enum Expression {
-    Placeholder,
+#[derive(PartialEq, Clone, Debug)]
+pub struct BlockStatement {
+    pub token: Token,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Expression {
     Identifier(Identifier),
+    Integer {
+        token: Token,
+        value: i64,
+    },
+    Float {
+        token: Token,
+        value: f64,
+    },
+    Boolean {
+        token: Token,
+        value: bool,
+    },
+    String {
+        token: Token,
+        value: String,
+    },
+    Array {
+        token: Token,
+        elements: Vec<Expression>,
+    },
+    Hash {
+        token: Token,
+        pairs: Vec<(Expression, Expression)>,
+    },
+    Index {
+        token: Token,
+        left: Box<Expression>,
+        index: Box<Expression>,
+    },
+    Prefix {
+        token: Token,
+        operator: String,
+        right: Box<Expression>,
+    },
+    Infix {
+        token: Token,
+        left: Box<Expression>,
+        operator: String,
+        right: Box<Expression>,
+    },
+    If {
+        token: Token,
+        condition: Box<Expression>,
+        consequence: BlockStatement,
+        alternative: Option<BlockStatement>,
+    },
+    Function {
+        token: Token,
+        parameters: Vec<Identifier>,
+        body: BlockStatement,
+    },
+    Call {
+        token: Token,
+        function: Box<Expression>,
+        arguments: Vec<Expression>,
+    },
+    /// Only meaningful bound by a top-level `let`, see [`crate::macro_expansion`]
+    Macro {
+        token: Token,
+        parameters: Vec<Identifier>,
+        body: BlockStatement,
+    },
+}
+
+impl Expression {
+    /// The token the expression was parsed from: its first one, or the operator, `(` or `[` after
+    /// the left hand side.
+    pub fn token(&self) -> &Token {
+        use Expression as E;
+        match self {
+            E::Identifier(identifier) => &identifier.token,
+            E::Integer { token, .. } | E::Float { token, .. } | E::Boolean { token, .. } | E::String { token, .. }
+            | E::Array { token, .. } | E::Hash { token, .. } | E::Index { token, .. } | E::Prefix { token, .. }
+            | E::Infix { token, .. } | E::If { token, .. } | E::Function { token, .. } | E::Call { token, .. }
+            | E::Macro { token, .. } => token,
+        }
+    }
 }
 
-enum Statement {
+#[derive(PartialEq, Clone, Debug)]
+pub enum Statement {
     Let {
         token: Token,
         name: Identifier,
@@ -30,15 +115,56 @@ enum Statement {
     },
 }
 
+#[derive(Debug)]
 pub struct Program {
-    statements: Vec<Statement>,
+    pub statements: Vec<Statement>,
 }
 
+/// Binding power of operators, from weakest to strongest.
+#[derive(PartialEq, PartialOrd, Copy, Clone, Debug)]
+pub(crate) enum Precedence {
+    Lowest,
+    Or,
+    And,
+    Equals,
+    LessGreater,
+    Sum,
+    Product,
+    Prefix,
+    Call,
+    Index,
+}
+
+impl Precedence {
+    pub(crate) fn of(tok_type: TokenType) -> Self {
+        use TokenType as T;
+        match tok_type {
+            T::Or => Precedence::Or,
+            T::And => Precedence::And,
+            T::Eq | T::NotEq => Precedence::Equals,
+            T::Lt | T::Gt | T::LtEq | T::GtEq => Precedence::LessGreater,
+            T::Plus | T::Minus => Precedence::Sum,
+            T::Slash | T::Asterisk => Precedence::Product,
+            T::LParen => Precedence::Call,
+            T::LBracket => Precedence::Index,
+            _ => Precedence::Lowest,
+        }
+    }
+}
+
+/// How deeply expressions can nest, counting every operator folded into the left operand of the
+/// next. Everything walking the tree recurses on the Rust stack, which this keeps it inside.
+pub const MAX_NESTING: usize = 256;
+
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
     cur_token: Option<Token>,
     peek_token: Option<Token>,
-    errors: Vec<String>,
+    errors: Vec<ParseError>,
+    /// Expressions the current token is nested in
+    depth: usize,
+    /// Set once nesting went too deep, after which nothing more is parsed
+    too_deep: bool,
 }
 
 impl<'a> Parser<'a> {
@@ -48,6 +174,8 @@ impl<'a> Parser<'a> {
             cur_token: None,
             peek_token: None,
             errors: Vec::new(),
+            depth: 0,
+            too_deep: false,
         };
         // Initialize cur_token and peek_token
         l.next_token();
@@ -55,20 +183,25 @@ impl<'a> Parser<'a> {
         l
     }
 
-    pub fn errors(&self) -> &[String] {
+    pub fn errors(&self) -> &[ParseError] {
         &self.errors
     }
 
+    /// Comments skipped so far, all of them once parsed.
+    pub fn comments(&self) -> &[Slice] {
+        self.lexer.comments()
+    }
+
     pub fn next_token(&mut self) {
         self.cur_token = self.peek_token.take();
         self.peek_token = self.lexer.next_token();
     }
 
-    pub fn parse(&mut self) -> Result<Program, ParseError> {
+    pub fn parse(&mut self) -> Result<Program, Vec<ParseError>> {
         let mut statements = Vec::new();
 
         loop {
-            if self.cur_token.is_none() {
+            if self.cur_token.is_none() || self.too_deep {
                 break;
             }
             if let Some(statement) = self.parse_statement() {
@@ -76,54 +209,287 @@ impl<'a> Parser<'a> {
             }
             self.next_token();
         }
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
         Ok(Program { statements })
     }
 
     fn parse_statement(&mut self) -> Option<Statement> {
         use token::TokenType as T;
-        match self.cur_token.clone()?.tok_type {
+        match self.cur_token?.tok_type {
             T::Let => self.parse_let_statement(),
             T::Return => self.parse_return_statement(),
-            _ => None
+            _ => self.parse_expression_statement(),
         }
     }
 
     fn parse_let_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token?;
         if !self.expect_peek(TokenType::Identifier) {
             return None;
         }
-
-        let token = self.cur_token.unwrap();
-        let name = Identifier {
-            token,
-            value: self.lexer.program().token_substring(token),
-        };
+        let name = self.cur_identifier()?;
 
         if !self.expect_peek(TokenType::Assign) {
             return None;
         }
+        self.next_token();
+        let value = self.parse_expression(Precedence::Lowest)?;
+        self.skip_peek(TokenType::Semicolon);
+
+        Some(Statement::Let { token, name, value })
+    }
 
-        while !self.cur_type_is(TokenType::Semicolon) {
+    fn parse_return_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token?;
+        self.next_token();
+        let value = self.parse_expression(Precedence::Lowest)?;
+        self.skip_peek(TokenType::Semicolon);
+        Some(Statement::Return { token, value })
+    }
+
+    fn parse_expression_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token?;
+        let expression = self.parse_expression(Precedence::Lowest)?;
+        self.skip_peek(TokenType::Semicolon);
+        Some(Statement::Expression { token, expression })
+    }
+
+    fn parse_block_statement(&mut self) -> Option<BlockStatement> {
+        let token = self.cur_token?;
+        let mut statements = Vec::new();
+        self.next_token();
+        while !self.cur_type_is(TokenType::RBrace) {
+            if self.too_deep {
+                return None;
+            }
+            if self.cur_token.is_none() {
+                self.error_at_end(ParseErrorKind::UnexpectedToken { expected: TokenType::RBrace, found: TokenType::Eof });
+                return None;
+            }
+            if let Some(statement) = self.parse_statement() {
+                statements.push(statement);
+            }
             self.next_token();
         }
+        Some(BlockStatement { token, statements })
+    }
 
-        Some(Statement::Let {
-            token,
-            name,
-            value: Expression::Placeholder,
-        })
+    /// Pratt parsing: parse a prefix expression, then keep folding it into infix expressions as
+    /// long as the next operator binds tighter than `precedence`.
+    fn parse_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        let outer = self.depth;
+        let expression = self.parse_nested_expression(precedence);
+        self.depth = outer;
+        expression
     }
 
-    fn parse_return_statement(&mut self) -> Option<Statement> {
-        let statement = Statement::Return {
-            token: self.cur_token.unwrap(),
-            value: Expression::Placeholder,
+    fn parse_nested_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        self.nest()?;
+        let mut left = self.parse_prefix()?;
+        while !self.peek_type_is(TokenType::Semicolon) && precedence < self.peek_precedence() {
+            self.next_token();
+            self.nest()?;
+            left = self.parse_infix(left)?;
+        }
+        Some(left)
+    }
+
+    /// Go one level deeper, reporting only the first time that goes past `MAX_NESTING`.
+    fn nest(&mut self) -> Option<()> {
+        if self.too_deep {
+            return None;
+        }
+        if self.depth == MAX_NESTING {
+            self.too_deep = true;
+            match self.cur_token {
+                Some(token) => self.report(ParseError { kind: ParseErrorKind::TooDeep, span: token.literal }),
+                None => self.error_at_end(ParseErrorKind::TooDeep),
+            }
+            return None;
+        }
+        self.depth += 1;
+        Some(())
+    }
+
+    fn parse_prefix(&mut self) -> Option<Expression> {
+        use token::TokenType as T;
+        let Some(token) = self.cur_token else {
+            self.error_at_end(ParseErrorKind::ExpectedExpression { found: T::Eof });
+            return None;
         };
+        match token.tok_type {
+            T::Identifier => self.cur_identifier().map(Expression::Identifier),
+            T::Int => {
+                let literal = self.lexer.program().token_substring(token);
+                match literal.parse() {
+                    Ok(value) => Some(Expression::Integer { token, value }),
+                    Err(_) => {
+                        self.report(ParseError { kind: ParseErrorKind::InvalidInteger(literal), span: token.literal });
+                        None
+                    }
+                }
+            }
+            T::Float => {
+                let literal = self.lexer.program().token_substring(token);
+                let value = literal.parse().expect("the lexer only makes floats of digits around a dot");
+                Some(Expression::Float { token, value })
+            }
+            T::True | T::False => Some(Expression::Boolean { token, value: token.tok_type == T::True }),
+            T::String => Some(Expression::String { token, value: self.lexer.program().token_substring(token) }),
+            T::LBracket => {
+                let elements = self.parse_expression_list(T::RBracket)?;
+                Some(Expression::Array { token, elements })
+            }
+            T::LBrace => self.parse_hash_literal(),
+            T::Bang | T::Minus => {
+                let operator = self.lexer.program().token_substring(token);
+                self.next_token();
+                let right = self.parse_expression(Precedence::Prefix)?;
+                Some(Expression::Prefix { token, operator, right: Box::new(right) })
+            }
+            T::LParen => {
+                self.next_token();
+                let expression = self.parse_expression(Precedence::Lowest)?;
+                self.expect_peek(T::RParen).then_some(expression)
+            }
+            T::If => self.parse_if_expression(),
+            T::Function | T::Macro => self.parse_function_literal(),
+            T::Illegal(reason) => {
+                self.report(ParseError { kind: ParseErrorKind::Illegal(reason), span: token.literal });
+                None
+            }
+            found => {
+                self.report(ParseError { kind: ParseErrorKind::ExpectedExpression { found }, span: token.literal });
+                None
+            }
+        }
+    }
+
+    fn parse_infix(&mut self, left: Expression) -> Option<Expression> {
+        let token = self.cur_token?;
+        match token.tok_type {
+            TokenType::LParen => {
+                let arguments = self.parse_expression_list(TokenType::RParen)?;
+                return Some(Expression::Call { token, function: Box::new(left), arguments });
+            }
+            TokenType::LBracket => {
+                self.next_token();
+                let index = self.parse_expression(Precedence::Lowest)?;
+                return self.expect_peek(TokenType::RBracket)
+                    .then(|| Expression::Index { token, left: Box::new(left), index: Box::new(index) });
+            }
+            _ => {}
+        }
+        let operator = self.lexer.program().token_substring(token);
+        let precedence = self.cur_precedence();
+        self.next_token();
+        let right = self.parse_expression(precedence)?;
+        Some(Expression::Infix { token, left: Box::new(left), operator, right: Box::new(right) })
+    }
+
+    fn parse_if_expression(&mut self) -> Option<Expression> {
+        let token = self.cur_token?;
+        if !self.expect_peek(TokenType::LParen) {
+            return None;
+        }
         self.next_token();
-        while !self.cur_type_is(TokenType::Semicolon) {
-            self.next_token()
+        let condition = self.parse_expression(Precedence::Lowest)?;
+        if !self.expect_peek(TokenType::RParen) || !self.expect_peek(TokenType::LBrace) {
+            return None;
+        }
+        let consequence = self.parse_block_statement()?;
+
+        let mut alternative = None;
+        if self.peek_type_is(TokenType::Else) {
+            self.next_token();
+            if !self.expect_peek(TokenType::LBrace) {
+                return None;
+            }
+            alternative = Some(self.parse_block_statement()?);
+        }
+        Some(Expression::If { token, condition: Box::new(condition), consequence, alternative })
+    }
+
+    fn parse_function_literal(&mut self) -> Option<Expression> {
+        let token = self.cur_token?;
+        if !self.expect_peek(TokenType::LParen) {
+            return None;
+        }
+        let parameters = self.parse_function_parameters()?;
+        if !self.expect_peek(TokenType::LBrace) {
+            return None;
+        }
+        let body = self.parse_block_statement()?;
+        match token.tok_type {
+            TokenType::Macro => Some(Expression::Macro { token, parameters, body }),
+            _ => Some(Expression::Function { token, parameters, body }),
+        }
+    }
+
+    fn parse_function_parameters(&mut self) -> Option<Vec<Identifier>> {
+        let mut parameters = Vec::new();
+        if self.peek_type_is(TokenType::RParen) {
+            self.next_token();
+            return Some(parameters);
+        }
+        loop {
+            if !self.expect_peek(TokenType::Identifier) {
+                return None;
+            }
+            parameters.push(self.cur_identifier()?);
+            if !self.peek_type_is(TokenType::Comma) {
+                break;
+            }
+            self.next_token();
+        }
+        self.expect_peek(TokenType::RParen).then_some(parameters)
+    }
+
+    /// Comma separated expressions up to `end`, as in call arguments and array literals.
+    fn parse_expression_list(&mut self, end: TokenType) -> Option<Vec<Expression>> {
+        let mut list = Vec::new();
+        if self.peek_type_is(end) {
+            self.next_token();
+            return Some(list);
+        }
+        self.next_token();
+        list.push(self.parse_expression(Precedence::Lowest)?);
+        while self.peek_type_is(TokenType::Comma) {
+            self.next_token();
+            self.next_token();
+            list.push(self.parse_expression(Precedence::Lowest)?);
+        }
+        self.expect_peek(end).then_some(list)
+    }
+
+    fn parse_hash_literal(&mut self) -> Option<Expression> {
+        let token = self.cur_token?;
+        let mut pairs = Vec::new();
+        while !self.peek_type_is(TokenType::RBrace) {
+            self.next_token();
+            let key = self.parse_expression(Precedence::Lowest)?;
+            if !self.expect_peek(TokenType::Colon) {
+                return None;
+            }
+            self.next_token();
+            let value = self.parse_expression(Precedence::Lowest)?;
+            pairs.push((key, value));
+            if !self.peek_type_is(TokenType::RBrace) && !self.expect_peek(TokenType::Comma) {
+                return None;
+            }
         }
-        Some(statement)
+        self.next_token();
+        Some(Expression::Hash { token, pairs })
+    }
+
+    fn cur_identifier(&self) -> Option<Identifier> {
+        let token = self.cur_token?;
+        Some(Identifier {
+            token,
+            value: self.lexer.program().token_substring(token),
+        })
     }
 
     fn cur_type_is(&self, tok_type: TokenType) -> bool {
@@ -139,11 +505,38 @@ impl<'a> Parser<'a> {
             self.next_token();
             true
         } else {
-            self.errors.push(format!("expected next token to be {:?}, got {:?} instead", tok_type, self.peek_token));
+            match self.peek_token {
+                Some(found) => self.report(ParseError {
+                    kind: ParseErrorKind::UnexpectedToken { expected: tok_type, found: found.tok_type },
+                    span: found.literal,
+                }),
+                None => self.error_at_end(ParseErrorKind::UnexpectedToken { expected: tok_type, found: TokenType::Eof }),
+            }
             false
         }
     }
 
+    /// Keep only the first error about each token, statements carry on parsing after an error and
+    /// would otherwise trip over the same token again.
+    fn report(&mut self, error: ParseError) {
+        if self.errors.last().map(|last| last.span) != Some(error.span) {
+            self.errors.push(error);
+        }
+    }
+
+    /// Report something missing once the input ran out, right after its last token.
+    fn error_at_end(&mut self, kind: ParseErrorKind) {
+        let span = Slice::new(self.lexer.program().end_of_input(), 0);
+        self.report(ParseError { kind, span });
+    }
+
+    /// Move past the next token if it is optional punctuation like a trailing semicolon.
+    fn skip_peek(&mut self, tok_type: TokenType) {
+        if self.peek_type_is(tok_type) {
+            self.next_token();
+        }
+    }
+
     fn peek_type_is(&self, tok_type: TokenType) -> bool {
         if let Some(t) = &self.peek_token {
             t.tok_type == tok_type
@@ -151,11 +544,119 @@ impl<'a> Parser<'a> {
             false
         }
     }
+
+    fn peek_precedence(&self) -> Precedence {
+        self.peek_token.map_or(Precedence::Lowest, |t| Precedence::of(t.tok_type))
+    }
+
+    fn cur_precedence(&self) -> Precedence {
+        self.cur_token.map_or(Precedence::Lowest, |t| Precedence::of(t.tok_type))
+    }
 }
 
-#[derive(Debug)]
-enum ParseError {
-    Generic
+#[derive(PartialEq, Clone, Debug)]
+pub enum ParseErrorKind {
+    UnexpectedToken { expected: TokenType, found: TokenType },
+    ExpectedExpression { found: TokenType },
+    InvalidInteger(String),
+    Illegal(IllegalReason),
+    TooDeep,
+}
+
+/// What went wrong and where, `span` being the offending token or the end of the input when
+/// something is missing there.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Slice,
+}
+
+impl ParseError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.to_string(), self.span)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedToken { expected, found } => write!(f, "expected {}, found {}", expected, found),
+            ParseErrorKind::ExpectedExpression { found } => write!(f, "expected an expression, found {}", found),
+            ParseErrorKind::InvalidInteger(literal) => write!(f, "could not parse {} as integer", literal),
+            ParseErrorKind::Illegal(reason) => write!(f, "{}", reason),
+            ParseErrorKind::TooDeep => write!(f, "expression nested too deeply, the limit is {} levels", MAX_NESTING),
+        }
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+impl fmt::Display for BlockStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.statements.iter().try_for_each(|s| write!(f, "{}", s))
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Identifier(identifier) => write!(f, "{}", identifier),
+            Expression::Integer { value, .. } => write!(f, "{}", value),
+            Expression::Float { value, .. } => write!(f, "{:?}", value),
+            Expression::Boolean { value, .. } => write!(f, "{}", value),
+            Expression::String { value, .. } => write!(f, "{}", value),
+            Expression::Array { elements, .. } => {
+                let elements = elements.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Expression::Hash { pairs, .. } => {
+                let pairs = pairs.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>();
+                write!(f, "{{{}}}", pairs.join(", "))
+            }
+            Expression::Index { left, index, .. } => write!(f, "({}[{}])", left, index),
+            Expression::Prefix { operator, right, .. } => write!(f, "({}{})", operator, right),
+            Expression::Infix { left, operator, right, .. } => write!(f, "({} {} {})", left, operator, right),
+            Expression::If { condition, consequence, alternative, .. } => {
+                write!(f, "if{} {}", condition, consequence)?;
+                match alternative {
+                    Some(alternative) => write!(f, "else {}", alternative),
+                    None => Ok(()),
+                }
+            }
+            Expression::Function { parameters, body, .. } => {
+                let parameters = parameters.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "fn({}) {}", parameters.join(", "), body)
+            }
+            Expression::Call { function, arguments, .. } => {
+                let arguments = arguments.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                write!(f, "{}({})", function, arguments.join(", "))
+            }
+            Expression::Macro { parameters, body, .. } => {
+                let parameters = parameters.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "macro({}) {}", parameters.join(", "), body)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::Let { name, value, .. } => write!(f, "let {} = {};", name, value),
+            Statement::Return { value, .. } => write!(f, "return {};", value),
+            Statement::Expression { expression, .. } => write!(f, "{}", expression),
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.statements.iter().try_for_each(|s| write!(f, "{}", s))
+    }
 }
 
 #[cfg(test)]
@@ -163,12 +664,41 @@ mod test {
     use crate::lexer::{Lexer, RawMonkeyProgram};
     use super::*;
 
+    fn parse(input: &str) -> Program {
+        let p = RawMonkeyProgram::new(input);
+        let lexer = Lexer::new(&p);
+        let mut parser = Parser::new(lexer);
+        match parser.parse() {
+            Ok(program) => program,
+            Err(e) => panic!("parser had errors for {:?}:\n{:?}", input, e),
+        }
+    }
+
+    fn parse_errors(input: &str) -> Vec<ParseError> {
+        let p = RawMonkeyProgram::new(input);
+        let mut parser = Parser::new(Lexer::new(&p));
+        match parser.parse() {
+            Ok(program) => panic!("expected errors, parsed {}", program),
+            Err(errors) => errors,
+        }
+    }
+
+    /// The only statement of `input`, which has to be an expression statement.
+    fn parse_expression(input: &str) -> Expression {
+        let program = parse(input);
+        assert_eq!(program.statements.len(), 1, "{}", program);
+        match program.statements.into_iter().next() {
+            Some(Statement::Expression { expression, .. }) => expression,
+            s => panic!("not an expression statement: {:?}", s),
+        }
+    }
+
     #[test]
-    fn test_let_statements() -> Result<(), ParseError> {
+    fn test_let_statements() -> Result<(), Vec<ParseError>> {
         let input = r"
         let x = 5;
-        let y = 10;
-        let foobar = 838383;
+        let y = true;
+        let foobar = y;
         ";
 
         let p = RawMonkeyProgram::new(input);
@@ -177,19 +707,20 @@ mod test {
         let program = parser.parse()?;
         assert_eq!(program.statements.len(), 3);
 
-        let tests = vec![
-            ("x",),
-            ("y",),
-            ("foobar",),
+        let tests = [
+            ("x", "5"),
+            ("y", "true"),
+            ("foobar", "y"),
         ];
 
-        for (index, &(ident, )) in tests.iter().enumerate() {
+        for (index, &(ident, expected)) in tests.iter().enumerate() {
             let statement = &program.statements[index];
             if let Statement::Let { token, name, value } = statement {
                 assert_eq!(name.value, ident);
-                assert_eq!(ident, p.substring(token.literal))
+                assert_eq!("let", p.substring(token.literal));
+                assert_eq!(value.to_string(), expected);
             } else {
-                assert!(false);
+                panic!("statement was not a let statement");
             }
         }
 
@@ -197,11 +728,11 @@ mod test {
     }
 
     #[test]
-    fn test_return_statements() -> Result<(), ParseError> {
+    fn test_return_statements() -> Result<(), Vec<ParseError>> {
         let input = r"
         return 5;
         return 10;
-        return 993322;
+        return add(1, 2);
         ";
 
         let p = RawMonkeyProgram::new(input);
@@ -210,14 +741,235 @@ mod test {
         let program = parser.parse()?;
 
         assert_eq!(program.statements.len(), 3);
-        for statement in program.statements {
+        let expected = ["5", "10", "add(1, 2)"];
+        for (statement, expected) in program.statements.iter().zip(expected) {
             if let Statement::Return { token, value } = statement {
                 assert_eq!(token.tok_type, TokenType::Return);
+                assert_eq!(value.to_string(), expected);
             } else {
-                assert!(false, "statement was not a return statement")
+                panic!("statement was not a return statement")
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_literal_expressions() {
+        assert!(matches!(parse_expression("foobar;"), Expression::Identifier(Identifier { value, .. }) if value == "foobar"));
+        assert!(matches!(parse_expression("5;"), Expression::Integer { value: 5, .. }));
+        assert!(matches!(parse_expression("true;"), Expression::Boolean { value: true, .. }));
+        assert!(matches!(parse_expression("false"), Expression::Boolean { value: false, .. }));
+    }
+
+    #[test]
+    fn test_prefix_expressions() {
+        let tests = [("!5;", "!", "5"), ("-15;", "-", "15"), ("!true;", "!", "true")];
+        for (input, expected_operator, expected_right) in tests {
+            match parse_expression(input) {
+                Expression::Prefix { operator, right, .. } => {
+                    assert_eq!(operator, expected_operator);
+                    assert_eq!(right.to_string(), expected_right);
+                }
+                e => panic!("not a prefix expression: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_infix_expressions() {
+        for operator in ["+", "-", "*", "/", ">", "<", "==", "!="] {
+            match parse_expression(&format!("5 {} 6;", operator)) {
+                Expression::Infix { left, operator: op, right, .. } => {
+                    assert_eq!(op, operator);
+                    assert_eq!(left.to_string(), "5");
+                    assert_eq!(right.to_string(), "6");
+                }
+                e => panic!("not an infix expression: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let tests = [
+            ("-a * b", "((-a) * b)"),
+            ("!-a", "(!(-a))"),
+            ("a + b + c", "((a + b) + c)"),
+            ("a + b - c", "((a + b) - c)"),
+            ("a * b * c", "((a * b) * c)"),
+            ("a * b / c", "((a * b) / c)"),
+            ("a + b / c", "(a + (b / c))"),
+            ("a + b * c + d / e - f", "(((a + (b * c)) + (d / e)) - f)"),
+            ("3 + 4; -5 * 5", "(3 + 4)((-5) * 5)"),
+            ("5 > 4 == 3 < 4", "((5 > 4) == (3 < 4))"),
+            ("5 < 4 != 3 > 4", "((5 < 4) != (3 > 4))"),
+            ("3 + 4 * 5 == 3 * 1 + 4 * 5", "((3 + (4 * 5)) == ((3 * 1) + (4 * 5)))"),
+            ("true", "true"),
+            ("3 > 5 == false", "((3 > 5) == false)"),
+            ("1 + (2 + 3) + 4", "((1 + (2 + 3)) + 4)"),
+            ("(5 + 5) * 2", "((5 + 5) * 2)"),
+            ("2 / (5 + 5)", "(2 / (5 + 5))"),
+            ("-(5 + 5)", "(-(5 + 5))"),
+            ("!(true == true)", "(!(true == true))"),
+            ("a + add(b * c) + d", "((a + add((b * c))) + d)"),
+            ("add(a, b, 1, 2 * 3, 4 + 5, add(6, 7 * 8))", "add(a, b, 1, (2 * 3), (4 + 5), add(6, (7 * 8)))"),
+            ("add(a + b + c * d / f + g)", "add((((a + b) + ((c * d) / f)) + g))"),
+            ("a * [1, 2, 3, 4][b * c] * d", "((a * ([1, 2, 3, 4][(b * c)])) * d)"),
+            ("add(a * b[2], b[1], 2 * [1, 2][1])", "add((a * (b[2])), (b[1]), (2 * ([1, 2][1])))"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(parse(input).to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_if_expressions() {
+        match parse_expression("if (x < y) { x }") {
+            Expression::If { condition, consequence, alternative, .. } => {
+                assert_eq!(condition.to_string(), "(x < y)");
+                assert_eq!(consequence.statements.len(), 1);
+                assert_eq!(consequence.to_string(), "x");
+                assert!(alternative.is_none());
+            }
+            e => panic!("not an if expression: {:?}", e),
+        }
+        match parse_expression("if (x < y) { x } else { y; 1 }") {
+            Expression::If { alternative: Some(alternative), .. } => {
+                assert_eq!(alternative.statements.len(), 2);
+                assert_eq!(alternative.to_string(), "y1");
+            }
+            e => panic!("not an if else expression: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_function_literals() {
+        match parse_expression("fn(x, y) { x + y; }") {
+            Expression::Function { parameters, body, .. } => {
+                let names = parameters.iter().map(|p| p.value.as_str()).collect::<Vec<_>>();
+                assert_eq!(names, ["x", "y"]);
+                assert_eq!(body.to_string(), "(x + y)");
+            }
+            e => panic!("not a function literal: {:?}", e),
+        }
+        let tests = [("fn() {};", 0), ("fn(x) {};", 1), ("fn(x, y, z) {};", 3)];
+        for (input, count) in tests {
+            assert!(matches!(parse_expression(input), Expression::Function { parameters, .. } if parameters.len() == count));
+        }
+    }
+
+    #[test]
+    fn test_macro_literals() {
+        match parse_expression("macro(x, y) { x + y; }") {
+            Expression::Macro { parameters, body, .. } => {
+                let names = parameters.iter().map(|p| p.value.as_str()).collect::<Vec<_>>();
+                assert_eq!(names, ["x", "y"]);
+                assert_eq!(body.to_string(), "(x + y)");
+            }
+            e => panic!("not a macro literal: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_call_expressions() {
+        match parse_expression("add(1, 2 * 3, 4 + 5);") {
+            Expression::Call { function, arguments, .. } => {
+                assert_eq!(function.to_string(), "add");
+                let arguments = arguments.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                assert_eq!(arguments, ["1", "(2 * 3)", "(4 + 5)"]);
+            }
+            e => panic!("not a call expression: {:?}", e),
+        }
+        assert_eq!(parse("fn(x) { x }(5)").to_string(), "fn(x) x(5)");
+    }
+
+    #[test]
+    fn test_collection_literals() {
+        assert!(matches!(parse_expression(r#""hello world";"#), Expression::String { value, .. } if value == "hello world"));
+        match parse_expression("[1, 2 * 2, 3 + 3]") {
+            Expression::Array { elements, .. } => {
+                let elements = elements.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                assert_eq!(elements, ["1", "(2 * 2)", "(3 + 3)"]);
+            }
+            e => panic!("not an array literal: {:?}", e),
+        }
+        assert!(matches!(parse_expression("[]"), Expression::Array { elements, .. } if elements.is_empty()));
+        assert_eq!(parse_expression("myArray[1 + 1]").to_string(), "(myArray[(1 + 1)])");
+
+        let tests = [
+            (r#"{"one": 1, "two": 2, "three": 3}"#, "{one: 1, two: 2, three: 3}"),
+            ("{}", "{}"),
+            (r#"{"one": 0 + 1, true: 10 - 8, 3: 15 / 5}"#, "{one: (0 + 1), true: (10 - 8), 3: (15 / 5)}"),
+        ];
+        for (input, expected) in tests {
+            let expression = parse_expression(input);
+            assert!(matches!(expression, Expression::Hash { .. }));
+            assert_eq!(expression.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_floats_and_logical_operators() {
+        assert!(matches!(parse_expression("12.75"), Expression::Float { value, .. } if value == 12.75));
+        let tests = [
+            ("a && b || c", "((a && b) || c)"),
+            ("a || b && c", "(a || (b && c))"),
+            ("a < b == c <= d", "((a < b) == (c <= d))"),
+            ("!a && b >= 1.5", "((!a) && (b >= 1.5))"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(parse(input).to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        let nested = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(parse(&nested(MAX_NESTING - 1)).to_string(), "1");
+        let too_deep = [ParseError { kind: ParseErrorKind::TooDeep, span: Slice::new(MAX_NESTING, 1) }];
+        assert_eq!(parse_errors(&nested(MAX_NESTING)), too_deep);
+        assert_eq!(parse_errors(&nested(50_000)), too_deep);
+        assert_eq!(parse_errors(&format!("if (true) {{ {} }}; let x = 1", nested(50_000))).len(), 1);
+        assert_eq!(parse_errors(&"1 + ".repeat(50_000)).len(), 1);
+        assert_eq!(too_deep[0].to_string(), "expression nested too deeply, the limit is 256 levels");
+    }
+
+    #[test]
+    fn test_errors() {
+        use TokenType as T;
+        let error = |kind, start, len| ParseError { kind, span: Slice::new(start, len) };
+        let errors = parse_errors("let = 5;");
+        assert_eq!(errors[0], error(ParseErrorKind::UnexpectedToken { expected: T::Identifier, found: T::Assign }, 4, 1));
+        assert_eq!(errors[0].to_string(), "expected identifier, found `=`");
+        assert_eq!(parse_errors("+5;"), [error(ParseErrorKind::ExpectedExpression { found: T::Plus }, 0, 1)]);
+        assert_eq!(
+            parse_errors("99999999999999999999"),
+            [error(ParseErrorKind::InvalidInteger("99999999999999999999".to_string()), 0, 20)]
+        );
+        assert_eq!(
+            parse_errors("if (x) { x \n"),
+            [error(ParseErrorKind::UnexpectedToken { expected: T::RBrace, found: T::Eof }, 10, 0)]
+        );
+        assert_eq!(parse_errors("let x = \n"), [error(ParseErrorKind::ExpectedExpression { found: T::Eof }, 7, 0)]);
+        assert_eq!(parse_errors("add(1, 2"), [error(ParseErrorKind::UnexpectedToken { expected: T::RParen, found: T::Eof }, 8, 0)]);
+        assert_eq!(parse_errors("let x ="), [error(ParseErrorKind::ExpectedExpression { found: T::Eof }, 7, 0)]);
+        let errors = parse_errors("1 + @");
+        assert_eq!(errors, [error(ParseErrorKind::Illegal(IllegalReason::UnexpectedCharacter('@')), 4, 1)]);
+        assert_eq!(errors[0].to_string(), "unexpected character '@'");
+        assert_eq!(parse_errors("f(\"abc)")[0].to_string(), "unterminated string");
+    }
+
+    #[test]
+    fn test_errors_render_with_caret() {
+        let p = RawMonkeyProgram::new("let x = (1 + 2;");
+        let errors = Parser::new(Lexer::new(&p)).parse().unwrap_err();
+        let expected = "\
+error: expected `)`, found `;`
+ --> <repl>:1:15
+  |
+1 | let x = (1 + 2;
+  |               ^";
+        assert_eq!(errors[0].diagnostic().render(&p, "<repl>"), expected);
+    }
+}
@@ -1,24 +1,208 @@
 use std::io::{BufRead, Write};
+use crate::{evaluator, macro_expansion};
 use crate::lexer::{Lexer, RawMonkeyProgram};
+use crate::object::{Env, Environment, Object};
+use crate::parser::Parser;
+use crate::token::TokenType;
 
+/// What the REPL does with each input, switched with `:eval`, `:tokens` and `:ast`.
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum Mode {
+    Eval,
+    Tokens,
+    Ast,
+}
+
+const PROMPT: &str = ">> ";
+/// Shown while a brace, parenthesis or bracket is still open
+const CONTINUATION_PROMPT: &str = ".. ";
+
+/// Read and evaluate lines until the end of `input`, keeping bindings from one input to the next.
 pub fn start(mut input: impl BufRead, mut output: impl Write) -> std::io::Result<()> {
     let mut s = String::new();
-    output.write(">> ".as_bytes())?;
+    let env = Environment::new();
+    let macro_env = Environment::new();
+    let mut mode = Mode::Eval;
+    output.write_all(PROMPT.as_bytes())?;
     output.flush()?;
-    while let Ok(bytes_read) = input.read_line(&mut s) {
-        println!("We got some bytes {}", bytes_read);
+    loop {
+        let bytes_read = input.read_line(&mut s)?;
         if bytes_read == 0 {
             break;
         }
+        // Carry on reading until everything opened is closed again
+        if open_delimiters(&s) > 0 {
+            output.write_all(CONTINUATION_PROMPT.as_bytes())?;
+            output.flush()?;
+            continue;
+        }
 
-        let p = RawMonkeyProgram::from(s.as_str());
-        let mut l = Lexer::new(&p);
-        while let Some(t) = l.next_token() {
-            output.write(format!("{:?}\n", t).as_bytes())?;
+        match s.trim() {
+            "" => {}
+            ":eval" => mode = Mode::Eval,
+            ":tokens" => mode = Mode::Tokens,
+            ":ast" => mode = Mode::Ast,
+            command if command.starts_with(':') => {
+                writeln!(output, "unknown command {}, try :eval, :tokens or :ast", command)?
+            }
+            _ => {
+                let p = RawMonkeyProgram::from(s.as_str());
+                match mode {
+                    Mode::Eval => {
+                        // `let` and `puts` have nothing worth showing
+                        match evaluate(&p, "<repl>", &env, &macro_env, &mut output)? {
+                            None | Some(Object::Null) => {}
+                            Some(value) => writeln!(output, "{}", value)?,
+                        }
+                    }
+                    Mode::Tokens => dump_tokens(&p, &mut output)?,
+                    Mode::Ast => dump_ast(&p, &mut output)?,
+                }
+            }
         }
         s.clear();
-        output.write(">> ".as_bytes())?;
+        output.write_all(PROMPT.as_bytes())?;
         output.flush()?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Evaluate a whole script called `name`, reporting errors to `output` and returning whether it
+/// ran without any.
+pub fn run(source: &str, name: &str, mut output: impl Write) -> std::io::Result<bool> {
+    let p = RawMonkeyProgram::new(source);
+    Ok(evaluate(&p, name, &Environment::new(), &Environment::new(), &mut output)?.is_some())
+}
+
+/// Parse, expand the macros of and evaluate `p` in `env`, reporting any error to `output`. The
+/// value is `None` if there was an error.
+fn evaluate(
+    p: &RawMonkeyProgram,
+    name: &str,
+    env: &Env,
+    macro_env: &Env,
+    output: &mut impl Write,
+) -> std::io::Result<Option<Object>> {
+    let mut program = match Parser::new(Lexer::new(p)).parse() {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                writeln!(output, "{}", error.diagnostic().render(p, name))?;
+            }
+            return Ok(None);
+        }
+    };
+    macro_expansion::define_macros(&mut program, macro_env);
+    let program = match macro_expansion::expand_macros(program, macro_env) {
+        Ok(program) => program,
+        Err(message) => {
+            writeln!(output, "error: {}", message)?;
+            return Ok(None);
+        }
+    };
+    match evaluator::eval_program(&program, env) {
+        Object::Error(message) => {
+            writeln!(output, "error: {}", message)?;
+            Ok(None)
+        }
+        value => Ok(Some(value)),
+    }
+}
+
+fn dump_tokens(p: &RawMonkeyProgram, output: &mut impl Write) -> std::io::Result<()> {
+    let mut lexer = Lexer::new(p);
+    while let Some(token) = lexer.next_token() {
+        let (line, column) = p.line_col(token.literal.start);
+        writeln!(output, "{}:{} {:?} {:?}", line, column, token.tok_type, p.token_substring(token))?;
+    }
+    Ok(())
+}
+
+fn dump_ast(p: &RawMonkeyProgram, output: &mut impl Write) -> std::io::Result<()> {
+    match Parser::new(Lexer::new(p)).parse() {
+        Ok(program) => writeln!(output, "{:#?}", program.statements),
+        Err(errors) => {
+            for error in errors {
+                writeln!(output, "{}", error.diagnostic().render(p, "<repl>"))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// How many braces, parentheses and brackets in `input` are still waiting to be closed.
+fn open_delimiters(input: &str) -> i32 {
+    use TokenType as T;
+    let p = RawMonkeyProgram::new(input);
+    let mut lexer = Lexer::new(&p);
+    let mut open = 0;
+    while let Some(token) = lexer.next_token() {
+        match token.tok_type {
+            T::LBrace | T::LParen | T::LBracket => open += 1,
+            T::RBrace | T::RParen | T::RBracket => open -= 1,
+            _ => {}
+        }
+    }
+    open
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(input: &str) -> String {
+        let mut output = Vec::new();
+        start(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_bindings_persist_between_lines() {
+        assert_eq!(session("let a = 5;\na * 2\n"), ">> >> 10\n>> ");
+    }
+
+    #[test]
+    fn test_macros_persist_between_lines() {
+        let input = "let unless = macro(c, a, b) { quote(if (!(unquote(c))) { unquote(a) } else { unquote(b) }) };\nunless(1 > 2, 3, 4)\n";
+        assert_eq!(session(input), ">> >> 3\n>> ");
+    }
+
+    #[test]
+    fn test_multi_line_input() {
+        let input = "let add = fn(a, b) {\n  a + b\n};\nadd(1, [\n2][0])\n";
+        assert_eq!(session(input), ">> .. .. >> .. 3\n>> ");
+    }
+
+    #[test]
+    fn test_modes() {
+        let output = session(":tokens\nlet x\n:ast\nx\n:eval\n1\n:nope\n");
+        let (tokens, rest) = output.split_once("\n>> >> [\n").unwrap();
+        assert_eq!(tokens, ">> >> 1:1 Let \"let\"\n1:5 Identifier \"x\"");
+        let (ast, rest) = rest.split_once("\n]\n").unwrap();
+        assert!(ast.trim_start().starts_with("Expression {"), "{}", ast);
+        assert!(ast.contains("value: \"x\""), "{}", ast);
+        assert_eq!(rest, ">> >> 1\n>> unknown command :nope, try :eval, :tokens or :ast\n>> ");
+    }
+
+    #[test]
+    fn test_errors_are_reported() {
+        let output = session("let x = ;\nfoo\n");
+        assert_eq!(output, "\
+>> error: expected an expression, found `;`
+ --> <repl>:1:9
+  |
+1 | let x = ;
+  |         ^
+>> error: identifier not found: foo
+>> ");
+    }
+
+    #[test]
+    fn test_run() {
+        let mut output = Vec::new();
+        assert!(run("let x = 1;\nx + 1", "ok.monkey", &mut output).unwrap());
+        assert!(output.is_empty());
+        assert!(!run("1 + true", "bad.monkey", &mut output).unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), "error: type mismatch: INTEGER + BOOLEAN\n");
+    }
+}
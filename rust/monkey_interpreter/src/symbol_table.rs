@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum SymbolScope {
+    Global,
+    Local,
+    Builtin,
+    /// Local of an enclosing function, captured by a closure
+    Free,
+    /// The function being compiled, referring to itself by the name it is bound to
+    Function,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub scope: SymbolScope,
+    pub index: usize,
+}
+
+/// Names bound in one scope of the program being compiled, with the scope it is nested in.
+#[derive(Default, Debug)]
+pub struct SymbolTable {
+    outer: Option<Box<SymbolTable>>,
+    store: HashMap<String, Symbol>,
+    num_definitions: usize,
+    /// Symbols of enclosing functions used in this one, in the order they were captured
+    pub free_symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enclosed(outer: SymbolTable) -> Self {
+        Self {
+            outer: Some(Box::new(outer)),
+            ..Self::default()
+        }
+    }
+
+    /// The table this one is nested in, if any.
+    pub fn into_outer(self) -> Option<SymbolTable> {
+        self.outer.map(|outer| *outer)
+    }
+
+    pub fn num_definitions(&self) -> usize {
+        self.num_definitions
+    }
+
+    pub fn define(&mut self, name: &str) -> Symbol {
+        let scope = match self.outer {
+            None => SymbolScope::Global,
+            Some(_) => SymbolScope::Local,
+        };
+        let symbol = Symbol { name: name.to_string(), scope, index: self.num_definitions };
+        self.store.insert(name.to_string(), symbol.clone());
+        self.num_definitions += 1;
+        symbol
+    }
+
+    pub fn define_builtin(&mut self, index: usize, name: &str) -> Symbol {
+        let symbol = Symbol { name: name.to_string(), scope: SymbolScope::Builtin, index };
+        self.store.insert(name.to_string(), symbol.clone());
+        symbol
+    }
+
+    pub fn define_function_name(&mut self, name: &str) -> Symbol {
+        let symbol = Symbol { name: name.to_string(), scope: SymbolScope::Function, index: 0 };
+        self.store.insert(name.to_string(), symbol.clone());
+        symbol
+    }
+
+    fn define_free(&mut self, original: Symbol) -> Symbol {
+        let symbol = Symbol {
+            name: original.name.clone(),
+            scope: SymbolScope::Free,
+            index: self.free_symbols.len(),
+        };
+        self.free_symbols.push(original);
+        self.store.insert(symbol.name.clone(), symbol.clone());
+        symbol
+    }
+
+    /// Look `name` up here and then in the enclosing scopes. Locals of enclosing functions are
+    /// turned into free symbols of every function between them and this one.
+    pub fn resolve(&mut self, name: &str) -> Option<Symbol> {
+        if let Some(symbol) = self.store.get(name) {
+            return Some(symbol.clone());
+        }
+        let symbol = self.outer.as_mut()?.resolve(name)?;
+        match symbol.scope {
+            SymbolScope::Global | SymbolScope::Builtin => Some(symbol),
+            _ => Some(self.define_free(symbol)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn symbol(name: &str, scope: SymbolScope, index: usize) -> Symbol {
+        Symbol { name: name.to_string(), scope, index }
+    }
+
+    #[test]
+    fn test_define_and_resolve_nested() {
+        let mut global = SymbolTable::new();
+        assert_eq!(global.define("a"), symbol("a", SymbolScope::Global, 0));
+        global.define("b");
+        let mut first = SymbolTable::enclosed(global);
+        assert_eq!(first.define("c"), symbol("c", SymbolScope::Local, 0));
+        first.define("d");
+        let mut second = SymbolTable::enclosed(first);
+        second.define("e");
+        second.define("f");
+
+        assert_eq!(second.resolve("a"), Some(symbol("a", SymbolScope::Global, 0)));
+        assert_eq!(second.resolve("c"), Some(symbol("c", SymbolScope::Free, 0)));
+        assert_eq!(second.resolve("d"), Some(symbol("d", SymbolScope::Free, 1)));
+        assert_eq!(second.resolve("f"), Some(symbol("f", SymbolScope::Local, 1)));
+        assert_eq!(second.resolve("x"), None);
+        assert_eq!(second.free_symbols, [symbol("c", SymbolScope::Local, 0), symbol("d", SymbolScope::Local, 1)]);
+    }
+
+    #[test]
+    fn test_builtins_and_function_names() {
+        let mut global = SymbolTable::new();
+        global.define_builtin(3, "rest");
+        let mut local = SymbolTable::enclosed(global);
+        local.define_function_name("f");
+        local.define("f");
+        assert_eq!(local.resolve("rest"), Some(symbol("rest", SymbolScope::Builtin, 3)));
+        // A local of the same name shadows the function name
+        assert_eq!(local.resolve("f"), Some(symbol("f", SymbolScope::Local, 0)));
+    }
+}
@@ -1,11 +1,15 @@
+use std::fmt;
+
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum TokenType {
-    Illegal,
+    Illegal(IllegalReason),
     Eof,
 
     // Identifiers + literals
     Identifier,
     Int,
+    Float,
+    String,
 
     // Operators
     Bang,
@@ -16,20 +20,28 @@ pub enum TokenType {
     Asterisk,
     Lt,
     Gt,
+    LtEq,
+    GtEq,
     Eq,
     NotEq,
+    And,
+    Or,
 
     // Delimiters
     Comma,
     Semicolon,
+    Colon,
 
     LParen,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
 
     // Keywords
     Function,
+    Macro,
     Let,
     If,
     Else,
@@ -44,6 +56,7 @@ impl TokenType {
         use TokenType as T;
         match s.as_str() {
             "fn" => T::Function,
+            "macro" => T::Macro,
             "let" => T::Let,
             "if" => T::If,
             "else" => T::Else,
@@ -55,6 +68,74 @@ impl TokenType {
     }
 }
 
+/// How a token type is referred to in error messages.
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use TokenType as T;
+        let s = match self {
+            T::Illegal(reason) => return write!(f, "{}", reason),
+            T::Eof => "end of input",
+            T::Identifier => "identifier",
+            T::Int => "integer",
+            T::Float => "float",
+            T::String => "string",
+            T::Bang => "`!`",
+            T::Assign => "`=`",
+            T::Plus => "`+`",
+            T::Minus => "`-`",
+            T::Slash => "`/`",
+            T::Asterisk => "`*`",
+            T::Lt => "`<`",
+            T::Gt => "`>`",
+            T::LtEq => "`<=`",
+            T::GtEq => "`>=`",
+            T::Eq => "`==`",
+            T::NotEq => "`!=`",
+            T::And => "`&&`",
+            T::Or => "`||`",
+            T::Comma => "`,`",
+            T::Semicolon => "`;`",
+            T::Colon => "`:`",
+            T::LParen => "`(`",
+            T::RParen => "`)`",
+            T::LBrace => "`{`",
+            T::RBrace => "`}`",
+            T::LBracket => "`[`",
+            T::RBracket => "`]`",
+            T::Function => "`fn`",
+            T::Macro => "`macro`",
+            T::Let => "`let`",
+            T::If => "`if`",
+            T::Else => "`else`",
+            T::Return => "`return`",
+            T::True => "`true`",
+            T::False => "`false`",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Why the lexer could not make sense of part of the input.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum IllegalReason {
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    UnterminatedComment,
+    /// A number ending in a `.` without digits after it
+    MissingFraction,
+}
+
+impl fmt::Display for IllegalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IllegalReason::UnexpectedCharacter(ch) => write!(f, "unexpected character {:?}", ch),
+            IllegalReason::UnterminatedString => write!(f, "unterminated string"),
+            IllegalReason::UnterminatedComment => write!(f, "unterminated block comment"),
+            IllegalReason::MissingFraction => write!(f, "missing digits after the decimal point"),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Slice {
     pub start: usize,
@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use crate::builtins::BUILTINS;
+use crate::code::{self, Opcode};
+use crate::compiler::Bytecode;
+use crate::evaluator;
+use crate::object::{Closure, CompiledFunction, HashPair, Object};
+
+const STACK_SIZE: usize = 2048;
+const MAX_FRAMES: usize = 1024;
+
+/// Errors are reported with the same messages the tree-walking evaluator uses.
+#[derive(PartialEq, Debug)]
+pub struct VmError(pub String);
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+type Result<T> = std::result::Result<T, VmError>;
+
+/// A function call in progress.
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Where the arguments and locals of the call start on the stack
+    base_pointer: usize,
+}
+
+pub struct Vm {
+    constants: Vec<Object>,
+    stack: Vec<Object>,
+    globals: Vec<Object>,
+    frames: Vec<Frame>,
+    last_popped: Object,
+}
+
+impl Vm {
+    pub fn new(bytecode: Bytecode) -> Self {
+        Self::with_globals(bytecode, Vec::new())
+    }
+
+    /// Run with the globals a previous program left behind, as a REPL does.
+    pub fn with_globals(bytecode: Bytecode, globals: Vec<Object>) -> Self {
+        let main = Rc::new(Closure {
+            function: Rc::new(CompiledFunction {
+                instructions: bytecode.instructions,
+                num_locals: 0,
+                num_parameters: 0,
+            }),
+            free: Vec::new(),
+        });
+        Self {
+            constants: bytecode.constants,
+            stack: Vec::with_capacity(STACK_SIZE),
+            globals,
+            frames: vec![Frame { closure: main, ip: 0, base_pointer: 0 }],
+            last_popped: Object::Null,
+        }
+    }
+
+    pub fn into_globals(self) -> Vec<Object> {
+        self.globals
+    }
+
+    /// Value of the last expression statement, or of a `return` at the top level.
+    pub fn last_popped_stack_elem(&self) -> &Object {
+        &self.last_popped
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        while let Some((op, operand, extra)) = self.fetch() {
+            match op {
+                Opcode::Constant => self.push(self.constants[operand].clone())?,
+                Opcode::Pop => self.last_popped = self.pop(),
+                Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div => self.execute_binary_operation(op)?,
+                Opcode::Equal
+                | Opcode::NotEqual
+                | Opcode::GreaterThan
+                | Opcode::GreaterThanOrEqual
+                | Opcode::LessThan
+                | Opcode::LessThanOrEqual => self.execute_comparison(op)?,
+                Opcode::True => self.push(Object::Boolean(true))?,
+                Opcode::False => self.push(Object::Boolean(false))?,
+                Opcode::Null => self.push(Object::Null)?,
+                Opcode::Minus | Opcode::Bang => {
+                    let right = self.pop();
+                    let operator = if op == Opcode::Minus { "-" } else { "!" };
+                    let result = checked(evaluator::eval_prefix_expression(operator, right))?;
+                    self.push(result)?;
+                }
+                Opcode::Jump => self.frame_mut().ip = operand,
+                Opcode::JumpNotTruthy => {
+                    if !self.pop().is_truthy() {
+                        self.frame_mut().ip = operand;
+                    }
+                }
+                Opcode::GetGlobal => self.push(self.globals.get(operand).cloned().unwrap_or(Object::Null))?,
+                Opcode::SetGlobal => {
+                    let value = self.pop();
+                    if operand >= self.globals.len() {
+                        self.globals.resize(operand + 1, Object::Null);
+                    }
+                    self.globals[operand] = value;
+                }
+                Opcode::GetLocal => {
+                    let value = self.stack[self.frame().base_pointer + operand].clone();
+                    self.push(value)?;
+                }
+                Opcode::SetLocal => {
+                    let value = self.pop();
+                    let slot = self.frame().base_pointer + operand;
+                    self.stack[slot] = value;
+                }
+                Opcode::GetBuiltin => {
+                    let (name, function) = BUILTINS[operand];
+                    self.push(Object::Builtin(name, function))?;
+                }
+                Opcode::GetFree => {
+                    let value = self.frame().closure.free[operand].clone();
+                    self.push(value)?;
+                }
+                Opcode::CurrentClosure => {
+                    let closure = self.frame().closure.clone();
+                    self.push(Object::Closure(closure))?;
+                }
+                Opcode::Array => {
+                    let elements = self.stack.split_off(self.stack.len() - operand);
+                    self.push(Object::Array(Rc::new(elements)))?;
+                }
+                Opcode::Hash => {
+                    let hash = self.build_hash(operand)?;
+                    self.push(hash)?;
+                }
+                Opcode::Index => {
+                    let index = self.pop();
+                    let left = self.pop();
+                    self.push(checked(evaluator::eval_index_expression(left, index))?)?;
+                }
+                Opcode::Call => self.call(operand)?,
+                Opcode::ReturnValue | Opcode::Return => {
+                    let value = if op == Opcode::ReturnValue { self.pop() } else { Object::Null };
+                    let frame = self.frames.pop().expect("returning from a frame");
+                    if self.frames.is_empty() {
+                        // A return at the top level ends the program
+                        self.last_popped = value;
+                        return Ok(());
+                    }
+                    self.stack.truncate(frame.base_pointer - 1);
+                    self.push(value)?;
+                }
+                Opcode::Closure => self.push_closure(operand, extra)?,
+                Opcode::IdentifierNotFound => {
+                    return Err(VmError(format!("identifier not found: {}", self.constants[operand])));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the next instruction of the current frame and move past it, `None` once the
+    /// top level runs out of instructions.
+    fn fetch(&mut self) -> Option<(Opcode, usize, usize)> {
+        let frame = self.frames.last_mut()?;
+        let ins = &frame.closure.function.instructions;
+        let ip = frame.ip;
+        let op = Opcode::from_byte(*ins.get(ip)?).expect("compiler emits valid opcodes");
+        let (operand, extra, len) = match op.operand_widths() {
+            [] => (0, 0, 0),
+            [1] => (ins[ip + 1] as usize, 0, 1),
+            [2] => (code::read_u16(&ins[ip + 1..]), 0, 2),
+            _ => (code::read_u16(&ins[ip + 1..]), ins[ip + 3] as usize, 3),
+        };
+        frame.ip = ip + 1 + len;
+        Some((op, operand, extra))
+    }
+
+    fn execute_binary_operation(&mut self, op: Opcode) -> Result<()> {
+        let right = self.pop();
+        let left = self.pop();
+        let result = match (op, &left, &right) {
+            (Opcode::Add, Object::Integer(l), Object::Integer(r)) => Object::Integer(l.wrapping_add(*r)),
+            (Opcode::Sub, Object::Integer(l), Object::Integer(r)) => Object::Integer(l.wrapping_sub(*r)),
+            (Opcode::Mul, Object::Integer(l), Object::Integer(r)) => Object::Integer(l.wrapping_mul(*r)),
+            _ => {
+                let operator = match op {
+                    Opcode::Add => "+",
+                    Opcode::Sub => "-",
+                    Opcode::Mul => "*",
+                    _ => "/",
+                };
+                checked(evaluator::eval_infix_expression(operator, left, right))?
+            }
+        };
+        self.push(result)
+    }
+
+    fn execute_comparison(&mut self, op: Opcode) -> Result<()> {
+        let right = self.pop();
+        let left = self.pop();
+        let result = match (op, &left, &right) {
+            (Opcode::GreaterThan, Object::Integer(l), Object::Integer(r)) => Object::Boolean(l > r),
+            (Opcode::GreaterThanOrEqual, Object::Integer(l), Object::Integer(r)) => Object::Boolean(l >= r),
+            (Opcode::LessThan, Object::Integer(l), Object::Integer(r)) => Object::Boolean(l < r),
+            (Opcode::LessThanOrEqual, Object::Integer(l), Object::Integer(r)) => Object::Boolean(l <= r),
+            (Opcode::Equal, Object::Integer(l), Object::Integer(r)) => Object::Boolean(l == r),
+            (Opcode::NotEqual, Object::Integer(l), Object::Integer(r)) => Object::Boolean(l != r),
+            _ => {
+                let operator = match op {
+                    Opcode::Equal => "==",
+                    Opcode::NotEqual => "!=",
+                    Opcode::GreaterThanOrEqual => ">=",
+                    Opcode::LessThan => "<",
+                    Opcode::LessThanOrEqual => "<=",
+                    _ => ">",
+                };
+                checked(evaluator::eval_infix_expression(operator, left, right))?
+            }
+        };
+        self.push(result)
+    }
+
+    fn build_hash(&mut self, len: usize) -> Result<Object> {
+        let items = self.stack.split_off(self.stack.len() - len);
+        let mut hash = BTreeMap::new();
+        for pair in items.chunks(2) {
+            let (key, value) = (pair[0].clone(), pair[1].clone());
+            let hash_key = key
+                .hash_key()
+                .ok_or_else(|| VmError(format!("unusable as hash key: {}", key.type_name())))?;
+            hash.insert(hash_key, HashPair { key, value });
+        }
+        Ok(Object::Hash(Rc::new(hash)))
+    }
+
+    fn call(&mut self, num_args: usize) -> Result<()> {
+        let callee = self.stack[self.stack.len() - 1 - num_args].clone();
+        match callee {
+            Object::Closure(closure) => {
+                let function = &closure.function;
+                if function.num_parameters != num_args {
+                    return Err(VmError(format!(
+                        "wrong number of arguments: want={}, got={}", function.num_parameters, num_args
+                    )));
+                }
+                if self.frames.len() >= MAX_FRAMES {
+                    return Err(VmError("stack overflow".to_string()));
+                }
+                let base_pointer = self.stack.len() - num_args;
+                let locals = base_pointer + function.num_locals;
+                if locals >= STACK_SIZE {
+                    return Err(VmError("stack overflow".to_string()));
+                }
+                self.stack.resize(locals, Object::Null);
+                self.frames.push(Frame { closure, ip: 0, base_pointer });
+                Ok(())
+            }
+            Object::Builtin(_, builtin) => {
+                let args = self.stack.split_off(self.stack.len() - num_args);
+                self.pop();
+                let result = checked(builtin(&args))?;
+                self.push(result)
+            }
+            other => Err(VmError(format!("not a function: {}", other.type_name()))),
+        }
+    }
+
+    fn push_closure(&mut self, constant: usize, num_free: usize) -> Result<()> {
+        let function = match &self.constants[constant] {
+            Object::CompiledFunction(function) => function.clone(),
+            other => return Err(VmError(format!("not a function: {}", other.type_name()))),
+        };
+        let free = self.stack.split_off(self.stack.len() - num_free);
+        self.push(Object::Closure(Rc::new(Closure { function, free })))
+    }
+
+    fn push(&mut self, object: Object) -> Result<()> {
+        if self.stack.len() >= STACK_SIZE {
+            return Err(VmError("stack overflow".to_string()));
+        }
+        self.stack.push(object);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Object {
+        self.stack.pop().expect("compiler balances the stack")
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("running inside a frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("running inside a frame")
+    }
+}
+
+/// Turn error objects of the operations shared with the evaluator into VM errors.
+fn checked(object: Object) -> Result<Object> {
+    match object {
+        Object::Error(message) => Err(VmError(message)),
+        object => Ok(object),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::Compiler;
+    use crate::evaluator;
+    use crate::lexer::{Lexer, RawMonkeyProgram};
+    use crate::object::Environment;
+    use crate::parser::{Parser, Program};
+    use super::*;
+
+    /// Programs run through both backends, which have to agree on every result and error.
+    const CORPUS: &[&str] = &[
+        "5", "-10", "--10", "5 + 5 + 5 + 5 - 10", "2 * 2 * 2 * 2 * 2", "-50 + 100 + -50",
+        "(5 + 10 * 2 + 15 / 3) * 2 + -10", "50 / 2 * 2 + 10",
+        "true", "1 < 2", "1 > 2", "1 == 1", "1 != 2", "true != false", "(1 < 2) == true",
+        "!true", "!5", "!!5", "!(if (false) { 5; })",
+        "if (true) { 10 }", "if (false) { 10 }", "if (1) { 10 }", "if (1 > 2) { 10 } else { 20 }",
+        "if ((if (false) { 10 })) { 10 } else { 20 }", "if (true) { }", "if (true) { let a = 1; }",
+        "return 10; 9;", "9; return 2 * 5; 9;", "if (10 > 1) { if (10 > 1) { return 10; } return 1; }",
+        "let f = fn(x) { return x; x + 10; }; f(10);",
+        "5 + true;", "5 + true; 5;", "-true", "true + false;", "foobar", "1 / 0", "5(1)",
+        "fn(x) { x }(1, 2)", "fn(x) { x }()", r#""Hello" - "World""#,
+        r#"{"name": "Monkey"}[fn(x) { x }];"#, "{[1]: 2}", "1[0]",
+        "let a = 5; a;", "let a = 5; let b = a; let c = a + b + 5; c;", "let a = 1; let a = a + 1; a",
+        "let add = fn(a, b) { a + b }; add(1, 2)", "fn(x) { x; }(5)", "fn() { }()",
+        "let f = fn() { let a = 1; }; f()",
+        "let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));",
+        "let newAdder = fn(x) { fn(y) { x + y } }; let addTwo = newAdder(2); addTwo(2);",
+        "let add = fn(a, b) { a + b }; let applyFunc = fn(a, b, func) { func(a, b) }; applyFunc(2, 2, add);",
+        "let x = 1; let f = fn() { x }; let g = fn(x) { f() }; g(5)",
+        "let newClosure = fn(a, b) { let one = fn() { a; }; let two = fn() { b; }; fn() { one() + two(); }; }; newClosure(9, 90)()",
+        "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+        "let wrapper = fn() { let countDown = fn(x) { if (x == 0) { return 0; } else { countDown(x - 1); } }; countDown(1); }; wrapper();",
+        r#""Hello" + " " + "World!""#, r#""a" == "a""#, r#""a" != "b""#,
+        "[1, 2 * 2, 3 + 3]", "[1, 2, 3][1 + 1];", "[1, 2, 3][3]", "[1, 2, 3][-1]", "[[1, 1, 1]][0][0]",
+        r#"let two = "two"; {"one": 10 - 9, two: 1 + 1, "thr" + "ee": 6 / 2, 4: 4, true: 5}"#,
+        r#"{"foo": 5}["foo"]"#, r#"{"foo": 5}["bar"]"#, "{}[0]", "{true: 5}[true]",
+        r#"len("hello world")"#, "len(1)", r#"len("one", "two")"#, "len([1, 2, 3])", "first([1, 2, 3])",
+        "first([])", "first(1)", "last([1, 2, 3])", "rest([1, 2, 3])", "rest([])", "push([], 1)",
+        "1.5", "-2.5 * 2", "1 / 4.0", "2.5 + 1 > 3", "1.5 + true", "{1.5: 1}", "1 <= 2", "2 >= 2.5",
+        "1 < true", "1 <= true", "true < 1", r#"(1 + true) < (2 + "a")"#, r#"(1 + true) <= (2 + "a")"#,
+        "false && undefined", "true || undefined", "true && undefined", "if (false) { nope }",
+        "if (true) { 1 } else { nope }", "let f = fn() { nope }; 1", "let f = fn() { nope }; f()",
+        "1 && 2", "0 || false", "false && 1 + true", "true || -true", "true && -true", "!(1 < 2 && 2 <= 2 || false)",
+        "let a = [1]; push(a, 2); a", "push(1, 1)", "puts()", "let len = fn(x) { 42 }; len([])",
+        "let map = fn(arr, f) { let iter = fn(arr, acc) { if (len(arr) == 0) { acc } else { iter(rest(arr), push(acc, f(first(arr)))) } }; iter(arr, []); }; map([1, 2, 3], fn(x) { x * 2 })",
+        "let reduce = fn(arr, initial, f) { let iter = fn(arr, result) { if (len(arr) == 0) { result } else { iter(rest(arr), f(result, first(arr))) } }; iter(arr, initial); }; reduce([1, 2, 3, 4, 5], 0, fn(a, b) { a + b })",
+    ];
+
+    fn parse(input: &str) -> Program {
+        let p = RawMonkeyProgram::new(input);
+        Parser::new(Lexer::new(&p)).parse().unwrap_or_else(|e| panic!("parser had errors for {:?}:\n{:?}", input, e))
+    }
+
+    fn run(input: &str) -> std::result::Result<Object, String> {
+        let mut compiler = Compiler::new();
+        compiler.compile(&parse(input)).map_err(|e| e.to_string())?;
+        let mut vm = Vm::new(compiler.bytecode());
+        vm.run().map_err(|e| e.to_string())?;
+        Ok(vm.last_popped_stack_elem().clone())
+    }
+
+    #[test]
+    fn test_agrees_with_evaluator() {
+        for input in CORPUS {
+            let expected = match evaluator::eval_program(&parse(input), &Environment::new()) {
+                Object::Error(message) => Err(message),
+                object => Ok(object),
+            };
+            assert_eq!(run(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_results() {
+        assert_eq!(run("let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(20)"), Ok(Object::Integer(6765)));
+        assert_eq!(run("[1, 2][0] + {1: 2}[1]"), Ok(Object::Integer(3)));
+        assert_eq!(run("let one = fn() { 1 }; let two = fn() { one() + one() }; two()"), Ok(Object::Integer(2)));
+        assert!(matches!(run("fn() { 1 }"), Ok(Object::Closure(_))));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(run("let f = fn() { f() }; f()"), Err("stack overflow".to_string()));
+        assert_eq!(run("fn(a) { a }()"), Err("wrong number of arguments: want=1, got=0".to_string()));
+    }
+
+    #[test]
+    fn test_call_depth_agrees_with_evaluator() {
+        // The evaluator needs more stack for this than the test threads have
+        let deep = std::thread::Builder::new().stack_size(256 << 20).spawn(|| {
+            for n in [1022, 1023] {
+                let input = format!("let f = fn(n) {{ if (n == 0) {{ 0 }} else {{ f(n - 1) }} }}; f({})", n);
+                let expected = match evaluator::eval_program(&parse(&input), &Environment::new()) {
+                    Object::Error(message) => Err(message),
+                    object => Ok(object),
+                };
+                assert_eq!(run(&input), expected, "{}", input);
+            }
+        });
+        deep.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_globals_survive_between_runs() {
+        let mut compiler = Compiler::new();
+        compiler.compile(&parse("let a = 40;")).unwrap();
+        let mut vm = Vm::new(compiler.bytecode());
+        vm.run().unwrap();
+
+        let (symbols, constants) = compiler.into_state();
+        let mut compiler = Compiler::with_state(symbols, constants);
+        compiler.compile(&parse("a + 2")).unwrap();
+        let mut vm = Vm::with_globals(compiler.bytecode(), vm.into_globals());
+        vm.run().unwrap();
+        assert_eq!(vm.last_popped_stack_elem(), &Object::Integer(42));
+    }
+}
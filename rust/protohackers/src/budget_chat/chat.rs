@@ -0,0 +1,290 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+
+/// Where everyone starts out.
+pub const DEFAULT_ROOM: &str = "general";
+const ROOM_CAPACITY: usize = 256;
+/// Lines for one user that can wait to be read before more are dropped
+const DIRECT_CAPACITY: usize = 64;
+const MAX_NAME_LEN: usize = 16;
+
+pub type UserId = u32;
+
+/// A line for everyone in a room, but the user it came from.
+#[derive(Debug, Clone)]
+pub struct Msg {
+    pub data: Arc<String>,
+    pub from: UserId,
+}
+
+pub type RoomRecv = broadcast::Receiver<Msg>;
+/// Lines for one user only: direct messages and answers to their commands.
+pub type DirectRecv = mpsc::Receiver<Arc<String>>;
+
+struct Room {
+    send: broadcast::Sender<Msg>,
+    members: BTreeSet<UserId>,
+}
+
+struct User {
+    name: String,
+    room: String,
+    direct: mpsc::Sender<Arc<String>>,
+    /// Lines dropped since the user last had room for one
+    dropped: Cell<usize>,
+}
+
+impl User {
+    /// Queue `line` for the user alone. When they fall behind it is dropped, and once there is
+    /// room again they are told how many were.
+    fn send(&self, line: String) {
+        let dropped = self.dropped.get();
+        if dropped > 0 {
+            let notice = format!("* you fell behind, {} direct messages were dropped\n", dropped);
+            if self.direct.try_send(Arc::new(notice)).is_err() {
+                self.dropped.set(dropped + 1);
+                return;
+            }
+            self.dropped.set(0);
+        }
+        if let Err(TrySendError::Full(_)) = self.direct.try_send(Arc::new(line)) {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+}
+
+/// Every user and room. Rooms open when someone joins them and close when the last one leaves.
+pub struct Chat {
+    next_id: UserId,
+    users: HashMap<UserId, User>,
+    rooms: BTreeMap<String, Room>,
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Chat {
+            next_id: 0,
+            users: HashMap::new(),
+            rooms: BTreeMap::new(),
+        }
+    }
+
+    /// Sign in as `name` and enter the default room, or why that is not possible.
+    pub fn join(&mut self, name: &str) -> Result<(UserId, RoomRecv, DirectRecv), String> {
+        validate_name(name)?;
+        if self.user_named(name).is_some() {
+            return Err(format!("the name {} is already taken", name));
+        }
+        let user_id = self.next_id;
+        self.next_id += 1;
+        let (direct, direct_recv) = mpsc::channel(DIRECT_CAPACITY);
+        let user = User { name: name.into(), room: DEFAULT_ROOM.into(), direct, dropped: Cell::new(0) };
+        self.users.insert(user_id, user);
+        let room = self.enter(user_id, DEFAULT_ROOM);
+        Ok((user_id, room, direct_recv))
+    }
+
+    /// Leave the current room for `room`, whose messages now come through the receiver returned.
+    pub fn switch_room(&mut self, user_id: UserId, room: &str) -> Result<RoomRecv, String> {
+        validate_name(room).map_err(|_| format!("{} is not a room name", room))?;
+        if self.users[&user_id].room == room {
+            return Err(format!("you are already in {}", room));
+        }
+        self.leave_room(user_id);
+        self.users.get_mut(&user_id).unwrap().room = room.into();
+        Ok(self.enter(user_id, room))
+    }
+
+    pub fn leave(&mut self, user_id: UserId) {
+        self.leave_room(user_id);
+        self.users.remove(&user_id);
+    }
+
+    pub fn say(&self, user_id: UserId, text: &str) {
+        let user = &self.users[&user_id];
+        self.broadcast(&user.room, user_id, format!("[{}] {}\n", user.name, text));
+    }
+
+    pub fn whisper(&self, user_id: UserId, to: &str, text: &str) -> Result<(), String> {
+        let Some(to_id) = self.user_named(to) else {
+            return Err(format!("there is no one called {}", to));
+        };
+        let line = format!("[{} -> {}] {}", self.users[&user_id].name, to, text);
+        self.notice(to_id, &line);
+        if to_id != user_id {
+            self.notice(user_id, &line);
+        }
+        Ok(())
+    }
+
+    /// The open rooms and how many are in each.
+    pub fn rooms(&self) -> String {
+        let rooms = self.rooms.iter()
+            .map(|(name, room)| format!("{} ({})", name, room.members.len()))
+            .collect::<Vec<_>>();
+        format!("* Rooms: {}", rooms.join(", "))
+    }
+
+    /// Who is in the room with the user.
+    pub fn who(&self, user_id: UserId) -> String {
+        let room = &self.users[&user_id].room;
+        format!("* In {}: {}", room, self.names(room).join(", "))
+    }
+
+    /// Send `line` to the user alone.
+    pub fn notice(&self, user_id: UserId, line: &str) {
+        if let Some(user) = self.users.get(&user_id) {
+            user.send(format!("{}\n", line));
+        }
+    }
+
+    fn enter(&mut self, user_id: UserId, room: &str) -> RoomRecv {
+        let others = self.names(room);
+        self.notice(user_id, &format!("* The room contains: {}", others.join(", ")));
+        let name = self.users[&user_id].name.clone();
+        self.broadcast(room, user_id, format!("* {} has entered the room\n", name));
+
+        let room = self.rooms.entry(room.into()).or_insert_with(|| Room {
+            send: broadcast::channel(ROOM_CAPACITY).0,
+            members: BTreeSet::new(),
+        });
+        room.members.insert(user_id);
+        room.send.subscribe()
+    }
+
+    fn leave_room(&mut self, user_id: UserId) {
+        let Some(user) = self.users.get(&user_id) else {
+            return;
+        };
+        let room_name = user.room.clone();
+        let line = format!("* {} has left the room\n", user.name);
+        let Some(room) = self.rooms.get_mut(&room_name) else {
+            return;
+        };
+        room.members.remove(&user_id);
+        if room.members.is_empty() {
+            self.rooms.remove(&room_name);
+        } else {
+            self.broadcast(&room_name, user_id, line);
+        }
+    }
+
+    fn broadcast(&self, room: &str, from: UserId, line: String) {
+        if let Some(room) = self.rooms.get(room) {
+            // Nobody listening is fine, everyone may be on their way out
+            let _ = room.send.send(Msg { data: Arc::new(line), from });
+        }
+    }
+
+    fn names(&self, room: &str) -> Vec<&str> {
+        let Some(room) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        room.members.iter()
+            .map(|id| self.users[id].name.as_str())
+            .collect()
+    }
+
+    fn user_named(&self, name: &str) -> Option<UserId> {
+        self.users.iter().find(|(_, u)| u.name == name).map(|(id, _)| *id)
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("names are 1 to {} letters or digits", MAX_NAME_LEN));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(direct: &mut DirectRecv) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(line) = direct.try_recv() {
+            lines.push(line.trim_end_matches('\n').to_string());
+        }
+        lines
+    }
+
+    fn room_lines(room: &mut RoomRecv, user_id: UserId) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(msg) = room.try_recv() {
+            if msg.from != user_id {
+                lines.push(msg.data.trim_end_matches('\n').to_string());
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn names_are_valid_and_unique() {
+        let mut chat = Chat::new();
+        assert!(chat.join("alice").is_ok());
+        assert_eq!(chat.join("alice").err().unwrap(), "the name alice is already taken");
+        assert!(chat.join("").is_err());
+        assert!(chat.join("bob smith").is_err());
+        assert!(chat.join("abcdefghijklmnopq").is_err());
+        assert!(chat.join("abcdefghijklmnop").is_ok());
+    }
+
+    #[test]
+    fn rooms_keep_their_messages_apart() {
+        let mut chat = Chat::new();
+        let (alice, mut alice_room, mut alice_direct) = chat.join("alice").unwrap();
+        let (bob, _, mut bob_direct) = chat.join("bob").unwrap();
+        assert_eq!(lines(&mut alice_direct), ["* The room contains: "]);
+        assert_eq!(lines(&mut bob_direct), ["* The room contains: alice"]);
+        assert_eq!(room_lines(&mut alice_room, alice), ["* bob has entered the room"]);
+
+        let mut bob_games = chat.switch_room(bob, "games").unwrap();
+        assert_eq!(room_lines(&mut alice_room, alice), ["* bob has left the room"]);
+        assert_eq!(chat.rooms(), "* Rooms: games (1), general (1)");
+        assert_eq!(chat.who(bob), "* In games: bob");
+        chat.say(alice, "anyone?");
+        assert_eq!(room_lines(&mut bob_games, bob), Vec::<String>::new());
+        assert_eq!(chat.switch_room(bob, "games").err().unwrap(), "you are already in games");
+
+        chat.whisper(alice, "bob", "psst").unwrap();
+        assert_eq!(lines(&mut bob_direct), ["* The room contains: ", "[alice -> bob] psst"]);
+        assert_eq!(lines(&mut alice_direct), ["[alice -> bob] psst"]);
+        assert!(chat.whisper(alice, "carol", "hi").is_err());
+
+        // The last one out closes the room
+        chat.leave(bob);
+        assert_eq!(chat.rooms(), "* Rooms: general (1)");
+    }
+
+    #[test]
+    fn slow_readers_lag() {
+        let mut chat = Chat::new();
+        let (_, mut slow, _) = chat.join("slow").unwrap();
+        let (fast, _, _) = chat.join("fast").unwrap();
+        for i in 0..ROOM_CAPACITY + 10 {
+            chat.say(fast, &i.to_string());
+        }
+        // One entered message and ten chat lines more than the room holds
+        assert!(matches!(slow.try_recv(), Err(broadcast::error::TryRecvError::Lagged(11))));
+    }
+
+    #[test]
+    fn slow_direct_readers_drop_with_a_notice() {
+        let mut chat = Chat::new();
+        let (_, _, mut slow) = chat.join("slow").unwrap();
+        let (fast, _, _) = chat.join("fast").unwrap();
+        for i in 0..DIRECT_CAPACITY + 10 {
+            chat.whisper(fast, "slow", &i.to_string()).unwrap();
+        }
+        let received = lines(&mut slow);
+        assert_eq!(received.len(), DIRECT_CAPACITY);
+        assert_eq!(received.last().unwrap(), &format!("[fast -> slow] {}", DIRECT_CAPACITY - 2));
+
+        chat.whisper(fast, "slow", "caught up?").unwrap();
+        assert_eq!(lines(&mut slow), ["* you fell behind, 11 direct messages were dropped", "[fast -> slow] caught up?"]);
+    }
+}
@@ -0,0 +1,323 @@
+mod chat;
+
+use std::{io, mem};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use anyhow::{format_err, Result};
+use chat::{Chat, DirectRecv, Msg, RoomRecv, UserId};
+use crate::server::Handler;
+
+const MAX_MESSAGE_LEN: usize = 1000;
+/// The most a line of `MAX_MESSAGE_LEN` characters takes in UTF-8, with its `\r\n`
+const MAX_LINE_BYTES: usize = 4 * MAX_MESSAGE_LEN + 2;
+
+pub struct BudgetChat {
+    chat: Arc<Mutex<Chat>>,
+}
+
+impl BudgetChat {
+    pub fn new() -> Self {
+        BudgetChat { chat: Arc::new(Mutex::new(Chat::new())) }
+    }
+}
+
+impl Handler for BudgetChat {
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        handle_user_session(stream, self.chat.clone()).await
+    }
+}
+
+async fn handle_user_session(stream: TcpStream, chat: Arc<Mutex<Chat>>) -> Result<()> {
+    let mut stream = BufStream::new(stream);
+    let mut lines = LineReader::new();
+    let username = request_name(&mut stream, &mut lines).await?;
+    let joined = chat.lock().unwrap().join(&username);
+    let (user_id, room, direct) = match joined {
+        Ok(joined) => joined,
+        Err(reason) => {
+            tracing::info!("Rejected username {:?}: {}", username, reason);
+            stream.write_all(format!("* {}\n", reason).as_bytes()).await?;
+            stream.flush().await?;
+            return Err(format_err!(reason));
+        }
+    };
+    tracing::info!("user entered: {} {}", username, user_id);
+
+    let result = chat_session(&mut stream, &mut lines, &chat, user_id, room, direct).await;
+    chat.lock().unwrap().leave(user_id);
+    result
+}
+
+async fn chat_session(stream: &mut BufStream<TcpStream>,
+                      lines: &mut LineReader,
+                      chat: &Mutex<Chat>,
+                      user_id: UserId,
+                      mut room: RoomRecv,
+                      mut direct: DirectRecv) -> Result<()> {
+    let mut limiter = RateLimiter::new();
+    loop {
+        tokio::select! {
+            // Answers to commands come before whatever the room says next
+            biased;
+            line = direct.recv() => {
+                let Some(line) = line else {
+                    break Ok(());
+                };
+                stream.write_all(line.as_bytes()).await?;
+                stream.flush().await?;
+            }
+            msg = room.recv() => {
+                if let Some(line) = room_line(msg, user_id) {
+                    stream.write_all(line.as_bytes()).await?;
+                    stream.flush().await?;
+                }
+            }
+            line = lines.next(stream) => {
+                let line = line?;
+                let mut chat = chat.lock().unwrap();
+                match line {
+                    Line::End => break Ok(()),
+                    _ if !limiter.allow(Instant::now()) => chat.notice(user_id, "* slow down, that message was dropped"),
+                    Line::Text(line) if line.chars().count() <= MAX_MESSAGE_LEN => {
+                        if let Some(new_room) = run_line(&mut chat, user_id, &line) {
+                            room = new_room;
+                        }
+                    }
+                    Line::Text(_) | Line::TooLong(_) => {
+                        chat.notice(user_id, &format!("* messages are at most {} characters", MAX_MESSAGE_LEN));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What to write for a message from the room, a lagging reader is told how much it missed.
+fn room_line(msg: Result<Msg, RecvError>, user_id: UserId) -> Option<Arc<String>> {
+    match msg {
+        Ok(msg) if msg.from == user_id => None,
+        Ok(msg) => Some(msg.data),
+        Err(RecvError::Lagged(n)) => Some(Arc::new(format!("* you fell behind, {} messages were dropped\n", n))),
+        // The room only closes once this user left it
+        Err(RecvError::Closed) => None,
+    }
+}
+
+/// Say `line` or run it as a command, returns the room to listen to if that changed.
+fn run_line(chat: &mut Chat, user_id: UserId, line: &str) -> Option<RoomRecv> {
+    let Some(command) = line.strip_prefix('/') else {
+        chat.say(user_id, line);
+        return None;
+    };
+    let (command, args) = command.split_once(' ').unwrap_or((command, ""));
+    let reply = match command {
+        "join" => match chat.switch_room(user_id, args.trim()) {
+            Ok(room) => return Some(room),
+            Err(e) => format!("* {}", e),
+        },
+        "rooms" => chat.rooms(),
+        "who" => chat.who(user_id),
+        "msg" => match args.split_once(' ') {
+            Some((to, text)) if !text.trim().is_empty() => match chat.whisper(user_id, to, text) {
+                Ok(()) => return None,
+                Err(e) => format!("* {}", e),
+            },
+            _ => "* usage: /msg <user> <message>".into(),
+        },
+        _ => format!("* unknown command /{}, try /join, /rooms, /who or /msg", command),
+    };
+    chat.notice(user_id, &reply);
+    None
+}
+
+/// Lets a burst of messages through, then one every `REFILL`.
+struct RateLimiter {
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    const BURST: f64 = 20.0;
+    const REFILL: Duration = Duration::from_millis(200);
+
+    fn new() -> Self {
+        RateLimiter { tokens: Self::BURST, last: None }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        if let Some(last) = self.last {
+            let refilled = now.saturating_duration_since(last).as_secs_f64() / Self::REFILL.as_secs_f64();
+            self.tokens = (self.tokens + refilled).min(Self::BURST);
+        }
+        self.last = Some(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+async fn request_name(stream: &mut BufStream<TcpStream>, lines: &mut LineReader) -> Result<String> {
+    stream.write_all("Welcome to budget chat! What shall I call you?\n".as_bytes()).await?;
+    stream.flush().await?;
+    match lines.next(stream).await? {
+        Line::End => Ok(String::new()),
+        // Far too long for a name, but what there is of it is turned down like any other
+        Line::Text(name) | Line::TooLong(name) => Ok(name.trim().into()),
+    }
+}
+
+enum Line {
+    Text(String),
+    /// The start of a line longer than `MAX_LINE_BYTES`, the rest of which is skipped
+    TooLong(String),
+    End,
+}
+
+/// Reads lines without ever holding more than `MAX_LINE_BYTES` of one, so a client that never
+/// sends a newline cannot make the server buffer without limit.
+struct LineReader {
+    buf: Vec<u8>,
+    /// Skipping to the end of a line that was too long
+    skipping: bool,
+}
+
+impl LineReader {
+    fn new() -> Self {
+        LineReader { buf: Vec::new(), skipping: false }
+    }
+
+    /// The next line, without its line ending. Cancel safe, what a cancelled call read is kept
+    /// for the next one.
+    async fn next(&mut self, stream: &mut BufStream<TcpStream>) -> io::Result<Line> {
+        loop {
+            let limit = (MAX_LINE_BYTES - self.buf.len()) as u64;
+            let n = (&mut *stream).take(limit).read_until(b'\n', &mut self.buf).await?;
+            if self.buf.ends_with(b"\n") {
+                let line = self.take_line();
+                if !mem::replace(&mut self.skipping, false) {
+                    return Ok(Line::Text(line));
+                }
+            } else if self.buf.len() == MAX_LINE_BYTES {
+                let line = self.take_line();
+                if !mem::replace(&mut self.skipping, true) {
+                    return Ok(Line::TooLong(line));
+                }
+            } else if n == 0 {
+                // The stream ended, maybe halfway through a line
+                let line = self.take_line();
+                return Ok(if self.skipping || line.is_empty() { Line::End } else { Line::Text(line) });
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> String {
+        let line = String::from_utf8_lossy(&self.buf).trim_end_matches(['\n', '\r']).to_string();
+        self.buf.clear();
+        line
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::testing::{Client, TestServer};
+    use super::*;
+
+    async fn sign_in(server: &TestServer, name: &str) -> Client {
+        let mut client = server.connect().await;
+        assert_eq!(client.line().await.unwrap(), "Welcome to budget chat! What shall I call you?");
+        client.send(format!("{}\n", name).as_bytes()).await;
+        client
+    }
+
+    #[tokio::test]
+    async fn chat_across_rooms() {
+        let server = TestServer::start(BudgetChat::new()).await;
+        let mut alice = sign_in(&server, "alice").await;
+        assert_eq!(alice.line().await.unwrap(), "* The room contains: ");
+        let mut bob = sign_in(&server, "bob").await;
+        assert_eq!(bob.line().await.unwrap(), "* The room contains: alice");
+        assert_eq!(alice.line().await.unwrap(), "* bob has entered the room");
+
+        bob.send(b"hi all\n").await;
+        assert_eq!(alice.line().await.unwrap(), "[bob] hi all");
+
+        bob.send(b"/join games\n").await;
+        assert_eq!(bob.line().await.unwrap(), "* The room contains: ");
+        assert_eq!(alice.line().await.unwrap(), "* bob has left the room");
+        bob.send(b"/rooms\n").await;
+        assert_eq!(bob.line().await.unwrap(), "* Rooms: games (1), general (1)");
+        bob.send(b"/who\n").await;
+        assert_eq!(bob.line().await.unwrap(), "* In games: bob");
+
+        alice.send(b"/msg bob still there?\n").await;
+        assert_eq!(alice.line().await.unwrap(), "[alice -> bob] still there?");
+        assert_eq!(bob.line().await.unwrap(), "[alice -> bob] still there?");
+        alice.send(b"/msg carol hi\n").await;
+        assert_eq!(alice.line().await.unwrap(), "* there is no one called carol");
+        alice.send(b"/dance\n").await;
+        assert!(alice.line().await.unwrap().starts_with("* unknown command /dance"));
+
+        alice.send(format!("{}\n", "x".repeat(MAX_MESSAGE_LEN + 1)).as_bytes()).await;
+        assert_eq!(alice.line().await.unwrap(), "* messages are at most 1000 characters");
+        // Far longer lines are only read as far as the limit and then skipped
+        for _ in 0..100 {
+            alice.send("x".repeat(MAX_LINE_BYTES).as_bytes()).await;
+        }
+        alice.send(b"\nback to normal\n").await;
+        assert_eq!(alice.line().await.unwrap(), "* messages are at most 1000 characters");
+        alice.send(b"/msg bob back\n").await;
+        assert_eq!(alice.line().await.unwrap(), "[alice -> bob] back");
+        assert_eq!(bob.line().await.unwrap(), "[alice -> bob] back");
+
+        drop((alice, bob));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn names_must_be_unique() {
+        let server = TestServer::start(BudgetChat::new()).await;
+        let mut alice = sign_in(&server, "alice").await;
+        assert_eq!(alice.line().await.unwrap(), "* The room contains: ");
+        let mut again = sign_in(&server, "alice").await;
+        assert_eq!(again.line().await.unwrap(), "* the name alice is already taken");
+        assert_eq!(again.line().await, None);
+        let mut bad = sign_in(&server, "no way").await;
+        assert!(bad.line().await.unwrap().starts_with("* names are"));
+        assert_eq!(bad.line().await, None);
+        let mut long = sign_in(&server, &"x".repeat(2 * MAX_LINE_BYTES)).await;
+        assert!(long.line().await.unwrap().starts_with("* names are"));
+        assert_eq!(long.line().await, None);
+        drop(alice);
+        server.stop().await;
+    }
+
+    #[test]
+    fn lagging_readers_get_a_notice() {
+        let msg = Msg { data: Arc::new("[bob] hi\n".into()), from: 1 };
+        assert_eq!(room_line(Ok(msg.clone()), 0).unwrap().as_str(), "[bob] hi\n");
+        assert_eq!(room_line(Ok(msg), 1), None);
+        assert_eq!(room_line(Err(RecvError::Lagged(7)), 0).unwrap().as_str(),
+                   "* you fell behind, 7 messages were dropped\n");
+    }
+
+    #[test]
+    fn rate_limit_refills_over_time() {
+        let mut limiter = RateLimiter::new();
+        let start = Instant::now();
+        for _ in 0..20 {
+            assert!(limiter.allow(start));
+        }
+        assert!(!limiter.allow(start));
+        assert!(!limiter.allow(start + Duration::from_millis(100)));
+        assert!(limiter.allow(start + Duration::from_millis(250)));
+        assert!(!limiter.allow(start + Duration::from_millis(250)));
+        // Never more than the burst, however long the pause
+        let later = start + Duration::from_secs(3600);
+        assert_eq!((0..30).filter(|_| limiter.allow(later)).count(), 20);
+    }
+}
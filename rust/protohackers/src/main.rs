@@ -1,3 +1,4 @@
+mod server;
 mod prime_time;
 mod means_to_end;
 mod budget_chat;
@@ -6,48 +7,58 @@ mod mob_in_the_middle;
 mod speed_daemon;
 
 use std::env;
+use std::path::{Path, PathBuf};
+use anyhow::{format_err, Result};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use server::{Handler, ServerConfig};
 
+/// `protohackers <command> <addr> [--max-connections <n>] [args...]`
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let args: Vec<String> = env::args().collect();
-    let cmd = &args[1];
-    let addr = &args[2];
-    match cmd.as_str() {
-        "smoke-test" => smoke_test().await,
-        "prime-time" => prime_time::run().await,
-        "means-to-end" => means_to_end::run(addr).await.unwrap(),
-        "budget-chat" => budget_chat::run(addr).await.unwrap(),
-        "udpdb" => udpdb::run(addr).await.unwrap(),
-        "mob-in-the-middle" => mob_in_the_middle::run(addr).await.unwrap(),
-        "speed-daemon" => speed_daemon::run(addr).await.unwrap(),
-        _ => println!("unsupported command"),
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut max_connections = None;
+    if let Some(i) = args.iter().position(|a| a == "--max-connections") {
+        let n = args.get(i + 1).ok_or_else(|| format_err!("--max-connections needs a number"))?;
+        max_connections = Some(n.parse()?);
+        args.drain(i..i + 2);
+    }
+    if args.len() < 2 {
+        return Err(format_err!("usage: protohackers <command> <addr> [--max-connections <n>] [args...]"));
+    }
+    let mut config = ServerConfig::new(&args[1]);
+    config.max_connections = max_connections;
+
+    match args[0].as_str() {
+        "smoke-test" => server::run(config, SmokeTest).await,
+        "prime-time" => server::run(config, prime_time::PrimeTime).await,
+        "means-to-end" => server::run(config, means_to_end::MeansToEnd::new(args.get(2).map(PathBuf::from))).await,
+        "budget-chat" => server::run(config, budget_chat::BudgetChat::new()).await,
+        "udpdb" => udpdb::run(config).await,
+        "mob-in-the-middle" => {
+            let handler = mob_in_the_middle::MobInTheMiddle::new(args.get(2).map(Path::new))?;
+            server::run(config, handler).await
+        }
+        "speed-daemon" => {
+            let handler = speed_daemon::SpeedDaemon::new(args.get(2).map(Path::new))?;
+            server::run(config, handler).await
+        }
+        _ => Err(format_err!("unsupported command")),
     }
 }
 
-async fn smoke_test() {
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:14293").await.unwrap();
-    println!("listening");
-    loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
-        println!("Received new connection");
-        tokio::spawn(async move {
-            loop {
-                let mut buf = vec![0; 1024];
-                let n = socket
-                    .read(&mut buf)
-                    .await
-                    .expect("failed to read data from socket");
-                if n == 0 {
-                    println!("connection closed");
-                    return;
-                }
-                socket
-                    .write_all(&buf[..n])
-                    .await
-                    .expect("failed to write data to socket")
+struct SmokeTest;
+
+impl Handler for SmokeTest {
+    async fn handle(&self, mut socket: TcpStream) -> Result<()> {
+        let mut buf = vec![0; 1024];
+        loop {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
             }
-        });
+            socket.write_all(&buf[..n]).await?;
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,184 @@
+mod store;
+
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::net::TcpStream;
+use anyhow::{format_err, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use store::Store;
+use crate::server::Handler;
+
+/// Prices only last as long as the connection, unless it starts by choosing a session with an
+/// `S` message carrying a big endian u64 key. With a `data_dir`, such a session is kept in a log
+/// there and a later connection choosing the same key carries on with its prices.
+pub struct MeansToEnd {
+    data_dir: Option<PathBuf>,
+    open_sessions: Mutex<HashSet<u64>>,
+}
+
+impl MeansToEnd {
+    pub fn new(data_dir: Option<PathBuf>) -> Self {
+        MeansToEnd { data_dir, open_sessions: Mutex::new(HashSet::new()) }
+    }
+}
+
+impl Handler for MeansToEnd {
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut stream = BufStream::new(stream);
+        let mut buf = [0; 9];
+        let mut store = Store::in_memory();
+        let mut _claim = None;
+        let mut first = true;
+
+        while read_message(&mut stream, &mut buf).await? {
+            match Operation::parse(&buf)? {
+                Operation::Session { key } if first => {
+                    let Some(dir) = &self.data_dir else {
+                        return Err(format_err!("sessions are not kept without a data directory"));
+                    };
+                    _claim = Some(Claim::new(&self.open_sessions, key)?);
+                    store = Store::open(dir, key)?;
+                    tracing::info!("Session {} starts with {} prices", key, store.len());
+                }
+                Operation::Session { key } => return Err(format_err!("session {} chosen after the first message", key)),
+                Operation::Insert { timestamp, price } => store.insert(timestamp, price)?,
+                Operation::Query { min_time, max_time } => {
+                    store.flush()?;
+                    stream.write_i32(store.mean(min_time, max_time)).await?;
+                    stream.flush().await?;
+                }
+            }
+            first = false;
+        }
+        store.flush()
+    }
+}
+
+/// Read the next message into `buf`, false once the client is done.
+async fn read_message(stream: &mut BufStream<TcpStream>, buf: &mut [u8; 9]) -> Result<bool> {
+    match stream.read_exact(buf).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Keeps a session to one connection at a time, its log has a single writer.
+struct Claim<'a> {
+    open_sessions: &'a Mutex<HashSet<u64>>,
+    key: u64,
+}
+
+impl<'a> Claim<'a> {
+    fn new(open_sessions: &'a Mutex<HashSet<u64>>, key: u64) -> Result<Self> {
+        if !open_sessions.lock().unwrap().insert(key) {
+            return Err(format_err!("session {} is already open", key));
+        }
+        Ok(Claim { open_sessions, key })
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.open_sessions.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[derive(Debug)]
+enum Operation {
+    Insert { timestamp: i32, price: i32 },
+    Query { min_time: i32, max_time: i32 },
+    Session { key: u64 },
+}
+
+impl Operation {
+    fn parse(data: &[u8]) -> Result<Operation> {
+        let op = data[0];
+        let int_0 = i32::from_be_bytes(data[1..5].try_into().unwrap());
+        let int_1 = i32::from_be_bytes(data[5..9].try_into().unwrap());
+        match op as char {
+            'I' => Ok(Operation::Insert {
+                timestamp: int_0,
+                price: int_1,
+            }),
+            'Q' => Ok(Operation::Query {
+                min_time: int_0,
+                max_time: int_1,
+            }),
+            'S' => Ok(Operation::Session {
+                key: u64::from_be_bytes(data[1..9].try_into().unwrap()),
+            }),
+            _ => Err(format_err!("bad message: {:?}", data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+    use crate::server::testing::TestServer;
+    use super::*;
+
+    fn message(op: u8, a: i32, b: i32) -> Vec<u8> {
+        let mut data = vec![op];
+        data.extend(a.to_be_bytes());
+        data.extend(b.to_be_bytes());
+        data
+    }
+
+    #[tokio::test]
+    async fn sessions_are_separate() {
+        let server = TestServer::start(MeansToEnd::new(None)).await;
+        let mut a = server.connect().await;
+        let mut b = server.connect().await;
+        for (timestamp, price) in [(12345, 101), (12346, 102), (12347, 100), (40960, 5)] {
+            a.send(&message(b'I', timestamp, price)).await;
+        }
+        b.send(&message(b'I', 12345, 1)).await;
+        a.send(&message(b'Q', 12288, 16384)).await;
+        b.send(&message(b'Q', 12288, 16384)).await;
+        assert_eq!(a.0.read_i32().await.unwrap(), 101);
+        assert_eq!(b.0.read_i32().await.unwrap(), 1);
+        drop((a, b));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn sessions_carry_on_by_key() {
+        let dir = std::env::temp_dir().join(format!("means-to-end-keys-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let server = TestServer::start(MeansToEnd::new(Some(dir.clone()))).await;
+        let mut a = server.connect().await;
+        a.send(&message(b'S', 0, 7)).await;
+        a.send(&message(b'I', 1, 10)).await;
+        a.send(&message(b'Q', 0, 10)).await;
+        assert_eq!(a.0.read_i32().await.unwrap(), 10);
+        // Not while someone else has it open
+        let mut b = server.connect().await;
+        b.send(&message(b'S', 0, 7)).await;
+        assert!(b.0.read_i32().await.is_err());
+        // Only first
+        let mut c = server.connect().await;
+        c.send(&message(b'I', 1, 10)).await;
+        c.send(&message(b'S', 0, 8)).await;
+        assert!(c.0.read_i32().await.is_err());
+        drop((a, b, c));
+        server.stop().await;
+
+        let server = TestServer::start(MeansToEnd::new(Some(dir.clone()))).await;
+        let mut a = server.connect().await;
+        a.send(&message(b'S', 0, 7)).await;
+        a.send(&message(b'I', 2, 20)).await;
+        a.send(&message(b'Q', 0, 10)).await;
+        assert_eq!(a.0.read_i32().await.unwrap(), 15);
+        // Without a key, nothing is kept
+        let mut b = server.connect().await;
+        b.send(&message(b'Q', 0, 10)).await;
+        assert_eq!(b.0.read_i32().await.unwrap(), 0);
+        drop((a, b));
+        server.stop().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
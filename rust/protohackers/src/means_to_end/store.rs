@@ -0,0 +1,421 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+/// Bytes per point in a session log, big endian timestamp then price, as on the wire.
+const RECORD_LEN: u64 = 8;
+const NIL: u32 = u32::MAX;
+
+/// The prices of one session, ordered by timestamp.
+///
+/// A treap where every node also knows how many points and how much money are below it, so the
+/// mean over any time range takes two walks from the root, O(log n) whatever the order of inserts.
+/// Points with the same timestamp are all kept, the protocol leaves that case undefined.
+///
+/// Every index seeds its priorities from the random keys std uses against hash flooding, so a
+/// client cannot pick timestamps that line the tree up into a chain.
+pub struct PriceIndex {
+    nodes: Vec<Node>,
+    root: u32,
+    seed: u64,
+}
+
+struct Node {
+    timestamp: i32,
+    price: i32,
+    priority: u64,
+    left: u32,
+    right: u32,
+    /// Points in this subtree and the sum of their prices
+    count: u64,
+    sum: i64,
+}
+
+impl PriceIndex {
+    pub fn new() -> Self {
+        PriceIndex {
+            nodes: Vec::new(),
+            root: NIL,
+            // xorshift never leaves zero
+            seed: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn insert(&mut self, timestamp: i32, price: i32) {
+        // xorshift, the priorities only need to look random to keep the tree balanced
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let priority = self.seed;
+        let bound = timestamp as i64 + 1;
+
+        // Walk down past the nodes that stay above the new one, which now has them to count
+        let mut parent = NIL;
+        let mut at = self.root;
+        while at != NIL && self.nodes[at as usize].priority > priority {
+            let n = &mut self.nodes[at as usize];
+            n.count += 1;
+            n.sum += price as i64;
+            parent = at;
+            at = if (n.timestamp as i64) < bound { n.right } else { n.left };
+        }
+
+        let (left, right) = self.split(at, bound);
+        let node = self.nodes.len() as u32;
+        self.nodes.push(Node {
+            timestamp,
+            price,
+            priority,
+            left,
+            right,
+            count: 0,
+            sum: 0,
+        });
+        self.update(node);
+        if parent == NIL {
+            self.root = node;
+        } else if (self.nodes[parent as usize].timestamp as i64) < bound {
+            self.nodes[parent as usize].right = node;
+        } else {
+            self.nodes[parent as usize].left = node;
+        }
+    }
+
+    /// Mean price of the points in `min_time..=max_time`, 0 when there are none.
+    pub fn mean(&self, min_time: i32, max_time: i32) -> i32 {
+        if min_time > max_time {
+            return 0;
+        }
+        let (count_to, sum_to) = self.below(max_time as i64 + 1);
+        let (count_from, sum_from) = self.below(min_time as i64);
+        let count = count_to - count_from;
+        if count == 0 {
+            0
+        } else {
+            ((sum_to - sum_from) / count as i64) as i32
+        }
+    }
+
+    /// How many points have a timestamp below `bound` and the sum of their prices.
+    fn below(&self, bound: i64) -> (u64, i64) {
+        let (mut count, mut sum) = (0, 0);
+        let mut node = self.root;
+        while node != NIL {
+            let n = &self.nodes[node as usize];
+            if (n.timestamp as i64) < bound {
+                count += self.count(n.left) + 1;
+                sum += self.sum(n.left) + n.price as i64;
+                node = n.right;
+            } else {
+                node = n.left;
+            }
+        }
+        (count, sum)
+    }
+
+    /// Splits the subtree at `node` into the points below `bound` and the rest.
+    ///
+    /// Iterative, so even a tree that came out deep cannot overflow the stack.
+    fn split(&mut self, mut node: u32, bound: i64) -> (u32, u32) {
+        let (mut left, mut right) = (NIL, NIL);
+        // The last node put on each side, whose inner child is the next one on that side
+        let (mut left_last, mut right_last) = (NIL, NIL);
+        let mut path = Vec::new();
+        while node != NIL {
+            path.push(node);
+            let n = &self.nodes[node as usize];
+            if (n.timestamp as i64) < bound {
+                let next = n.right;
+                match left_last {
+                    NIL => left = node,
+                    last => self.nodes[last as usize].right = node,
+                }
+                left_last = node;
+                node = next;
+            } else {
+                let next = n.left;
+                match right_last {
+                    NIL => right = node,
+                    last => self.nodes[last as usize].left = node,
+                }
+                right_last = node;
+                node = next;
+            }
+        }
+        if left_last != NIL {
+            self.nodes[left_last as usize].right = NIL;
+        }
+        if right_last != NIL {
+            self.nodes[right_last as usize].left = NIL;
+        }
+        // Children come after their parents on the path
+        for &node in path.iter().rev() {
+            self.update(node);
+        }
+        (left, right)
+    }
+
+    fn update(&mut self, node: u32) {
+        let (left, right) = (self.nodes[node as usize].left, self.nodes[node as usize].right);
+        let count = self.count(left) + self.count(right) + 1;
+        let sum = self.sum(left) + self.sum(right) + self.nodes[node as usize].price as i64;
+        let n = &mut self.nodes[node as usize];
+        n.count = count;
+        n.sum = sum;
+    }
+
+    fn count(&self, node: u32) -> u64 {
+        if node == NIL { 0 } else { self.nodes[node as usize].count }
+    }
+
+    fn sum(&self, node: u32) -> i64 {
+        if node == NIL { 0 } else { self.nodes[node as usize].sum }
+    }
+}
+
+/// A session's prices, optionally backed by an append-only log in a data directory.
+///
+/// Opening a session that already has a log replays it, so its prices outlive the connection
+/// and the server process. Inserts are buffered and only written out by `flush`, which the
+/// session calls before answering each query, so an answer never covers prices that would be
+/// lost to the process crashing. Nothing is synced to survive the machine crashing.
+pub struct Store {
+    index: PriceIndex,
+    log: Option<BufWriter<File>>,
+}
+
+impl Store {
+    /// A store that only lives as long as the session.
+    pub fn in_memory() -> Self {
+        Store {
+            index: PriceIndex::new(),
+            log: None,
+        }
+    }
+
+    /// The store of `session` in `dir`, with every point the session ever inserted.
+    pub fn open(dir: &Path, session: u64) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(log_path(dir, session))?;
+
+        // A crash can leave half a record at the end, drop it so appends stay aligned
+        let len = file.metadata()?.len();
+        if len % RECORD_LEN != 0 {
+            file.set_len(len - len % RECORD_LEN)?;
+        }
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut index = PriceIndex::new();
+        for record in data.chunks_exact(RECORD_LEN as usize) {
+            let timestamp = i32::from_be_bytes(record[0..4].try_into().unwrap());
+            let price = i32::from_be_bytes(record[4..8].try_into().unwrap());
+            index.insert(timestamp, price);
+        }
+        Ok(Store {
+            index,
+            log: Some(BufWriter::new(file)),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn insert(&mut self, timestamp: i32, price: i32) -> Result<()> {
+        if let Some(log) = &mut self.log {
+            let mut record = [0; RECORD_LEN as usize];
+            record[0..4].copy_from_slice(&timestamp.to_be_bytes());
+            record[4..8].copy_from_slice(&price.to_be_bytes());
+            log.write_all(&record)?;
+        }
+        self.index.insert(timestamp, price);
+        Ok(())
+    }
+
+    /// Write out the inserts buffered so far.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(log) = &mut self.log {
+            log.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn mean(&self, min_time: i32, max_time: i32) -> i32 {
+        self.index.mean(min_time, max_time)
+    }
+}
+
+fn log_path(dir: &Path, session: u64) -> PathBuf {
+    dir.join(format!("{}.log", session))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+    use super::*;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, range: i32) -> i32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % range as u64) as i32
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("means-to-end-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn naive_mean(points: &[(i32, i32)], min_time: i32, max_time: i32) -> i32 {
+        let prices = points.iter()
+            .filter(|(t, _)| min_time <= *t && *t <= max_time)
+            .map(|(_, p)| *p as i64)
+            .collect::<Vec<_>>();
+        if prices.is_empty() {
+            0
+        } else {
+            (prices.iter().sum::<i64>() / prices.len() as i64) as i32
+        }
+    }
+
+    #[test]
+    fn mean_of_example_session() {
+        let mut index = PriceIndex::new();
+        index.insert(12345, 101);
+        index.insert(12346, 102);
+        index.insert(12347, 100);
+        index.insert(40960, 5);
+        assert_eq!(index.mean(12288, 16384), 101);
+        assert_eq!(index.mean(16384, 12288), 0);
+        assert_eq!(index.mean(0, 100), 0);
+        assert_eq!(index.mean(i32::MIN, i32::MAX), 77);
+    }
+
+    #[test]
+    fn mean_matches_naive() {
+        let mut random = Random(7);
+        let mut index = PriceIndex::new();
+        let mut points = Vec::new();
+        for _ in 0..2000 {
+            let point = (random.next(500) - 250, random.next(2000) - 1000);
+            index.insert(point.0, point.1);
+            points.push(point);
+            let (a, b) = (random.next(600) - 300, random.next(600) - 300);
+            assert_eq!(index.mean(a, b), naive_mean(&points, a, b), "mean({}, {})", a, b);
+        }
+        assert_eq!(index.len(), points.len());
+    }
+
+    #[test]
+    fn sessions_outlive_the_store() {
+        let dir = temp_dir("replay");
+        let mut store = Store::open(&dir, 0).unwrap();
+        store.insert(1, 10).unwrap();
+        store.insert(2, 20).unwrap();
+        drop(store);
+        Store::open(&dir, 1).unwrap().insert(1, 1000).unwrap();
+
+        let mut store = Store::open(&dir, 0).unwrap();
+        assert_eq!(store.mean(0, 10), 15);
+        store.insert(3, 30).unwrap();
+        // Written out without waiting for the store to go
+        store.flush().unwrap();
+        assert_eq!(Store::open(&dir, 0).unwrap().mean(0, 10), 20);
+
+        // Half a record from a crash is dropped
+        let mut file = OpenOptions::new().append(true).open(log_path(&dir, 0)).unwrap();
+        file.write_all(&[0, 0, 0]).unwrap();
+        let mut store = Store::open(&dir, 0).unwrap();
+        store.insert(4, 40).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let store = Store::open(&dir, 0).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.mean(0, 10), 25);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sorted_inserts_stay_shallow() {
+        let mut index = PriceIndex::new();
+        for timestamp in 0..100_000 {
+            index.insert(timestamp, 1);
+        }
+        let mut deepest = 0;
+        let mut nodes = vec![(index.root, 1)];
+        while let Some((node, depth)) = nodes.pop() {
+            if node != NIL {
+                deepest = deepest.max(depth);
+                let n = &index.nodes[node as usize];
+                nodes.extend([(n.left, depth + 1), (n.right, depth + 1)]);
+            }
+        }
+        assert!(deepest < 100, "depth {}", deepest);
+        assert_eq!(index.mean(0, 99_999), 1);
+    }
+
+    #[test]
+    fn indexes_draw_different_priorities() {
+        let (mut a, mut b) = (PriceIndex::new(), PriceIndex::new());
+        a.insert(0, 0);
+        b.insert(0, 0);
+        assert_ne!(a.nodes[0].priority, b.nodes[0].priority);
+    }
+
+    /// Run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn load_millions_of_points_per_session() {
+        const SESSIONS: u64 = 3;
+        const POINTS: usize = 3_000_000;
+        let dir = temp_dir("load");
+        let mut random = Random(42);
+
+        for session in 0..SESSIONS {
+            let start = Instant::now();
+            let mut store = Store::open(&dir, session).unwrap();
+            let mut prices_by_time = vec![0i64; 1 << 16];
+            for _ in 0..POINTS {
+                let (timestamp, price) = (random.next(1 << 16), random.next(1 << 20));
+                store.insert(timestamp, price).unwrap();
+                prices_by_time[timestamp as usize] += price as i64;
+            }
+            println!("session {}: {} inserts in {:?}", session, POINTS, start.elapsed());
+
+            let start = Instant::now();
+            let total = prices_by_time.iter().sum::<i64>();
+            assert_eq!(store.mean(i32::MIN, i32::MAX) as i64, total / POINTS as i64);
+            for _ in 0..100_000 {
+                store.mean(random.next(1 << 16), random.next(1 << 16));
+            }
+            println!("session {}: 100000 queries in {:?}", session, start.elapsed());
+
+            let start = Instant::now();
+            let replayed = Store::open(&dir, session).unwrap();
+            println!("session {}: replayed in {:?}", session, start.elapsed());
+            assert_eq!(replayed.len(), POINTS);
+            for _ in 0..1000 {
+                let (a, b) = (random.next(1 << 16), random.next(1 << 16));
+                assert_eq!(replayed.mean(a, b), store.mean(a, b));
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+mod rewrite;
+
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use anyhow::Result;
+use rewrite::{Direction, ProxyConfig, Rewriter};
+use crate::server::Handler;
+
+/// A line-oriented man in the middle: every line between a client and the upstream server goes
+/// through the rewrite rules of its direction. Either side hanging up disconnects the other.
+pub struct MobInTheMiddle {
+    upstream: String,
+    forward_partial_lines: bool,
+    rewriter: Rewriter,
+}
+
+impl MobInTheMiddle {
+    /// Proxy as the configuration file at `config` says, or as the Mob in the Middle problem
+    /// wants without one.
+    pub fn new(config: Option<&Path>) -> Result<Self> {
+        let config = match config {
+            Some(path) => ProxyConfig::load(path)?,
+            None => ProxyConfig::boguscoin(),
+        };
+        Self::with_config(config)
+    }
+
+    fn with_config(config: ProxyConfig) -> Result<Self> {
+        Ok(MobInTheMiddle {
+            rewriter: Rewriter::new(&config.rules)?,
+            upstream: config.upstream,
+            forward_partial_lines: config.forward_partial_lines,
+        })
+    }
+}
+
+impl Handler for MobInTheMiddle {
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        let upstream = TcpStream::connect(&self.upstream).await?;
+        let (client_read, client_write) = stream.into_split();
+        let (upstream_read, upstream_write) = upstream.into_split();
+        tokio::select! {
+            result = self.forward(client_read, upstream_write, Direction::ToUpstream) => result,
+            result = self.forward(upstream_read, client_write, Direction::ToClient) => result,
+        }
+    }
+}
+
+impl MobInTheMiddle {
+    /// Pass rewritten lines on until `from` hangs up.
+    async fn forward(&self, from: OwnedReadHalf, to: OwnedWriteHalf, direction: Direction) -> Result<()> {
+        let mut from = BufReader::new(from);
+        let mut to = BufWriter::new(to);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            if from.read_until(b'\n', &mut buf).await? == 0 {
+                tracing::info!("{:?}: closed", direction);
+                return Ok(());
+            }
+            let complete = buf.ends_with(b"\n");
+            if !complete && !self.forward_partial_lines {
+                tracing::info!("{:?}: closed, dropping a partial line of {} bytes", direction, buf.len());
+                return Ok(());
+            }
+
+            let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+            let rewritten = self.rewriter.rewrite(line, direction);
+            // Only what came out, the line going in may hold what the rules are there to hide
+            tracing::debug!("{:?}: {}", direction, String::from_utf8_lossy(&rewritten));
+            to.write_all(&rewritten).await?;
+            if complete {
+                to.write_all(b"\n").await?;
+            }
+            to.flush().await?;
+            if !complete {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+    use crate::server::testing::{Client, TestServer};
+    use super::*;
+
+    /// A proxy in front of a local upstream server that the test plays.
+    struct Setup {
+        proxy: TestServer,
+        upstream: TcpListener,
+    }
+
+    impl Setup {
+        async fn new(rules: &str, forward_partial_lines: bool) -> Self {
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = format!(
+                r#"{{"upstream": "{}", "forward_partial_lines": {}, "rules": {}}}"#,
+                upstream.local_addr().unwrap(), forward_partial_lines, rules,
+            );
+            let proxy = MobInTheMiddle::with_config(serde_json::from_str(&config).unwrap()).unwrap();
+            Setup { proxy: TestServer::start(proxy).await, upstream }
+        }
+
+        /// A client of the proxy and the connection the proxy made upstream for it.
+        async fn connect(&self) -> (Client, Client) {
+            let client = self.proxy.connect().await;
+            let (upstream, _) = self.upstream.accept().await.unwrap();
+            (client, Client(tokio::io::BufStream::new(upstream)))
+        }
+    }
+
+    const RULES: &str = r#"[
+        {"pattern": "^7[[:alnum:]]{25,34}$", "replacement": "7YWHMfk9JZe0LM0g1ZauHuiSxhI", "per_word": true},
+        {"pattern": "password=\\S+", "replacement": "password=***", "direction": "to_client"}
+    ]"#;
+
+    #[tokio::test]
+    async fn rewrites_each_direction() {
+        let setup = Setup::new(RULES, false).await;
+        let (mut client, mut upstream) = setup.connect().await;
+
+        upstream.send(b"Welcome, password=hunter2\n").await;
+        assert_eq!(client.line().await.unwrap(), "Welcome, password=***");
+        client.send(b"pay 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX password=hunter2\n").await;
+        assert_eq!(upstream.line().await.unwrap(), "pay 7YWHMfk9JZe0LM0g1ZauHuiSxhI password=hunter2");
+        upstream.send(b"[bob] send to 7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T\n").await;
+        assert_eq!(client.line().await.unwrap(), "[bob] send to 7YWHMfk9JZe0LM0g1ZauHuiSxhI");
+
+        drop((client, upstream));
+        setup.proxy.stop().await;
+    }
+
+    #[tokio::test]
+    async fn partial_lines_are_dropped_on_disconnect() {
+        let setup = Setup::new(RULES, false).await;
+        let (mut client, mut upstream) = setup.connect().await;
+        client.send(b"complete\nnot compl").await;
+        client.0.shutdown().await.unwrap();
+        assert_eq!(upstream.line().await.unwrap(), "complete");
+        // The rest is dropped and the upstream connection closes with the client's
+        assert_eq!(upstream.line().await, None);
+        drop((client, upstream));
+        setup.proxy.stop().await;
+    }
+
+    #[tokio::test]
+    async fn partial_lines_can_be_forwarded() {
+        let setup = Setup::new(RULES, true).await;
+        let (mut client, mut upstream) = setup.connect().await;
+        upstream.send(b"password=x").await;
+        upstream.0.shutdown().await.unwrap();
+        let mut rest = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut client.0, &mut rest).await.unwrap();
+        assert_eq!(rest, "password=***");
+        drop((client, upstream));
+        setup.proxy.stop().await;
+    }
+
+    #[tokio::test]
+    async fn upstream_hanging_up_disconnects_the_client() {
+        let setup = Setup::new("[]", false).await;
+        let (mut client, upstream) = setup.connect().await;
+        drop(upstream);
+        assert_eq!(client.line().await, None);
+        drop(client);
+        setup.proxy.stop().await;
+    }
+}
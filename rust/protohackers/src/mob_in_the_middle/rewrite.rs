@@ -0,0 +1,219 @@
+use std::path::Path;
+use anyhow::{format_err, Result};
+use regex::bytes::Regex;
+use serde::Deserialize;
+
+/// Which way a line is going through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ToUpstream,
+    ToClient,
+}
+
+/// The proxy configuration file, as JSON:
+///
+/// ```json
+/// {
+///     "upstream": "chat.protohackers.com:16963",
+///     "forward_partial_lines": false,
+///     "rules": [
+///         {"pattern": "^7[[:alnum:]]{25,34}$", "replacement": "7YWHMfk9JZe0LM0g1ZauHuiSxhI", "per_word": true},
+///         {"pattern": "secret", "replacement": "******", "direction": "to_client"}
+///     ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct ProxyConfig {
+    pub upstream: String,
+    /// Whether a line cut off by a disconnect still goes through, without a newline
+    #[serde(default)]
+    pub forward_partial_lines: bool,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RuleConfig {
+    pub pattern: String,
+    /// May refer to groups of the pattern, as in `$1`
+    pub replacement: String,
+    /// Both ways if not given
+    pub direction: Option<Direction>,
+    /// Match the pattern against each space separated word on its own, rather than the line
+    #[serde(default)]
+    pub per_word: bool,
+}
+
+impl ProxyConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| format_err!("{}: {}", path.display(), e))
+    }
+
+    /// Where the Mob in the Middle problem wants it: Boguscoin addresses become Tom's.
+    pub fn boguscoin() -> Self {
+        ProxyConfig {
+            upstream: "chat.protohackers.com:16963".into(),
+            forward_partial_lines: false,
+            rules: vec![RuleConfig {
+                pattern: "^7[[:alnum:]]{25,34}$".into(),
+                replacement: "7YWHMfk9JZe0LM0g1ZauHuiSxhI".into(),
+                direction: None,
+                per_word: true,
+            }],
+        }
+    }
+}
+
+struct Rule {
+    regex: Regex,
+    replacement: Vec<u8>,
+    direction: Option<Direction>,
+    per_word: bool,
+}
+
+/// Applies the rules of a configuration in order, each to the output of the one before.
+pub struct Rewriter {
+    rules: Vec<Rule>,
+}
+
+impl Rewriter {
+    pub fn new(rules: &[RuleConfig]) -> Result<Self> {
+        let rules = rules.iter()
+            .map(|rule| Ok(Rule {
+                regex: Regex::new(&rule.pattern)?,
+                replacement: rule.replacement.clone().into_bytes(),
+                direction: rule.direction,
+                per_word: rule.per_word,
+            }))
+            .collect::<Result<_>>()?;
+        Ok(Rewriter { rules })
+    }
+
+    /// Rewrite a line, without its newline, going `direction`. Lines need not be UTF-8, and
+    /// whatever no rule matches comes out byte for byte.
+    pub fn rewrite(&self, line: &[u8], direction: Direction) -> Vec<u8> {
+        let mut line = line.to_vec();
+        for rule in &self.rules {
+            if rule.direction.is_some_and(|d| d != direction) {
+                continue;
+            }
+            line = if rule.per_word {
+                rewrite_words(&line, rule)
+            } else {
+                rule.regex.replace_all(&line, rule.replacement.as_slice()).into_owned()
+            };
+        }
+        line
+    }
+}
+
+/// Apply `rule` to each word of `line`, keeping the spaces in between. Only spaces separate
+/// words, as in the Mob in the Middle problem, where an address followed by a tab or a `\r` is
+/// not an address.
+fn rewrite_words(line: &[u8], rule: &Rule) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    for (i, word) in line.split(|&b| b == b' ').enumerate() {
+        if i > 0 {
+            out.push(b' ');
+        }
+        out.extend_from_slice(&rule.regex.replace_all(word, rule.replacement.as_slice()));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn boguscoin() -> Rewriter {
+        Rewriter::new(&ProxyConfig::boguscoin().rules).unwrap()
+    }
+
+    fn rewrite(rewriter: &Rewriter, line: &str, direction: Direction) -> String {
+        String::from_utf8(rewriter.rewrite(line.as_bytes(), direction)).unwrap()
+    }
+
+    fn is_boguscoin_addr(addr: &str) -> bool {
+        rewrite(&boguscoin(), addr, Direction::ToClient) != addr
+    }
+
+    #[test]
+    fn bogus_coin_works() {
+        assert!(is_boguscoin_addr("7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T"));
+        assert!(is_boguscoin_addr("7LOrwbDlS8NujgjddyogWgIM93MV5N2VR"));
+        assert!(!is_boguscoin_addr("6LOrwbDlS8NujgjddyogWgIM93MV5N2VR"));
+        assert!(!is_boguscoin_addr("7L3MV5N2VR"));
+        assert!(!is_boguscoin_addr("7LOrwbDlS8NujgjddyogWgIM93&MV5N2VR"));
+        assert!(!is_boguscoin_addr("7LOrwbDlS8NujgjddyogWgIM93-MV5N2VRaaaaaaaaaaaaaaaaaaaaaaaaaaaaffffff"));
+    }
+
+    #[test]
+    fn rewrite_msg_works() {
+        assert_eq!(
+            rewrite(&boguscoin(), "[hello] i am a coin 7LOrwbDlS8NujgjddyogWgIM93MV5N2VR", Direction::ToUpstream),
+            "[hello] i am a coin 7YWHMfk9JZe0LM0g1ZauHuiSxhI"
+        );
+        // Each word on its own, side by side and only whole words
+        assert_eq!(
+            rewrite(&boguscoin(), "7F1u3wSD5RbOHQmupo9nx4TnhQ 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX-x", Direction::ToClient),
+            "7YWHMfk9JZe0LM0g1ZauHuiSxhI 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX-x"
+        );
+    }
+
+    #[test]
+    fn words_split_on_spaces_only() {
+        assert_eq!(
+            rewrite(&boguscoin(), " 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX  now 7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T ", Direction::ToUpstream),
+            " 7YWHMfk9JZe0LM0g1ZauHuiSxhI  now 7YWHMfk9JZe0LM0g1ZauHuiSxhI "
+        );
+        let line = "pay\t7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX now 7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T\r";
+        assert_eq!(rewrite(&boguscoin(), line, Direction::ToUpstream), line);
+    }
+
+    #[test]
+    fn bytes_pass_through_untouched() {
+        let line = b"\xff\xfe not utf-8 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX \xc3";
+        assert_eq!(boguscoin().rewrite(line, Direction::ToClient), b"\xff\xfe not utf-8 7YWHMfk9JZe0LM0g1ZauHuiSxhI \xc3");
+        let config: ProxyConfig = serde_json::from_str(r#"{"upstream": "x", "rules": [{"pattern": "nothing", "replacement": "here"}]}"#).unwrap();
+        assert_eq!(Rewriter::new(&config.rules).unwrap().rewrite(&line[..], Direction::ToClient), line);
+    }
+
+    #[test]
+    fn rules_apply_in_order_and_direction() {
+        let config: ProxyConfig = serde_json::from_str(r#"{
+            "upstream": "localhost:1",
+            "rules": [
+                {"pattern": "cat", "replacement": "dog"},
+                {"pattern": "(\\w+)@example\\.com", "replacement": "$1@example.org", "direction": "to_upstream"},
+                {"pattern": "dog", "replacement": "wolf", "direction": "to_client"}
+            ]
+        }"#).unwrap();
+        assert!(!config.forward_partial_lines);
+        let rewriter = Rewriter::new(&config.rules).unwrap();
+        assert_eq!(rewrite(&rewriter, "cat mail@example.com", Direction::ToUpstream), "dog mail@example.org");
+        assert_eq!(rewrite(&rewriter, "cat mail@example.com", Direction::ToClient), "wolf mail@example.com");
+    }
+
+    #[test]
+    fn bad_configs_are_rejected() {
+        assert!(serde_json::from_str::<ProxyConfig>(r#"{"rules": []}"#).is_err());
+        assert!(serde_json::from_str::<ProxyConfig>(r#"{"upstream": "x", "rules": [{"pattern": "a", "replacement": "b", "direction": "sideways"}]}"#).is_err());
+        let config: ProxyConfig = serde_json::from_str(r#"{"upstream": "x", "rules": [{"pattern": "(", "replacement": ""}]}"#).unwrap();
+        assert!(Rewriter::new(&config.rules).is_err());
+    }
+
+    #[test]
+    fn example_config_is_the_default() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_inputs/mob_in_the_middle.json");
+        let config = ProxyConfig::load(&path).unwrap();
+        let default = ProxyConfig::boguscoin();
+        assert_eq!(config.upstream, default.upstream);
+        let line = "send 7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T please";
+        for direction in [Direction::ToUpstream, Direction::ToClient] {
+            assert_eq!(rewrite(&Rewriter::new(&config.rules).unwrap(), line, direction),
+                       rewrite(&Rewriter::new(&default.rules).unwrap(), line, direction));
+        }
+    }
+}
@@ -1,6 +1,9 @@
+use anyhow::Result;
 use tokio::io::{AsyncBufReadExt,AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
+use crate::server::Handler;
 
 #[derive(Deserialize)]
 struct Request {
@@ -14,48 +17,44 @@ struct Response {
     prime: bool,
 }
 
-pub async fn run() {
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:14293").await.unwrap();
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        println!("Received new connection");
+pub struct PrimeTime;
 
-        tokio::spawn(async move {
-            let mut buf_stream = BufStream::new(socket);
-            loop {
-                // let mut buf = Vec::new();
-                let mut buf = String::new();
-                let n = buf_stream.read_line(&mut buf).await.unwrap();
-                // let data_str = String::from_utf8_lossy(&buf[..n]);
-                // let trimmed = data_str.trim();
-                let trimmed = buf.trim();
+impl Handler for PrimeTime {
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut buf_stream = BufStream::new(stream);
+        loop {
+            let mut buf = String::new();
+            let n = buf_stream.read_line(&mut buf).await?;
+            let trimmed = buf.trim();
 
-                if n == 0 {
-                    buf_stream.write_all("malformed".as_bytes()).await.unwrap();
+            if n == 0 {
+                buf_stream.write_all("malformed".as_bytes()).await?;
+                buf_stream.flush().await?;
+                break;
+            }
+            let response = match serde_json::from_str::<Request>(trimmed) {
+                Err(e) => {
+                    tracing::info!("malformed request: {:?}", e);
+                    None
+                }
+                Ok(req) => process(req),
+            };
+            match response {
+                None => {
+                    buf_stream.write_all("malformed".as_bytes()).await?;
+                    buf_stream.flush().await?;
                     break;
                 }
-                match serde_json::from_str::<Request>(&trimmed) {
-                    Err(e) => {
-                        println!("ERROR: {:?}", e);
-                        buf_stream.write_all("malformed".as_bytes()).await.unwrap();
-                        break;
-                    }
-                    Ok(req) => match process(req) {
-                        None => {
-                            buf_stream.write_all("malformed".as_bytes()).await.unwrap();
-                            break;
-                        }
-                        Some(res) => {
-                            let data = serde_json::to_string(&res).unwrap();
-                            println!("response: {}", data);
-                            buf_stream.write_all(data.as_bytes()).await.unwrap();
-                            buf_stream.write_all("\n".as_bytes()).await.unwrap();
-                            buf_stream.flush().await.unwrap();
-                        }
-                    }
+                Some(res) => {
+                    let data = serde_json::to_string(&res)?;
+                    tracing::info!("response: {}", data);
+                    buf_stream.write_all(data.as_bytes()).await?;
+                    buf_stream.write_all("\n".as_bytes()).await?;
+                    buf_stream.flush().await?;
                 }
             }
-        });
+        }
+        Ok(())
     }
 }
 
@@ -96,7 +95,8 @@ fn is_prime(x: i64) -> bool {
 
 #[cfg(test)]
 mod test {
-    use crate::prime_time::is_prime;
+    use crate::prime_time::{is_prime, PrimeTime};
+    use crate::server::testing::TestServer;
 
     #[test]
     fn is_prime_works() {
@@ -114,4 +114,18 @@ mod test {
             assert_eq!(is_prime(case.0), case.1, "is_prime({})", case.0);
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn answers_until_malformed() {
+        let server = TestServer::start(PrimeTime).await;
+        let mut client = server.connect().await;
+        client.send(b"{\"method\":\"isPrime\",\"number\":7}\n").await;
+        assert_eq!(client.line().await.as_deref(), Some(r#"{"method":"isPrime","prime":true}"#));
+        client.send(b"{\"method\":\"isPrime\",\"number\":7.5}\n").await;
+        assert_eq!(client.line().await.as_deref(), Some(r#"{"method":"isPrime","prime":false}"#));
+        client.send(b"{\"method\":\"isComposite\",\"number\":4}\n").await;
+        assert_eq!(client.line().await.as_deref(), Some("malformed"));
+        assert_eq!(client.line().await, None);
+        server.stop().await;
+    }
+}
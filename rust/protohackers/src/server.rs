@@ -0,0 +1,255 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+/// How long to wait before accepting again after accepting failed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What a protocol does with each connection, the server takes care of accepting and spawning.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, stream: TcpStream) -> impl Future<Output = Result<()>> + Send;
+}
+
+pub struct ServerConfig {
+    pub addr: String,
+    /// Connections beyond this wait to be accepted until another one closes
+    pub max_connections: Option<usize>,
+    /// How long open sessions get to finish after a shutdown before they are cut off
+    pub drain_timeout: Duration,
+}
+
+impl ServerConfig {
+    pub fn new(addr: &str) -> Self {
+        ServerConfig {
+            addr: addr.into(),
+            max_connections: None,
+            drain_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Serve `handler` on the configured address until SIGINT.
+pub async fn run<H: Handler>(config: ServerConfig, handler: H) -> Result<()> {
+    let listener = TcpListener::bind(&config.addr).await?;
+    tracing::info!("listening on {}", listener.local_addr()?);
+    serve(listener, &config, handler, shutdown_signal()).await
+}
+
+/// Resolves on SIGINT, for servers that do not go through `run`.
+pub async fn shutdown_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!("cannot listen for SIGINT: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Accept connections on `listener` until `shutdown` resolves, then stop accepting and let the
+/// open sessions drain.
+pub async fn serve<H: Handler>(
+    listener: TcpListener,
+    config: &ServerConfig,
+    handler: H,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let handler = Arc::new(handler);
+    let limit = config.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+    let mut sessions = JoinSet::new();
+    let mut next_id = 0u64;
+    tokio::pin!(shutdown);
+
+    loop {
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit?),
+                _ = &mut shutdown => break,
+            },
+            None => None,
+        };
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    // Running out of file descriptors or a client giving up before it was
+                    // accepted passes, and must not take the sessions already open down with it
+                    Err(e) => {
+                        tracing::error!("cannot accept: {}", e);
+                        tokio::select! {
+                            _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                            _ = &mut shutdown => break,
+                        }
+                    }
+                };
+                let handler = handler.clone();
+                let span = tracing::info_span!("connection", id = next_id, %peer);
+                next_id += 1;
+                sessions.spawn(async move {
+                    tracing::info!("connected");
+                    match handler.handle(socket).await {
+                        Ok(()) => tracing::info!("closed"),
+                        Err(e) => tracing::info!("closed with error: {:?}", e),
+                    }
+                    drop(permit);
+                }.instrument(span));
+            }
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    tracing::info!("shutting down, draining {} sessions", sessions.len());
+    drop(listener);
+    let drained = tokio::time::timeout(config.drain_timeout, async {
+        while sessions.join_next().await.is_some() {}
+    }).await;
+    if drained.is_err() {
+        tracing::info!("cutting off {} sessions", sessions.len());
+        sessions.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Runs a handler in process and connects clients to it.
+#[cfg(test)]
+pub mod testing {
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use super::*;
+
+    pub struct TestServer {
+        pub addr: SocketAddr,
+        shutdown: oneshot::Sender<()>,
+        server: JoinHandle<Result<()>>,
+    }
+
+    impl TestServer {
+        pub async fn start<H: Handler>(handler: H) -> Self {
+            Self::with_config(ServerConfig::new("127.0.0.1:0"), handler).await
+        }
+
+        pub async fn with_config<H: Handler>(config: ServerConfig, handler: H) -> Self {
+            let listener = TcpListener::bind(&config.addr).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (shutdown, signal) = oneshot::channel();
+            let server = tokio::spawn(async move {
+                serve(listener, &config, handler, async { let _ = signal.await; }).await
+            });
+            TestServer { addr, shutdown, server }
+        }
+
+        pub async fn connect(&self) -> Client {
+            Client(BufStream::new(TcpStream::connect(self.addr).await.unwrap()))
+        }
+
+        /// Shut the server down the way SIGINT would and wait for it to drain.
+        pub async fn stop(self) {
+            let _ = self.shutdown.send(());
+            self.server.await.unwrap().unwrap();
+        }
+    }
+
+    pub struct Client(pub BufStream<TcpStream>);
+
+    impl Client {
+        pub async fn send(&mut self, data: &[u8]) {
+            self.0.write_all(data).await.unwrap();
+            self.0.flush().await.unwrap();
+        }
+
+        /// The next line without its newline, `None` once the server closed the connection.
+        pub async fn line(&mut self) -> Option<String> {
+            let mut line = String::new();
+            let n = self.0.read_line(&mut line).await.unwrap();
+            (n > 0).then(|| line.trim_end_matches('\n').to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+    use super::testing::TestServer;
+    use super::*;
+
+    /// Echoes lines back and counts the sessions open at once.
+    #[derive(Default)]
+    struct Echo {
+        open: AtomicUsize,
+        most_open: AtomicUsize,
+    }
+
+    impl Handler for Arc<Echo> {
+        async fn handle(&self, stream: TcpStream) -> Result<()> {
+            let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_open.fetch_max(open, Ordering::SeqCst);
+            let mut stream = BufStream::new(stream);
+            let mut line = String::new();
+            while stream.read_line(&mut line).await? > 0 {
+                stream.write_all(line.as_bytes()).await?;
+                stream.flush().await?;
+                line.clear();
+            }
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_each_connection() {
+        let server = TestServer::start(Arc::new(Echo::default())).await;
+        let mut a = server.connect().await;
+        let mut b = server.connect().await;
+        a.send(b"hello\n").await;
+        b.send(b"world\n").await;
+        assert_eq!(b.line().await.as_deref(), Some("world"));
+        assert_eq!(a.line().await.as_deref(), Some("hello"));
+        drop((a, b));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn limits_connections() {
+        let echo = Arc::new(Echo::default());
+        let mut config = ServerConfig::new("127.0.0.1:0");
+        config.max_connections = Some(1);
+        let server = TestServer::with_config(config, echo.clone()).await;
+
+        let mut a = server.connect().await;
+        a.send(b"a\n").await;
+        assert_eq!(a.line().await.as_deref(), Some("a"));
+        // Connects, but is only served once `a` is gone
+        let mut b = server.connect().await;
+        b.send(b"b\n").await;
+        let waiting = tokio::time::timeout(Duration::from_millis(100), b.line()).await;
+        assert!(waiting.is_err());
+        drop(a);
+        assert_eq!(b.line().await.as_deref(), Some("b"));
+        assert_eq!(echo.most_open.load(Ordering::SeqCst), 1);
+        drop(b);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_then_cuts_off_sessions() {
+        let echo = Arc::new(Echo::default());
+        let mut config = ServerConfig::new("127.0.0.1:0");
+        config.drain_timeout = Duration::from_millis(100);
+        let server = TestServer::with_config(config, echo.clone()).await;
+        let addr = server.addr;
+
+        let mut client = server.connect().await;
+        client.send(b"still here\n").await;
+        assert_eq!(client.line().await.as_deref(), Some("still here"));
+        server.stop().await;
+
+        assert_eq!(client.line().await, None);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -1,14 +1,15 @@
-use tokio::io::{AsyncReadExt, BufReader, BufWriter};
-use tokio::net::tcp::{OwnedReadHalf};
+use tokio::io::BufWriter;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
-use crate::speed_daemon::events::ClientEvt;
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use crate::speed_daemon::codec::{CodecError, Message, SpeedDaemonCodec};
+use crate::speed_daemon::events::{ClientEvt, OutgoingEvt};
 use crate::speed_daemon::server::{ClientData, ClientIdEvt};
 
 pub(crate) async fn handle_client_session(socket: TcpStream, server_tx: UnboundedSender<ClientIdEvt>) -> anyhow::Result<()> {
     let (read, write) = socket.into_split();
-    let mut read_stream = BufReader::new(read);
     let mut write_stream = BufWriter::new(write);
 
     let (tx, mut rx) = mpsc::channel(32);
@@ -17,54 +18,113 @@ pub(crate) async fn handle_client_session(socket: TcpStream, server_tx: Unbounde
     server_tx.send((0, ClientEvt::NewClient(id_tx, tx)))?;
     let client_id = id_rx.recv().await.unwrap();
 
-    let read_handle = tokio::spawn(async move {
-        loop {
-            let n = read_stream.read_u8().await?;
-            let evt = parse_event(n, &mut read_stream).await?;
-            server_tx.send((client_id, evt))?;
+    let reader_tx = server_tx.clone();
+    let mut read_handle = tokio::spawn(async move {
+        let mut messages = FramedRead::new(read, SpeedDaemonCodec);
+        while let Some(message) = messages.next().await {
+            let evt = match message {
+                Ok(message) => client_event(message),
+                Err(CodecError::Io(e)) => return Err(e.into()),
+                Err(e) => ClientEvt::Illegal(format!("illegal msg: {}", e)),
+            };
+            let illegal = matches!(evt, ClientEvt::Illegal(_));
+            reader_tx.send((client_id, evt))?;
+            if illegal {
+                // Nothing after it can be read, the connection closes once the error is out
+                std::future::pending::<()>().await;
+            }
         }
+        Ok(())
     });
 
-    loop {
-        let evt = rx.recv().await;
-        match evt {
-            None => break,
-            Some(evt) => {
-                evt.send(&mut write_stream).await?;
-            }
+    // Runs until either side of the connection is done, then tells the server the client is gone
+    let result = loop {
+        tokio::select! {
+            evt = rx.recv() => match evt {
+                None => break Ok(()),
+                Some(evt) => {
+                    if let Err(e) = evt.send(&mut write_stream).await {
+                        break Err(e);
+                    }
+                    match evt {
+                        OutgoingEvt::Error(_) => break Ok(()),
+                        OutgoingEvt::Ticket(id, _) => server_tx.send((client_id, ClientEvt::TicketSent(id)))?,
+                        OutgoingEvt::Heartbeat => {}
+                    }
+                }
+            },
+            read = &mut read_handle => break read?,
         }
-    }
-    let _: anyhow::Result<()> = read_handle.await?;
-    Ok(())
+    };
+    read_handle.abort();
+    server_tx.send((client_id, ClientEvt::Disconnected))?;
+    result
 }
 
-async fn parse_event(evt_type: u8, stream: &mut BufReader<OwnedReadHalf>) -> anyhow::Result<ClientEvt> {
-    let evt = match evt_type {
-        0x20 => {
-            let len = stream.read_u8().await?;
-            let mut buf = vec![0; len as usize];
-            let _ = stream.read_exact(&mut buf).await?;
-            ClientEvt::Plate(String::from_utf8(buf)?, stream.read_u32().await?)
+/// What a message from the client means to the server.
+fn client_event(message: Message) -> ClientEvt {
+    match message {
+        Message::Plate { plate, timestamp } => ClientEvt::Plate(plate, timestamp),
+        Message::WantHeartbeat { interval } => ClientEvt::WantHeartbeat(interval),
+        Message::IAmCamera { road, mile, limit } => ClientEvt::Register(ClientData::Camera { road, mile, limit }),
+        Message::IAmDispatcher { roads } => ClientEvt::Register(ClientData::Dispatcher(roads)),
+        Message::Error { .. } | Message::Ticket(_) | Message::Heartbeat => {
+            ClientEvt::Illegal("illegal msg: only the server sends that".into())
         }
-        0x40 => {
-            let interval = stream.read_u32().await?;
-            ClientEvt::WantHeartbeat(interval)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+    use crate::server::testing::TestServer;
+    use crate::speed_daemon::codec;
+    use crate::speed_daemon::SpeedDaemon;
+    use super::*;
+
+    async fn replies(input: &[u8]) -> Vec<Message> {
+        let server = TestServer::start(SpeedDaemon::new(None).unwrap()).await;
+        let mut client = server.connect().await;
+        client.send(input).await;
+        let mut output = Vec::new();
+        client.0.read_to_end(&mut output).await.unwrap();
+        let mut messages = Vec::new();
+        let mut rest = output.as_slice();
+        while let Some((message, used)) = codec::decode(rest).unwrap() {
+            messages.push(message);
+            rest = &rest[used..];
         }
-        0x80 => {
-            let road = stream.read_u16().await?;
-            let mile = stream.read_u16().await?;
-            let limit = stream.read_u16().await?;
-            ClientEvt::Register(ClientData::Camera { road, mile, limit })
+        assert!(rest.is_empty());
+        server.stop().await;
+        messages
+    }
+
+    #[tokio::test]
+    async fn illegal_messages_get_an_error_and_close() {
+        let cases: [&[u8]; 4] = [
+            // Unknown type, after a valid camera
+            &[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c, 0x99],
+            // Only the server sends heartbeats
+            &[0x41],
+            &[0x40, 0, 0, 0, 0, 0x40, 0, 0, 0, 0],
+            // A plate from a client that is no camera
+            &[0x20, 0x01, b'A', 0, 0, 0, 0],
+        ];
+        for input in cases {
+            let replies = replies(input).await;
+            assert!(matches!(replies.as_slice(), [Message::Error { .. }]), "{:?} got {:?}", input, replies);
         }
-        0x81 => {
-            let num_roads = stream.read_u8().await?;
-            let mut roads = Vec::with_capacity(num_roads as usize);
-            for _ in 0..num_roads {
-                roads.push(stream.read_u16().await?);
-            }
-            ClientEvt::Register(ClientData::Dispatcher(roads))
+    }
+
+    #[tokio::test]
+    async fn heartbeats_come_at_the_interval() {
+        let server = TestServer::start(SpeedDaemon::new(None).unwrap()).await;
+        let mut client = server.connect().await;
+        client.send(&[0x40, 0, 0, 0, 1]).await;
+        for _ in 0..3 {
+            assert_eq!(client.0.read_u8().await.unwrap(), 0x41);
         }
-        _ => panic!("UNSUPPORTED TYPE: 0x{:X}", evt_type)
-    };
-    Ok(evt)
+        drop(client);
+        server.stop().await;
+    }
 }
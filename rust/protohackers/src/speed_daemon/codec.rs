@@ -0,0 +1,386 @@
+//! Every Speed Daemon message as bytes and back, without any sockets involved.
+//!
+//! `decode` and `encode` work on plain buffers, `SpeedDaemonCodec` puts them behind the
+//! `tokio_util` framing traits.
+
+use std::fmt;
+use std::io;
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use crate::speed_daemon::server::{Mile, Road, Timestamp};
+use crate::speed_daemon::ticket::Ticket;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Error { msg: String },
+    Plate { plate: String, timestamp: Timestamp },
+    Ticket(Ticket),
+    WantHeartbeat { interval: u32 },
+    Heartbeat,
+    IAmCamera { road: Road, mile: Mile, limit: u16 },
+    IAmDispatcher { roads: Vec<Road> },
+}
+
+impl Message {
+    /// An error, cut down to the 255 bytes a str can hold.
+    pub fn error(msg: &str) -> Self {
+        let mut end = msg.len().min(u8::MAX as usize);
+        while !msg.is_char_boundary(end) {
+            end -= 1;
+        }
+        Message::Error { msg: msg[..end].into() }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    UnknownType(u8),
+    /// A str that is not text
+    InvalidString,
+    /// A str or list too long for its one byte length
+    TooLong(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::UnknownType(t) => write!(f, "unknown message type 0x{:02X}", t),
+            CodecError::InvalidString => write!(f, "str is not valid text"),
+            CodecError::TooLong(len) => write!(f, "{} elements do not fit a one byte length", len),
+            CodecError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+/// The first message in `buf` and how many bytes it took, `None` while it is incomplete.
+pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, CodecError> {
+    let mut reader = Reader { buf, pos: 0 };
+    match reader.message() {
+        Ok(message) => Ok(Some((message, reader.pos))),
+        Err(Incomplete::Eof) => Ok(None),
+        Err(Incomplete::Invalid(e)) => Err(e),
+    }
+}
+
+/// Append `message` to `out`, which is left as it was if the message cannot be encoded.
+pub fn encode(message: &Message, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    let start = out.len();
+    let result = write_message(message, out);
+    if result.is_err() {
+        out.truncate(start);
+    }
+    result
+}
+
+fn write_message(message: &Message, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    match message {
+        Message::Error { msg } => {
+            out.push(0x10);
+            write_str(msg, out)?;
+        }
+        Message::Plate { plate, timestamp } => {
+            out.push(0x20);
+            write_str(plate, out)?;
+            out.extend(timestamp.to_be_bytes());
+        }
+        Message::Ticket(ticket) => {
+            out.push(0x21);
+            write_str(&ticket.plate, out)?;
+            out.extend(ticket.road.to_be_bytes());
+            out.extend(ticket.mile1.to_be_bytes());
+            out.extend(ticket.timestamp1.to_be_bytes());
+            out.extend(ticket.mile2.to_be_bytes());
+            out.extend(ticket.timestamp2.to_be_bytes());
+            out.extend(ticket.speed.to_be_bytes());
+        }
+        Message::WantHeartbeat { interval } => {
+            out.push(0x40);
+            out.extend(interval.to_be_bytes());
+        }
+        Message::Heartbeat => out.push(0x41),
+        Message::IAmCamera { road, mile, limit } => {
+            out.push(0x80);
+            out.extend(road.to_be_bytes());
+            out.extend(mile.to_be_bytes());
+            out.extend(limit.to_be_bytes());
+        }
+        Message::IAmDispatcher { roads } => {
+            out.push(0x81);
+            out.push(length(roads.len())?);
+            for road in roads {
+                out.extend(road.to_be_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_str(s: &str, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    out.push(length(s.len())?);
+    out.extend(s.as_bytes());
+    Ok(())
+}
+
+fn length(len: usize) -> Result<u8, CodecError> {
+    u8::try_from(len).map_err(|_| CodecError::TooLong(len))
+}
+
+enum Incomplete {
+    /// The buffer ends before the message does
+    Eof,
+    Invalid(CodecError),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn message(&mut self) -> Result<Message, Incomplete> {
+        let message = match self.u8()? {
+            0x10 => Message::Error { msg: self.str()? },
+            0x20 => Message::Plate { plate: self.str()?, timestamp: self.u32()? },
+            0x21 => Message::Ticket(Ticket {
+                plate: self.str()?,
+                road: self.u16()?,
+                mile1: self.u16()?,
+                timestamp1: self.u32()?,
+                mile2: self.u16()?,
+                timestamp2: self.u32()?,
+                speed: self.u16()?,
+            }),
+            0x40 => Message::WantHeartbeat { interval: self.u32()? },
+            0x41 => Message::Heartbeat,
+            0x80 => Message::IAmCamera { road: self.u16()?, mile: self.u16()?, limit: self.u16()? },
+            0x81 => {
+                let count = self.u8()?;
+                let roads = (0..count).map(|_| self.u16()).collect::<Result<_, _>>()?;
+                Message::IAmDispatcher { roads }
+            }
+            t => return Err(Incomplete::Invalid(CodecError::UnknownType(t))),
+        };
+        Ok(message)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Incomplete> {
+        let bytes = self.buf.get(self.pos..self.pos + N).ok_or(Incomplete::Eof)?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Incomplete> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Incomplete> {
+        Ok(u16::from_be_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Incomplete> {
+        Ok(u32::from_be_bytes(self.bytes()?))
+    }
+
+    fn str(&mut self) -> Result<String, Incomplete> {
+        let len = self.u8()? as usize;
+        let bytes = self.buf.get(self.pos..self.pos + len).ok_or(Incomplete::Eof)?;
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Incomplete::Invalid(CodecError::InvalidString))
+    }
+}
+
+pub struct SpeedDaemonCodec;
+
+impl Decoder for SpeedDaemonCodec {
+    type Item = Message;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        match decode(src)? {
+            Some((message, len)) => {
+                src.advance(len);
+                Ok(Some(message))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Message> for SpeedDaemonCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut out = Vec::new();
+        encode(&message, &mut out)?;
+        dst.extend_from_slice(&out);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn str(&mut self) -> String {
+            let len = self.below(20) as usize;
+            (0..len).map(|_| char::from(b'A' + self.below(26) as u8)).collect()
+        }
+
+        fn message(&mut self) -> Message {
+            match self.below(7) {
+                0 => Message::Error { msg: self.str() },
+                1 => Message::Plate { plate: self.str(), timestamp: self.next() as u32 },
+                2 => Message::Ticket(Ticket {
+                    plate: self.str(),
+                    road: self.next() as u16,
+                    mile1: self.next() as u16,
+                    timestamp1: self.next() as u32,
+                    mile2: self.next() as u16,
+                    timestamp2: self.next() as u32,
+                    speed: self.next() as u16,
+                }),
+                3 => Message::WantHeartbeat { interval: self.next() as u32 },
+                4 => Message::Heartbeat,
+                5 => Message::IAmCamera { road: self.next() as u16, mile: self.next() as u16, limit: self.next() as u16 },
+                _ => Message::IAmDispatcher { roads: (0..self.below(5)).map(|_| self.next() as u16).collect() },
+            }
+        }
+    }
+
+    fn bytes(message: &Message) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(message, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn encodes_spec_examples() {
+        assert_eq!(bytes(&Message::error("bad")), [0x10, 0x03, 0x62, 0x61, 0x64]);
+        assert_eq!(bytes(&Message::Plate { plate: "UN1X".into(), timestamp: 1000 }),
+                   [0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8]);
+        let ticket = Ticket {
+            plate: "UN1X".into(),
+            road: 66,
+            mile1: 100,
+            timestamp1: 123456,
+            mile2: 110,
+            timestamp2: 123816,
+            speed: 10000,
+        };
+        assert_eq!(bytes(&Message::Ticket(ticket)), [
+            0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+            0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+        ]);
+        assert_eq!(bytes(&Message::WantHeartbeat { interval: 1243 }), [0x40, 0x00, 0x00, 0x04, 0xdb]);
+        assert_eq!(bytes(&Message::Heartbeat), [0x41]);
+        assert_eq!(bytes(&Message::IAmCamera { road: 66, mile: 100, limit: 60 }),
+                   [0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c]);
+        assert_eq!(bytes(&Message::IAmDispatcher { roads: vec![66, 368, 5000] }),
+                   [0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88]);
+    }
+
+    #[test]
+    fn rejects_what_does_not_fit() {
+        let mut out = vec![1];
+        let long = "X".repeat(256);
+        assert!(matches!(encode(&Message::Error { msg: long.clone() }, &mut out), Err(CodecError::TooLong(256))));
+        let roads = vec![0; 300];
+        assert!(matches!(encode(&Message::IAmDispatcher { roads }, &mut out), Err(CodecError::TooLong(300))));
+        assert_eq!(out, [1]);
+
+        assert_eq!(Message::error(&long), Message::Error { msg: "X".repeat(255) });
+        // Cut at a character, not in the middle of one
+        let accents = "é".repeat(200);
+        assert_eq!(Message::error(&accents), Message::Error { msg: "é".repeat(127) });
+    }
+
+    #[test]
+    fn rejects_illegal_messages() {
+        assert!(matches!(decode(&[0x42]), Err(CodecError::UnknownType(0x42))));
+        assert!(matches!(decode(&[0x20, 0x02, 0xff, 0xfe, 0, 0, 0, 0]), Err(CodecError::InvalidString)));
+        assert!(matches!(decode(&[]), Ok(None)));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut random = Random(1);
+        for _ in 0..10_000 {
+            let message = random.message();
+            let mut encoded = bytes(&message);
+            let len = encoded.len();
+            // Every prefix is incomplete, and what follows the message is left alone
+            for end in 0..len {
+                assert!(matches!(decode(&encoded[..end]), Ok(None)), "{:?} cut at {}", message, end);
+            }
+            encoded.extend([0x41, 0x99]);
+            let (decoded, used) = decode(&encoded).unwrap().unwrap();
+            assert_eq!((decoded, used), (message, len));
+        }
+    }
+
+    #[test]
+    fn framing_over_split_input() {
+        let mut random = Random(2);
+        let messages = (0..1000).map(|_| random.message()).collect::<Vec<_>>();
+        let mut stream = Vec::new();
+        for message in &messages {
+            SpeedDaemonCodec.encode(message.clone(), &mut BytesMut::new()).unwrap();
+            encode(message, &mut stream).unwrap();
+        }
+
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        let mut rest = stream.as_slice();
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at((random.below(16) as usize).min(rest.len()));
+            rest = tail;
+            buf.extend_from_slice(chunk);
+            while let Some(message) = SpeedDaemonCodec.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert!(buf.is_empty());
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn fuzz_decode() {
+        let mut random = Random(3);
+        for _ in 0..100_000 {
+            let len = random.below(24) as usize;
+            // Mostly valid message types, to get past the first byte
+            let types = [0x10, 0x20, 0x21, 0x40, 0x41, 0x80, 0x81];
+            let mut input = (0..len).map(|_| random.next() as u8).collect::<Vec<_>>();
+            if len > 0 && random.below(4) > 0 {
+                input[0] = types[random.below(types.len() as u64) as usize];
+            }
+            // Whatever decodes encodes back to the same bytes
+            if let Ok(Some((message, used))) = decode(&input) {
+                assert_eq!(bytes(&message), &input[..used], "{:?}", input);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::speed_daemon::server::TicketId;
+use crate::speed_daemon::ticket::Ticket;
+
+/// Append-only record of every ticket issued and every ticket a dispatcher wrote out, one JSON
+/// object per line, so a restarted server knows which tickets still need delivering.
+pub struct Journal {
+    file: File,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Issued(TicketId, Ticket),
+    Delivered(TicketId),
+}
+
+/// What a journal held when it was opened.
+#[derive(Default)]
+pub struct Replay {
+    /// All tickets ever issued, delivered or not
+    pub issued: Vec<Ticket>,
+    /// Tickets still to deliver, oldest first
+    pub undelivered: Vec<(TicketId, Ticket)>,
+    pub next_id: TicketId,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<(Journal, Replay)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        // A crash can cut the last line short, it is skipped and the next entry starts afresh
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+
+        let mut undelivered = BTreeMap::new();
+        let mut replay = Replay::default();
+        for line in contents.lines() {
+            match serde_json::from_str(line) {
+                Ok(Entry::Issued(id, ticket)) => {
+                    replay.issued.push(ticket.clone());
+                    undelivered.insert(id, ticket);
+                    replay.next_id = replay.next_id.max(id + 1);
+                }
+                Ok(Entry::Delivered(id)) => {
+                    undelivered.remove(&id);
+                }
+                Err(e) => tracing::error!("skipping journal entry {:?}: {}", line, e),
+            }
+        }
+        replay.undelivered = undelivered.into_iter().collect();
+        Ok((Journal { file }, replay))
+    }
+
+    pub fn issued(&mut self, id: TicketId, ticket: &Ticket) -> Result<()> {
+        self.append(&Entry::Issued(id, ticket.clone()))
+    }
+
+    pub fn delivered(&mut self, id: TicketId) -> Result<()> {
+        self.append(&Entry::Delivered(id))
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
@@ -1,11 +1,17 @@
 mod client_session;
+mod codec;
 mod server;
 mod events;
+mod journal;
 mod ticket;
+mod tracker;
 
+use std::path::Path;
 use anyhow::Result;
-use tokio::net::ToSocketAddrs;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use crate::server::Handler;
+use crate::speed_daemon::server::ClientIdEvt;
 
 /// General idea
 /// - Each socket is managed by its own task.
@@ -15,22 +21,24 @@ use tokio::sync::mpsc;
 ///
 /// Constraints:
 /// - Build without a mutex, no shared state, only message passing
-pub async fn run<A: ToSocketAddrs>(addr: A) -> Result<()> {
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    let (server_tx, server_rx) = mpsc::unbounded_channel();
-    tokio::spawn(server::run_server(server_rx));
-    tracing::info!("Starting speed-daemon");
+pub struct SpeedDaemon {
+    server_tx: mpsc::UnboundedSender<ClientIdEvt>,
+}
+
+impl SpeedDaemon {
+    /// Must be created inside the runtime, it starts the worker. With a `journal`, tickets
+    /// survive a restart.
+    pub fn new(journal: Option<&Path>) -> Result<Self> {
+        let server = server::Server::new(journal)?;
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        tokio::spawn(server::run_server(server, server_rx));
+        tracing::info!("Starting speed-daemon");
+        Ok(SpeedDaemon { server_tx })
+    }
+}
 
-    let mut client_id = 0;
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        client_id += 1;
-        tracing::info!("Received new connection: {:?}", addr);
-        let tx = server_tx.clone();
-        let id = client_id;
-        tokio::spawn(async move {
-            let _ = client_session::handle_client_session(socket, tx).await;
-            tracing::info!("Client connection closed: {}", id)
-        });
+impl Handler for SpeedDaemon {
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        client_session::handle_client_session(stream, self.server_tx.clone()).await
     }
 }
@@ -1,34 +1,32 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::path::Path;
 use crate::speed_daemon::events::OutgoingEvt;
 use tokio::sync::mpsc::{Sender, UnboundedReceiver};
 use tokio::time;
 use crate::speed_daemon::events::ClientEvt;
+use crate::speed_daemon::journal::Journal;
 use crate::speed_daemon::ticket::Ticket;
+use crate::speed_daemon::tracker::PlateTracker;
 
-pub async fn run_server(mut server_rx: UnboundedReceiver<ClientIdEvt>) -> anyhow::Result<()> {
-    let mut server = Server::new();
-    loop {
-        let evt = server_rx.recv().await;
-        if evt.is_none() {
-            break;
-        }
-        let (client_id, evt) = evt.unwrap();
+pub async fn run_server(mut server: Server, mut server_rx: UnboundedReceiver<ClientIdEvt>) {
+    while let Some((client_id, evt)) = server_rx.recv().await {
         match evt {
             ClientEvt::NewClient(id_tx, client_tx) => {
                 let id = server.register(client_tx);
                 tracing::info!("New client registered: {}", id);
-                id_tx.send(id).await?;
-            }
-            ClientEvt::Plate(plate, timestamp) => {
-                server.record_plate(client_id, plate, timestamp).await?;
+                let _ = id_tx.send(id).await;
             }
+            ClientEvt::Plate(plate, timestamp) => server.record_plate(client_id, plate, timestamp),
             ClientEvt::WantHeartbeat(interval) => {
+                let Some(tx) = server.want_heartbeat(client_id) else {
+                    continue;
+                };
                 if interval == 0 {
                     continue;
                 }
-                let tx = server.client_tx(client_id).unwrap().clone();
                 tokio::spawn(async move {
-                    let mut interval = time::interval(time::Duration::from_millis((interval * 100) as u64));
+                    let mut interval = time::interval(time::Duration::from_millis(interval as u64 * 100));
                     loop {
                         interval.tick().await;
                         if tx.send(OutgoingEvt::Heartbeat).await.is_err() {
@@ -37,16 +35,12 @@ pub async fn run_server(mut server_rx: UnboundedReceiver<ClientIdEvt>) -> anyhow
                     }
                 });
             }
-            ClientEvt::Register(data) => server.identify_client(client_id, data).await?,
-            ClientEvt::PrintTickets => {
-                tracing::info!("Pending tickts: ");
-                for t in &server.pending_tickets {
-                    tracing::info!("{:?}", t);
-                }
-            }
+            ClientEvt::Register(data) => server.identify_client(client_id, data),
+            ClientEvt::TicketSent(ticket_id) => server.ticket_sent(client_id, ticket_id),
+            ClientEvt::Illegal(err) => server.client_err(client_id, err),
+            ClientEvt::Disconnected => server.remove_client(client_id),
         }
     }
-    Ok(())
 }
 
 pub type ClientIdEvt = (ClientId, ClientEvt);
@@ -55,6 +49,7 @@ pub type Mile = u16;
 pub type Timestamp = u32;
 
 pub type ClientId = u32;
+pub type TicketId = u64;
 
 #[derive(Debug)]
 pub enum ClientData {
@@ -69,159 +64,446 @@ pub enum ClientData {
 struct Client {
     tx: Sender<OutgoingEvt>,
     data: Option<ClientData>,
+    wants_heartbeat: bool,
 }
 
-struct Server {
+/// Every ticket is either waiting for a dispatcher or in flight to one until its session says it
+/// was written out. Tickets in flight to a dispatcher that goes away are queued again.
+pub struct Server {
     next_id: ClientId,
     clients: HashMap<ClientId, Client>,
     tracker: PlateTracker,
-    pending_tickets: Vec<Ticket>,
+    /// Dispatchers of each road, in the order they take turns
+    dispatchers: HashMap<Road, Vec<ClientId>>,
+    next_dispatcher: HashMap<Road, usize>,
+    next_ticket_id: TicketId,
+    pending_tickets: VecDeque<(TicketId, Ticket)>,
+    in_flight: HashMap<TicketId, (ClientId, Ticket)>,
+    journal: Option<Journal>,
 }
 
 impl Server {
-    fn new() -> Self {
-        let s = Self {
+    /// With a `journal`, tickets that were not delivered before a restart are delivered after it.
+    pub fn new(journal: Option<&Path>) -> anyhow::Result<Self> {
+        let mut server = Self {
             next_id: 0,
             clients: HashMap::new(),
             tracker: PlateTracker::new(),
-            pending_tickets: Vec::new(),
+            dispatchers: HashMap::new(),
+            next_dispatcher: HashMap::new(),
+            next_ticket_id: 0,
+            pending_tickets: VecDeque::new(),
+            in_flight: HashMap::new(),
+            journal: None,
         };
-        s
+        if let Some(path) = journal {
+            let (journal, replay) = Journal::open(path)?;
+            tracing::info!("{} tickets in the journal, {} undelivered", replay.issued.len(), replay.undelivered.len());
+            for ticket in &replay.issued {
+                server.tracker.restore(ticket);
+            }
+            server.next_ticket_id = replay.next_id;
+            server.pending_tickets.extend(replay.undelivered);
+            server.journal = Some(journal);
+        }
+        Ok(server)
     }
 
     fn register(&mut self, client_tx: Sender<OutgoingEvt>) -> ClientId {
         let id = self.next_id;
         self.next_id += 1;
-        self.clients.insert(id, Client { tx: client_tx, data: None });
+        self.clients.insert(id, Client { tx: client_tx, data: None, wants_heartbeat: false });
         id
     }
 
-    fn client_tx(&self, client_id: ClientId) -> Option<&Sender<OutgoingEvt>> {
-        self.clients.get(&client_id).map(|c| &c.tx)
+    /// Where to send the heartbeats to, unless the client already asked for them.
+    fn want_heartbeat(&mut self, client_id: ClientId) -> Option<Sender<OutgoingEvt>> {
+        let client = self.clients.get_mut(&client_id)?;
+        if client.wants_heartbeat {
+            self.client_err(client_id, "already sent WantHeartbeat".into());
+            return None;
+        }
+        client.wants_heartbeat = true;
+        Some(client.tx.clone())
     }
 
-    async fn identify_client(&mut self, client_id: ClientId, data: ClientData) -> anyhow::Result<()> {
-        let client = self.clients.get_mut(&client_id).unwrap();
+    fn identify_client(&mut self, client_id: ClientId, data: ClientData) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
         if client.data.is_some() {
-            self.client_err(client_id, "already registered".into()).await?;
-        } else {
-            client.data = Some(data);
-            if let Some(ClientData::Dispatcher(roads)) = &client.data {
-                let mut i = 0;
-                while i < self.pending_tickets.len() {
-                    let ticket = &self.pending_tickets[i];
-                    if roads.iter().any(|r| *r == ticket.road) {
-                        let ticket = self.pending_tickets.swap_remove(i);
-                        client.tx.send(OutgoingEvt::Ticket(ticket)).await?;
-                    } else {
-                        i += 1;
-                    }
-                }
+            self.client_err(client_id, "already registered".into());
+            return;
+        }
+        if let ClientData::Dispatcher(roads) = &data {
+            for road in roads {
+                self.dispatchers.entry(*road).or_default().push(client_id);
             }
         }
-        Ok(())
+        let is_dispatcher = matches!(data, ClientData::Dispatcher(_));
+        client.data = Some(data);
+        if is_dispatcher {
+            self.retry_pending();
+        }
     }
 
-    async fn record_plate(&mut self, client_id: ClientId, plate: String, timestamp: Timestamp) -> anyhow::Result<()> {
-        let client = self.clients.get(&client_id).unwrap();
+    fn record_plate(&mut self, client_id: ClientId, plate: String, timestamp: Timestamp) {
+        let Some(client) = self.clients.get(&client_id) else {
+            return;
+        };
         if let Some(ClientData::Camera { road, mile, limit }) = client.data {
-            for t in self.tracker.record_plate(plate, road, mile, timestamp, limit) {
-                self.send_ticket(t).await?;
+            for ticket in self.tracker.record_plate(plate, road, mile, timestamp, limit) {
+                let id = self.next_ticket_id;
+                self.next_ticket_id += 1;
+                if let Some(journal) = &mut self.journal {
+                    if let Err(e) = journal.issued(id, &ticket) {
+                        tracing::error!("cannot journal ticket {}: {:?}", id, e);
+                    }
+                }
+                self.send_ticket(id, ticket);
             }
         } else {
-            self.client_err(client_id, "client not identified as camera".into()).await?;
+            self.client_err(client_id, "client not identified as camera".into());
         }
-        Ok(())
     }
 
-    async fn client_err(&mut self, client_id: ClientId, err: String) -> anyhow::Result<()> {
-        let client = self.clients.get(&client_id).unwrap();
-        client.tx.send(OutgoingEvt::Error(err)).await?;
-        self.clients.remove(&client_id);
-        Ok(())
+    fn ticket_sent(&mut self, client_id: ClientId, ticket_id: TicketId) {
+        if self.in_flight.get(&ticket_id).is_some_and(|(c, _)| *c == client_id) {
+            self.in_flight.remove(&ticket_id);
+            if let Some(journal) = &mut self.journal {
+                if let Err(e) = journal.delivered(ticket_id) {
+                    tracing::error!("cannot journal delivery of ticket {}: {:?}", ticket_id, e);
+                }
+            }
+        }
+        // The dispatcher has room again for tickets that found it busy
+        self.retry_pending();
     }
 
-    async fn send_ticket(&mut self, ticket: Ticket) -> anyhow::Result<()> {
-        // tracing::info!("Sending ticket: {:?}", ticket);
+    /// Error out the client, the session closes the connection once the error is written.
+    fn client_err(&mut self, client_id: ClientId, err: String) {
+        if let Some(client) = self.clients.get(&client_id) {
+            let _ = client.tx.try_send(OutgoingEvt::Error(err));
+        }
+        self.remove_client(client_id);
+    }
 
-        for c in self.clients.values() {
-            if let Some(ClientData::Dispatcher(roads)) = &c.data {
-                if roads.iter().any(|r| *r == ticket.road) {
-                    c.tx.send(OutgoingEvt::Ticket(ticket)).await?;
-                    return Ok(());
+    fn remove_client(&mut self, client_id: ClientId) {
+        let Some(client) = self.clients.remove(&client_id) else {
+            return;
+        };
+        tracing::info!("Client removed: {}", client_id);
+        if let Some(ClientData::Dispatcher(roads)) = client.data {
+            for road in roads {
+                if let Some(dispatchers) = self.dispatchers.get_mut(&road) {
+                    dispatchers.retain(|d| *d != client_id);
                 }
             }
+            let mut lost = self.in_flight.iter()
+                .filter(|(_, (c, _))| *c == client_id)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            lost.sort();
+            for id in lost {
+                let (_, ticket) = self.in_flight.remove(&id).unwrap();
+                tracing::info!("Requeuing ticket {}", id);
+                self.send_ticket(id, ticket);
+            }
         }
-        self.pending_tickets.push(ticket);
-        Ok(())
     }
-}
 
-#[derive(Default)]
-struct PlateData {
-    tickets: Vec<u32>,
-    data: HashMap<Road, Vec<(Mile, Timestamp)>>,
-}
+    /// Hand the ticket to the next dispatcher of its road that can take it, or keep it for later.
+    fn send_ticket(&mut self, id: TicketId, ticket: Ticket) {
+        let dispatchers = self.dispatchers.get(&ticket.road).map(|d| d.as_slice()).unwrap_or_default();
+        let next = self.next_dispatcher.entry(ticket.road).or_default();
+        for i in 0..dispatchers.len() {
+            let client_id = dispatchers[(*next + i) % dispatchers.len()];
+            let tx = &self.clients[&client_id].tx;
+            if tx.try_send(OutgoingEvt::Ticket(id, ticket.clone())).is_ok() {
+                *next = (*next + i + 1) % dispatchers.len();
+                self.in_flight.insert(id, (client_id, ticket));
+                return;
+            }
+        }
+        self.pending_tickets.push_back((id, ticket));
+    }
 
-struct PlateTracker {
-    data: HashMap<String, PlateData>,
+    fn retry_pending(&mut self) {
+        for (id, ticket) in mem::take(&mut self.pending_tickets) {
+            self.send_ticket(id, ticket);
+        }
+    }
 }
 
-impl PlateTracker {
-    fn new() -> Self {
-        Self {
-            data: HashMap::new(),
-        }
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc::{self, Receiver, UnboundedSender};
+    use super::*;
+
+    /// Drives a `Server` through the events client sessions would send it.
+    struct Scenario {
+        server_tx: UnboundedSender<ClientIdEvt>,
+        /// Every client's outgoing events, kept open so the server can always write to them
+        clients: HashMap<ClientId, Receiver<OutgoingEvt>>,
     }
 
-    fn record_plate(&mut self, plate: String, road: Road, mile: Mile, timestamp: Timestamp, limit: u16) -> Vec<Ticket> {
-        let mut results = Vec::new();
-        let plate_data = self.data.entry(plate.clone()).or_insert_with(PlateData::default);
-        let road_data = plate_data.data.entry(road).or_insert_with(Vec::default);
-        road_data.push((mile, timestamp));
-        road_data.sort_by_key(|t| t.1);
+    impl Scenario {
+        fn new() -> Self {
+            Self::with_journal(None)
+        }
+
+        fn with_journal(journal: Option<&Path>) -> Self {
+            let (server_tx, server_rx) = mpsc::unbounded_channel();
+            tokio::spawn(run_server(Server::new(journal).unwrap(), server_rx));
+            Scenario { server_tx, clients: HashMap::new() }
+        }
 
-        tracing::info!("Recording data: {:?}", road_data);
-        if road_data.len() < 2 {
-            return results;
+        async fn connect(&mut self) -> ClientId {
+            let (tx, rx) = mpsc::channel(32);
+            let (id_tx, mut id_rx) = mpsc::channel(1);
+            self.server_tx.send((0, ClientEvt::NewClient(id_tx, tx))).unwrap();
+            let id = id_rx.recv().await.unwrap();
+            self.clients.insert(id, rx);
+            id
         }
 
-        for i in 0..road_data.len() - 1 {
-            let pent = road_data[i];
-            let last = road_data[i + 1];
+        async fn camera(&mut self, road: Road, mile: Mile, limit: u16) -> ClientId {
+            let id = self.connect().await;
+            self.server_tx.send((id, ClientEvt::Register(ClientData::Camera { road, mile, limit }))).unwrap();
+            id
+        }
 
-            let dist = last.0.abs_diff(pent.0) as f64;
-            let time = (last.1 - pent.1) as f64;
-            let m_per_s = dist / time;
-            let m_per_h = m_per_s * 60.0 * 60.0;
-            if m_per_h as u16 > limit {
-                let day_1 = pent.1 / 86400;
-                let day_2 = last.1 / 86400;
+        async fn dispatcher(&mut self, roads: &[Road]) -> ClientId {
+            let id = self.connect().await;
+            self.server_tx.send((id, ClientEvt::Register(ClientData::Dispatcher(roads.to_vec())))).unwrap();
+            id
+        }
 
-                if plate_data.tickets.iter().any(|t| *t == day_1 || *t == day_2) {
-                    tracing::info!("ticket already issued for day");
-                    continue;
-                }
+        fn plate(&self, camera: ClientId, plate: &str, timestamp: Timestamp) {
+            self.server_tx.send((camera, ClientEvt::Plate(plate.into(), timestamp))).unwrap();
+        }
+
+        fn disconnect(&mut self, client: ClientId) {
+            self.clients.remove(&client);
+            self.server_tx.send((client, ClientEvt::Disconnected)).unwrap();
+        }
+
+        /// The tickets `dispatcher` got and wrote out, once the server handled everything sent
+        /// before.
+        async fn tickets(&mut self, dispatcher: ClientId) -> Vec<Ticket> {
+            let tickets = self.received(dispatcher).await;
+            for (id, _) in &tickets {
+                self.server_tx.send((dispatcher, ClientEvt::TicketSent(*id))).unwrap();
+            }
+            tickets.into_iter().map(|(_, ticket)| ticket).collect()
+        }
 
-                plate_data.tickets.push(day_1);
-                if day_1 != day_2 {
-                    plate_data.tickets.push(day_2);
+        /// The tickets `dispatcher` got but has not written out yet.
+        async fn received(&mut self, dispatcher: ClientId) -> Vec<(TicketId, Ticket)> {
+            // Events are handled in order, so a new client getting its id means the rest are done
+            self.connect().await;
+            let rx = self.clients.get_mut(&dispatcher).unwrap();
+            let mut tickets = Vec::new();
+            while let Ok(evt) = rx.try_recv() {
+                match evt {
+                    OutgoingEvt::Ticket(id, ticket) => tickets.push((id, ticket)),
+                    evt => panic!("dispatcher got {:?}", evt),
                 }
-                let ticket = Ticket {
-                    plate: plate.clone(),
-                    road,
-                    mile1: pent.0,
-                    timestamp1: pent.1,
-                    mile2: last.0,
-                    timestamp2: last.1,
-                    speed: (m_per_h * 100.0) as u16,
-                    day_1,
-                    day_2,
-                };
-                tracing::info!("TICKET {:?}", ticket);
-                results.push(ticket);
             }
+            tickets
         }
-        results
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("speed-daemon-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn ticket(plate: &str, road: Road, first: (Mile, Timestamp), second: (Mile, Timestamp), speed: u16) -> Ticket {
+        Ticket {
+            plate: plate.into(),
+            road,
+            mile1: first.0,
+            timestamp1: first.1,
+            mile2: second.0,
+            timestamp2: second.1,
+            speed,
+        }
+    }
+
+    #[tokio::test]
+    async fn example_session() {
+        let mut s = Scenario::new();
+        let first = s.camera(123, 8, 60).await;
+        let second = s.camera(123, 9, 60).await;
+        let dispatcher = s.dispatcher(&[123]).await;
+        s.plate(first, "UN1X", 0);
+        s.plate(second, "UN1X", 45);
+        assert_eq!(s.tickets(dispatcher).await, vec![ticket("UN1X", 123, (8, 0), (9, 45), 8000)]);
+    }
+
+    #[tokio::test]
+    async fn observations_out_of_order() {
+        let mut s = Scenario::new();
+        let cameras = [s.camera(1, 0, 60).await, s.camera(1, 10, 60).await, s.camera(1, 60, 60).await];
+        let dispatcher = s.dispatcher(&[1]).await;
+        s.plate(cameras[2], "CAR", 3600);
+        s.plate(cameras[0], "CAR", 0);
+        assert_eq!(s.tickets(dispatcher).await, vec![]);
+        // Splits an hour at 60 mph into 20 mph and then 100 mph
+        s.plate(cameras[1], "CAR", 1800);
+        assert_eq!(s.tickets(dispatcher).await, vec![ticket("CAR", 1, (10, 1800), (60, 3600), 10000)]);
+        // Neither the old pairs nor a repeated sighting bring it up again
+        s.plate(cameras[1], "CAR", 1800);
+        s.plate(cameras[0], "CAR", 7200);
+        assert_eq!(s.tickets(dispatcher).await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn one_ticket_per_day_across_multi_day_tickets() {
+        let mut s = Scenario::new();
+        let road_1 = [s.camera(1, 0, 100).await, s.camera(1, 60000, 100).await];
+        let road_2 = [s.camera(2, 0, 100).await, s.camera(2, 1000, 100).await];
+        let dispatcher = s.dispatcher(&[1, 2]).await;
+
+        // Covers days 0, 1 and 2
+        s.plate(road_1[0], "CAR", 100);
+        s.plate(road_1[1], "CAR", 2 * 86400 + 100);
+        assert_eq!(s.tickets(dispatcher).await.len(), 1);
+
+        // Day 1 is covered even though neither observation fell on it
+        s.plate(road_2[0], "CAR", 86400 + 100);
+        s.plate(road_2[1], "CAR", 86400 + 200);
+        assert_eq!(s.tickets(dispatcher).await, vec![]);
+
+        s.plate(road_2[0], "CAR", 3 * 86400 + 100);
+        s.plate(road_2[1], "CAR", 3 * 86400 + 200);
+        assert_eq!(s.tickets(dispatcher).await, vec![ticket("CAR", 2, (0, 3 * 86400 + 100), (1000, 3 * 86400 + 200), 65535)]);
+
+        // Other cars are not affected
+        s.plate(road_2[0], "OTHER", 86400 + 100);
+        s.plate(road_2[1], "OTHER", 86400 + 200);
+        assert_eq!(s.tickets(dispatcher).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tickets_wait_for_a_dispatcher() {
+        let mut s = Scenario::new();
+        let cameras = [s.camera(5, 0, 50).await, s.camera(5, 1, 50).await];
+        let elsewhere = s.dispatcher(&[6]).await;
+        s.plate(cameras[0], "CAR", 1000);
+        s.plate(cameras[1], "CAR", 1060);
+        assert_eq!(s.tickets(elsewhere).await, vec![]);
+        let dispatcher = s.dispatcher(&[4, 5]).await;
+        assert_eq!(s.tickets(dispatcher).await, vec![ticket("CAR", 5, (0, 1000), (1, 1060), 6000)]);
+    }
+
+    #[tokio::test]
+    async fn dispatchers_take_turns() {
+        let mut s = Scenario::new();
+        let cameras = [s.camera(7, 0, 50).await, s.camera(7, 1, 50).await];
+        let dispatchers = [s.dispatcher(&[7]).await, s.dispatcher(&[3, 7]).await];
+        for plate in ["A", "B", "C"] {
+            s.plate(cameras[0], plate, 0);
+            s.plate(cameras[1], plate, 60);
+        }
+        let plates = |tickets: Vec<Ticket>| tickets.into_iter().map(|t| t.plate).collect::<Vec<_>>();
+        assert_eq!(plates(s.tickets(dispatchers[0]).await), ["A", "C"]);
+        assert_eq!(plates(s.tickets(dispatchers[1]).await), ["B"]);
+    }
+
+    #[tokio::test]
+    async fn tickets_lost_with_a_dispatcher_go_to_another() {
+        let mut s = Scenario::new();
+        let cameras = [s.camera(7, 0, 50).await, s.camera(7, 1, 50).await];
+        let first = s.dispatcher(&[7]).await;
+        s.plate(cameras[0], "A", 0);
+        s.plate(cameras[1], "A", 60);
+        s.plate(cameras[0], "B", 0);
+        s.plate(cameras[1], "B", 60);
+        // Writes out A, but goes away before B
+        let received = s.received(first).await;
+        assert_eq!(received.len(), 2);
+        s.server_tx.send((first, ClientEvt::TicketSent(received[0].0))).unwrap();
+        s.disconnect(first);
+
+        let second = s.dispatcher(&[7]).await;
+        let tickets = s.tickets(second).await;
+        assert_eq!(tickets, vec![received[1].1.clone()]);
+        assert_eq!(tickets[0].plate, "B");
+    }
+
+    #[tokio::test]
+    async fn disconnected_dispatchers_get_no_tickets() {
+        let mut s = Scenario::new();
+        let cameras = [s.camera(7, 0, 50).await, s.camera(7, 1, 50).await];
+        let gone = s.dispatcher(&[7]).await;
+        s.disconnect(gone);
+        s.plate(cameras[0], "A", 0);
+        s.plate(cameras[1], "A", 60);
+        let dispatcher = s.dispatcher(&[7]).await;
+        assert_eq!(s.tickets(dispatcher).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_remove_the_client() {
+        let mut s = Scenario::new();
+        let camera = s.camera(7, 0, 50).await;
+        s.server_tx.send((camera, ClientEvt::Register(ClientData::Dispatcher(vec![7])))).unwrap();
+        s.plate(camera, "A", 0);
+        s.connect().await;
+        let rx = s.clients.get_mut(&camera).unwrap();
+        assert!(matches!(rx.try_recv(), Ok(OutgoingEvt::Error(_))));
+        // Nothing more once the error is out, the server dropped its end
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn any_heartbeat_interval_is_fine() {
+        let mut s = Scenario::new();
+        let client = s.connect().await;
+        s.server_tx.send((client, ClientEvt::WantHeartbeat(u32::MAX))).unwrap();
+        // The first tick is right away, the next one in over thirteen years
+        let rx = s.clients.get_mut(&client).unwrap();
+        assert!(matches!(rx.recv().await, Some(OutgoingEvt::Heartbeat)));
+        // and the server is still there
+        s.connect().await;
+    }
+
+    #[tokio::test]
+    async fn journal_keeps_tickets_across_restarts() {
+        let journal = temp_path("journal");
+        {
+            let mut s = Scenario::with_journal(Some(&journal));
+            let cameras = [s.camera(7, 0, 50).await, s.camera(7, 1, 50).await];
+            let dispatcher = s.dispatcher(&[7]).await;
+            s.plate(cameras[0], "A", 0);
+            s.plate(cameras[1], "A", 60);
+            assert_eq!(s.tickets(dispatcher).await.len(), 1);
+            s.disconnect(dispatcher);
+            s.plate(cameras[0], "B", 0);
+            s.plate(cameras[1], "B", 60);
+            s.connect().await;
+        }
+
+        let mut s = Scenario::with_journal(Some(&journal));
+        let dispatcher = s.dispatcher(&[7]).await;
+        let tickets = s.tickets(dispatcher).await;
+        assert_eq!(tickets, vec![ticket("B", 7, (0, 0), (1, 60), 6000)]);
+        // A already has a ticket for day 0
+        let cameras = [s.camera(7, 0, 50).await, s.camera(7, 1, 50).await];
+        s.plate(cameras[0], "A", 1000);
+        s.plate(cameras[1], "A", 1060);
+        assert_eq!(s.tickets(dispatcher).await, vec![]);
+        drop(s);
+
+        // Only B was delivered since, nothing is left
+        let mut s = Scenario::with_journal(Some(&journal));
+        let dispatcher = s.dispatcher(&[7]).await;
+        assert_eq!(s.tickets(dispatcher).await, vec![]);
+        fs::remove_file(&journal).unwrap();
     }
 }
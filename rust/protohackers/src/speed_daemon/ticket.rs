@@ -1,6 +1,7 @@
+use serde::{Deserialize, Serialize};
 use crate::speed_daemon::server::{Mile, Road};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Ticket {
     pub plate: String,
     pub road: Road,
@@ -9,6 +10,4 @@ pub struct Ticket {
     pub mile2: Mile,
     pub timestamp2: u32,
     pub speed: u16,
-    pub day_1: u32,
-    pub day_2: u32,
 }
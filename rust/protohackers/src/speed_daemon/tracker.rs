@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound::{Excluded, Unbounded};
+use crate::speed_daemon::server::{Mile, Road, Timestamp};
+use crate::speed_daemon::ticket::Ticket;
+
+const SECONDS_PER_DAY: Timestamp = 86400;
+
+#[derive(Default)]
+struct PlateData {
+    /// Days the plate already has a ticket for
+    ticketed_days: HashSet<u32>,
+    /// Where the plate was seen on each road, by time
+    roads: HashMap<Road, BTreeMap<Timestamp, Mile>>,
+}
+
+/// Turns plate observations into tickets.
+///
+/// Observations can come in any order, so each one is only compared to its neighbours in time on
+/// the same road, the pairs it actually splits or extends. Every other pair was already checked
+/// when it became adjacent.
+pub struct PlateTracker {
+    data: HashMap<String, PlateData>,
+}
+
+impl PlateTracker {
+    pub fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+        }
+    }
+
+    /// Remember a ticket issued before a restart, so its days are not ticketed again.
+    pub fn restore(&mut self, ticket: &Ticket) {
+        let days = ticket.timestamp1 / SECONDS_PER_DAY..=ticket.timestamp2 / SECONDS_PER_DAY;
+        self.data.entry(ticket.plate.clone()).or_default().ticketed_days.extend(days);
+    }
+
+    pub fn record_plate(&mut self, plate: String, road: Road, mile: Mile, timestamp: Timestamp, limit: u16) -> Vec<Ticket> {
+        let plate_data = self.data.entry(plate.clone()).or_default();
+        let observations = plate_data.roads.entry(road).or_default();
+        if observations.contains_key(&timestamp) {
+            // A car cannot be in two places at once, the first sighting stands
+            tracing::info!("duplicate observation of {} at {}", plate, timestamp);
+            return Vec::new();
+        }
+        observations.insert(timestamp, mile);
+
+        let before = observations.range(..timestamp).next_back().map(|(t, m)| (*m, *t));
+        let after = observations.range((Excluded(timestamp), Unbounded)).next().map(|(t, m)| (*m, *t));
+        let pairs = [
+            before.map(|b| (b, (mile, timestamp))),
+            after.map(|a| ((mile, timestamp), a)),
+        ];
+
+        let mut results = Vec::new();
+        for (first, second) in pairs.into_iter().flatten() {
+            let Some(speed) = speeding(first, second, limit) else {
+                continue;
+            };
+            let days = first.1 / SECONDS_PER_DAY..=second.1 / SECONDS_PER_DAY;
+            if days.clone().any(|day| plate_data.ticketed_days.contains(&day)) {
+                tracing::info!("{} already has a ticket for a day in {:?}", plate, days);
+                continue;
+            }
+            plate_data.ticketed_days.extend(days);
+            let ticket = Ticket {
+                plate: plate.clone(),
+                road,
+                mile1: first.0,
+                timestamp1: first.1,
+                mile2: second.0,
+                timestamp2: second.1,
+                speed,
+            };
+            tracing::info!("TICKET {:?}", ticket);
+            results.push(ticket);
+        }
+        results
+    }
+}
+
+/// The average speed between two observations in hundredths of a mile per hour, rounded, if it
+/// is at least half a mile per hour over `limit`.
+fn speeding(first: (Mile, Timestamp), second: (Mile, Timestamp), limit: u16) -> Option<u16> {
+    let miles = first.0.abs_diff(second.0) as u64;
+    let seconds = (second.1 - first.1) as u64;
+    // miles / hours >= limit + 0.5, without leaving integers
+    if miles * 3600 * 2 < (limit as u64 * 2 + 1) * seconds {
+        return None;
+    }
+    let speed = (miles * 3600 * 100 + seconds / 2) / seconds;
+    Some(speed.min(u16::MAX as u64) as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn speeding_rounds_and_allows_half_a_mile() {
+        // 1 mile in 60 seconds is 60 mph
+        assert_eq!(speeding((0, 0), (1, 60), 60), None);
+        assert_eq!(speeding((0, 0), (1, 60), 59), Some(6000));
+        // 60.4 mph is within the limit, 60.5 is not
+        assert_eq!(speeding((0, 0), (151, 9000), 60), None);
+        assert_eq!(speeding((0, 0), (121, 7200), 60), Some(6050));
+        // 2/3 of a mile a minute is 40 mph, 1 mile in 7 seconds is 514.29 mph
+        assert_eq!(speeding((3, 0), (1, 180), 30), Some(4000));
+        assert_eq!(speeding((0, 0), (1, 7), 100), Some(51429));
+        assert_eq!(speeding((0, 0), (65535, 1), 100), Some(u16::MAX));
+    }
+}
@@ -1,14 +1,22 @@
 use std::collections::HashMap;
-use std::net::{ToSocketAddrs, UdpSocket};
 use anyhow::Result;
+use tokio::net::UdpSocket;
+use crate::server::{shutdown_signal, ServerConfig};
 
-pub async fn run<A: ToSocketAddrs>(addr: A) -> Result<()> {
+/// Datagrams have no connections to hand to a `Handler`, so this only shares the address and
+/// the shutdown on SIGINT with the other servers.
+pub async fn run(config: ServerConfig) -> Result<()> {
     tracing::info!("starting UdpDB");
-    let socket = UdpSocket::bind(addr)?;
+    let socket = UdpSocket::bind(&config.addr).await?;
     let mut cache = HashMap::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
         let mut buf = vec![0; 1024];
-        let (n, origin) = socket.recv_from(&mut buf)?;
+        let (n, origin) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = &mut shutdown => break Ok(()),
+        };
         let cmd = Cmd::parse(&String::from_utf8_lossy(&buf[..n]));
         tracing::info!("new cmd: {:?}", cmd);
 
@@ -24,7 +32,7 @@ pub async fn run<A: ToSocketAddrs>(addr: A) -> Result<()> {
         };
 
         if let Some(r) = resp {
-            socket.send_to(r.as_bytes(), origin)?;
+            socket.send_to(r.as_bytes(), origin).await?;
         }
     }
 }
@@ -46,6 +54,6 @@ impl Cmd {
             return Cmd::Insert { key: key.into(), value: value.into() };
         }
 
-        return Cmd::Retrieve { key: txt.into() };
+        Cmd::Retrieve { key: txt.into() }
     }
 }
\ No newline at end of file
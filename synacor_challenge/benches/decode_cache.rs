@@ -0,0 +1,101 @@
+//! Compares the plain interpreter against the decode cache and the block compiler on the
+//! challenge's hot loops.
+//!
+//! Run with `cargo bench`.
+
+use std::io;
+use std::time::{Duration, Instant};
+use synacor_challenge::synacorvm::console::StreamConsole;
+use synacor_challenge::synacorvm::virtual_machine::VirtualMachine;
+
+type Machine = VirtualMachine<StreamConsole<io::Empty, io::Sink>>;
+
+/// Instructions to run of the teleporter confirmation, far fewer than it needs to finish.
+const CONFIRMATION_STEPS: usize = 20_000_000;
+/// Times each workload runs in each mode, keeping the fastest.
+const TRIES: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Interpreter,
+    DecodeCache,
+    Blocks,
+}
+
+fn machine(mode: Mode) -> Machine {
+    let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, StreamConsole::new(io::empty(), io::sink()));
+    machine.load_program_from_bytes(include_bytes!("../spec/challenge.bin"));
+    match mode {
+        Mode::Interpreter => {}
+        Mode::DecodeCache => machine.enable_decode_cache(),
+        Mode::Blocks => machine.enable_block_compiler(),
+    }
+    machine
+}
+
+/// Boot through the self-test up to the first prompt.
+fn boot(machine: &mut Machine, mode: Mode) -> usize {
+    machine.reset();
+    if mode == Mode::Blocks {
+        return machine.run_for(usize::MAX).unwrap();
+    }
+    let mut steps = 0;
+    while machine.is_running() && !machine.waiting_for_input() {
+        machine.step().unwrap();
+        steps += 1;
+    }
+    steps
+}
+
+/// Run the recursive confirmation routine at 6049 with the eighth register set.
+fn confirmation(machine: &mut Machine, mode: Mode) -> usize {
+    boot(machine, mode);
+    machine.set_register_value(0, 4);
+    machine.set_register_value(1, 1);
+    machine.set_register_value(7, 1);
+    machine.set_instruction_counter(6049);
+    if mode == Mode::Blocks {
+        assert_eq!(machine.run_for(CONFIRMATION_STEPS).unwrap(), CONFIRMATION_STEPS);
+        return CONFIRMATION_STEPS;
+    }
+    for _ in 0..CONFIRMATION_STEPS {
+        machine.step().unwrap();
+    }
+    CONFIRMATION_STEPS
+}
+
+fn bench(name: &str, runs: u32, workload: fn(&mut Machine, Mode) -> usize) {
+    let time = |mode: Mode| {
+        let mut machine = machine(mode);
+        let mut steps = 0;
+        let start = Instant::now();
+        for _ in 0..runs {
+            steps += workload(&mut machine, mode);
+        }
+        (start.elapsed(), steps, machine.registers().to_owned())
+    };
+    // Best of a few tries, taking turns so a busy machine slows every mode alike
+    let mut best = [Duration::MAX; 3];
+    let mut steps = 0;
+    for _ in 0..TRIES {
+        let (plain, plain_steps, registers) = time(Mode::Interpreter);
+        let (cached, ..) = time(Mode::DecodeCache);
+        let (compiled, compiled_steps, compiled_registers) = time(Mode::Blocks);
+        assert_eq!((compiled_steps, compiled_registers), (plain_steps, registers));
+        steps = plain_steps;
+        for (best, elapsed) in best.iter_mut().zip([plain, cached, compiled]) {
+            *best = (*best).min(elapsed);
+        }
+    }
+    let [plain, cached, compiled] = best;
+    let rate = |elapsed: Duration| steps as f64 / elapsed.as_secs_f64() / 1e6;
+    println!("{}: {} instructions", name, steps);
+    println!("  interpreter    {:>10.2?} {:>8.1} M instr/s", plain, rate(plain));
+    println!("  decode cache   {:>10.2?} {:>8.1} M instr/s  {:>6.2}x", cached, rate(cached), plain.as_secs_f64() / cached.as_secs_f64());
+    println!("  block compiler {:>10.2?} {:>8.1} M instr/s  {:>6.2}x", compiled, rate(compiled), plain.as_secs_f64() / compiled.as_secs_f64());
+}
+
+fn main() {
+    bench("boot", 20, boot);
+    bench("teleporter confirmation", 1, confirmation);
+}
@@ -0,0 +1,2 @@
+pub mod synacorvm;
+pub mod solve;
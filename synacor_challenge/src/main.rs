@@ -1,52 +1,145 @@
-use std::collections::HashMap;
-use crate::synacorvm::virtual_machine::VirtualMachine;
-use crate::synacorvm::operations::Result;
-
-mod synacorvm;
+use std::{env, fs, io, process, thread};
+use std::path::Path;
+use synacor_challenge::solve;
+use synacor_challenge::synacorvm::assembler;
+use synacor_challenge::synacorvm::console::StreamConsole;
+use synacor_challenge::synacorvm::debugger::Debugger;
+use synacor_challenge::synacorvm::disassembler::Disassembly;
+use synacor_challenge::synacorvm::host::Host;
+use synacor_challenge::synacorvm::savestate::{self, SaveState};
+use synacor_challenge::synacorvm::trace::{self, Tracer};
+use synacor_challenge::synacorvm::virtual_machine::VirtualMachine;
+use synacor_challenge::synacorvm::operations::Result;
 
 
 fn main() -> Result<()> {
-    let test_bin = include_bytes!("../spec/challenge.bin");
+    let mut args: Vec<String> = env::args().collect();
+    // `--bin <path>` swaps the challenge for another program image, e.g. a patched one
+    let program = match take_option(&mut args, "--bin") {
+        Some(path) => fs::read(path).expect("Could not read program"),
+        None => include_bytes!("../spec/challenge.bin").to_vec(),
+    };
+    // `--load-state <slot or path>` starts from a save state instead of booting
+    let save = take_option(&mut args, "--load-state").map(|slot| load_save(&slot));
+    // `--trace <file>` logs executed instructions, limited to `--trace-range <start>-<end>`
+    let trace_range = take_option(&mut args, "--trace-range")
+        .map(|range| trace::parse_range(&range).expect("Trace range must look like 6027-6067"))
+        .unwrap_or(0..=usize::MAX);
+    let tracer = take_option(&mut args, "--trace").map(|path| {
+        let file = fs::File::create(path).expect("Could not create trace file");
+        Tracer::new(io::BufWriter::new(file), trace_range)
+    });
+    // `--profile <file>` writes instruction and call counts once the machine stops
+    let profile = take_option(&mut args, "--profile");
+
     let mut machine = VirtualMachine::default();
-    machine.load_program_from_bytes(test_bin);
-    machine.run()
+    machine.load_program_from_bytes(&program);
+    machine.enable_decode_cache();
+    machine.enable_block_compiler();
+    machine.set_tracer(tracer);
+    if profile.is_some() {
+        machine.enable_profiler();
+    }
+    match args.get(1).map(String::as_str) {
+        Some("debug") => {
+            if let Some(save) = save {
+                machine.restore(save.state);
+            }
+            let mut debugger = Debugger::new(machine);
+            debugger.run()?;
+            write_profile(debugger.machine(), profile);
+            Ok(())
+        }
+        Some("play") => play(machine, args.get(2), save, profile),
+        Some("disasm") => disasm(&program, &args[2..]),
+        Some("asm") => {
+            assemble(&args[2], &args[3]);
+            Ok(())
+        }
+        Some("solve") => solve(machine, args.get(2).map(String::as_str) == Some("play"), profile),
+        _ => play(machine, None, save, profile),
+    }
+}
+
+/// Remove `flag` and the value following it from `args`.
+fn take_option(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == flag)?;
+    let value = args.get(i + 1).unwrap_or_else(|| panic!("{} needs a value", flag)).clone();
+    args.drain(i..=i + 1);
+    Some(value)
+}
+
+fn load_save(slot: &str) -> SaveState {
+    let path = match Path::new(slot).is_file() {
+        true => Path::new(slot).to_path_buf(),
+        false => savestate::slot_path(Path::new("saves"), slot).expect("Invalid save slot name"),
+    };
+    SaveState::load(&path).unwrap_or_else(|e| {
+        eprintln!("Could not load {}: {}", path.display(), e);
+        process::exit(1);
+    })
+}
 
-    // for i in 0..=32768 {
-    //     if i % 1000 == 0 {
-    //         println!("i {}", i);
-    //     }
-    //     if heavy_func(i) == 6 {
-    //         println!("IT IS {}", i);
-    //         break;
-    //     }
-    // }
+/// Play the game on the terminal, optionally entering the commands in `script` first.
+fn play(machine: VirtualMachine, script: Option<&String>, save: Option<SaveState>, profile: Option<String>) -> Result<()> {
+    let mut host = Host::new(machine);
+    if let Some(save) = save {
+        host.resume_from(save);
+    }
+    if let Some(path) = script {
+        let commands = fs::read_to_string(path).expect("Could not read script");
+        host.queue_commands(commands.lines());
+    }
+    host.run()?;
+    write_profile(host.machine(), profile);
+    Ok(())
 }
 
-fn heavy_func(r7: u16) -> u16 {
-    let mut memo = HashMap::new();
-    f6049(4, 1, r7, &mut memo)
+fn write_profile(machine: &VirtualMachine, path: Option<String>) {
+    if let (Some(profiler), Some(path)) = (machine.profiler(), path) {
+        fs::write(path, profiler.report(50)).expect("Could not write profile");
+    }
 }
 
-fn f6049(r0: u16, r1: u16, r7: u16, memo: &mut HashMap<(u16, u16), u16>) -> u16 {
-    if let Some(r) = memo.get(&(r0, r1)) {
-        return *r;
+/// Work out every puzzle and print the commands playing through the whole game, or enter them
+/// into the game right away with `solve play`.
+fn solve(machine: VirtualMachine, play: bool, profile: Option<String>) -> Result<()> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let r7 = solve::find_eighth_register(threads).expect("No eighth register value passes the confirmation");
+    eprintln!("Eighth register: {}", r7);
+    let commands = solve::playthrough(machine.memory(), r7).expect("Could not solve the challenge");
+    if !play {
+        commands.iter().for_each(|command| println!("{}", command));
+        return Ok(());
     }
+    let mut host = Host::new(machine);
+    host.queue_commands(commands);
+    host.run()?;
+    write_profile(host.machine(), profile);
+    Ok(())
+}
 
-    let result = if r0 == 0 {
-        add(r1, 1)
-    } else if r1 == 0 {
-        f6049(add(r0, 32767), r7, r7, memo)
-    } else {
-        f6049(
-            add(r0, 32767),
-            f6049(r0, add(r1, 32767), r7, memo),
-            r7,
-            memo)
-    };
-    memo.insert((r0, r1), result);
-    result
+/// Print a listing of the program as it is in memory once the self-test has decrypted it.
+fn disasm(program: &[u8], extra_entry_points: &[String]) -> Result<()> {
+    let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, StreamConsole::new(io::empty(), io::sink()));
+    machine.load_program_from_bytes(program);
+    machine.reset();
+    machine.run_for(usize::MAX)?;
+
+    let mut entry_points = vec![0];
+    entry_points.extend(extra_entry_points.iter().map(|e| e.parse::<usize>().expect("Entry points must be addresses")));
+    print!("{}", Disassembly::new(machine.memory(), &entry_points));
+    Ok(())
 }
 
-fn add(a: u16, b: u16) -> u16 {
-    (a + b) % 32768
+/// Assemble the listing at `source` into a program image at `output`.
+fn assemble(source: &str, output: &str) {
+    let listing = fs::read_to_string(source).expect("Could not read source");
+    match assembler::assemble(&listing) {
+        Ok(image) => fs::write(output, assembler::to_bytes(&image)).expect("Could not write program"),
+        Err(e) => {
+            eprintln!("{}: {}", source, e);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,238 @@
+//! Solvers for the challenge's puzzles, each producing the commands that solve it so a whole
+//! playthrough can be queued into a [`Host`](crate::synacorvm::host::Host).
+use std::collections::{HashSet, VecDeque};
+use std::thread;
+
+/// Coins as described by `look <coin>`, the number of dots or sides is the value.
+pub const COINS: [(&str, u16); 5] = [
+    ("red coin", 2),
+    ("corroded coin", 3),
+    ("shiny coin", 5),
+    ("concave coin", 7),
+    ("blue coin", 9),
+];
+
+/// The vault antechamber floor as drawn on the journal, north at the top. The orb starts in the
+/// bottom left room and has to weigh `VAULT_WEIGHT` when it reaches the door in the top right.
+pub const VAULT: [[&str; 4]; 4] = [
+    ["*", "8", "-", "1"],
+    ["4", "*", "11", "*"],
+    ["+", "4", "-", "18"],
+    ["22", "-", "9", "*"],
+];
+pub const VAULT_WEIGHT: u16 = 30;
+
+/// From the foothills to the monument, picking up the lantern, can and every coin on the way.
+const TO_MONUMENT: &[&str] = &[
+    "take tablet", "use tablet", "doorway", "north", "north", "bridge", "continue", "down", "east",
+    "take empty lantern", "west", "west", "passage", "ladder", "west", "south", "north", "take can",
+    "west", "ladder", "use can", "use lantern", "darkness", "continue", "west", "west", "west",
+    "west", "north", "take red coin", "north", "east", "take concave coin", "down",
+    "take corroded coin", "up", "west", "west", "take blue coin", "up", "take shiny coin", "down",
+    "east",
+];
+
+/// Through the opened door to Synacor Headquarters, where the teleporter lands the first time.
+const TO_HEADQUARTERS: &[&str] = &[
+    "north", "take teleporter", "use teleporter", "take business card", "take strange book",
+];
+
+/// From the beach to the orb at the vault antechamber.
+const TO_ORB: &[&str] = &[
+    "west", "north", "north", "north", "north", "north", "north", "north", "east", "take journal",
+    "west", "north", "north", "take orb",
+];
+
+const IN_VAULT: &[&str] = &["vault", "take mirror", "use mirror"];
+
+/// Order the coins so that `_ + _ * _^2 + _^3 - _` comes out as `total`, as the monument asks.
+pub fn coin_order<'a>(coins: &[(&'a str, u16)], total: i64) -> Option<Vec<&'a str>> {
+    let mut order = coins.to_vec();
+    permute(&mut order, 0, &mut |order| {
+        let [a, b, c, d, e] = match order {
+            [a, b, c, d, e] => [a.1, b.1, c.1, d.1, e.1].map(i64::from),
+            _ => return false,
+        };
+        a + b * c.pow(2) + d.pow(3) - e == total
+    })
+    .then(|| order.iter().map(|(name, _)| *name).collect())
+}
+
+/// Visit every permutation of `items[k..]` until `found` accepts one, leaving it in `items`.
+fn permute<T>(items: &mut [T], k: usize, found: &mut impl FnMut(&[T]) -> bool) -> bool {
+    if k == items.len() {
+        return found(items);
+    }
+    for i in k..items.len() {
+        items.swap(k, i);
+        if permute(items, k + 1, found) {
+            return true;
+        }
+        items.swap(k, i);
+    }
+    false
+}
+
+/// What the teleporter confirmation routine at 6049 returns for `r0 = 4, r1 = 1` and the given
+/// eighth register; the teleporter only works when this is 6.
+///
+/// The routine is an Ackermann-like function. Instead of memoizing `f6049(r0, r1)` on the way
+/// down, which recurses far too deep, the memo table is filled one `r0` row at a time.
+pub fn confirmation(r7: u16) -> u16 {
+    let mut row = (0..32768u16).map(|r1| add(r1, 1)).collect::<Vec<_>>();
+    for _ in 1..4 {
+        let mut next = vec![0; 32768];
+        next[0] = row[r7 as usize];
+        for r1 in 1..32768 {
+            next[r1] = row[next[r1 - 1] as usize];
+        }
+        row = next;
+    }
+    // f6049(4, 1) = f6049(3, f6049(4, 0)) = f6049(3, f6049(3, r7))
+    row[row[r7 as usize] as usize]
+}
+
+/// Search every eighth register value for the one passing the confirmation, spread over
+/// `threads` threads.
+pub fn find_eighth_register(threads: usize) -> Option<u16> {
+    let threads = threads.max(1);
+    thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|first| scope.spawn(move || (1 + first..32768).step_by(threads).find(|r7| confirmation(*r7 as u16) == 6)))
+            .collect::<Vec<_>>();
+        workers.into_iter().filter_map(|w| w.join().unwrap()).min().map(|r7| r7 as u16)
+    })
+}
+
+/// Words to write so the teleporter skips calling the confirmation routine and goes on as if it
+/// returned 6: the `set r0, 4; set r1, 1; call ...; eq r1, r0, 6` sequence is looked up in
+/// `memory`, its `set r0, 4` is turned into `set r0, 6` and the call into two `noop`s.
+pub fn confirmation_bypass(memory: &[u16]) -> Option<Vec<(usize, u16)>> {
+    let addr = memory.windows(12).position(|w| {
+        w[..7] == [1, 32768, 4, 1, 32769, 1, 17] && w[8..] == [4, 32769, 32768, 6]
+    })?;
+    Some(vec![(addr + 2, 6), (addr + 6, 21), (addr + 7, 21)])
+}
+
+/// Meta commands setting the eighth register and patching out the confirmation, followed by
+/// using the teleporter again.
+pub fn teleporter_commands(memory: &[u16], r7: u16) -> Option<Vec<String>> {
+    let mut commands = vec![format!("reg8 {}", r7)];
+    for (addr, value) in confirmation_bypass(memory)? {
+        commands.push(format!("poke {} {}", addr, value));
+    }
+    commands.push("use teleporter".to_string());
+    Some(commands)
+}
+
+/// Shortest walk carrying the orb from the bottom left room of `grid` to the door in the top
+/// right so it weighs `weight` when it gets there.
+///
+/// Walking into a number applies the operator of the room before it to the orb. The orb vanishes
+/// when it goes back to the first room or reaches the door with the wrong weight, and it cannot
+/// weigh nothing or less.
+pub fn vault_path(grid: &[[&str; 4]], weight: u16) -> Option<Vec<&'static str>> {
+    let height = grid.len();
+    let start = (0, height - 1);
+    let door = (3, 0);
+    let initial = grid[start.1][start.0].parse::<i32>().ok()?;
+    const MOVES: [(&str, isize, isize); 4] = [("north", 0, -1), ("south", 0, 1), ("east", 1, 0), ("west", -1, 0)];
+
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([(start, initial, None::<&str>, Vec::new())]);
+    while let Some(((x, y), value, op, path)) = queue.pop_front() {
+        for (direction, dx, dy) in MOVES {
+            let (Some(nx), Some(ny)) = (x.checked_add_signed(dx), y.checked_add_signed(dy)) else {
+                continue;
+            };
+            if nx >= 4 || ny >= height || (nx, ny) == start {
+                continue;
+            }
+            let room = grid[ny][nx];
+            let (value, op) = match (room.parse::<i32>(), op) {
+                (Ok(n), Some("+")) => (value + n, None),
+                (Ok(n), Some("-")) => (value - n, None),
+                (Ok(n), Some("*")) => (value * n, None),
+                (Ok(_), _) => continue,
+                (Err(_), _) => (value, Some(room)),
+            };
+            if value <= 0 || value >= 32768 {
+                continue;
+            }
+            let mut path = path.clone();
+            path.push(direction);
+            if (nx, ny) == door {
+                if op.is_none() && value == weight as i32 {
+                    return Some(path);
+                }
+                continue;
+            }
+            if seen.insert(((nx, ny), value, op)) {
+                queue.push_back(((nx, ny), value, op, path));
+            }
+        }
+    }
+    None
+}
+
+/// Every command from the start of the game to reading the last code off the mirror. `memory` is
+/// the program the teleporter code to patch is looked up in.
+pub fn playthrough(memory: &[u16], r7: u16) -> Option<Vec<String>> {
+    let mut commands = TO_MONUMENT.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    let coins = coin_order(&COINS, 399)?;
+    commands.extend(coins.iter().map(|coin| format!("use {}", coin)));
+    commands.extend(TO_HEADQUARTERS.iter().map(|c| c.to_string()));
+    commands.extend(teleporter_commands(memory, r7)?);
+    commands.extend(TO_ORB.iter().map(|c| c.to_string()));
+    commands.extend(vault_path(&VAULT, VAULT_WEIGHT)?.iter().map(|c| c.to_string()));
+    commands.extend(IN_VAULT.iter().map(|c| c.to_string()));
+    Some(commands)
+}
+
+fn add(a: u16, b: u16) -> u16 {
+    (a + b) % 32768
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synacorvm::console::StreamConsole;
+    use crate::synacorvm::host::Host;
+    use crate::synacorvm::virtual_machine::VirtualMachine;
+    use super::*;
+
+    #[test]
+    fn orders_coins_for_monument() {
+        let order = coin_order(&COINS, 399).unwrap();
+        assert_eq!(order, ["blue coin", "red coin", "shiny coin", "concave coin", "corroded coin"]);
+        assert_eq!(coin_order(&COINS, 400), None);
+    }
+
+    #[test]
+    fn confirms_only_the_right_eighth_register() {
+        assert_eq!(confirmation(25734), 6);
+        assert_ne!(confirmation(25733), 6);
+        assert_ne!(confirmation(1), 6);
+    }
+
+    #[test]
+    fn finds_shortest_vault_path() {
+        let path = vault_path(&VAULT, VAULT_WEIGHT).unwrap();
+        assert_eq!(
+            path,
+            ["north", "east", "east", "north", "west", "south", "east", "east", "west", "north", "north", "east"]
+        );
+    }
+
+    #[test]
+    fn plays_through_the_whole_challenge() {
+        let program = include_bytes!("../spec/challenge.bin");
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, StreamConsole::new("".as_bytes(), Vec::new()));
+        machine.load_program_from_bytes(program);
+        let commands = playthrough(machine.memory(), 25734).unwrap();
+        let mut host = Host::new(machine);
+        host.queue_commands(commands);
+        host.run().unwrap();
+        let output = String::from_utf8_lossy(host.machine().console().output());
+        assert!(output.contains("You gaze into the mirror"), "{}", output);
+    }
+}
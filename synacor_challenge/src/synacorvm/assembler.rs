@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::{fmt, result};
+use crate::synacorvm::operations::{self, Operand, Operation};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+enum Item<'a> {
+    Instruction { opcode: u16, operands: Vec<&'a str> },
+    Words(Vec<&'a str>),
+    String(String),
+    Org(usize),
+}
+
+struct Line<'a> {
+    number: usize,
+    item: Item<'a>,
+}
+
+/// Assemble source in the listing format the disassembler produces into a memory image.
+///
+/// ```text
+/// ; comments run to the end of the line
+/// start:                  ; labels name the address of what follows them
+///     set   r0, str_hello ; operands are r0..r7, numbers, 'c' characters or labels
+///     call  print
+///     halt
+/// print:
+///     ...
+/// str_hello:
+///     .word 6             ; raw words
+///     .string "hello\n"   ; one word per character, no length prefix
+///     .org  6000          ; zero fill up to an address
+/// ```
+///
+/// Everything is placed one after the other starting at address 0.
+pub fn assemble(source: &str) -> Result<Vec<u16>> {
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = 0;
+    for (i, text) in source.lines().enumerate() {
+        let number = i + 1;
+        let err = |message: String| Error { line: number, message };
+        let (label, item) = parse_line(text).map_err(err)?;
+        if let Some(label) = label {
+            if labels.insert(label, addr).is_some() {
+                return Err(err(format!("label `{}` is defined twice", label)));
+            }
+        }
+        if let Some(item) = item {
+            addr = match &item {
+                Item::Instruction { operands, .. } => addr + 1 + operands.len(),
+                Item::Words(words) => addr + words.len(),
+                Item::String(s) => addr + s.chars().count(),
+                Item::Org(org) if *org >= addr => *org,
+                Item::Org(org) => return Err(err(format!(".org {} is behind the current address {}", org, addr))),
+            };
+            lines.push(Line { number, item });
+        }
+    }
+
+    let mut image = Vec::with_capacity(addr);
+    for line in lines {
+        let err = |message: String| Error { line: line.number, message };
+        match line.item {
+            Item::Instruction { opcode, operands } => {
+                let mut words = vec![opcode];
+                for operand in operands {
+                    words.push(parse_value(operand, &labels).map_err(err)?);
+                }
+                let op = Operation::from(&words).map_err(|e| match e {
+                    operations::Error::OperandValueToHigh { value, .. } => {
+                        err(format!("{} is neither a literal nor a register", value))
+                    }
+                    e => err(e.to_string()),
+                })?;
+                if let Some(Operand::Literal { .. }) = op.destination() {
+                    return Err(err(format!("`{}` has to write into a register", op.mnemonic())));
+                }
+                image.extend(words);
+            }
+            Item::Words(words) => {
+                for word in words {
+                    image.push(parse_value(word, &labels).map_err(err)?);
+                }
+            }
+            Item::String(s) => image.extend(s.chars().map(|c| c as u16)),
+            Item::Org(org) => image.resize(org, 0),
+        }
+    }
+    Ok(image)
+}
+
+/// Little-endian bytes, the format `load_program_from_bytes` reads.
+pub fn to_bytes(image: &[u16]) -> Vec<u8> {
+    image.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+fn parse_line(text: &str) -> result::Result<(Option<&str>, Option<Item<'_>>), String> {
+    let text = strip_comment(text).trim();
+    let (label, rest) = match text.split_once(':') {
+        Some((label, rest)) if is_identifier(label) => (Some(label), rest.trim()),
+        _ => (None, text),
+    };
+    if rest.is_empty() {
+        return Ok((label, None));
+    }
+
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let args = args.trim();
+    let item = match name {
+        ".word" => Item::Words(split_operands(args)?),
+        ".string" => Item::String(parse_string(args)?),
+        ".org" => Item::Org(parse_number(args)? as usize),
+        mnemonic => {
+            let (opcode, count) = Operation::opcode_of(mnemonic)
+                .ok_or_else(|| format!("unknown instruction `{}`", mnemonic))?;
+            let operands = split_operands(args)?;
+            if operands.len() != count {
+                return Err(format!("`{}` takes {} operands, found {}", mnemonic, count, operands.len()));
+            }
+            Item::Instruction { opcode, operands }
+        }
+    };
+    Ok((label, Some(item)))
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        match c {
+            ';' => return &text[..i],
+            '\'' | '"' => match closing_quote(&text[i..]) {
+                Some(close) => i += close + 1,
+                None => return text,
+            },
+            c => i += c.len_utf8(),
+        }
+    }
+    text
+}
+
+/// Index of the quote closing the literal that `s` starts with.
+fn closing_quote(s: &str) -> Option<usize> {
+    let quote = s.chars().next()?;
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Split on commas and whitespace, keeping character literals like `' '` and `','` whole.
+fn split_operands(args: &str) -> result::Result<Vec<&str>, String> {
+    let mut operands = Vec::new();
+    let mut rest = args.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    while !rest.is_empty() {
+        let end = if rest.starts_with('\'') {
+            closing_quote(rest).ok_or_else(|| format!("unterminated character in `{}`", args))? + 1
+        } else {
+            rest.find(|c: char| c == ',' || c.is_whitespace()).unwrap_or(rest.len())
+        };
+        operands.push(&rest[..end]);
+        rest = rest[end..].trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+    Ok(operands)
+}
+
+fn parse_value(operand: &str, labels: &HashMap<&str, usize>) -> result::Result<u16, String> {
+    if let Some(index) = operand.strip_prefix('r').and_then(|i| i.parse::<u16>().ok()) {
+        return match index {
+            0..=7 => Ok(32768 + index),
+            _ => Err(format!("there is no register `{}`", operand)),
+        };
+    }
+    if operand.starts_with('\'') {
+        let chars = unescape(&operand[1..operand.len() - 1])?.chars().collect::<Vec<_>>();
+        return match chars.as_slice() {
+            [c] => Ok(*c as u16),
+            _ => Err(format!("`{}` is not a single character", operand)),
+        };
+    }
+    if is_identifier(operand) {
+        return labels
+            .get(operand)
+            .map(|addr| *addr as u16)
+            .ok_or_else(|| format!("undefined label `{}`", operand));
+    }
+    match parse_number(operand)? {
+        n if n <= u16::MAX as u32 => Ok(n as u16),
+        n => Err(format!("{} does not fit in 16 bits", n)),
+    }
+}
+
+fn parse_number(s: &str) -> result::Result<u32, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number `{}`", s))
+}
+
+fn parse_string(args: &str) -> result::Result<String, String> {
+    match args.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(body) => unescape(body),
+        None => Err(format!("expected a quoted string, found `{}`", args)),
+    }
+}
+
+fn unescape(s: &str) -> result::Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c @ ('\\' | '\'' | '"')) => out.push(c),
+            other => return Err(format!("invalid escape `\\{}`", other.map(String::from).unwrap_or_default())),
+        }
+    }
+    Ok(out)
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synacorvm::console::StreamConsole;
+    use crate::synacorvm::disassembler::Disassembly;
+    use crate::synacorvm::virtual_machine::VirtualMachine;
+    use super::*;
+
+    const EVERY_OPCODE: &str = r#"
+        set   r0, 'A'
+        push  r0
+        pop   r1
+        eq    r2, r0, r1
+        gt    r3, r0, 64
+        jt    r2, taken
+        halt
+    taken:
+        jf    r3, fail
+        add   r4, 32758, 15
+        mult  r5, 32767, 2
+        mod   r6, 17, 5
+        and   r7, 6, 3
+        or    r7, r7, 1
+        not   r0, 21845
+        wmem  cell, r4
+        rmem  r3, cell
+        call  echo
+        noop
+        halt
+    fail:
+        out   'F'
+        halt
+    echo:                   ; copy one character of input to the output
+        in    r2
+        out   r2
+        ret
+    cell: .word 0
+    "#;
+
+    #[test]
+    fn assembles_program_using_every_opcode() {
+        let image = assemble(EVERY_OPCODE).unwrap();
+        let console = StreamConsole::new("x\n".as_bytes(), Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 16, console);
+        machine.load_program_from_bytes(&to_bytes(&image));
+        machine.run().unwrap();
+        assert_eq!(machine.console().output(), b"x");
+        assert_eq!(machine.registers(), &[10922, 65, 120, 5, 5, 32766, 2, 3]);
+        assert_eq!(image.last(), Some(&0));
+        assert_eq!(machine.memory()[image.len() - 1], 5);
+    }
+
+    #[test]
+    fn encodes_operands_and_directives() {
+        let image = assemble("a: out ' '\n jmp a ; back\n.word r7, 0x10, ',', a\n.string \"a;\\\"\\n\"\n.org 15\n.word 1").unwrap();
+        assert_eq!(image, vec![19, 32, 6, 0, 32775, 16, 44, 0, 97, 59, 34, 10, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn round_trips_challenge_through_disassembler() {
+        let bytes = include_bytes!("../../spec/challenge.bin");
+        let mut memory = bytes.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect::<Vec<_>>();
+        let listing = Disassembly::new(&memory, &[0]).to_string();
+        while memory.last() == Some(&0) {
+            memory.pop();
+        }
+        assert_eq!(assemble(&listing).unwrap(), memory);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(error("halt\nfrob r0").line, 2);
+        assert_eq!(error("frob r0").message, "unknown instruction `frob`");
+        assert_eq!(error("add r0, 1").message, "`add` takes 3 operands, found 2");
+        assert_eq!(error("jmp nowhere").message, "undefined label `nowhere`");
+        assert_eq!(error("set 4, 1").message, "`set` has to write into a register");
+        assert_eq!(error("out r8").message, "there is no register `r8`");
+        assert_eq!(error("a: halt\na: halt").message, "label `a` is defined twice");
+        assert_eq!(error("noop\n.org 0").message, ".org 0 is behind the current address 1");
+    }
+}
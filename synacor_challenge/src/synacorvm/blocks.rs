@@ -0,0 +1,571 @@
+use std::collections::VecDeque;
+use std::{mem, vec};
+use crate::synacorvm::console::Console;
+use crate::synacorvm::operations::{Operand, Operation};
+
+/// Most instructions along one path through a block, which also stops chaining through jumps
+/// that loop forever.
+const MAX_PATH_LEN: usize = 64;
+/// Steps in a block past which conditional branches are no longer followed both ways.
+const MAX_BLOCK_STEPS: usize = 256;
+/// Blocks compiled before the cache starts over, self-modifying code keeps invalidating them.
+const MAX_BLOCKS: usize = 1 << 16;
+const NONE: u32 = u32::MAX;
+/// Times a `ret` leaves blocks before they are compiled again to carry on where it usually
+/// returns to.
+const PROFILE_RUNS: u32 = 16;
+
+/// Where a `ret` is expected to return to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Hint {
+    Unknown,
+    Returns(u16),
+}
+
+/// A running majority vote over where a `ret` returned to.
+#[derive(Clone, Copy, Default)]
+struct Profile {
+    runs: u32,
+    candidate: u16,
+    lead: u32,
+}
+
+/// An operand in its raw encoding: a literal below 32768, register `n` at 32768 + `n`. Reading
+/// one needs no branch that way.
+#[derive(Clone, Copy)]
+struct Val(u16);
+
+/// One instruction with its operands resolved. Destinations are register indexes.
+#[derive(Clone, Copy)]
+enum Op {
+    Set { dst: u8, src: Val },
+    Push { src: Val },
+    Pop { dst: u8 },
+    Eq { dst: u8, lhs: Val, rhs: Val },
+    Gt { dst: u8, lhs: Val, rhs: Val },
+    Add { dst: u8, lhs: Val, rhs: Val },
+    Mult { dst: u8, lhs: Val, rhs: Val },
+    Mod { dst: u8, lhs: Val, rhs: Val },
+    And { dst: u8, lhs: Val, rhs: Val },
+    Or { dst: u8, lhs: Val, rhs: Val },
+    Not { dst: u8, src: Val },
+    Rmem { dst: u8, src: Val },
+    Out { src: Val },
+    /// A `ret` to an address pushed earlier in the block
+    Drop,
+    /// A `ret` expected to return to `expect`, leaving the block when it does not
+    Ret { expect: u16 },
+    /// A conditional branch followed both ways: when `src` being non-zero is `when`, carry on
+    /// at step `to`
+    If { src: Val, when: bool, to: u16 },
+    /// A conditional branch to a register or past the size limit: when `src` being non-zero is
+    /// `when`, leave the block for `exit`
+    Guard { src: Val, when: bool, exit: Val },
+    /// Leaves the block for `next` when it overwrites compiled code
+    Wmem { dst: Val, src: Val, next: u16 },
+    // Everything below leaves the block
+    Jmp { tgt: Val },
+    Call { tgt: Val, back: u16 },
+    Return,
+    Halt,
+    /// Carry on at `to` without executing anything, the path got too long
+    Goto { to: u16 },
+    /// Back at the start of the block: go round again while that fits in the budget
+    Loop,
+    /// The instruction is left to the interpreter: `in`, anything that does not decode, and
+    /// writes into literals
+    Interpret,
+}
+
+struct Step {
+    op: Op,
+    addr: u16,
+    /// Instructions executed on the way to this one since the block started or last looped
+    done: u16,
+}
+
+/// What compiling a path knows about the stack.
+#[derive(Clone, Default)]
+struct Shadow {
+    /// Values the path pushed and has not popped yet, when known
+    pushed: Vec<Option<u16>>,
+    /// How many of the last of those are literals the path has not actually pushed yet, so a
+    /// `call` followed by its `ret` pushes nothing
+    deferred: usize,
+}
+
+impl Shadow {
+    fn push_later(&mut self, value: u16) {
+        self.pushed.push(Some(value));
+        self.deferred += 1;
+    }
+
+    /// Push what is deferred, for anything that looks at the stack or might leave the block.
+    fn flush(&mut self, steps: &mut Vec<Step>, addr: usize, done: usize) {
+        for value in &self.pushed[self.pushed.len() - self.deferred..] {
+            let op = Op::Push { src: Val(value.unwrap()) };
+            steps.push(Step { op, addr: addr as u16, done: done as u16 });
+        }
+        self.deferred = 0;
+    }
+
+    /// Pop the value on top, returning it when known and whether it was only deferred.
+    fn pop(&mut self) -> (Option<u16>, bool) {
+        let deferred = self.deferred > 0;
+        self.deferred = self.deferred.saturating_sub(1);
+        (self.pushed.pop().flatten(), deferred)
+    }
+}
+
+/// Straight-line code from one address, chained through `jmp`, `call` and `ret` wherever they
+/// go is known, and following conditional branches both ways. Every path through it ends in a
+/// step leaving the block.
+struct Block {
+    start: usize,
+    steps: Vec<Step>,
+    /// Instructions executed along the longest path, not going round
+    len: usize,
+}
+
+/// The parts of a machine a block runs on.
+pub(crate) struct Cpu<'a, C> {
+    pub instruction_counter: &'a mut usize,
+    pub running: &'a mut bool,
+    pub registers: &'a mut [u16; 8],
+    pub memory: &'a mut [u16],
+    pub stack: &'a mut Vec<u16>,
+    pub console: &'a mut C,
+}
+
+enum Exit {
+    Left { done: usize },
+    /// Left through the `ret` at `addr`
+    Returned { done: usize, addr: usize },
+    /// Left after writing into memory at `addr`
+    Wrote { done: usize, addr: usize },
+    /// Stopped at an instruction the interpreter has to run
+    Interpret { done: usize, addr: usize },
+}
+
+/// Compiled blocks by their start address.
+///
+/// Every block remembers the words it was compiled from, and a write to one of them drops the
+/// blocks using it, so self-modifying code still behaves.
+pub(crate) struct BlockCache {
+    blocks: Vec<Block>,
+    /// The block starting at each address, or `NONE`
+    entry: Vec<u32>,
+    /// The blocks compiled from each word, some of them possibly dropped already
+    users: Vec<Vec<u32>>,
+    /// Where the `ret` at each address returns to
+    hints: Vec<Hint>,
+    profiles: Vec<Profile>,
+    /// Addresses blocks wrote to
+    written: Vec<usize>,
+}
+
+impl BlockCache {
+    pub fn new(memory: usize) -> Self {
+        BlockCache {
+            blocks: Vec::new(),
+            entry: vec![NONE; memory],
+            users: vec![Vec::new(); memory],
+            hints: vec![Hint::Unknown; memory],
+            profiles: vec![Profile::default(); memory],
+            written: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.hints.fill(Hint::Unknown);
+        self.profiles.fill(Profile::default());
+        self.drop_blocks();
+    }
+
+    fn drop_blocks(&mut self) {
+        self.blocks.clear();
+        self.entry.fill(NONE);
+        self.users.iter_mut().for_each(Vec::clear);
+    }
+
+    /// Drop every block compiled from the word at `addr`.
+    pub fn invalidate(&mut self, addr: usize) {
+        let Some(users) = self.users.get_mut(addr) else {
+            return;
+        };
+        for id in mem::take(users) {
+            let start = self.blocks[id as usize].start;
+            if self.entry[start] == id {
+                self.entry[start] = NONE;
+            }
+        }
+    }
+
+    /// The addresses blocks wrote to since the last call.
+    pub fn written(&mut self) -> vec::Drain<'_, usize> {
+        self.written.drain(..)
+    }
+
+    /// Run blocks for at most `limit` instructions, returning how many ran. Stops early when the
+    /// machine halts, the next instruction is one for the interpreter, or the next block might
+    /// not fit in what is left of `limit`.
+    pub fn run<C: Console>(&mut self, cpu: &mut Cpu<C>, limit: usize) -> usize {
+        let mut executed = 0;
+        while *cpu.running {
+            let ic = *cpu.instruction_counter;
+            let id = match self.entry.get(ic) {
+                Some(&NONE) => self.compile(ic, cpu.memory),
+                Some(&id) => id,
+                None => break,
+            };
+            let block = &self.blocks[id as usize];
+            let budget = limit - executed;
+            if block.len > budget {
+                break;
+            }
+            match block.run(cpu, budget, &self.users, &mut self.written) {
+                Exit::Left { done } => executed += done,
+                Exit::Returned { done, addr } => {
+                    executed += done;
+                    self.profile(id, addr, *cpu.instruction_counter as u16);
+                }
+                Exit::Wrote { done, addr } => {
+                    executed += done;
+                    self.invalidate(addr);
+                }
+                Exit::Interpret { done, addr } => {
+                    *cpu.instruction_counter = addr;
+                    executed += done;
+                    break;
+                }
+            }
+        }
+        executed
+    }
+
+    /// Count where the `ret` at `addr` that block `id` left through went, and once one place
+    /// wins a vote over the last `PROFILE_RUNS` returns drop the block so it gets compiled again
+    /// past it.
+    fn profile(&mut self, id: u32, addr: usize, to: u16) {
+        if self.hints[addr] == Hint::Unknown {
+            let profile = &mut self.profiles[addr];
+            if profile.lead == 0 {
+                profile.candidate = to;
+            }
+            if to == profile.candidate {
+                profile.lead += 1;
+            } else {
+                profile.lead -= 1;
+            }
+            profile.runs += 1;
+            if profile.runs < PROFILE_RUNS {
+                return;
+            }
+            let winner = (profile.lead > 0).then_some(profile.candidate);
+            *profile = Profile::default();
+            match winner {
+                Some(to) => self.hints[addr] = Hint::Returns(to),
+                None => return,
+            }
+        }
+        let start = self.blocks[id as usize].start;
+        if self.entry[start] == id {
+            self.entry[start] = NONE;
+        }
+    }
+
+    fn compile(&mut self, start: usize, memory: &[u16]) -> u32 {
+        if self.blocks.len() >= MAX_BLOCKS {
+            self.drop_blocks();
+        }
+        let id = self.blocks.len() as u32;
+        let mut steps: Vec<Step> = Vec::new();
+        let mut longest = 0;
+        // Paths still to compile: the `If` step jumping to it, where it starts, the instructions
+        // executed before it and the stack on the way
+        let mut pending = VecDeque::from([(None::<usize>, start, 0, Shadow::default())]);
+        while let Some((from, mut addr, mut done, mut stack)) = pending.pop_front() {
+            if let Some(from) = from {
+                let to = steps.len() as u16;
+                if let Op::If { to: target, .. } = &mut steps[from].op {
+                    *target = to;
+                }
+            }
+            let exit = loop {
+                if addr == start && done > 0 {
+                    break Op::Loop;
+                }
+                if done == MAX_PATH_LEN || steps.len() >= MAX_BLOCK_STEPS {
+                    break Op::Goto { to: addr as u16 };
+                }
+                let Ok(op) = Operation::decode(memory, addr) else {
+                    if let Some(users) = self.users.get_mut(addr) {
+                        users.push(id);
+                    }
+                    break Op::Interpret;
+                };
+                let len = op.instr_len();
+                for word in addr..addr + len {
+                    self.users[word].push(id);
+                }
+                if matches!(op.destination(), Some(Operand::Literal { .. })) {
+                    break Op::Interpret;
+                }
+                let step = |op| Step { op, addr: addr as u16, done: done as u16 };
+                match op {
+                    Operation::Jmp { tgt: Operand::Literal { value } } => {
+                        addr = value as usize;
+                        done += 1;
+                        continue;
+                    }
+                    Operation::Call { tgt: Operand::Literal { value } } => {
+                        stack.push_later(addr as u16 + 2);
+                        addr = value as usize;
+                        done += 1;
+                        continue;
+                    }
+                    Operation::Ret => {
+                        let to = match (stack.pop(), self.hints[addr]) {
+                            ((Some(to), true), _) => to,
+                            ((Some(to), false), _) => {
+                                steps.push(step(Op::Drop));
+                                to
+                            }
+                            (_, Hint::Returns(to)) => {
+                                steps.push(step(Op::Ret { expect: to }));
+                                to
+                            }
+                            _ => break Op::Return,
+                        };
+                        addr = to as usize;
+                        done += 1;
+                        continue;
+                    }
+                    Operation::Jt { src, tgt } | Operation::Jf { src, tgt } => {
+                        let when = matches!(op, Operation::Jt { .. });
+                        match tgt {
+                            Operand::Literal { value } if steps.len() < MAX_BLOCK_STEPS => {
+                                pending.push_back((Some(steps.len()), value as usize, done + 1, stack.clone()));
+                                steps.push(step(Op::If { src: val(src), when, to: 0 }));
+                            }
+                            _ => {
+                                stack.flush(&mut steps, addr, done);
+                                steps.push(step(Op::Guard { src: val(src), when, exit: val(tgt) }));
+                            }
+                        }
+                        addr += len;
+                        done += 1;
+                        continue;
+                    }
+                    Operation::Noop => {
+                        addr += len;
+                        done += 1;
+                        continue;
+                    }
+                    Operation::Push { src: Operand::Literal { value } } => {
+                        stack.push_later(value);
+                        addr += len;
+                        done += 1;
+                        continue;
+                    }
+                    Operation::Push { .. } => {
+                        stack.flush(&mut steps, addr, done);
+                        stack.pushed.push(None);
+                    }
+                    Operation::Pop { dst } => {
+                        if let (Some(value), true) = stack.pop() {
+                            steps.push(step(Op::Set { dst: reg(dst), src: Val(value) }));
+                            addr += len;
+                            done += 1;
+                            continue;
+                        }
+                    }
+                    // These can leave the block
+                    Operation::Mod { .. } | Operation::Rmem { .. } | Operation::Out { .. } | Operation::Wmem { .. } => {
+                        stack.flush(&mut steps, addr, done);
+                    }
+                    _ => {}
+                }
+                let op = match op {
+                    Operation::Set { dst, src } => Op::Set { dst: reg(dst), src: val(src) },
+                    Operation::Push { src } => Op::Push { src: val(src) },
+                    Operation::Pop { dst } => Op::Pop { dst: reg(dst) },
+                    Operation::Eq { dst, lhs, rhs } => Op::Eq { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::Gt { dst, lhs, rhs } => Op::Gt { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::Add { dst, lhs, rhs } => Op::Add { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::Mult { dst, lhs, rhs } => Op::Mult { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::Mod { dst, lhs, rhs } => Op::Mod { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::And { dst, lhs, rhs } => Op::And { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::Or { dst, lhs, rhs } => Op::Or { dst: reg(dst), lhs: val(lhs), rhs: val(rhs) },
+                    Operation::Not { dst, src } => Op::Not { dst: reg(dst), src: val(src) },
+                    Operation::Rmem { dst, src } => Op::Rmem { dst: reg(dst), src: val(src) },
+                    Operation::Out { src } => Op::Out { src: val(src) },
+                    Operation::Jmp { tgt } => break Op::Jmp { tgt: val(tgt) },
+                    Operation::Call { tgt } => break Op::Call { tgt: val(tgt), back: addr as u16 + 2 },
+                    Operation::Halt => break Op::Halt,
+                    Operation::Wmem { dst, src } => Op::Wmem { dst: val(dst), src: val(src), next: addr as u16 + 3 },
+                    Operation::In { .. } => break Op::Interpret,
+                    Operation::Ret | Operation::Jt { .. } | Operation::Jf { .. } | Operation::Noop => {
+                        unreachable!("handled above")
+                    }
+                };
+                steps.push(step(op));
+                addr += len;
+                done += 1;
+            };
+            stack.flush(&mut steps, addr, done);
+            longest = longest.max(match exit {
+                Op::Goto { .. } | Op::Interpret | Op::Loop => done,
+                _ => done + 1,
+            });
+            steps.push(Step { op: exit, addr: addr as u16, done: done as u16 });
+        }
+        self.blocks.push(Block { start, steps, len: longest });
+        self.entry[start] = id;
+        id
+    }
+}
+
+fn reg(operand: Operand) -> u8 {
+    match operand {
+        Operand::Reg { index } => index as u8,
+        Operand::Literal { .. } => unreachable!("writes into literals are left to the interpreter"),
+    }
+}
+
+fn val(operand: Operand) -> Val {
+    Val(operand.to_raw())
+}
+
+impl Block {
+    #[inline]
+    /// Run the block, going round for at most `budget` instructions. Writes into memory are
+    /// added to `written`, and leave the block when they hit a word in `users`.
+    fn run<C: Console>(&self, cpu: &mut Cpu<C>, budget: usize, users: &[Vec<u32>], written: &mut Vec<usize>) -> Exit {
+        let regs = &mut *cpu.registers;
+        macro_rules! get {
+            ($val:expr) => {{
+                let Val(raw) = $val;
+                let reg = (raw >> 15).wrapping_neg();
+                (regs[(raw & 7) as usize] & reg) | (raw & !reg)
+            }};
+        }
+        macro_rules! set {
+            ($dst:expr, $value:expr) => {{
+                let value = $value;
+                regs[($dst & 7) as usize] = value;
+            }};
+        }
+        // Instructions executed going round before the latest time through
+        let mut base = 0;
+        let interpret = |step: &Step, base: usize| Exit::Interpret { done: base + step.done as usize, addr: step.addr as usize };
+        let left = |step: &Step, base: usize| Exit::Left { done: base + step.done as usize + 1 };
+
+        let mut pc = 0;
+        loop {
+            let step = &self.steps[pc];
+            pc += 1;
+            match step.op {
+                Op::Set { dst, src } => set!(dst, get!(src)),
+                Op::Push { src } => cpu.stack.push(get!(src)),
+                Op::Pop { dst } => match cpu.stack.pop() {
+                    Some(value) => set!(dst, value),
+                    None => return interpret(step, base),
+                },
+                Op::Eq { dst, lhs, rhs } => set!(dst, (get!(lhs) == get!(rhs)) as u16),
+                Op::Gt { dst, lhs, rhs } => set!(dst, (get!(lhs) > get!(rhs)) as u16),
+                Op::Add { dst, lhs, rhs } => set!(dst, ((get!(lhs) as u32 + get!(rhs) as u32) % 32768) as u16),
+                Op::Mult { dst, lhs, rhs } => set!(dst, ((get!(lhs) as u32 * get!(rhs) as u32) % 32768) as u16),
+                Op::Mod { dst, lhs, rhs } => match get!(lhs).checked_rem(get!(rhs)) {
+                    Some(value) => set!(dst, value % 32768),
+                    None => return interpret(step, base),
+                },
+                Op::And { dst, lhs, rhs } => set!(dst, get!(lhs) & get!(rhs)),
+                Op::Or { dst, lhs, rhs } => set!(dst, get!(lhs) | get!(rhs)),
+                Op::Not { dst, src } => set!(dst, !get!(src) & 0b0111111111111111),
+                Op::Rmem { dst, src } => match cpu.memory.get(get!(src) as usize) {
+                    Some(&value) => set!(dst, value),
+                    None => return interpret(step, base),
+                },
+                Op::Out { src } => match get!(src) {
+                    c if c > 127 => return interpret(step, base),
+                    c => cpu.console.write_char(c as u8 as char),
+                },
+                Op::Drop => {
+                    cpu.stack.pop();
+                }
+                Op::Ret { expect } => match cpu.stack.pop() {
+                    Some(to) if to == expect => {}
+                    Some(to) => {
+                        *cpu.instruction_counter = to as usize;
+                        return left(step, base);
+                    }
+                    None => return interpret(step, base),
+                },
+                Op::If { src, when, to } => {
+                    if (get!(src) != 0) == when {
+                        pc = to as usize;
+                    }
+                }
+                Op::Guard { src, when, exit } => {
+                    if (get!(src) != 0) == when {
+                        *cpu.instruction_counter = get!(exit) as usize;
+                        return left(step, base);
+                    }
+                }
+                Op::Jmp { tgt } => {
+                    *cpu.instruction_counter = get!(tgt) as usize;
+                    return left(step, base);
+                }
+                Op::Call { tgt, back } => {
+                    cpu.stack.push(back);
+                    *cpu.instruction_counter = get!(tgt) as usize;
+                    return left(step, base);
+                }
+                Op::Return => {
+                    return match cpu.stack.pop() {
+                        Some(to) => {
+                            *cpu.instruction_counter = to as usize;
+                            Exit::Returned { done: base + step.done as usize + 1, addr: step.addr as usize }
+                        }
+                        None => {
+                            *cpu.running = false;
+                            *cpu.instruction_counter = step.addr as usize + 1;
+                            left(step, base)
+                        }
+                    };
+                }
+                Op::Halt => {
+                    *cpu.running = false;
+                    *cpu.instruction_counter = step.addr as usize;
+                    return left(step, base);
+                }
+                Op::Wmem { dst, src, next } => {
+                    let addr = get!(dst) as usize;
+                    let Some(cell) = cpu.memory.get_mut(addr) else {
+                        return interpret(step, base);
+                    };
+                    *cell = get!(src);
+                    written.push(addr);
+                    if !users[addr].is_empty() {
+                        *cpu.instruction_counter = next as usize;
+                        return Exit::Wrote { done: base + step.done as usize + 1, addr };
+                    }
+                }
+                Op::Goto { to } => {
+                    *cpu.instruction_counter = to as usize;
+                    return Exit::Left { done: base + step.done as usize };
+                }
+                Op::Loop => {
+                    base += step.done as usize;
+                    if base + self.len > budget {
+                        *cpu.instruction_counter = self.start;
+                        return Exit::Left { done: base };
+                    }
+                    pc = 0;
+                }
+                Op::Interpret => return interpret(step, base),
+            }
+        }
+    }
+}
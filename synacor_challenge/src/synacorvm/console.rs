@@ -0,0 +1,72 @@
+use std::io::{self, BufRead, Write};
+
+/// Where the machine's `in` and `out` instructions are connected to.
+pub trait Console {
+    /// Read the next line of input, including its trailing newline. `None` once input is exhausted.
+    fn read_line(&mut self) -> Option<String>;
+
+    fn write_char(&mut self, c: char);
+
+    fn write_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.write_char(c));
+    }
+}
+
+/// A [`Console`] over any pair of reader and writer, e.g. a script file and an in-memory buffer.
+pub struct StreamConsole<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> StreamConsole<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+}
+
+impl<R: BufRead, W: Write> Console for StreamConsole<R, W> {
+    fn read_line(&mut self) -> Option<String> {
+        self.output.flush().expect("Could not flush output");
+        read_line_from(&mut self.input)
+    }
+
+    fn write_char(&mut self, c: char) {
+        write!(self.output, "{}", c).expect("Could not write output");
+    }
+
+    fn write_str(&mut self, s: &str) {
+        self.output.write_all(s.as_bytes()).expect("Could not write output");
+    }
+}
+
+/// The terminal. Stdin is only locked while a line is being read, so other readers can share it.
+#[derive(Default)]
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn read_line(&mut self) -> Option<String> {
+        io::stdout().flush().expect("Could not flush stdout");
+        read_line_from(&mut io::stdin().lock())
+    }
+
+    fn write_char(&mut self, c: char) {
+        print!("{}", c);
+    }
+
+    fn write_str(&mut self, s: &str) {
+        print!("{}", s);
+    }
+}
+
+fn read_line_from(input: &mut impl BufRead) -> Option<String> {
+    let mut buffer = String::new();
+    match input.read_line(&mut buffer).expect("Could not read input") {
+        0 => None,
+        _ if !buffer.ends_with('\n') => Some(buffer + "\n"),
+        _ => Some(buffer),
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use crate::synacorvm::console::{Console, StdConsole};
+use crate::synacorvm::operations;
+use crate::synacorvm::operations::Operation;
+use crate::synacorvm::trace::{self, Tracer};
+use crate::synacorvm::virtual_machine::VirtualMachine;
+
+const HELP: &str = "\
+commands:
+  break <addr>           stop before executing <addr>          (b)
+  delete <addr>          remove breakpoint at <addr>
+  watch mem <addr>       stop when memory at <addr> changes
+  watch reg <rN>         stop when register <rN> changes
+  unwatch <n>            remove watchpoint number <n>
+  info                   list breakpoints and watchpoints
+  step [n]               execute <n> instructions (default 1)  (s)
+  continue               run until something stops the machine (c)
+  finish                 run until the current function returns
+  regs                   show registers and instruction counter
+  reg <rN> <value>       set register <rN>
+  stack                  show the stack, top first
+  peek <addr> [len]      show <len> memory words from <addr>   (x)
+  poke <addr> <value>..  write values to memory from <addr>
+  jump <addr>            move the instruction counter to <addr>
+  disas [addr] [n]       disassemble <n> instructions from <addr>
+  trace <file> [a-b]     log instructions (optionally only from a to b) to <file>
+  trace off              stop tracing
+  profile                start counting instructions, or show the counts so far
+  input <text>           queue a line of game input
+  source <file>          run debugger commands from <file>
+  quit                   leave the debugger                    (q)
+numbers may be decimal or 0x prefixed hex; an empty line repeats the last command";
+
+/// Interactive debugger wrapping a [`VirtualMachine`].
+///
+/// Commands are read from stdin at the `sdb>` prompt. Game input never comes from that prompt,
+/// it is queued explicitly with `input`, and the machine stops whenever it would block on `in`.
+pub struct Debugger<C = StdConsole> {
+    machine: VirtualMachine<C>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    last_command: Option<String>,
+    /// Scripts being run by `source`, innermost last
+    sourcing: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WatchTarget {
+    Memory(usize),
+    Register(usize),
+}
+
+struct Watchpoint {
+    target: WatchTarget,
+    last: u16,
+}
+
+#[derive(Debug, PartialEq)]
+enum Resume {
+    Step(usize),
+    Continue,
+    Finish,
+}
+
+#[derive(Debug, PartialEq)]
+enum Stop {
+    Breakpoint(usize),
+    Watchpoint { target: WatchTarget, old: u16, new: u16 },
+    WaitingForInput,
+    Halted,
+    Stepped,
+    Finished,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Break(usize),
+    Delete(usize),
+    Watch(WatchTarget),
+    Unwatch(usize),
+    Info,
+    Resume(Resume),
+    Regs,
+    SetReg { index: usize, value: u16 },
+    Stack,
+    Peek { addr: usize, len: usize },
+    Poke { addr: usize, values: Vec<u16> },
+    Jump(usize),
+    Disas { addr: Option<usize>, count: usize },
+    Trace { path: String, range: RangeInclusive<usize> },
+    TraceOff,
+    Profile,
+    Input(String),
+    Source(String),
+    Help,
+    Quit,
+}
+
+impl<C: Console> Debugger<C> {
+    pub fn new(machine: VirtualMachine<C>) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            last_command: None,
+            sourcing: Vec::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &VirtualMachine<C> {
+        &self.machine
+    }
+
+    pub fn run(&mut self) -> operations::Result<()> {
+        if !self.machine.is_running() {
+            self.machine.reset();
+        }
+        self.print_location();
+        let stdin = io::stdin();
+        loop {
+            print!("sdb> ");
+            io::stdout().flush().expect("Could not flush stdout");
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).expect("Could not read stdin") == 0 {
+                return Ok(());
+            }
+            let line = match (line.trim(), &self.last_command) {
+                ("", Some(last)) => last.clone(),
+                ("", None) => continue,
+                (line, _) => line.to_string(),
+            };
+            self.last_command = Some(line.clone());
+            if !self.execute(&line) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Execute a single debugger command, returning false when the debugger should exit.
+    fn execute(&mut self, line: &str) -> bool {
+        let command = match parse_command(line) {
+            Ok(command) => command,
+            Err(msg) => {
+                println!("error: {}", msg);
+                return true;
+            }
+        };
+        match command {
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+                println!("breakpoint at {}", addr);
+            }
+            Command::Delete(addr) => {
+                if !self.breakpoints.remove(&addr) {
+                    println!("no breakpoint at {}", addr);
+                }
+            }
+            Command::Watch(WatchTarget::Memory(addr)) if addr >= self.machine.memory().len() => {
+                println!("error: address {} is outside of memory", addr);
+            }
+            Command::Watch(target) => {
+                let last = self.watched_value(target);
+                self.watchpoints.push(Watchpoint { target, last });
+                println!("watchpoint {}: {} = {}", self.watchpoints.len() - 1, describe(target), last);
+            }
+            Command::Unwatch(n) => {
+                if n < self.watchpoints.len() {
+                    self.watchpoints.remove(n);
+                } else {
+                    println!("no watchpoint {}", n);
+                }
+            }
+            Command::Info => {
+                for addr in &self.breakpoints {
+                    println!("break {}", addr);
+                }
+                for (i, w) in self.watchpoints.iter().enumerate() {
+                    println!("watch {}: {} = {}", i, describe(w.target), w.last);
+                }
+            }
+            Command::Resume(mode) => {
+                match self.resume(mode) {
+                    Ok(stop) => self.report(stop),
+                    Err(e) => println!("machine fault: {}", e),
+                }
+            }
+            Command::Regs => self.print_registers(),
+            Command::SetReg { index, value } => {
+                self.machine.set_register_value(index, value);
+                self.sync_watchpoints();
+            }
+            Command::Stack => {
+                for (depth, value) in self.machine.stack().iter().rev().enumerate() {
+                    println!("{:>4}: {}", depth, value);
+                }
+            }
+            Command::Peek { addr, len } => {
+                let memory = self.machine.memory();
+                let end = (addr + len).min(memory.len());
+                for (i, chunk) in memory[addr.min(end)..end].chunks(8).enumerate() {
+                    let words = chunk.iter().map(|w| format!("{:>6}", w)).collect::<String>();
+                    println!("{:<5}:{}", addr + i * 8, words);
+                }
+            }
+            Command::Poke { addr, values } => {
+                if addr + values.len() > self.machine.memory().len() {
+                    println!("error: write past end of memory");
+                } else {
+                    for (i, value) in values.into_iter().enumerate() {
+                        self.machine.write_memory(addr + i, value);
+                    }
+                    self.sync_watchpoints();
+                }
+            }
+            Command::Jump(addr) => {
+                self.machine.set_instruction_counter(addr);
+                self.print_location();
+            }
+            Command::Disas { addr, count } => {
+                let addr = addr.unwrap_or(self.machine.instruction_counter());
+                self.machine.dump_instructions(addr, count);
+            }
+            Command::Trace { path, range } => {
+                match fs::File::create(&path) {
+                    Ok(file) => self.machine.set_tracer(Some(Tracer::new(BufWriter::new(file), range))),
+                    Err(e) => println!("error: could not create {}: {}", path, e),
+                }
+            }
+            Command::TraceOff => self.machine.set_tracer(None),
+            Command::Profile => {
+                match self.machine.profiler() {
+                    Some(profiler) => print!("{}", profiler.report(20)),
+                    None => {
+                        self.machine.enable_profiler();
+                        println!("profiling");
+                    }
+                }
+            }
+            Command::Input(text) => self.machine.push_input(&format!("{}\n", text)),
+            Command::Source(path) => return self.source(&path),
+            Command::Help => println!("{}", HELP),
+            Command::Quit => return false,
+        }
+        true
+    }
+
+    /// Execute the commands in the script at `path`, returning false when one of them quits.
+    fn source(&mut self, path: &str) -> bool {
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(e) => {
+                println!("error: could not read {}: {}", path, e);
+                return true;
+            }
+        };
+        // A script that sources itself, directly or through others, would never end
+        let id = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        if self.sourcing.contains(&id) {
+            println!("error: {} is already being sourced", path);
+            return true;
+        }
+        self.sourcing.push(id);
+        let keep_going = script.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .all(|line| self.execute(line));
+        self.sourcing.pop();
+        keep_going
+    }
+
+    fn resume(&mut self, mode: Resume) -> operations::Result<Stop> {
+        let mut executed = 0;
+        let mut depth: i32 = 0;
+        loop {
+            if !self.machine.is_running() {
+                return Ok(Stop::Halted);
+            }
+            if self.machine.waiting_for_input() {
+                return Ok(Stop::WaitingForInput);
+            }
+            let ic = self.machine.instruction_counter();
+            // Never stop on the breakpoint we are resuming from.
+            if executed > 0 && self.breakpoints.contains(&ic) {
+                return Ok(Stop::Breakpoint(ic));
+            }
+
+            let op = self.machine.current_operation()?;
+            self.machine.step()?;
+            executed += 1;
+            match op {
+                Operation::Call { .. } => depth += 1,
+                Operation::Ret => depth -= 1,
+                _ => {}
+            }
+
+            if let Some(stop) = self.check_watchpoints() {
+                return Ok(stop);
+            }
+            match mode {
+                Resume::Step(n) if executed >= n => return Ok(Stop::Stepped),
+                Resume::Finish if depth < 0 => return Ok(Stop::Finished),
+                _ => {}
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        let mut stop = None;
+        for i in 0..self.watchpoints.len() {
+            let target = self.watchpoints[i].target;
+            let new = self.watched_value(target);
+            let old = std::mem::replace(&mut self.watchpoints[i].last, new);
+            if old != new && stop.is_none() {
+                stop = Some(Stop::Watchpoint { target, old, new });
+            }
+        }
+        stop
+    }
+
+    /// Refresh watchpoint values after a change made from the prompt, so it doesn't trigger them.
+    fn sync_watchpoints(&mut self) {
+        for i in 0..self.watchpoints.len() {
+            self.watchpoints[i].last = self.watched_value(self.watchpoints[i].target);
+        }
+    }
+
+    fn watched_value(&self, target: WatchTarget) -> u16 {
+        match target {
+            WatchTarget::Memory(addr) => self.machine.memory()[addr],
+            WatchTarget::Register(index) => self.machine.registers()[index],
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Breakpoint(addr) => println!("breakpoint at {}", addr),
+            Stop::Watchpoint { target, old, new } => {
+                println!("{} changed: {} -> {}", describe(target), old, new)
+            }
+            Stop::WaitingForInput => println!("waiting for game input, queue some with `input`"),
+            Stop::Halted => {
+                println!("machine halted");
+                return;
+            }
+            Stop::Stepped | Stop::Finished => {}
+        }
+        self.print_location();
+    }
+
+    fn print_location(&self) {
+        self.machine.dump_instructions(self.machine.instruction_counter(), 1);
+    }
+
+    fn print_registers(&self) {
+        let regs = self.machine.registers()
+            .iter()
+            .enumerate()
+            .map(|(i, r)| format!("r{}={}", i, r))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("ic={} {}", self.machine.instruction_counter(), regs);
+    }
+}
+
+fn describe(target: WatchTarget) -> String {
+    match target {
+        WatchTarget::Memory(addr) => format!("mem[{}]", addr),
+        WatchTarget::Register(index) => format!("r{}", index),
+    }
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut parts = line.split_whitespace();
+    let name = parts.next().ok_or("empty command")?;
+    let args = parts.collect::<Vec<_>>();
+    let command = match (name, args.as_slice()) {
+        ("break" | "b", [addr]) => Command::Break(parse_addr(addr)?),
+        ("delete", [addr]) => Command::Delete(parse_addr(addr)?),
+        ("watch", ["mem", addr]) => Command::Watch(WatchTarget::Memory(parse_addr(addr)?)),
+        ("watch", ["reg", reg]) => Command::Watch(WatchTarget::Register(parse_register(reg)?)),
+        ("unwatch", [n]) => Command::Unwatch(parse_number(n)? as usize),
+        ("info", []) => Command::Info,
+        ("step" | "s", []) => Command::Resume(Resume::Step(1)),
+        ("step" | "s", [n]) => Command::Resume(Resume::Step(parse_number(n)?.max(1) as usize)),
+        ("continue" | "c", []) => Command::Resume(Resume::Continue),
+        ("finish", []) => Command::Resume(Resume::Finish),
+        ("regs", []) => Command::Regs,
+        ("reg", [reg, value]) => Command::SetReg {
+            index: parse_register(reg)?,
+            value: parse_value(value)?,
+        },
+        ("stack", []) => Command::Stack,
+        ("peek" | "x", [addr]) => Command::Peek { addr: parse_addr(addr)?, len: 1 },
+        ("peek" | "x", [addr, len]) => Command::Peek {
+            addr: parse_addr(addr)?,
+            len: parse_number(len)? as usize,
+        },
+        ("poke", [addr, values @ ..]) if !values.is_empty() => Command::Poke {
+            addr: parse_addr(addr)?,
+            values: values.iter().map(|v| parse_value(v)).collect::<Result<_, _>>()?,
+        },
+        ("jump", [addr]) => Command::Jump(parse_addr(addr)?),
+        ("disas", []) => Command::Disas { addr: None, count: 10 },
+        ("disas", [addr]) => Command::Disas { addr: Some(parse_addr(addr)?), count: 10 },
+        ("disas", [addr, n]) => Command::Disas {
+            addr: Some(parse_addr(addr)?),
+            count: parse_number(n)? as usize,
+        },
+        ("trace", ["off"]) => Command::TraceOff,
+        ("trace", [path]) => Command::Trace { path: path.to_string(), range: 0..=usize::MAX },
+        ("trace", [path, range]) => Command::Trace {
+            path: path.to_string(),
+            range: trace::parse_range(range).ok_or_else(|| format!("invalid range `{}`", range))?,
+        },
+        ("profile", []) => Command::Profile,
+        ("input", _) => Command::Input(line.trim_start()["input".len()..].trim().to_string()),
+        ("source", [path]) => Command::Source(path.to_string()),
+        ("help" | "h", []) => Command::Help,
+        ("quit" | "q", []) => Command::Quit,
+        _ => return Err(format!("unrecognised command `{}`, try `help`", line)),
+    };
+    Ok(command)
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number `{}`", s))
+}
+
+fn parse_addr(s: &str) -> Result<usize, String> {
+    match parse_number(s)? {
+        n if n < 32768 => Ok(n as usize),
+        n => Err(format!("address {} is outside of memory", n)),
+    }
+}
+
+fn parse_value(s: &str) -> Result<u16, String> {
+    match parse_number(s)? {
+        n if n <= u16::MAX as u32 => Ok(n as u16),
+        n => Err(format!("value {} does not fit in 16 bits", n)),
+    }
+}
+
+fn parse_register(s: &str) -> Result<usize, String> {
+    match s.strip_prefix('r').map(str::parse::<usize>) {
+        Some(Ok(index)) if index < 8 => Ok(index),
+        _ => Err(format!("invalid register `{}`, expected r0..r7", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synacorvm::assembler::{assemble, to_bytes};
+    use crate::synacorvm::console::StreamConsole;
+    use super::*;
+
+    const PROGRAM: &str = r#"
+        set   r0, 1             ; 0
+        call  func              ; 3
+        wmem  cell, 7           ; 5
+        add   r1, r0, 1         ; 8
+        in    r2                ; 12
+        halt                    ; 14
+    func:
+        set   r0, 2             ; 15
+        ret                     ; 18
+    cell: .word 0               ; 19
+    "#;
+
+    type TestDebugger = Debugger<StreamConsole<&'static [u8], Vec<u8>>>;
+
+    fn debugger() -> TestDebugger {
+        let console = StreamConsole::new(&b""[..], Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 16, console);
+        machine.load_program_from_bytes(&to_bytes(&assemble(PROGRAM).unwrap()));
+        machine.reset();
+        Debugger::new(machine)
+    }
+
+    fn resume(debugger: &mut TestDebugger, mode: Resume) -> (Stop, usize) {
+        let stop = debugger.resume(mode).unwrap();
+        (stop, debugger.machine.instruction_counter())
+    }
+
+    #[test]
+    fn parses_commands() {
+        let tests: &[(&str, Command)] = &[
+            ("b 0x10", Command::Break(16)),
+            ("delete 3", Command::Delete(3)),
+            ("watch mem 19", Command::Watch(WatchTarget::Memory(19))),
+            ("watch reg r7", Command::Watch(WatchTarget::Register(7))),
+            ("unwatch 0", Command::Unwatch(0)),
+            ("s", Command::Resume(Resume::Step(1))),
+            ("step 0", Command::Resume(Resume::Step(1))),
+            ("step 5", Command::Resume(Resume::Step(5))),
+            ("c", Command::Resume(Resume::Continue)),
+            ("finish", Command::Resume(Resume::Finish)),
+            ("reg r1 0xffff", Command::SetReg { index: 1, value: 65535 }),
+            ("x 100", Command::Peek { addr: 100, len: 1 }),
+            ("peek 100 16", Command::Peek { addr: 100, len: 16 }),
+            ("poke 5 1 2 3", Command::Poke { addr: 5, values: vec![1, 2, 3] }),
+            ("disas", Command::Disas { addr: None, count: 10 }),
+            ("disas 6 2", Command::Disas { addr: Some(6), count: 2 }),
+            ("trace out.log 10-20", Command::Trace { path: "out.log".into(), range: 10..=20 }),
+            ("trace off", Command::TraceOff),
+            ("input  use  tablet ", Command::Input("use  tablet".into())),
+            ("source cmds.txt", Command::Source("cmds.txt".into())),
+            ("q", Command::Quit),
+        ];
+        for (line, expected) in tests {
+            assert_eq!(parse_command(line).as_ref(), Ok(expected), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let tests = [
+            ("", "empty command"),
+            ("frobnicate", "unrecognised command `frobnicate`, try `help`"),
+            ("break", "unrecognised command `break`, try `help`"),
+            ("break 1 2", "unrecognised command `break 1 2`, try `help`"),
+            ("break 32768", "address 32768 is outside of memory"),
+            ("break 0xzz", "invalid number `0xzz`"),
+            ("break -1", "invalid number `-1`"),
+            ("watch reg r8", "invalid register `r8`, expected r0..r7"),
+            ("watch stack 1", "unrecognised command `watch stack 1`, try `help`"),
+            ("reg r0 65536", "value 65536 does not fit in 16 bits"),
+            ("poke 5", "unrecognised command `poke 5`, try `help`"),
+            ("trace out.log 20-10", "invalid range `20-10`"),
+        ];
+        for (line, expected) in tests {
+            assert_eq!(parse_command(line).err().as_deref(), Some(expected), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn step_and_finish() {
+        let mut d = debugger();
+        assert_eq!(resume(&mut d, Resume::Step(1)), (Stop::Stepped, 3));
+        assert_eq!(resume(&mut d, Resume::Step(2)), (Stop::Stepped, 18));
+        assert_eq!(resume(&mut d, Resume::Finish), (Stop::Finished, 5));
+        assert_eq!(d.machine.registers()[0], 2);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut d = debugger();
+        d.execute("break 8");
+        d.execute("break 15");
+        d.execute("watch mem 19");
+        assert_eq!(resume(&mut d, Resume::Continue), (Stop::Breakpoint(15), 15));
+        let changed = Stop::Watchpoint { target: WatchTarget::Memory(19), old: 0, new: 7 };
+        assert_eq!(resume(&mut d, Resume::Continue), (changed, 8));
+        // Already at the breakpoint, so it does not stop there again
+        d.execute("watch reg r1");
+        let changed = Stop::Watchpoint { target: WatchTarget::Register(1), old: 0, new: 3 };
+        assert_eq!(resume(&mut d, Resume::Continue), (changed, 12));
+        assert_eq!(resume(&mut d, Resume::Continue), (Stop::WaitingForInput, 12));
+        d.execute("input x");
+        assert_eq!(resume(&mut d, Resume::Continue), (Stop::Halted, 14));
+        assert_eq!(d.machine.registers()[2], 'x' as u16);
+    }
+
+    #[test]
+    fn memory_commands_stay_inside_a_small_memory() {
+        let console = StreamConsole::new(&b""[..], Vec::new());
+        let mut d = Debugger::new(VirtualMachine::with_console(64, 16, console));
+        d.execute("watch mem 64");
+        d.execute("poke 63 1 2");
+        d.execute("peek 60 10");
+        assert!(d.watchpoints.is_empty());
+        assert_eq!(d.machine.memory()[63], 0);
+        d.execute("watch mem 63");
+        assert_eq!(d.watchpoints.len(), 1);
+    }
+
+    #[test]
+    fn scripts_cannot_source_themselves() {
+        let path = std::env::temp_dir().join(format!("sdb-loop-{}.txt", std::process::id()));
+        fs::write(&path, format!("break 8\n# comment\nsource {}\nbreak 12\n", path.display())).unwrap();
+        let mut d = debugger();
+        assert!(d.execute(&format!("source {}", path.display())));
+        assert_eq!(d.breakpoints, BTreeSet::from([8, 12]));
+        assert!(d.sourcing.is_empty());
+        fs::write(&path, "break 3\nquit\nbreak 5\n").unwrap();
+        assert!(!d.execute(&format!("source {}", path.display())));
+        assert!(d.breakpoints.contains(&3) && !d.breakpoints.contains(&5));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::synacorvm::operations::{Operand, Operation};
+
+/// Recursive-descent disassembly of a memory image.
+///
+/// Code is discovered by following every path from the entry points through `jmp`, `jt`, `jf` and
+/// `call` with literal targets. Everything that is never reached is data, where length-prefixed
+/// strings are recovered and the rest is left as raw words. Targets that only exist in registers
+/// at runtime cannot be followed, pass them as extra entry points instead.
+///
+/// The [`Display`](fmt::Display) output is a listing the assembler accepts, and assembling it
+/// reproduces the disassembled image word for word.
+pub struct Disassembly<'a> {
+    memory: &'a [u16],
+    code: BTreeMap<usize, Operation>,
+    strings: BTreeMap<usize, usize>,
+    labels: BTreeMap<usize, Label>,
+    xrefs: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Label {
+    Function,
+    Jump,
+    String,
+}
+
+impl<'a> Disassembly<'a> {
+    pub fn new(memory: &'a [u16], entry_points: &[usize]) -> Self {
+        // Zeroed memory past the end of the program is noise
+        let len = memory.iter().rposition(|w| *w != 0).map_or(0, |i| i + 1);
+        let mut disassembly = Self {
+            memory: &memory[..len],
+            code: BTreeMap::new(),
+            strings: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            xrefs: BTreeMap::new(),
+        };
+        disassembly.trace(entry_points);
+        disassembly.find_strings();
+        disassembly.label_string_references();
+        disassembly
+    }
+
+    /// Instruction starting at `addr`, if it was reached as code.
+    pub fn instruction_at(&self, addr: usize) -> Option<&Operation> {
+        self.code.get(&addr)
+    }
+
+    /// Addresses of the instructions that reference `addr` as a target or string.
+    pub fn references_to(&self, addr: usize) -> impl Iterator<Item = usize> + '_ {
+        self.xrefs.get(&addr).into_iter().flat_map(|refs| refs.iter().copied())
+    }
+
+    pub fn label(&self, addr: usize) -> Option<String> {
+        self.labels.get(&addr).map(|label| match label {
+            Label::Function => format!("fn_{}", addr),
+            Label::Jump => format!("l_{}", addr),
+            Label::String => format!("str_{}", addr),
+        })
+    }
+
+    /// Labels only appear in the listing at the start of an instruction or string.
+    fn emitted_label(&self, addr: usize) -> Option<String> {
+        if self.code.contains_key(&addr) || self.strings.contains_key(&addr) {
+            self.label(addr)
+        } else {
+            None
+        }
+    }
+
+    fn trace(&mut self, entry_points: &[usize]) {
+        // Which instruction each word of memory belongs to
+        let mut owner = vec![None; self.memory.len()];
+        // Registers with a value known from a `set` earlier in the same straight line of code,
+        // enough to follow the `set r0, <addr>` / `call r0` pairs the challenge uses.
+        let mut pending = entry_points.iter().map(|e| (*e, [None; 8])).collect::<Vec<_>>();
+        while let Some((addr, mut known)) = pending.pop() {
+            if addr >= self.memory.len() || owner[addr].is_some() {
+                continue;
+            }
+            let op = match Operation::decode(self.memory, addr) {
+                Ok(op) => op,
+                Err(_) => continue,
+            };
+            let len = op.instr_len();
+            if owner[addr..addr + len].iter().any(Option::is_some) {
+                continue;
+            }
+            owner[addr..addr + len].iter_mut().for_each(|o| *o = Some(addr));
+            self.code.insert(addr, op);
+
+            let next = addr + len;
+            match op {
+                Operation::Halt | Operation::Ret => {}
+                Operation::Jmp { tgt } => {
+                    if let Some(tgt) = self.reference(addr, tgt, &known, Label::Jump) {
+                        pending.push((tgt, [None; 8]));
+                    }
+                }
+                Operation::Jt { tgt, .. } | Operation::Jf { tgt, .. } => {
+                    if let Some(tgt) = self.reference(addr, tgt, &known, Label::Jump) {
+                        pending.push((tgt, [None; 8]));
+                    }
+                    pending.push((next, known));
+                }
+                Operation::Call { tgt } => {
+                    if let Some(tgt) = self.reference(addr, tgt, &known, Label::Function) {
+                        pending.push((tgt, [None; 8]));
+                    }
+                    // The callee may clobber anything
+                    pending.push((next, [None; 8]));
+                }
+                _ => {
+                    if let Some(Operand::Reg { index }) = op.destination() {
+                        known[index as usize] = match op {
+                            Operation::Set { src: Operand::Literal { value }, .. } => Some(value),
+                            _ => None,
+                        };
+                    }
+                    pending.push((next, known));
+                }
+            }
+        }
+    }
+
+    fn reference(&mut self, from: usize, tgt: Operand, known: &[Option<u16>; 8], label: Label) -> Option<usize> {
+        let tgt = match tgt {
+            Operand::Literal { value } => value as usize,
+            Operand::Reg { index } => known[index as usize]? as usize,
+        };
+        self.add_label(tgt, label);
+        self.xrefs.entry(tgt).or_default().insert(from);
+        Some(tgt)
+    }
+
+    fn add_label(&mut self, addr: usize, label: Label) {
+        // A function label wins over a jump label, calls say more about the target.
+        let entry = self.labels.entry(addr).or_insert(label);
+        *entry = (*entry).min(label);
+    }
+
+    /// Find length-prefixed runs of printable text in the words that weren't reached as code.
+    fn find_strings(&mut self) {
+        let mut addr = 0;
+        while addr < self.memory.len() {
+            if let Some(end) = self.data_end(addr) {
+                let len = self.memory[addr] as usize;
+                if len > 0 && addr + len < end && self.memory[addr + 1..=addr + len].iter().all(|w| is_text(*w)) {
+                    self.strings.insert(addr, len);
+                    addr += len + 1;
+                    continue;
+                }
+            }
+            addr += 1;
+        }
+    }
+
+    /// Literal operands pointing at a recovered string are most likely the string's address.
+    fn label_string_references(&mut self) {
+        let mut found = Vec::new();
+        for (addr, op) in &self.code {
+            let pointer = match *op {
+                Operation::Set { src, .. } |
+                Operation::Push { src } |
+                Operation::Rmem { src, .. } |
+                Operation::Wmem { dst: src, .. } => src,
+                _ => continue,
+            };
+            if let Operand::Literal { value } = pointer {
+                if self.strings.contains_key(&(value as usize)) {
+                    found.push((*addr, value as usize));
+                }
+            }
+        }
+        for (from, tgt) in found {
+            self.add_label(tgt, Label::String);
+            self.xrefs.entry(tgt).or_default().insert(from);
+        }
+    }
+
+    /// End of the run of data words starting at `addr`, `None` if `addr` is code.
+    fn data_end(&self, addr: usize) -> Option<usize> {
+        if self.owner_of(addr).is_some() {
+            return None;
+        }
+        let end = self.code.range(addr..).next().map_or(self.memory.len(), |(start, _)| *start);
+        Some(end)
+    }
+
+    fn owner_of(&self, addr: usize) -> Option<usize> {
+        self.code
+            .range(..=addr)
+            .next_back()
+            .filter(|(start, op)| addr < *start + op.instr_len())
+            .map(|(start, _)| *start)
+    }
+
+    fn format_operand(&self, op: &Operation, operand: Operand, position: usize) -> String {
+        match operand {
+            Operand::Reg { index } => format!("r{}", index),
+            Operand::Literal { value } => {
+                let is_target = match op {
+                    Operation::Jmp { .. } | Operation::Call { .. } => true,
+                    Operation::Jt { .. } | Operation::Jf { .. } => position == 1,
+                    Operation::Set { .. } | Operation::Rmem { .. } => position == 1,
+                    Operation::Push { .. } | Operation::Wmem { .. } => position == 0,
+                    _ => false,
+                };
+                match (is_target, self.emitted_label(value as usize)) {
+                    (true, Some(label)) => label,
+                    _ => match op {
+                        Operation::Out { .. } if is_text(value) => format!("'{}'", escape(value, '\'')),
+                        _ => value.to_string(),
+                    },
+                }
+            }
+        }
+    }
+
+    fn fmt_instruction(&self, f: &mut fmt::Formatter<'_>, addr: usize, op: &Operation) -> fmt::Result {
+        let operands = op.operands()
+            .into_iter()
+            .enumerate()
+            .map(|(i, operand)| self.format_operand(op, operand, i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let text = format!("{:<5} {}", op.mnemonic(), operands);
+        writeln!(f, "    {:<32}; {}", text.trim_end(), addr)
+    }
+
+    fn fmt_data(&self, f: &mut fmt::Formatter<'_>, start: usize, end: usize) -> fmt::Result {
+        let mut addr = start;
+        while addr < end {
+            if let Some(len) = self.strings.get(&addr) {
+                if let Some(label) = self.label(addr) {
+                    writeln!(f, "{}:", label)?;
+                }
+                let text = self.memory[addr + 1..=addr + len]
+                    .iter()
+                    .map(|w| escape(*w, '"'))
+                    .collect::<String>();
+                writeln!(f, "    {:<32}; {}", format!(".word {}", len), addr)?;
+                writeln!(f, "    .string \"{}\"", text)?;
+                addr += len + 1;
+                continue;
+            }
+            let row_end = self.strings.range(addr..end).next().map_or(end, |(s, _)| *s).min(addr + 8);
+            let words = self.memory[addr..row_end]
+                .iter()
+                .map(u16::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "    {:<32}; {}", format!(".word {}", words), addr)?;
+            addr = row_end;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut addr = 0;
+        while addr < self.memory.len() {
+            match self.code.get(&addr) {
+                Some(op) => {
+                    if let Some(label) = self.label(addr) {
+                        writeln!(f, "{}:", label)?;
+                    }
+                    self.fmt_instruction(f, addr, op)?;
+                    addr += op.instr_len();
+                }
+                None => {
+                    let end = self.data_end(addr).unwrap_or(addr + 1);
+                    writeln!(f)?;
+                    self.fmt_data(f, addr, end)?;
+                    writeln!(f)?;
+                    addr = end;
+                }
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "; cross references")?;
+        for (addr, refs) in &self.xrefs {
+            let label = self.label(*addr).unwrap_or_else(|| addr.to_string());
+            let refs = refs.iter().map(usize::to_string).collect::<Vec<_>>().join(" ");
+            writeln!(f, "; {:<12} {}", label, refs)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_text(w: u16) -> bool {
+    (32..127).contains(&w) || w == b'\n' as u16
+}
+
+/// Render a printable word inside a literal delimited by `quote`.
+fn escape(w: u16, quote: char) -> String {
+    match char::from_u32(w as u32).unwrap_or('?') {
+        '\n' => "\\n".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if c == quote => format!("\\{}", c),
+        c => c.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_reachable_code_from_data() {
+        // jmp 4; <junk>; call 8; halt; out r0; ret
+        let memory = [6, 4, 19, 65, 17, 8, 0, 21, 19, 32768, 18];
+        let disassembly = Disassembly::new(&memory, &[0]);
+        assert_eq!(disassembly.instruction_at(0), Some(&Operation::Jmp { tgt: Operand::Literal { value: 4 } }));
+        assert_eq!(disassembly.instruction_at(2), None);
+        assert_eq!(disassembly.instruction_at(7), None);
+        assert!(disassembly.instruction_at(8).is_some());
+        assert_eq!(disassembly.label(4).as_deref(), Some("l_4"));
+        assert_eq!(disassembly.label(8).as_deref(), Some("fn_8"));
+        assert_eq!(disassembly.references_to(8).collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[test]
+    fn follows_calls_through_registers_set_just_before() {
+        // set r1 6; call r1; halt; ret
+        let memory = [1, 32769, 6, 17, 32769, 0, 18];
+        let disassembly = Disassembly::new(&memory, &[0]);
+        assert_eq!(disassembly.instruction_at(6), Some(&Operation::Ret));
+        assert_eq!(disassembly.references_to(6).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn recovers_referenced_strings() {
+        // set r0 4; halt; "hi\n" with its length prefix
+        let memory = [1, 32768, 4, 0, 3, 104, 105, 10];
+        let listing = Disassembly::new(&memory, &[0]).to_string();
+        assert!(listing.contains("set   r0, str_4"));
+        assert!(listing.contains("str_4:\n    .word 3"));
+        assert!(listing.contains(".string \"hi\\n\""));
+        assert!(listing.contains("; str_4        0"));
+    }
+}
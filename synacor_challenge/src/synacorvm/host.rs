@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use crate::synacorvm::console::{Console, StdConsole};
+use crate::synacorvm::operations;
+use crate::synacorvm::savestate::{self, SaveState};
+use crate::synacorvm::virtual_machine::{State, VirtualMachine};
+
+/// Runs a machine for a player, intercepting meta commands typed at the game prompt before the
+/// game ever sees them:
+///
+/// - `save` / `load` push and pop snapshots of the machine
+/// - `save <name>` / `load <name>` write and read the save slot `name` on disk
+/// - `commands` lists every line entered so far
+/// - `reg8 [value]` sets the eighth register to the teleporter energy level, 25734 by default
+/// - `poke <addr> <value>` writes a word into memory, e.g. to patch out the teleporter check
+///
+/// When the game halts (usually because the player died) the last save is loaded instead.
+pub struct Host<C = StdConsole> {
+    machine: VirtualMachine<C>,
+    states: Vec<State>,
+    commands: Vec<String>,
+    script: VecDeque<String>,
+    save_dir: PathBuf,
+}
+
+impl<C: Console> Host<C> {
+    pub fn new(machine: VirtualMachine<C>) -> Self {
+        Self {
+            machine,
+            states: Vec::new(),
+            commands: Vec::new(),
+            script: VecDeque::new(),
+            save_dir: PathBuf::from("saves"),
+        }
+    }
+
+    /// Directory the named save slots are kept in, `saves` unless changed.
+    pub fn set_save_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.save_dir = dir.into();
+    }
+
+    /// Continue from a save state instead of booting the program.
+    pub fn resume_from(&mut self, save: SaveState) {
+        self.machine.restore(save.state);
+        self.commands = save.commands;
+        self.machine.push_input("look\n");
+    }
+
+    /// Queue lines to be entered before anything is read from the console.
+    pub fn queue_commands<I, S>(&mut self, lines: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        for line in lines {
+            let mut line = line.into();
+            if !line.ends_with('\n') {
+                line.push('\n');
+            }
+            self.script.push_back(line);
+        }
+    }
+
+    pub fn machine(&self) -> &VirtualMachine<C> {
+        &self.machine
+    }
+
+    pub fn run(&mut self) -> operations::Result<()> {
+        if !self.machine.is_running() {
+            self.machine.reset();
+        }
+        loop {
+            if !self.machine.is_running() {
+                if !self.load() {
+                    return Ok(());
+                }
+                continue;
+            }
+            if self.machine.waiting_for_input() {
+                let line = match self.script.pop_front() {
+                    Some(line) => line,
+                    None => match self.machine.console_mut().read_line() {
+                        Some(line) => line,
+                        None => return Ok(()),
+                    },
+                };
+                self.enter(line);
+                continue;
+            }
+            self.machine.run_for(usize::MAX)?;
+        }
+    }
+
+    fn enter(&mut self, line: String) {
+        if line != "commands\n" {
+            self.commands.push(line.clone());
+        }
+
+        match line.trim_end().split_once(' ') {
+            Some(("save", name)) => return self.save_slot(name.trim()),
+            Some(("load", name)) => return self.load_slot(name.trim()),
+            Some(("reg8", value)) => return self.set_eighth_register(value.trim()),
+            Some(("poke", args)) => return self.poke(args.trim()),
+            _ => {}
+        }
+        match line.as_str() {
+            "save\n" => {
+                self.states.push(self.machine.snapshot());
+                let msg = format!("State saved: {}\n", self.states.len());
+                self.machine.console_mut().write_str(&msg);
+                self.machine.push_input("look\n");
+            }
+            "load\n" => {
+                self.load();
+            }
+            "commands\n" => {
+                let mut listing = String::from("START COMMANDS\n");
+                self.commands.iter().for_each(|c| listing.push_str(c));
+                listing.push_str("END COMMANDS\n");
+                self.machine.console_mut().write_str(&listing);
+            }
+            "reg8\n" => self.set_eighth_register("25734"),
+            _ => self.machine.push_input(&line),
+        }
+    }
+
+    fn save_slot(&mut self, name: &str) {
+        let msg = match savestate::slot_path(&self.save_dir, name) {
+            None => format!("Invalid save slot name: {}\n", name),
+            Some(path) => {
+                let save = SaveState {
+                    state: self.machine.snapshot(),
+                    commands: self.commands.clone(),
+                };
+                match save.save(&path) {
+                    Ok(()) => format!("State saved to {}\n", path.display()),
+                    Err(e) => format!("Could not save {}: {}\n", path.display(), e),
+                }
+            }
+        };
+        self.machine.console_mut().write_str(&msg);
+        self.machine.push_input("look\n");
+    }
+
+    fn load_slot(&mut self, name: &str) {
+        let msg = match savestate::slot_path(&self.save_dir, name) {
+            None => format!("Invalid save slot name: {}\n", name),
+            Some(path) => match SaveState::load(&path) {
+                Ok(save) => {
+                    self.resume_from(save);
+                    format!("State loaded from {}\n", path.display())
+                }
+                Err(e) => {
+                    self.machine.push_input("look\n");
+                    format!("Could not load {}: {}\n", path.display(), e)
+                }
+            },
+        };
+        self.machine.console_mut().write_str(&msg);
+    }
+
+    fn set_eighth_register(&mut self, value: &str) {
+        let msg = match value.parse::<u16>() {
+            Ok(value) if value < 32768 => {
+                self.machine.set_register_value(7, value);
+                "Buffer set\n".to_string()
+            }
+            _ => format!("Invalid register value: {}\n", value),
+        };
+        self.machine.console_mut().write_str(&msg);
+    }
+
+    fn poke(&mut self, args: &str) {
+        let parsed = args.split_once(' ').and_then(|(addr, value)| {
+            Some((addr.parse::<usize>().ok()?, value.trim().parse::<u16>().ok()?))
+        });
+        let msg = match parsed {
+            Some((addr, value)) if self.machine.write_memory(addr, value) => {
+                format!("Memory at {} set to {}\n", addr, value)
+            }
+            _ => format!("Invalid poke: {}\n", args),
+        };
+        self.machine.console_mut().write_str(&msg);
+    }
+
+    fn load(&mut self) -> bool {
+        match self.states.pop() {
+            None => {
+                self.machine.console_mut().write_str("No saved states to revert to\n");
+                false
+            }
+            Some(state) => {
+                self.machine.restore(state);
+                let msg = format!("loaded states: states left : {}\n", self.states.len());
+                self.machine.console_mut().write_str(&msg);
+                self.machine.push_input("look\n");
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synacorvm::console::StreamConsole;
+    use super::*;
+
+    fn play(script: &'static str) -> String {
+        let console = StreamConsole::new(script.as_bytes(), Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, console);
+        machine.load_program_from_bytes(include_bytes!("../../spec/challenge.bin"));
+        let mut host = Host::new(machine);
+        host.run().unwrap();
+        String::from_utf8(host.machine().console().output().clone()).unwrap()
+    }
+
+    #[test]
+    fn challenge_passes_self_test() {
+        let output = play("");
+        assert!(output.contains("self-test complete, all tests pass"));
+        assert!(output.contains("== Foothills =="));
+    }
+
+    #[test]
+    fn challenge_reveals_tablet_code() {
+        let output = play("take tablet\nuse tablet\n");
+        assert!(output.contains("You find yourself writing \"pWDWTEfURAdS\" on the tablet."));
+    }
+
+    #[test]
+    fn meta_commands_stay_in_host() {
+        let output = play("take tablet\nsave\ndrop tablet\nload\ncommands\n");
+        assert!(output.contains("State saved: 1"));
+        assert!(output.contains("loaded states: states left : 0"));
+        assert!(output.contains("START COMMANDS\ntake tablet\nsave\ndrop tablet\nload\nEND COMMANDS\n"));
+        assert!(!output.contains("I don't understand"));
+    }
+
+    #[test]
+    fn queued_commands_run_before_console() {
+        let console = StreamConsole::new("use tablet\n".as_bytes(), Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, console);
+        machine.load_program_from_bytes(include_bytes!("../../spec/challenge.bin"));
+        let mut host = Host::new(machine);
+        host.queue_commands(["take tablet"]);
+        host.run().unwrap();
+        let output = String::from_utf8_lossy(host.machine().console().output());
+        assert!(output.contains("pWDWTEfURAdS"));
+    }
+
+    #[test]
+    fn named_slots_survive_a_new_host() {
+        let dir = std::env::temp_dir().join(format!("synacor-saves-{}", std::process::id()));
+        let challenge = include_bytes!("../../spec/challenge.bin");
+
+        let console = StreamConsole::new("take tablet\nsave tablet\n".as_bytes(), Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, console);
+        machine.load_program_from_bytes(challenge);
+        let mut host = Host::new(machine);
+        host.set_save_dir(&dir);
+        host.run().unwrap();
+
+        let console = StreamConsole::new("load tablet\nuse tablet\ncommands\n".as_bytes(), Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, console);
+        machine.load_program_from_bytes(challenge);
+        let mut host = Host::new(machine);
+        host.set_save_dir(&dir);
+        host.run().unwrap();
+        let output = String::from_utf8_lossy(host.machine().console().output()).to_string();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.contains("pWDWTEfURAdS"));
+        assert!(output.contains("START COMMANDS\ntake tablet\nsave tablet\nuse tablet\nEND COMMANDS"));
+    }
+}
@@ -1,3 +1,10 @@
 pub mod virtual_machine;
 pub mod operations;
-mod storage;
\ No newline at end of file
+pub mod console;
+pub mod host;
+pub mod savestate;
+pub mod trace;
+pub mod debugger;
+pub mod disassembler;
+pub mod assembler;
+mod blocks;
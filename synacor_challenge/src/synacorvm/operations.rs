@@ -1,20 +1,94 @@
-use std::result;
-use crate::synacorvm::operations::Error::OperandValueToHigh;
+use std::{fmt, result};
 
-#[derive(Debug)]
+/// Everything that can go wrong decoding or executing an instruction. `addr` is always the
+/// address of the faulting instruction.
+#[derive(Debug, PartialEq, Eq)]
 pub enum Error {
+    /// The instruction counter points past the end of memory.
+    InstructionOutOfBounds {
+        addr: usize,
+    },
+    /// The instruction's operands run past the end of memory.
+    TruncatedInstruction {
+        addr: usize,
+        code: u16,
+    },
     UnknownOpcode {
+        addr: usize,
         code: u16,
     },
+    /// An operand in the invalid range 32776..65535.
     OperandValueToHigh {
+        addr: usize,
+        value: u16,
+    },
+    /// Writing a result into a literal rather than a register.
+    LiteralDestination {
+        addr: usize,
+        op: Operation,
+    },
+    /// `pop` with nothing on the stack.
+    EmptyStack {
+        addr: usize,
+        op: Operation,
+    },
+    /// `out` of a value that isn't ascii.
+    InvalidCharacter {
+        addr: usize,
+        op: Operation,
         value: u16,
     },
+    /// `rmem` or `wmem` of an address outside of memory.
+    MemoryOutOfBounds {
+        addr: usize,
+        op: Operation,
+        target: u16,
+    },
+    /// `mod` by zero.
+    DivisionByZero {
+        addr: usize,
+        op: Operation,
+    },
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+impl Error {
+    pub fn addr(&self) -> usize {
+        match *self {
+            Error::InstructionOutOfBounds { addr } |
+            Error::TruncatedInstruction { addr, .. } |
+            Error::UnknownOpcode { addr, .. } |
+            Error::OperandValueToHigh { addr, .. } |
+            Error::LiteralDestination { addr, .. } |
+            Error::EmptyStack { addr, .. } |
+            Error::InvalidCharacter { addr, .. } |
+            Error::MemoryOutOfBounds { addr, .. } |
+            Error::DivisionByZero { addr, .. } => addr,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InstructionOutOfBounds { addr } => write!(f, "{}: instruction is outside of memory", addr),
+            Error::TruncatedInstruction { addr, code } => write!(f, "{}: opcode {} is cut off by the end of memory", addr, code),
+            Error::UnknownOpcode { addr, code } => write!(f, "{}: unknown opcode {}", addr, code),
+            Error::OperandValueToHigh { addr, value } => write!(f, "{}: operand value {} is too high", addr, value),
+            Error::LiteralDestination { addr, op } => write!(f, "{}: `{}` cannot write into a literal", addr, op),
+            Error::EmptyStack { addr, op } => write!(f, "{}: `{}` on an empty stack", addr, op),
+            Error::InvalidCharacter { addr, op, value } => write!(f, "{}: `{}` of {}, which isn't ascii", addr, op, value),
+            Error::MemoryOutOfBounds { addr, op, target } => write!(f, "{}: `{}` of address {} outside of memory", addr, op, target),
+            Error::DivisionByZero { addr, op } => write!(f, "{}: `{}` divides by zero", addr, op),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
 
-#[derive(Debug)]
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Operation {
     Halt,
     Set { dst: Operand, src: Operand },
@@ -40,25 +114,168 @@ pub enum Operation {
     Noop,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Operand {
     Literal { value: u16 },
     Reg { index: u16 },
 }
 
 impl Operand {
-    fn from_raw(raw: u16) -> Result<Self> {
+    fn from_raw(raw: u16) -> Option<Self> {
         if raw <= 32767 {
-            Ok(Operand::Literal { value: raw })
+            Some(Operand::Literal { value: raw })
         } else if raw <= 32775 {
-            Ok(Operand::Reg { index: raw - 32768 })
+            Some(Operand::Reg { index: raw - 32768 })
         } else {
-            Err(OperandValueToHigh { value: raw })
+            None
+        }
+    }
+
+    pub fn to_raw(&self) -> u16 {
+        match self {
+            Operand::Literal { value } => *value,
+            Operand::Reg { index } => index + 32768,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Literal { value } => write!(f, "{}", value),
+            Operand::Reg { index } => write!(f, "r{}", index),
         }
     }
 }
 
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        for (i, operand) in self.operands().iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", sep, operand)?;
+        }
+        Ok(())
+    }
+}
+
 impl Operation {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Operation::Halt => "halt",
+            Operation::Set { .. } => "set",
+            Operation::Push { .. } => "push",
+            Operation::Pop { .. } => "pop",
+            Operation::Eq { .. } => "eq",
+            Operation::Gt { .. } => "gt",
+            Operation::And { .. } => "and",
+            Operation::Or { .. } => "or",
+            Operation::Not { .. } => "not",
+            Operation::Rmem { .. } => "rmem",
+            Operation::Wmem { .. } => "wmem",
+            Operation::Jmp { .. } => "jmp",
+            Operation::Jt { .. } => "jt",
+            Operation::Jf { .. } => "jf",
+            Operation::Add { .. } => "add",
+            Operation::Mult { .. } => "mult",
+            Operation::Mod { .. } => "mod",
+            Operation::Call { .. } => "call",
+            Operation::Ret => "ret",
+            Operation::Out { .. } => "out",
+            Operation::In { .. } => "in",
+            Operation::Noop => "noop"
+        }
+    }
+
+    pub fn opcode(&self) -> u16 {
+        match self {
+            Operation::Halt => 0,
+            Operation::Set { .. } => 1,
+            Operation::Push { .. } => 2,
+            Operation::Pop { .. } => 3,
+            Operation::Eq { .. } => 4,
+            Operation::Gt { .. } => 5,
+            Operation::Jmp { .. } => 6,
+            Operation::Jt { .. } => 7,
+            Operation::Jf { .. } => 8,
+            Operation::Add { .. } => 9,
+            Operation::Mult { .. } => 10,
+            Operation::Mod { .. } => 11,
+            Operation::And { .. } => 12,
+            Operation::Or { .. } => 13,
+            Operation::Not { .. } => 14,
+            Operation::Rmem { .. } => 15,
+            Operation::Wmem { .. } => 16,
+            Operation::Call { .. } => 17,
+            Operation::Ret => 18,
+            Operation::Out { .. } => 19,
+            Operation::In { .. } => 20,
+            Operation::Noop => 21,
+        }
+    }
+
+    /// Opcode and operand count of the operation named `mnemonic`.
+    pub fn opcode_of(mnemonic: &str) -> Option<(u16, usize)> {
+        (0..=21)
+            .filter_map(|code| Operation::from(&[code, 0, 0, 0]).ok())
+            .find(|op| op.mnemonic() == mnemonic)
+            .map(|op| (op.opcode(), op.instr_len() - 1))
+    }
+
+    /// The instruction as it is laid out in memory.
+    pub fn encode(&self) -> Vec<u16> {
+        let mut words = vec![self.opcode()];
+        words.extend(self.operands().iter().map(Operand::to_raw));
+        words
+    }
+
+    /// The operand this operation writes its result into, which has to be a register.
+    pub fn destination(&self) -> Option<Operand> {
+        match *self {
+            Operation::Set { dst, .. } |
+            Operation::Pop { dst } |
+            Operation::Eq { dst, .. } |
+            Operation::Gt { dst, .. } |
+            Operation::And { dst, .. } |
+            Operation::Or { dst, .. } |
+            Operation::Not { dst, .. } |
+            Operation::Rmem { dst, .. } |
+            Operation::Add { dst, .. } |
+            Operation::Mult { dst, .. } |
+            Operation::Mod { dst, .. } |
+            Operation::In { dst } => Some(dst),
+            _ => None,
+        }
+    }
+
+    /// Operands in the order they are encoded.
+    pub fn operands(&self) -> Vec<Operand> {
+        match *self {
+            Operation::Halt |
+            Operation::Ret |
+            Operation::Noop => vec![],
+            Operation::Push { src } |
+            Operation::Out { src } => vec![src],
+            Operation::Pop { dst } |
+            Operation::In { dst } => vec![dst],
+            Operation::Jmp { tgt } |
+            Operation::Call { tgt } => vec![tgt],
+            Operation::Jt { src, tgt } |
+            Operation::Jf { src, tgt } => vec![src, tgt],
+            Operation::Set { dst, src } |
+            Operation::Not { dst, src } |
+            Operation::Rmem { dst, src } |
+            Operation::Wmem { dst, src } => vec![dst, src],
+            Operation::Eq { dst, lhs, rhs } |
+            Operation::Gt { dst, lhs, rhs } |
+            Operation::And { dst, lhs, rhs } |
+            Operation::Or { dst, lhs, rhs } |
+            Operation::Add { dst, lhs, rhs } |
+            Operation::Mult { dst, lhs, rhs } |
+            Operation::Mod { dst, lhs, rhs } => vec![dst, lhs, rhs],
+        }
+    }
+
     pub fn instr_len(&self) -> usize {
         match self {
             Operation::Halt |
@@ -98,88 +315,98 @@ impl Operation {
 impl Operation {
     /// Attempt to parse operation starting at beginning of `raw`.
     pub fn from(raw: &[u16]) -> Result<Self> {
-        let op = match raw[0] {
+        Self::decode(raw, 0)
+    }
+
+    /// Attempt to parse the operation at `addr` in `memory`.
+    pub fn decode(memory: &[u16], addr: usize) -> Result<Self> {
+        let code = *memory.get(addr).ok_or(Error::InstructionOutOfBounds { addr })?;
+        let operand = |i: usize| {
+            let raw = *memory.get(addr + i).ok_or(Error::TruncatedInstruction { addr, code })?;
+            Operand::from_raw(raw).ok_or(Error::OperandValueToHigh { addr, value: raw })
+        };
+        let op = match code {
             0 => Operation::Halt,
             1 => Operation::Set {
-                dst: Operand::from_raw(raw[1])?,
-                src: Operand::from_raw(raw[2])?,
+                dst: operand(1)?,
+                src: operand(2)?,
             },
             2 => Operation::Push {
-                src: Operand::from_raw(raw[1])?,
+                src: operand(1)?,
             },
             3 => Operation::Pop {
-                dst: Operand::from_raw(raw[1])?,
+                dst: operand(1)?,
             },
             4 => Operation::Eq {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             5 => Operation::Gt {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             6 => Operation::Jmp {
-                tgt: Operand::from_raw(raw[1])?,
+                tgt: operand(1)?,
             },
             7 => Operation::Jt {
-                src: Operand::from_raw(raw[1])?,
-                tgt: Operand::from_raw(raw[2])?,
+                src: operand(1)?,
+                tgt: operand(2)?,
             },
             8 => Operation::Jf {
-                src: Operand::from_raw(raw[1])?,
-                tgt: Operand::from_raw(raw[2])?,
+                src: operand(1)?,
+                tgt: operand(2)?,
             },
             9 => Operation::Add {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             10 => Operation::Mult {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             11 => Operation::Mod {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             12 => Operation::And {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             13 => Operation::Or {
-                dst: Operand::from_raw(raw[1])?,
-                lhs: Operand::from_raw(raw[2])?,
-                rhs: Operand::from_raw(raw[3])?,
+                dst: operand(1)?,
+                lhs: operand(2)?,
+                rhs: operand(3)?,
             },
             14 => Operation::Not {
-                dst: Operand::from_raw(raw[1])?,
-                src: Operand::from_raw(raw[2])?,
+                dst: operand(1)?,
+                src: operand(2)?,
             },
             15 => Operation::Rmem {
-                dst: Operand::from_raw(raw[1])?,
-                src: Operand::from_raw(raw[2])?,
+                dst: operand(1)?,
+                src: operand(2)?,
             },
             16 => Operation::Wmem {
-                dst: Operand::from_raw(raw[1])?,
-                src: Operand::from_raw(raw[2])?,
+                dst: operand(1)?,
+                src: operand(2)?,
             },
             17 => Operation::Call {
-                tgt: Operand::from_raw(raw[1])?,
+                tgt: operand(1)?,
             },
             18 => Operation::Ret,
             19 => Operation::Out {
-                src: Operand::from_raw(raw[1])?
+                src: operand(1)?
             },
             20 => Operation::In {
-                dst: Operand::from_raw(raw[1])?
+                dst: operand(1)?
             },
             21 => Operation::Noop,
-            code => return Err(Error::UnknownOpcode { code }),
+            code => return Err(Error::UnknownOpcode { addr, code }),
         };
         Ok(op)
     }
@@ -188,33 +415,8 @@ impl Operation {
 pub fn print_op(addr: usize, memory: &[u16]) -> Result<usize> {
     let op = Operation::from(memory)?;
     print!("{:<4}: ", addr);
-    let alias = match op {
-        Operation::Halt => "halt",
-        Operation::Set { .. } => "set",
-        Operation::Push { .. } => "push",
-        Operation::Pop { .. } => "pop",
-        Operation::Eq { .. } => "eq",
-        Operation::Gt { .. } => "gt",
-        Operation::And { .. } => "and",
-        Operation::Or { .. } => "or",
-        Operation::Not { .. } => "not",
-        Operation::Rmem { .. } => "rmem",
-        Operation::Wmem { .. } => "wmem",
-        Operation::Jmp { .. } => "jmp",
-        Operation::Jt { .. } => "jt",
-        Operation::Jf { .. } => "jf",
-        Operation::Add { .. } => "add",
-        Operation::Mult { .. } => "mult",
-        Operation::Mod { .. } => "mod",
-        Operation::Call { .. } => "call",
-        Operation::Ret => "ret",
-        Operation::Out { .. } => "out",
-        Operation::In { .. } => "in",
-        Operation::Noop => "noop"
-    };
-    print!("{:<4}", alias);
-    for i in 1..op.instr_len() {
-        let o = Operand::from_raw(memory[i])?;
+    print!("{:<4}", op.mnemonic());
+    for o in op.operands() {
         let s = match o {
             Operand::Literal { value } => {
                 if let Operation::Out { .. } = op {
@@ -229,4 +431,4 @@ pub fn print_op(addr: usize, memory: &[u16]) -> Result<usize> {
     }
     println!();
     Ok(op.instr_len())
-}
\ No newline at end of file
+}
@@ -0,0 +1,208 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::synacorvm::virtual_machine::State;
+
+const MAGIC: &[u8; 8] = b"SYNSTATE";
+const VERSION: u16 = 1;
+/// The whole 15-bit address space, every machine that can be saved has exactly this much memory.
+const MEMORY_WORDS: usize = 1 << 15;
+
+/// A machine snapshot together with the commands that led to it, as stored on disk.
+///
+/// The file is little-endian throughout: the magic and a version, then the instruction counter,
+/// running flag, registers, and length-prefixed memory, stack, pending input and command history.
+pub struct SaveState {
+    pub state: State,
+    pub commands: Vec<String>,
+}
+
+impl SaveState {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let state = &self.state;
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        write_u32(w, state.instruction_counter as u32)?;
+        w.write_all(&[state.running as u8])?;
+        write_words(w, &state.registers)?;
+        write_u32(w, state.memory.len() as u32)?;
+        write_words(w, &state.memory)?;
+        write_u32(w, state.stack.len() as u32)?;
+        write_words(w, &state.stack)?;
+        write_u32(w, state.input.len() as u32)?;
+        write_words(w, &state.input.iter().copied().collect::<Vec<_>>())?;
+        write_u32(w, self.commands.len() as u32)?;
+        for command in &self.commands {
+            write_u32(w, command.len() as u32)?;
+            w.write_all(command.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+        let version = read_u16(r)?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported save state version {}", version)));
+        }
+
+        let instruction_counter = read_u32(r)? as usize;
+        let mut running = [0];
+        r.read_exact(&mut running)?;
+        let mut registers = [0; 8];
+        for register in registers.iter_mut() {
+            *register = read_u16(r)?;
+        }
+        let memory = read_words(r)?;
+        let stack = read_words(r)?;
+        let input = read_words(r)?.into();
+        let count = read_u32(r)?;
+        let mut commands = Vec::new();
+        for _ in 0..count {
+            let len = read_u32(r)? as usize;
+            let mut bytes = Vec::new();
+            r.take(len as u64).read_to_end(&mut bytes)?;
+            if bytes.len() != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            commands.push(String::from_utf8(bytes).map_err(|_| invalid("command is not utf-8"))?);
+        }
+
+        if memory.len() != MEMORY_WORDS {
+            return Err(invalid(&format!("memory is {} words instead of {}", memory.len(), MEMORY_WORDS)));
+        }
+        if instruction_counter >= memory.len() {
+            return Err(invalid("instruction counter is outside of memory"));
+        }
+        let state = State {
+            instruction_counter,
+            running: running[0] != 0,
+            registers,
+            memory,
+            stack,
+            input,
+        };
+        Ok(Self { state, commands })
+    }
+}
+
+/// Where the save slot `name` lives in `dir`. Names are limited to letters, digits, `-` and `_`
+/// so a slot can never point outside of `dir`.
+pub fn slot_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| dir.join(format!("{}.state", name)))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_words(w: &mut impl Write, words: &[u16]) -> io::Result<()> {
+    let bytes = words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>();
+    w.write_all(&bytes)
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    r.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_words(r: &mut impl Read) -> io::Result<Vec<u16>> {
+    let len = read_u32(r)? as usize;
+    // Memory is at most 2^15 words, anything much larger is corrupt rather than a reason to allocate
+    if len > 1 << 20 {
+        return Err(invalid("length is too large"));
+    }
+    (0..len).map(|_| read_u16(r)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synacorvm::virtual_machine::VirtualMachine;
+    use super::*;
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut machine = VirtualMachine::default();
+        machine.load_program_from_bytes(&[9, 0, 1, 128, 2, 0, 3, 0]);
+        machine.set_register_value(7, 25734);
+        machine.push_input("look\n");
+        let save = SaveState {
+            state: machine.snapshot(),
+            commands: vec!["take tablet\n".to_string(), "use tablet\n".to_string()],
+        };
+
+        let mut bytes = Vec::new();
+        save.write_to(&mut bytes).unwrap();
+        let loaded = SaveState::read_from(&mut bytes.as_slice()).unwrap();
+
+        let mut restored = VirtualMachine::default();
+        restored.restore(loaded.state);
+        assert_eq!(restored.registers(), machine.registers());
+        assert_eq!(restored.memory(), machine.memory());
+        assert!(!restored.waiting_for_input());
+        assert_eq!(loaded.commands, save.commands);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let err = SaveState::read_from(&mut b"not a save state at all".as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = SaveState::read_from(&mut &MAGIC[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_other_memory_sizes() {
+        let save = |memory: Vec<u16>| {
+            let mut machine = VirtualMachine::default();
+            machine.load_program_from_bytes(&[21, 0, 0, 0]);
+            let mut state = machine.snapshot();
+            state.memory = memory;
+            let mut bytes = Vec::new();
+            SaveState { state, commands: Vec::new() }.write_to(&mut bytes).unwrap();
+            SaveState::read_from(&mut bytes.as_slice())
+        };
+        assert!(save(vec![21; MEMORY_WORDS]).is_ok());
+        for len in [2, MEMORY_WORDS - 1, MEMORY_WORDS + 1, 1 << 20] {
+            let err = save(vec![21; len]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{} words", len);
+        }
+    }
+
+    #[test]
+    fn slot_names_stay_inside_directory() {
+        let dir = Path::new("saves");
+        assert_eq!(slot_path(dir, "before-vault_2"), Some(dir.join("before-vault_2.state")));
+        assert_eq!(slot_path(dir, "../etc/passwd"), None);
+        assert_eq!(slot_path(dir, ""), None);
+    }
+}
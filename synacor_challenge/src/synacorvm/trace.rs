@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use crate::synacorvm::operations::Operation;
+
+/// Writes one line per executed instruction, with the registers and stack depth it was executed
+/// with, for instructions whose address is in `range`.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    range: RangeInclusive<usize>,
+}
+
+impl Tracer {
+    pub fn new(output: impl Write + 'static, range: RangeInclusive<usize>) -> Self {
+        Self {
+            output: Box::new(output),
+            range,
+        }
+    }
+
+    pub(crate) fn record(&mut self, addr: usize, op: &Operation, registers: &[u16; 8], stack_depth: usize) {
+        if !self.range.contains(&addr) {
+            return;
+        }
+        let registers = registers.iter().map(u16::to_string).collect::<Vec<_>>().join(" ");
+        writeln!(self.output, "{:>5}: {:<24} [{}] sp={}", addr, op.to_string(), registers, stack_depth)
+            .expect("Could not write trace");
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Counts how often every address is executed and how often each function calls another.
+///
+/// Functions are identified by the address they were called at, code running outside of any
+/// call is attributed to the entry point 0.
+pub struct Profiler {
+    counts: Vec<u64>,
+    calls: HashMap<(usize, usize), u64>,
+    call_stack: Vec<usize>,
+    self_counts: HashMap<usize, u64>,
+}
+
+impl Profiler {
+    pub fn new(memory_size: usize) -> Self {
+        Self {
+            counts: vec![0; memory_size],
+            calls: HashMap::new(),
+            call_stack: Vec::new(),
+            self_counts: HashMap::new(),
+        }
+    }
+
+    /// Count `op` being executed at `addr`, `call_target` is where a `call` is about to jump to.
+    pub(crate) fn record(&mut self, addr: usize, op: &Operation, call_target: Option<usize>) {
+        self.counts[addr] += 1;
+        let function = self.current_function();
+        *self.self_counts.entry(function).or_default() += 1;
+        if let Some(target) = call_target {
+            *self.calls.entry((function, target)).or_default() += 1;
+            self.call_stack.push(target);
+        } else if let Operation::Ret = op {
+            self.call_stack.pop();
+        }
+    }
+
+    pub fn count(&self, addr: usize) -> u64 {
+        self.counts[addr]
+    }
+
+    pub fn call_count(&self, caller: usize, callee: usize) -> u64 {
+        self.calls.get(&(caller, callee)).copied().unwrap_or(0)
+    }
+
+    fn current_function(&self) -> usize {
+        self.call_stack.last().copied().unwrap_or(0)
+    }
+
+    /// Text report of the `top` hottest addresses and functions, and every call graph edge.
+    pub fn report(&self, top: usize) -> String {
+        let total = self.counts.iter().sum::<u64>().max(1);
+        let percent = |count: u64| count as f64 * 100.0 / total as f64;
+        let mut out = String::new();
+
+        writeln!(out, "{} instructions executed", total).unwrap();
+        writeln!(out, "\nhot addresses").unwrap();
+        let mut hot = self.counts.iter().enumerate().filter(|(_, c)| **c > 0).collect::<Vec<_>>();
+        hot.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(&b.0)));
+        for (addr, count) in hot.into_iter().take(top) {
+            writeln!(out, "{:>7} {:>12} {:>6.2}%", addr, count, percent(*count)).unwrap();
+        }
+
+        writeln!(out, "\nhot functions (self)").unwrap();
+        let mut functions = self.self_counts.iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (function, count) in functions.into_iter().take(top) {
+            writeln!(out, "{:>7} {:>12} {:>6.2}%", function, count, percent(*count)).unwrap();
+        }
+
+        writeln!(out, "\ncall graph").unwrap();
+        let mut calls = self.calls.iter().collect::<Vec<_>>();
+        calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for ((caller, callee), count) in calls {
+            writeln!(out, "{:>7} -> {:<7} {:>12}", caller, callee, count).unwrap();
+        }
+        out
+    }
+}
+
+/// Parse an address range written as `start-end`, both ends included.
+pub fn parse_range(s: &str) -> Option<RangeInclusive<usize>> {
+    let (start, end) = s.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end).then_some(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::synacorvm::assembler::{assemble, to_bytes};
+    use crate::synacorvm::virtual_machine::VirtualMachine;
+    use super::*;
+
+    const CALL_LOOP: &str = "
+            set   r0, 3         ; 0
+        loop:
+            call  f             ; 3
+            add   r0, r0, 32767 ; 5
+            jt    r0, loop      ; 9
+            halt                ; 12
+        f:  ret                 ; 13
+    ";
+
+    fn machine() -> VirtualMachine {
+        let mut machine = VirtualMachine::default();
+        machine.load_program_from_bytes(&to_bytes(&assemble(CALL_LOOP).unwrap()));
+        machine
+    }
+
+    #[test]
+    fn profiles_addresses_and_calls() {
+        let mut machine = machine();
+        machine.enable_profiler();
+        machine.run().unwrap();
+        let profiler = machine.profiler().unwrap();
+        assert_eq!(profiler.count(0), 1);
+        assert_eq!(profiler.count(3), 3);
+        assert_eq!(profiler.count(13), 3);
+        assert_eq!(profiler.call_count(0, 13), 3);
+        assert!(profiler.report(5).contains("      0 -> 13                 3"));
+    }
+
+    #[test]
+    fn traces_only_addresses_in_range() {
+        let path = std::env::temp_dir().join(format!("synacor-trace-{}", std::process::id()));
+        let mut machine = machine();
+        machine.set_tracer(Some(Tracer::new(fs::File::create(&path).unwrap(), 9..=12)));
+        machine.run().unwrap();
+        machine.set_tracer(None);
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines = trace.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "    9: jt r0, 3                 [2 0 0 0 0 0 0 0] sp=0");
+        assert!(lines[3].starts_with("   12: halt"));
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("6027-6067"), Some(6027..=6067));
+        assert_eq!(parse_range("10-1"), None);
+        assert_eq!(parse_range("10"), None);
+    }
+}
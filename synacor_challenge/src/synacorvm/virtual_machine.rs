@@ -1,26 +1,35 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::io;
+use std::collections::VecDeque;
+use crate::synacorvm::blocks::{BlockCache, Cpu};
+use crate::synacorvm::console::{Console, StdConsole};
 use crate::synacorvm::operations;
-use crate::synacorvm::operations::{Operand, Operation};
+use crate::synacorvm::operations::{Error, Operand, Operation};
+use crate::synacorvm::trace::{Profiler, Tracer};
 
-struct State {
-    instruction_counter: usize,
-    running: bool,
-    registers: [u16; 8],
-    memory: Vec<u16>,
-    stack: Vec<u16>,
-    input: VecDeque<u16>,
+/// Snapshot of everything needed to resume a machine from the same point.
+pub struct State {
+    pub(crate) instruction_counter: usize,
+    pub(crate) running: bool,
+    pub(crate) registers: [u16; 8],
+    pub(crate) memory: Vec<u16>,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) input: VecDeque<u16>,
 }
 
-pub struct VirtualMachine {
+/// Longest instruction: an opcode and three operands.
+const MAX_INSTR_LEN: usize = 4;
+
+pub struct VirtualMachine<C = StdConsole> {
     instruction_counter: usize,
     running: bool,
     registers: [u16; 8],
     memory: Vec<u16>,
     stack: Vec<u16>,
     input: VecDeque<u16>,
-    states: Vec<State>,
-    commands: Vec<String>,
+    console: C,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    decoded: Option<Vec<Option<Operation>>>,
+    blocks: Option<BlockCache>,
 }
 
 impl Default for VirtualMachine {
@@ -31,78 +40,135 @@ impl Default for VirtualMachine {
 
 impl VirtualMachine {
     pub fn new(memory: usize, starting_stack: usize) -> Self {
+        Self::with_console(memory, starting_stack, StdConsole)
+    }
+}
+
+impl<C: Console> VirtualMachine<C> {
+    pub fn with_console(memory: usize, starting_stack: usize, console: C) -> Self {
         Self {
             instruction_counter: 0,
             registers: [0; 8],
             running: false,
             memory: vec![0; memory],
-            stack: Vec::new(),
+            stack: Vec::with_capacity(starting_stack),
             input: VecDeque::new(),
-            states: Vec::new(),
-            commands: Vec::new(),
+            console,
+            tracer: None,
+            profiler: None,
+            decoded: None,
+            blocks: None,
         }
     }
 
     pub fn load_program_from_bytes(&mut self, data: &[u8]) {
         assert_eq!(data.len() % 2, 0);
-        println!("Loading program bytes: {}", data.len());
         let mut i = 0;
         while i * 2 < data.len() {
             self.memory[i] = as_u16_le(&data[i * 2..i * 2 + 2]);
             i += 1;
         }
-        println!("Success {}", self.memory[6147]);
-        let x = 16357;
-        let s = self.memory[x..(x+1000).min(self.memory.len())]
-            .iter()
-            .map(|x| char::from_u32(*x as u32).unwrap())
-            .collect::<String>();
-        println!("DATA AT: {} : {}", x, s);
+        self.clear_decode_cache();
     }
 
     pub fn run(&mut self) -> operations::Result<()> {
-        self.instruction_counter = 0;
-        self.running = true;
+        self.reset();
         while self.running {
+            if self.blocks.is_some() {
+                self.run_for(usize::MAX)?;
+                if !self.running {
+                    break;
+                }
+            }
             self.step()?;
         }
         Ok(())
     }
 
+    /// Run until the machine stops, wants input that is not queued yet, or has executed `limit`
+    /// instructions, returning how many it executed. Goes through compiled blocks when the block
+    /// compiler is enabled and nothing is tracing or profiling.
+    pub fn run_for(&mut self, limit: usize) -> operations::Result<usize> {
+        let mut executed = 0;
+        while self.running && executed < limit {
+            if self.tracer.is_none() && self.profiler.is_none() {
+                if let Some(blocks) = &mut self.blocks {
+                    let mut cpu = Cpu {
+                        instruction_counter: &mut self.instruction_counter,
+                        running: &mut self.running,
+                        registers: &mut self.registers,
+                        memory: &mut self.memory,
+                        stack: &mut self.stack,
+                        console: &mut self.console,
+                    };
+                    let ran = blocks.run(&mut cpu, limit - executed);
+                    executed += ran;
+                    for addr in blocks.written() {
+                        if let Some(decoded) = &mut self.decoded {
+                            forget_decoded(decoded, addr);
+                        }
+                    }
+                    if ran > 0 {
+                        continue;
+                    }
+                }
+            }
+            if !self.running || executed == limit {
+                break;
+            }
+            // As `waiting_for_input`, but through the decode cache
+            if self.input.is_empty() && matches!(self.fetch(), Ok(Operation::In { .. })) {
+                break;
+            }
+            self.step()?;
+            executed += 1;
+        }
+        Ok(executed)
+    }
+
+    /// Point the machine back at the entry point without touching memory.
+    pub fn reset(&mut self) {
+        self.instruction_counter = 0;
+        self.running = true;
+    }
+
     pub fn step(&mut self) -> operations::Result<()> {
         let mut jumped = false;
-        let op = Operation::from(&self.memory[self.instruction_counter..])?;
-        // Used to escape deadlock in challenge binary
-        if self.instruction_counter == 6072 {
-            self.registers[0] = 0;
-            self.registers[1] = 5;
+        let op = self.fetch()?;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(self.instruction_counter, &op, &self.registers, self.stack.len());
+        }
+        if self.profiler.is_some() {
+            let call_target = match &op {
+                Operation::Call { tgt } => Some(self.value_of(tgt) as usize),
+                _ => None,
+            };
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(self.instruction_counter, &op, call_target);
+            }
         }
         match &op {
             Operation::Halt => {
-                if !self.pop_state() {
-                    self.push_state();
-                    println!("Shutting down");
-                    self.running = false;
-                }
+                self.running = false;
                 jumped = true;
             }
             Operation::Set { dst, src } => {
-                self.set_register(dst, self.value_of(src))
+                self.set_register(&op, dst, self.value_of(src))?
             }
             Operation::Push { src } => {
                 self.push_stack(self.value_of(src))
             }
             Operation::Pop { dst } => {
-                let value = self.pop_stack().expect("Stack shouldn't be empty on pop");
-                self.set_register(dst, value);
+                let value = self.pop_stack().ok_or(Error::EmptyStack { addr: self.instruction_counter, op })?;
+                self.set_register(&op, dst, value)?;
             }
             Operation::Eq { dst, lhs, rhs } => {
                 let result = if self.value_of(lhs) == self.value_of(rhs) { 1 } else { 0 };
-                self.set_register(dst, result);
+                self.set_register(&op, dst, result)?;
             }
             Operation::Gt { dst, lhs, rhs } => {
                 let result = if self.value_of(lhs) > self.value_of(rhs) { 1 } else { 0 };
-                self.set_register(dst, result);
+                self.set_register(&op, dst, result)?;
             }
             Operation::Jmp { tgt } => {
                 jumped = true;
@@ -121,38 +187,51 @@ impl VirtualMachine {
                 }
             }
             Operation::Add { dst, lhs, rhs } => {
-                let result = self.value_of(lhs) + self.value_of(rhs);
-                self.set_register(dst, result % 32768);
+                let result = self.value_of(lhs) as u32 + self.value_of(rhs) as u32;
+                self.set_register(&op, dst, (result % 32768) as u16)?;
             }
             Operation::Mult { dst, lhs, rhs } => {
                 let result = self.value_of(lhs) as u32 * self.value_of(rhs) as u32;
-                self.set_register(dst, (result % 32768) as u16);
+                self.set_register(&op, dst, (result % 32768) as u16)?;
             }
             Operation::Mod { dst, lhs, rhs } => {
-                let result = self.value_of(lhs) % self.value_of(rhs);
-                self.set_register(dst, result % 32768);
+                let result = self.value_of(lhs)
+                    .checked_rem(self.value_of(rhs))
+                    .ok_or(Error::DivisionByZero { addr: self.instruction_counter, op })?;
+                self.set_register(&op, dst, result % 32768)?;
             }
             Operation::And { dst, lhs, rhs } => {
                 let result = self.value_of(lhs) & self.value_of(rhs);
-                self.set_register(dst, result);
+                self.set_register(&op, dst, result)?;
             }
             Operation::Or { dst, lhs, rhs } => {
                 let result = self.value_of(lhs) | self.value_of(rhs);
-                self.set_register(dst, result);
+                self.set_register(&op, dst, result)?;
             }
             Operation::Not { dst, src } => {
                 let result = !self.value_of(src) & 0b0111111111111111;
-                self.set_register(dst, result);
+                self.set_register(&op, dst, result)?;
             }
             Operation::Rmem { dst, src } => {
                 let addr = self.value_of(src);
-                let val = self.memory[addr as usize];
-                self.set_register(dst, val);
+                let val = *self.memory.get(addr as usize).ok_or(Error::MemoryOutOfBounds {
+                    addr: self.instruction_counter,
+                    op,
+                    target: addr,
+                })?;
+                self.set_register(&op, dst, val)?;
             }
             Operation::Wmem { dst, src } => {
                 let val = self.value_of(src);
                 let addr = self.value_of(dst);
-                self.memory[addr as usize] = val;
+                let ic = self.instruction_counter;
+                let cell = self.memory.get_mut(addr as usize).ok_or(Error::MemoryOutOfBounds {
+                    addr: ic,
+                    op,
+                    target: addr,
+                })?;
+                *cell = val;
+                self.invalidate_decoded(addr as usize);
             }
             Operation::Call { tgt } => {
                 self.push_stack(self.instruction_counter as u16 + 2);
@@ -162,7 +241,6 @@ impl VirtualMachine {
             Operation::Ret => {
                 match self.pop_stack() {
                     None => {
-                        println!("halted: stack is empty");
                         self.running = false;
                     }
                     Some(addr) => {
@@ -172,14 +250,21 @@ impl VirtualMachine {
                 }
             }
             Operation::In { dst } => {
-                let value = self.read_input();
-                self.set_register(dst, value);
+                match self.read_input() {
+                    Some(value) => self.set_register(&op, dst, value)?,
+                    None => {
+                        // Input is exhausted, nothing more can happen
+                        self.running = false;
+                        jumped = true;
+                    }
+                }
             }
             Operation::Out { src } => {
                 let c = self.value_of(src);
-                let o = char::from_u32(c as u32)
-                    .expect("This should be a valid ascii value");
-                print!("{}", o);
+                if c > 127 {
+                    return Err(Error::InvalidCharacter { addr: self.instruction_counter, op, value: c });
+                }
+                self.console.write_char(c as u8 as char);
             }
             Operation::Noop => { /* Do Nothing */ }
         }
@@ -189,10 +274,13 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn set_register(&mut self, tgt: &Operand, value: u16) {
+    fn set_register(&mut self, op: &Operation, tgt: &Operand, value: u16) -> operations::Result<()> {
         match tgt {
-            Operand::Literal { .. } => panic!("Invalid operation: Cannot set value of literal"),
-            Operand::Reg { index } => self.registers[*index as usize] = value,
+            Operand::Literal { .. } => Err(Error::LiteralDestination { addr: self.instruction_counter, op: *op }),
+            Operand::Reg { index } => {
+                self.registers[*index as usize] = value;
+                Ok(())
+            }
         }
     }
 
@@ -217,82 +305,163 @@ impl VirtualMachine {
         self.instruction_counter = self.value_of(target) as usize;
     }
 
-    fn incr_instruction(&mut self) {
-        self.instruction_counter += 1;
+    /// Decode the current instruction, going through the decode cache when it is enabled.
+    fn fetch(&mut self) -> operations::Result<Operation> {
+        let ic = self.instruction_counter;
+        if let Some(Some(op)) = self.decoded.as_ref().and_then(|decoded| decoded.get(ic)) {
+            return Ok(*op);
+        }
+        let op = Operation::decode(&self.memory, ic)?;
+        if let Some(decoded) = &mut self.decoded {
+            decoded[ic] = Some(op);
+        }
+        Ok(op)
     }
 
-    fn read_input(&mut self) -> u16 {
-        if let Some(c) = self.input.pop_front() {
-            return c;
+    /// Forget every cached instruction and compiled block that the word at `addr` is part of.
+    fn invalidate_decoded(&mut self, addr: usize) {
+        if let Some(decoded) = &mut self.decoded {
+            forget_decoded(decoded, addr);
         }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.invalidate(addr);
+        }
+    }
 
-        let mut buffer = String::new();
-        io::stdin().read_line(&mut buffer).expect("Could not read stdin");
-        if buffer != "commands\n" {
-            self.commands.push(buffer.clone());
+    fn clear_decode_cache(&mut self) {
+        if let Some(decoded) = &mut self.decoded {
+            decoded.fill(None);
         }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.clear();
+        }
+    }
 
-        match buffer.as_str() {
-            "save\n" => {
-                self.push_state();
-                buffer = "look\n".into();
-            }
-            "load\n" => {
-                self.pop_state();
-                buffer = "look\n".into();
-            }
-            "commands\n" => {
-                println!("START COMMANDS");
-                for s in &self.commands {
-                    print!("{}", s);
-                }
-                println!("END COMMANDS");
-            }
-            "reg8\n" => {
-                self.registers[7] = 25734;
-                println!("Buffer set");
-            }
-            e => {}
+    fn read_input(&mut self) -> Option<u16> {
+        if self.input.is_empty() {
+            let line = self.console.read_line()?;
+            self.push_input(&line);
         }
-        buffer.bytes().for_each(|b| self.input.push_back(b as u16));
-        self.input.pop_front().expect("Input should not be empty")
+        self.input.pop_front()
     }
 
-    fn push_state(&mut self) {
-        let state = State {
+    pub fn snapshot(&self) -> State {
+        State {
             instruction_counter: self.instruction_counter,
             running: self.running,
-            registers: self.registers.clone(),
+            registers: self.registers,
             memory: self.memory.clone(),
             stack: self.stack.clone(),
             input: self.input.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: State) {
+        self.instruction_counter = state.instruction_counter;
+        self.running = state.running;
+        self.registers = state.registers;
+        self.memory = state.memory;
+        self.stack = state.stack;
+        self.input = state.input;
+        self.clear_decode_cache();
+    }
+
+    pub fn current_operation(&self) -> operations::Result<Operation> {
+        Operation::decode(&self.memory, self.instruction_counter)
+    }
+
+    pub fn instruction_counter(&self) -> usize {
+        self.instruction_counter
+    }
+
+    pub fn set_instruction_counter(&mut self, addr: usize) {
+        self.instruction_counter = addr;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn registers(&self) -> &[u16; 8] {
+        &self.registers
+    }
+
+    pub fn set_register_value(&mut self, index: usize, value: u16) {
+        self.registers[index] = value;
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn memory(&self) -> &[u16] {
+        &self.memory
+    }
+
+    /// Write `value` into memory at `addr`, returning false when that is outside of memory.
+    pub fn write_memory(&mut self, addr: usize, value: u16) -> bool {
+        let Some(word) = self.memory.get_mut(addr) else {
+            return false;
         };
-        self.states.push(state);
-        println!("State saved: {}", self.states.len());
-    }
-
-    fn pop_state(&mut self) -> bool {
-        match self.states.pop() {
-            None => {
-                println!("No saved states to revert to");
-                false
-            }
-            Some(s) => {
-                self.instruction_counter = s.instruction_counter;
-                self.running = s.running;
-                self.registers = s.registers;
-                self.memory = s.memory;
-                self.stack = s.stack;
-                self.input = s.input;
-                println!("loaded states: states left : {}", self.states.len());
-                true
-            }
+        *word = value;
+        self.invalidate_decoded(addr);
+        true
+    }
+
+    /// Cache instructions once they are decoded instead of decoding them every time they run.
+    /// Writes into memory drop the cached instructions they overlap, so self-modifying code
+    /// still behaves.
+    pub fn enable_decode_cache(&mut self) {
+        self.decoded = Some(vec![None; self.memory.len()]);
+    }
+
+    /// Compile straight-line code into blocks with their operands already resolved, and run
+    /// those from `run` and `run_for` instead of decoding one instruction at a time. Like the
+    /// decode cache, writes into memory drop the blocks compiled from what they overwrite.
+    pub fn enable_block_compiler(&mut self) {
+        self.blocks = Some(BlockCache::new(self.memory.len()));
+    }
+
+    /// Log every executed instruction through `tracer`, or stop tracing with `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        if let Some(mut old) = std::mem::replace(&mut self.tracer, tracer) {
+            old.flush().expect("Could not flush trace");
         }
     }
 
-    pub fn dump_instructions(&self, start: usize) {
+    /// Start counting executed instructions and calls, discarding any earlier profile.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new(self.memory.len()));
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    /// Queue a line of game input, as if it had been typed at the game prompt.
+    pub fn push_input(&mut self, line: &str) {
+        line.bytes().for_each(|b| self.input.push_back(b as u16));
+    }
+
+    /// True when the next instruction is `in` and there is nothing queued to satisfy it.
+    pub fn waiting_for_input(&self) -> bool {
+        self.input.is_empty() && matches!(self.current_operation(), Ok(Operation::In { .. }))
+    }
+
+    pub fn dump_instructions(&self, start: usize, count: usize) {
         let mut i = start;
-        while i < self.memory.len() {
+        for _ in 0..count {
+            if i >= self.memory.len() {
+                break;
+            }
             if let Ok(up) = operations::print_op(i, &self.memory[i..]) {
                 i += up
             } else {
@@ -302,6 +471,242 @@ impl VirtualMachine {
     }
 }
 
+/// Forget every cached instruction that the word at `addr` is part of.
+fn forget_decoded(decoded: &mut [Option<Operation>], addr: usize) {
+    let start = addr.saturating_sub(MAX_INSTR_LEN - 1);
+    decoded[start..=addr].fill(None);
+}
+
 fn as_u16_le(data: &[u8]) -> u16 {
     data[0] as u16 | ((data[1] as u16) << 8)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::synacorvm::console::StreamConsole;
+    use super::*;
+
+    fn machine_with_program(program: &[u16], input: &'static str) -> VirtualMachine<StreamConsole<&'static [u8], Vec<u8>>> {
+        let bytes = program.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+        let console = StreamConsole::new(input.as_bytes(), Vec::new());
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 16, console);
+        machine.load_program_from_bytes(&bytes);
+        machine
+    }
+
+    #[test]
+    fn runs_spec_example_program() {
+        // set r1 61; add r0 r1 4; out r0; halt
+        let mut machine = machine_with_program(&[1, 32769, 61, 9, 32768, 32769, 4, 19, 32768, 0], "");
+        machine.run().unwrap();
+        assert_eq!(machine.console().output(), b"A");
+    }
+
+    #[test]
+    fn echoes_input_through_console() {
+        // in r0; out r0; jmp 0
+        let mut machine = machine_with_program(&[20, 32768, 19, 32768, 6, 0], "hi\nthere");
+        machine.run().unwrap();
+        assert_eq!(machine.console().output(), b"hi\nthere\n");
+    }
+
+    fn fault(program: &[u16]) -> Error {
+        fault_in(32, program)
+    }
+
+    /// Run `program` in a machine with only `memory` words of memory, checking the block compiler
+    /// stops on the same fault.
+    fn fault_in(memory: usize, program: &[u16]) -> Error {
+        let bytes = program.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+        let run = |compiled: bool| {
+            let mut machine = VirtualMachine::with_console(memory, 16, StreamConsole::new("".as_bytes(), Vec::new()));
+            machine.load_program_from_bytes(&bytes);
+            if compiled {
+                machine.enable_block_compiler();
+            }
+            let error = machine.run().unwrap_err();
+            (error, machine.instruction_counter(), *machine.registers(), machine.console().output().clone())
+        };
+        let interpreted = run(false);
+        assert_eq!(run(true), interpreted);
+        interpreted.0
+    }
+
+    fn lit(value: u16) -> Operand {
+        Operand::Literal { value }
+    }
+
+    const R0: Operand = Operand::Reg { index: 0 };
+
+    #[test]
+    fn faults_on_malformed_instructions() {
+        assert_eq!(fault(&[21, 22]), Error::UnknownOpcode { addr: 1, code: 22 });
+        assert_eq!(fault(&[21, 19, 40000]), Error::OperandValueToHigh { addr: 1, value: 40000 });
+        assert_eq!(fault(&[6, 40]), Error::InstructionOutOfBounds { addr: 40 });
+        let mut words = vec![21; 31];
+        words.push(9);
+        assert_eq!(fault(&words), Error::TruncatedInstruction { addr: 31, code: 9 });
+    }
+
+    #[test]
+    fn faults_on_writes_into_literals() {
+        let op = Operation::Set { dst: lit(5), src: lit(1) };
+        assert_eq!(fault(&[1, 5, 1]), Error::LiteralDestination { addr: 0, op });
+        let op = Operation::Add { dst: lit(5), lhs: lit(1), rhs: lit(1) };
+        assert_eq!(fault(&[21, 9, 5, 1, 1]), Error::LiteralDestination { addr: 1, op });
+    }
+
+    #[test]
+    fn faults_on_pop_from_empty_stack() {
+        let op = Operation::Pop { dst: R0 };
+        assert_eq!(fault(&[2, 1, 3, 32768, 3, 32768]), Error::EmptyStack { addr: 4, op });
+    }
+
+    #[test]
+    fn faults_on_output_that_isnt_ascii() {
+        let op = Operation::Out { src: R0 };
+        assert_eq!(fault(&[1, 32768, 200, 19, 32768]), Error::InvalidCharacter { addr: 3, op, value: 200 });
+    }
+
+    #[test]
+    fn faults_on_memory_access_outside_of_memory() {
+        let op = Operation::Rmem { dst: R0, src: lit(100) };
+        assert_eq!(fault(&[15, 32768, 100]), Error::MemoryOutOfBounds { addr: 0, op, target: 100 });
+        let op = Operation::Wmem { dst: lit(100), src: lit(1) };
+        assert_eq!(fault(&[16, 100, 1]), Error::MemoryOutOfBounds { addr: 0, op, target: 100 });
+    }
+
+    #[test]
+    fn faults_on_mod_by_zero() {
+        let op = Operation::Mod { dst: R0, lhs: lit(7), rhs: lit(0) };
+        assert_eq!(fault(&[11, 32768, 7, 0]), Error::DivisionByZero { addr: 0, op });
+    }
+
+    #[test]
+    fn fault_leaves_instruction_counter_on_faulting_instruction() {
+        let mut machine = machine_with_program(&[21, 3, 32768], "");
+        machine.reset();
+        machine.step().unwrap();
+        assert!(machine.step().is_err());
+        assert_eq!(machine.instruction_counter(), 1);
+        assert!(machine.is_running());
+    }
+
+    #[test]
+    fn decode_cache_sees_self_modifying_code() {
+        // out 'A'; wmem 1 'B'; jt r1 13; set r1 1; jmp 0; halt
+        let program = [19, 65, 16, 1, 66, 7, 32769, 13, 1, 32769, 1, 6, 0, 0];
+        let mut machine = machine_with_program(&program, "");
+        machine.enable_decode_cache();
+        machine.run().unwrap();
+        assert_eq!(machine.console().output(), b"AB");
+
+        // out 'A'; wmem 0 21; jmp 0, turning the out into a noop followed by 'A' as an opcode
+        let mut machine = machine_with_program(&[19, 65, 16, 0, 21, 6, 0], "");
+        machine.enable_decode_cache();
+        assert_eq!(machine.run(), Err(Error::UnknownOpcode { addr: 1, code: 65 }));
+        assert_eq!(machine.console().output(), b"A");
+    }
+
+    #[test]
+    fn decode_cache_runs_challenge_like_interpreter() {
+        let boot = |cached: bool| {
+            let console = StreamConsole::new("take tablet\nuse tablet\n".as_bytes(), Vec::new());
+            let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, console);
+            machine.load_program_from_bytes(include_bytes!("../../spec/challenge.bin"));
+            if cached {
+                machine.enable_decode_cache();
+            }
+            machine.run().unwrap();
+            machine.console().output().clone()
+        };
+        assert_eq!(boot(true), boot(false));
+    }
+
+    #[test]
+    fn block_compiler_sees_self_modifying_code() {
+        // out 'A'; wmem 1 'B'; jt r1 13; set r1 1; jmp 0; halt
+        let program = [19, 65, 16, 1, 66, 7, 32769, 13, 1, 32769, 1, 6, 0, 0];
+        let mut machine = machine_with_program(&program, "");
+        machine.enable_block_compiler();
+        machine.run().unwrap();
+        assert_eq!(machine.console().output(), b"AB");
+
+        // out 'A'; wmem 0 21; jmp 0, turning the out into a noop followed by 'A' as an opcode
+        let mut machine = machine_with_program(&[19, 65, 16, 0, 21, 6, 0], "");
+        machine.enable_block_compiler();
+        assert_eq!(machine.run(), Err(Error::UnknownOpcode { addr: 1, code: 65 }));
+        assert_eq!(machine.console().output(), b"A");
+    }
+
+    #[test]
+    fn block_compiler_runs_challenge_like_interpreter() {
+        let boot = |compiled: bool| {
+            let console = StreamConsole::new("take tablet\nuse tablet\nlook\n".as_bytes(), Vec::new());
+            let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, console);
+            machine.load_program_from_bytes(include_bytes!("../../spec/challenge.bin"));
+            if compiled {
+                machine.enable_block_compiler();
+            }
+            machine.run().unwrap();
+            (machine.console().output().clone(), *machine.registers(), machine.stack().to_vec(), machine.memory().to_vec())
+        };
+        assert_eq!(boot(true), boot(false));
+    }
+
+    /// The challenge at its first prompt, about to run the teleporter confirmation with the
+    /// eighth register set.
+    fn confirming(compiled: bool) -> VirtualMachine<StreamConsole<&'static [u8], Vec<u8>>> {
+        let mut machine = VirtualMachine::with_console(2_usize.pow(15), 1000, StreamConsole::new("".as_bytes(), Vec::new()));
+        machine.load_program_from_bytes(include_bytes!("../../spec/challenge.bin"));
+        if compiled {
+            machine.enable_block_compiler();
+        }
+        machine.reset();
+        machine.run_for(usize::MAX).unwrap();
+        assert!(machine.waiting_for_input());
+        machine.set_register_value(0, 4);
+        machine.set_register_value(1, 1);
+        machine.set_register_value(7, 1);
+        machine.set_instruction_counter(6049);
+        machine
+    }
+
+    #[test]
+    fn run_for_stops_after_exactly_limit_instructions() {
+        let mut interpreted = confirming(false);
+        let mut compiled = confirming(true);
+        for limit in [1, 7, 64, 1000, 123_457] {
+            for _ in 0..limit {
+                interpreted.step().unwrap();
+            }
+            assert_eq!(compiled.run_for(limit), Ok(limit));
+            assert_eq!(compiled.instruction_counter(), interpreted.instruction_counter());
+            assert_eq!(compiled.registers(), interpreted.registers());
+            assert_eq!(compiled.stack(), interpreted.stack());
+        }
+    }
+
+    #[test]
+    fn run_for_stops_at_halt_and_when_waiting_for_input() {
+        // out 'A'; in r0; out r0; halt
+        let mut machine = machine_with_program(&[19, 65, 20, 32768, 19, 32768, 0], "");
+        machine.enable_block_compiler();
+        machine.reset();
+        assert_eq!(machine.run_for(100), Ok(1));
+        assert!(machine.waiting_for_input());
+        machine.push_input("B");
+        assert_eq!(machine.run_for(100), Ok(3));
+        assert!(!machine.is_running());
+        assert_eq!(machine.instruction_counter(), 6);
+        assert_eq!(machine.console().output(), b"AB");
+    }
+
+    #[test]
+    fn arithmetic_wraps_even_with_out_of_range_register_values() {
+        // rmem r0 8; add r1 r0 r0; halt; .word 65535
+        let mut machine = machine_with_program(&[15, 32768, 8, 9, 32769, 32768, 32768, 0, 65535], "");
+        machine.run().unwrap();
+        assert_eq!(machine.registers()[1], (65535u32 * 2 % 32768) as u16);
+    }
+}